@@ -0,0 +1,196 @@
+use crate::annotation::{self, AccuracyReport, AnnotationKind, AnnotationSettings};
+use crate::engine::EngineConfig;
+use crate::errors::Error;
+use crate::game::Game;
+use crate::pgn::{PgnGame, PgnReader};
+use crate::state::StateHandle;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Parameters of the `analyze` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalyzeConfig {
+    pub engine: String,
+    pub movetime_ms: u64,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// Games annotated at the same time, each by its own engine process.
+    pub threads: usize,
+}
+
+/// What became of a game of the input file.
+struct Row {
+    white: String,
+    black: String,
+    /// Written to the output, annotated or as it was read if its annotation failed.
+    pgn: Option<PgnGame>,
+    outcome: Result<AccuracyReport, Error>,
+}
+
+/// Annotates every game of the input file into the output file, reporting progress and a summary
+/// on stderr. Returns false if any game failed.
+pub async fn run(config: AnalyzeConfig) -> Result<bool, Error> {
+    let input = BufReader::new(File::open(&config.input)?);
+    let games: Vec<Result<PgnGame, Error>> = PgnReader::new(input).collect();
+    let total = games.len();
+
+    let state = StateHandle::default();
+    let threads = config.threads.clamp(1, total.max(1));
+    for worker in 0..threads {
+        let engine = EngineConfig::new(&config.engine);
+        state
+            .add_engine(&format!("engine-{}", worker + 1), engine)
+            .await?;
+    }
+
+    // Each worker takes the next game once done with the previous one
+    let queue = Arc::new(Mutex::new(games.into_iter().enumerate()));
+    let workers = (0..threads).map(|worker| {
+        let state = state.clone();
+        let queue = queue.clone();
+        let settings = AnnotationSettings {
+            engine_id: format!("engine-{}", worker + 1),
+            movetime_per_ply_ms: config.movetime_ms,
+            kind: AnnotationKind::Full,
+        };
+        tokio::spawn(async move {
+            let mut rows = Vec::new();
+            loop {
+                let next = queue.lock()?.next();
+                let (index, game) = match next {
+                    Some(next) => next,
+                    None => return Ok::<_, Error>(rows),
+                };
+                let row = annotate_game(&state, index + 1, game, &settings).await;
+                eprintln!("[{}/{}] {}", index + 1, total, describe(&row));
+                rows.push((index, row));
+            }
+        })
+    });
+
+    let mut rows = Vec::with_capacity(total);
+    for worker in workers.collect::<Vec<_>>() {
+        rows.extend(worker.await.expect("annotation worker panicked")?);
+    }
+    rows.sort_by_key(|(index, _)| *index);
+    let _ = state.shutdown().await;
+
+    let mut output = BufWriter::new(File::create(&config.output)?);
+    for (_, row) in &rows {
+        if let Some(pgn) = &row.pgn {
+            pgn.write(&mut output)?;
+        }
+    }
+    output.flush()?;
+
+    print_summary(&rows);
+    Ok(rows.iter().all(|(_, row)| row.outcome.is_ok()))
+}
+
+async fn annotate_game(
+    state: &StateHandle,
+    number: usize,
+    pgn: Result<PgnGame, Error>,
+    settings: &AnnotationSettings,
+) -> Row {
+    let pgn = match pgn {
+        Ok(pgn) => pgn,
+        Err(err) => {
+            return Row {
+                white: String::from("?"),
+                black: String::from("?"),
+                pgn: None,
+                outcome: Err(err),
+            }
+        }
+    };
+    let white = pgn.header("White").unwrap_or("?").to_string();
+    let black = pgn.header("Black").unwrap_or("?").to_string();
+
+    let id = format!("game-{}", number);
+    let annotated = async {
+        state.open_game(&id, Game::from_pgn(&pgn)?)?;
+        let report = annotation::annotate(state, &id, settings).await?;
+        let annotated = state.with_game(&id, |game| Ok(game.to_pgn()))?;
+        Ok((report, annotated))
+    };
+    let outcome = annotated.await;
+    let _ = state.close_game(&id);
+
+    match outcome {
+        Ok((report, annotated)) => Row {
+            white,
+            black,
+            pgn: Some(annotated),
+            outcome: Ok(report),
+        },
+        Err(err) => Row {
+            white,
+            black,
+            pgn: Some(pgn),
+            outcome: Err(err),
+        },
+    }
+}
+
+fn describe(row: &Row) -> String {
+    let outcome = match &row.outcome {
+        Ok(report) => format!(
+            "accuracy {} / {}",
+            percent(report.white.accuracy),
+            percent(report.black.accuracy)
+        ),
+        Err(err) => format!("failed: {}", err),
+    };
+    format!("{} - {}: {}", row.white, row.black, outcome)
+}
+
+fn percent(accuracy: Option<f64>) -> String {
+    accuracy.map_or(String::from("-"), |accuracy| format!("{:.1}%", accuracy))
+}
+
+fn loss(acpl: Option<u32>) -> String {
+    acpl.map_or(String::from("-"), |acpl| acpl.to_string())
+}
+
+fn print_summary(rows: &[(usize, Row)]) {
+    eprintln!();
+    eprintln!(
+        "{:>5}  {:<20}  {:<20}  {:>17}  {:>11}",
+        "Game", "White", "Black", "Accuracy", "ACPL"
+    );
+    for (index, row) in rows {
+        let (accuracy, acpl) = match &row.outcome {
+            Ok(report) => (
+                format!(
+                    "{} / {}",
+                    percent(report.white.accuracy),
+                    percent(report.black.accuracy)
+                ),
+                format!(
+                    "{} / {}",
+                    loss(report.white.average_centipawn_loss),
+                    loss(report.black.average_centipawn_loss)
+                ),
+            ),
+            Err(_) => (String::from("failed"), String::new()),
+        };
+        eprintln!(
+            "{:>5}  {:<20.20}  {:<20.20}  {:>17}  {:>11}",
+            index + 1,
+            row.white,
+            row.black,
+            accuracy,
+            acpl
+        );
+    }
+    let failed = rows.iter().filter(|(_, row)| row.outcome.is_err()).count();
+    eprintln!(
+        "\n{} games annotated, {} failed",
+        rows.len() - failed,
+        failed
+    );
+}
@@ -0,0 +1,562 @@
+use crate::api::{
+    response_from_error, response_from_game, response_from_notification, Notification,
+};
+use crate::engine::position_command;
+use crate::errors::Error;
+use crate::game::{Annotation, Evaluation, Score, Wdl};
+use crate::jobs::JobTicket;
+use crate::state::StateHandle;
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{Position, Setup};
+use tokio::sync::oneshot;
+
+/// Positions evaluated beyond this many centipawns are decided, moves played there don't count in the averages.
+const DECIDED_CP: i32 = 1000;
+
+/// Centipawn equivalent of a forced mate.
+const MATE_CP: i32 = 10_000;
+
+/// Winning chances, in percent, a move loses to be an inaccuracy, a mistake or a blunder.
+/// Same thresholds as lichess: 0.1, 0.2 and 0.3 of its -1 to 1 chances.
+const INACCURACY_DROP: f64 = 5.0;
+const MISTAKE_DROP: f64 = 10.0;
+const BLUNDER_DROP: f64 = 15.0;
+
+/// Quick evaluations keep the existing evaluations of positions searched at least this deep.
+const QUICK_EVAL_DEPTH: u32 = 12;
+
+/// Parameters of an annotation run, see `Request::AnnotateGame` and `Request::QuickEval`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotationSettings {
+    pub engine_id: String,
+    pub movetime_per_ply_ms: u64,
+    pub kind: AnnotationKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnotationKind {
+    /// Evaluates every position and reports the players' accuracy.
+    Full,
+    /// Short pass skipping positions already well evaluated, reporting the evaluations for a graph.
+    Quick,
+}
+
+/// Per-player summary of an annotated game.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AccuracyReport {
+    pub white: PlayerAccuracy,
+    pub black: PlayerAccuracy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PlayerAccuracy {
+    /// `None` when the player made no move in an undecided position.
+    pub average_centipawn_loss: Option<u32>,
+    /// Average of the moves' accuracies in percent, rounded to one decimal.
+    pub accuracy: Option<f64>,
+    pub best: u32,
+    pub excellent: u32,
+    pub good: u32,
+    pub inaccuracies: u32,
+    pub mistakes: u32,
+    pub blunders: u32,
+    /// Average win/draw/loss chances after the player's moves, from their point of view. Only
+    /// given when the engine reported them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_wdl: Option<Wdl>,
+    /// Points expected in percent from `average_wdl`, rounded to one decimal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_expected_score: Option<f64>,
+}
+
+/// Evaluates every position of game `id`'s current line, storing the evaluations in the game tree.
+/// Progress and the final report are sent as notifications.
+pub async fn run(state: StateHandle, id: String, settings: AnnotationSettings, ticket: JobTicket) {
+    let JobTicket { token, mut stop } = ticket;
+    let outcome = evaluate_line(&state, &id, &settings, &mut stop).await;
+    let _ = state.jobs().finish(&id, token);
+
+    let response = outcome.and_then(|evaluated| {
+        state.with_game(&id, |game| {
+            let notification = match (settings.kind, evaluated) {
+                (AnnotationKind::Full, Some(line)) => {
+                    let report = accuracy_report(&line.evaluations, line.white_moves_first);
+                    game.set_accuracy(report.clone());
+                    Notification::AnnotationFinished {
+                        id: id.clone(),
+                        stopped: false,
+                        report: Some(report),
+                    }
+                }
+                (AnnotationKind::Full, None) => Notification::AnnotationFinished {
+                    id: id.clone(),
+                    stopped: true,
+                    report: None,
+                },
+                (AnnotationKind::Quick, evaluated) => Notification::QuickEvalFinished {
+                    id: id.clone(),
+                    stopped: evaluated.is_none(),
+                    evaluations: evaluated.map(|line| line.evaluations).unwrap_or_default(),
+                },
+            };
+            Ok(response_from_game(id.clone(), state.game_repr(game))
+                .with_notification(notification))
+        })
+    });
+
+    match response {
+        Ok(response) => state.notify(response),
+        Err(err) => state.notify(response_from_error(err.with_id(&id))),
+    }
+}
+
+/// Fully annotates game `id` in the foreground, outside of any job, and records the report in the
+/// game. Progress is still notified.
+pub async fn annotate(
+    state: &StateHandle,
+    id: &str,
+    settings: &AnnotationSettings,
+) -> Result<AccuracyReport, Error> {
+    // Nothing ever asks to stop
+    let (_never, mut stop) = oneshot::channel();
+    let line = evaluate_line(state, id, settings, &mut stop)
+        .await?
+        .expect("annotation is never stopped");
+    let report = accuracy_report(&line.evaluations, line.white_moves_first);
+    state.with_game(id, |game| {
+        game.set_accuracy(report.clone());
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+struct EvaluatedLine {
+    /// Starting position included.
+    evaluations: Vec<Option<Evaluation>>,
+    white_moves_first: bool,
+}
+
+/// Returns `None` if stopped before the end.
+async fn evaluate_line(
+    state: &StateHandle,
+    id: &str,
+    settings: &AnnotationSettings,
+    stop: &mut oneshot::Receiver<()>,
+) -> Result<Option<EvaluatedLine>, Error> {
+    let handle = state.engines().get(&settings.engine_id)?;
+    let (fen, moves, chess960, line) = state.with_game(id, |game| {
+        Ok((
+            game.initial_fen(),
+            game.uci_line(),
+            game.is_chess960(),
+            game.line(),
+        ))
+    })?;
+    let (positions, known) = state.with_game(id, |game| {
+        Ok((game.positions(&line), game.evaluations(&line)?))
+    })?;
+
+    let mut engine = handle.lock().await;
+    engine.restore_options().await?;
+    engine.new_game().await?;
+    engine.set_chess960(chess960).await?;
+
+    let budget = Duration::from_millis(settings.movetime_per_ply_ms);
+    let go = format!("go movetime {}", settings.movetime_per_ply_ms);
+    let mut previous: Option<Evaluation> = None;
+    for (ply, position) in positions.iter().enumerate() {
+        let evaluation = if let Some(known) = known[ply].filter(|known| {
+            settings.kind == AnnotationKind::Quick && known.depth >= QUICK_EVAL_DEPTH
+        }) {
+            Some(known)
+        } else if position.is_checkmate() {
+            None
+        } else if position.is_stalemate() {
+            Some(Evaluation {
+                score: Score::Cp(0),
+                depth: 0,
+                wdl: Some(Wdl {
+                    win: 0,
+                    draw: 1000,
+                    loss: 0,
+                }),
+            })
+        } else {
+            let command = position_command(&fen, &moves[..ply]);
+            let best_move = tokio::select! {
+                best_move = engine.best_move(&command, &go, budget) => best_move?,
+                _ = &mut *stop => {
+                    engine.stop().await?;
+                    return Ok(None);
+                }
+            };
+            best_move.evaluation.map(|e| e.for_white(position.turn()))
+        };
+
+        if let Some(evaluation) = evaluation {
+            state.with_game(id, |game| game.set_evaluation(&line[..ply], evaluation))?;
+        }
+        // Moves are annotated as soon as the position after them is evaluated, so stopped
+        // annotations keep theirs
+        if let (AnnotationKind::Full, Some(before), Some(after)) =
+            (settings.kind, previous, evaluation)
+        {
+            let (before, after) = match positions[ply - 1].turn() {
+                shakmaty::Color::White => (centipawns(before.score), centipawns(after.score)),
+                shakmaty::Color::Black => (-centipawns(before.score), -centipawns(after.score)),
+            };
+            if let Some(annotation) = move_annotation(before, after) {
+                state.with_game(id, |game| game.set_annotation(&line[..ply], annotation))?;
+            }
+        }
+        previous = evaluation;
+        state.notify(response_from_notification(
+            Notification::AnnotationProgress {
+                id: id.to_string(),
+                ply: ply as u32,
+                total: moves.len() as u32,
+                evaluation,
+            },
+        ));
+    }
+
+    let evaluations = state.with_game(id, |game| game.evaluations(&line))?;
+    Ok(Some(EvaluatedLine {
+        evaluations,
+        white_moves_first: positions[0].turn() == shakmaty::Color::White,
+    }))
+}
+
+/// Builds the report from the evaluations (white's point of view) of every position of a line,
+/// starting position included. Moves missing an evaluation before or after them are skipped.
+pub fn accuracy_report(
+    evaluations: &[Option<Evaluation>],
+    white_moves_first: bool,
+) -> AccuracyReport {
+    let mut white = Tally::default();
+    let mut black = Tally::default();
+    for (ply, pair) in evaluations.windows(2).enumerate() {
+        let (before, after) = match (pair[0], pair[1]) {
+            (Some(before), Some(after)) => (before, after),
+            _ => continue,
+        };
+        let (before_cp, after_cp) = (centipawns(before.score), centipawns(after.score));
+        if (ply % 2 == 0) == white_moves_first {
+            white.add(before_cp, after_cp, after.wdl);
+        } else {
+            black.add(-before_cp, -after_cp, after.wdl.map(Wdl::flipped));
+        }
+    }
+
+    AccuracyReport {
+        white: white.finish(),
+        black: black.finish(),
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    losses: Vec<i32>,
+    accuracies: Vec<f64>,
+    wdls: Vec<Wdl>,
+    player: PlayerAccuracy,
+}
+
+impl Tally {
+    /// `before`, `after` and `wdl` (after the move) are seen from the point of view of the player
+    /// who moved.
+    fn add(&mut self, before: i32, after: i32, wdl: Option<Wdl>) {
+        self.wdls.extend(wdl);
+
+        let loss =
+            (before.clamp(-DECIDED_CP, DECIDED_CP) - after.clamp(-DECIDED_CP, DECIDED_CP)).max(0);
+        let drop = (win_percent(before) - win_percent(after)).max(0.0);
+
+        let bucket = match drop {
+            _ if loss == 0 => &mut self.player.best,
+            d if d < 2.0 => &mut self.player.excellent,
+            d if d < INACCURACY_DROP => &mut self.player.good,
+            d if d < MISTAKE_DROP => &mut self.player.inaccuracies,
+            d if d < BLUNDER_DROP => &mut self.player.mistakes,
+            _ => &mut self.player.blunders,
+        };
+        *bucket += 1;
+
+        if before.abs() <= DECIDED_CP {
+            self.losses.push(loss);
+            self.accuracies.push(move_accuracy(drop));
+        }
+    }
+
+    fn finish(self) -> PlayerAccuracy {
+        let (win, draw, loss) = self.wdls.iter().fold((0, 0, 0), |(win, draw, loss), wdl| {
+            (win + wdl.win, draw + wdl.draw, loss + wdl.loss)
+        });
+        let average_wdl = Wdl::normalized(win, draw, loss);
+        let player = PlayerAccuracy {
+            average_wdl,
+            average_expected_score: average_wdl
+                .map(|wdl| (wdl.expected_score() * 10.0).round() / 10.0),
+            ..self.player
+        };
+
+        let count = self.losses.len();
+        if count == 0 {
+            return player;
+        }
+        let loss = self.losses.iter().sum::<i32>() as f64 / count as f64;
+        let accuracy = self.accuracies.iter().sum::<f64>() / count as f64;
+        PlayerAccuracy {
+            average_centipawn_loss: Some(loss.round() as u32),
+            accuracy: Some((accuracy * 10.0).round() / 10.0),
+            ..player
+        }
+    }
+}
+
+/// `?!`, `?` or `??` for a move between evaluations `before` and `after`, seen from the point of
+/// view of the player who moved.
+fn move_annotation(before: i32, after: i32) -> Option<Annotation> {
+    match win_percent(before) - win_percent(after) {
+        d if d >= BLUNDER_DROP => Some(Annotation::Blunder),
+        d if d >= MISTAKE_DROP => Some(Annotation::Mistake),
+        d if d >= INACCURACY_DROP => Some(Annotation::Inaccuracy),
+        _ => None,
+    }
+}
+
+fn centipawns(score: Score) -> i32 {
+    match score {
+        Score::Cp(cp) => cp,
+        Score::Mate(moves) if moves > 0 => MATE_CP,
+        Score::Mate(_) => -MATE_CP,
+    }
+}
+
+/// Chances of winning in percent, as estimated by lichess from its games.
+fn win_percent(cp: i32) -> f64 {
+    50.0 + 50.0 * (2.0 / (1.0 + (-0.003_682_08 * cp as f64).exp()) - 1.0)
+}
+
+/// Lichess' accuracy of a move losing `drop` percent of winning chances.
+fn move_accuracy(drop: f64) -> f64 {
+    (103.1668 * (-0.04354 * drop).exp() - 3.1669).clamp(0.0, 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Response;
+    use crate::engine::tests::mock_engine;
+
+    use serde_json::Value;
+    use tokio::sync::broadcast;
+
+    fn cp(cp: i32) -> Option<Evaluation> {
+        Some(Evaluation {
+            score: Score::Cp(cp),
+            depth: 20,
+            wdl: None,
+        })
+    }
+
+    #[test]
+    fn report() {
+        let evaluations = vec![
+            cp(20),
+            cp(20),    // white: best
+            cp(35),    // black: excellent, 15cp
+            cp(-120),  // white: mistake, 155cp
+            cp(-100),  // black: excellent, 20cp
+            cp(-1200), // white: blunder, 900cp (capped)
+            cp(-1250), // black: best, already decided
+            cp(-900),  // white: best, already decided
+            Some(Evaluation {
+                score: Score::Mate(-2),
+                depth: 30,
+                wdl: None,
+            }), // black: best
+            None,
+            cp(0), // no evaluation before, skipped
+        ];
+        let report = accuracy_report(&evaluations, true);
+
+        assert_eq!(
+            report.white,
+            PlayerAccuracy {
+                average_centipawn_loss: Some(352),
+                accuracy: Some(55.9),
+                best: 2,
+                excellent: 0,
+                good: 0,
+                inaccuracies: 0,
+                mistakes: 1,
+                blunders: 1,
+                average_wdl: None,
+                average_expected_score: None,
+            }
+        );
+        assert_eq!(
+            report.black,
+            PlayerAccuracy {
+                average_centipawn_loss: Some(12),
+                accuracy: Some(95.5),
+                best: 2,
+                excellent: 2,
+                good: 0,
+                inaccuracies: 0,
+                mistakes: 0,
+                blunders: 0,
+                average_wdl: None,
+                average_expected_score: None,
+            }
+        );
+        assert_eq!(accuracy_report(&[cp(0)], true), AccuracyReport::default());
+    }
+
+    fn settings(kind: AnnotationKind) -> AnnotationSettings {
+        AnnotationSettings {
+            engine_id: String::from("sf"),
+            movetime_per_ply_ms: 10,
+            kind,
+        }
+    }
+
+    /// Collects notifications until the run ends, returns the progress and the final response.
+    async fn collect(notifications: &mut broadcast::Receiver<Response>) -> (Vec<Value>, Value) {
+        let mut progress = Vec::new();
+        loop {
+            let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+            let notification = response["notification"].clone();
+            match notification["type"].as_str() {
+                Some("annotation_progress") => progress.push(notification),
+                Some("annotation_finished") | Some("quick_eval_finished") => {
+                    return (progress, response)
+                }
+                _ => panic!("Unexpected notification {}", response),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn annotate_game() {
+        let state = StateHandle::default();
+        let mut notifications: broadcast::Receiver<Response> = state.subscribe();
+        state.new_game_default("g1").unwrap();
+        for uci in &["e2e4", "e7e5", "g1f3"] {
+            state.play_uci("g1", uci).unwrap();
+        }
+        // Scores are from the side to move's point of view
+        let engine = mock_engine(&["e2e4@30", "e7e5@-30", "g1f3@40", "b8c6@250"]);
+        state.add_engine("sf", engine).await.unwrap();
+
+        state
+            .start_annotation("g1", settings(AnnotationKind::Full))
+            .unwrap();
+        let (progress, finished) = collect(&mut notifications).await;
+
+        assert_eq!(progress.len(), 4);
+        assert_eq!(progress[3]["total"], 3);
+        assert_eq!(progress[1]["evaluation"]["score"]["cp"], 30);
+        assert_eq!(progress[3]["evaluation"]["score"]["cp"], -250);
+
+        let notification = &finished["notification"];
+        assert_eq!(notification["stopped"], false);
+        let report = &notification["report"];
+        assert_eq!(report["white"]["best"], 1);
+        assert_eq!(report["white"]["blunders"], 1);
+        assert_eq!(report["white"]["average_centipawn_loss"], 145);
+        assert_eq!(report["black"]["excellent"], 1);
+        assert_eq!(report["black"]["accuracy"], 96.0);
+        let repr: &Value = &finished["changed_games"][0]["game"];
+        assert_eq!(repr["accuracy"], notification["report"]);
+        assert_eq!(repr["last_move_annotation"], "blunder");
+        let pgn = serde_json::to_value(state.export_pgn("g1").unwrap()).unwrap();
+        assert!(pgn["pgn"].as_str().unwrap().contains(" 2. Nf3 $4 "));
+    }
+
+    #[tokio::test]
+    async fn quick_eval() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        state.new_game_default("g1").unwrap();
+        for uci in &["d2d4", "d7d5"] {
+            state.play_uci("g1", uci).unwrap();
+        }
+        // Already evaluated deep enough: the engine is only asked about the two other positions
+        let deep = Evaluation {
+            score: Score::Cp(25),
+            depth: 30,
+            wdl: None,
+        };
+        state
+            .with_game("g1", |game| {
+                let line = game.line();
+                game.set_evaluation(&line[..1], deep)
+            })
+            .unwrap();
+        state
+            .add_engine("sf", mock_engine(&["d2d4@20", "g1f3@30=500,400,100"]))
+            .await
+            .unwrap();
+
+        state
+            .start_annotation("g1", settings(AnnotationKind::Quick))
+            .unwrap();
+        let (progress, finished) = collect(&mut notifications).await;
+        assert_eq!(progress.len(), 3);
+
+        let notification = &finished["notification"];
+        assert_eq!(notification["stopped"], false);
+        let graph = notification["evaluations"].as_array().unwrap();
+        assert_eq!(graph.len(), 2 + 1);
+        assert_eq!(graph[0]["score"]["cp"], 20);
+        assert_eq!(graph[1]["depth"], 30);
+        assert_eq!(graph[2]["score"]["cp"], 30);
+        assert_eq!(graph[2]["wdl"]["win"], 500);
+        assert!(graph[0].get("wdl").is_none());
+    }
+
+    #[tokio::test]
+    async fn report_wdl() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        state.new_game_default("g1").unwrap();
+        for uci in &["e2e4", "e7e5", "g1f3"] {
+            state.play_uci("g1", uci).unwrap();
+        }
+        // Statistics are from the side to move's point of view, the last position has none
+        let engine = mock_engine(&[
+            "e2e4@30=400,500,100",
+            "e7e5@-30=100,500,400",
+            "g1f3@40=200,400,400",
+            "b8c6@20",
+        ]);
+        state.add_engine("sf", engine).await.unwrap();
+
+        state
+            .start_annotation("g1", settings(AnnotationKind::Full))
+            .unwrap();
+        let (_, finished) = collect(&mut notifications).await;
+
+        let report = &finished["notification"]["report"];
+        // White after 1. e4 (400/500/100) and 2. Nf3 (none)
+        assert_eq!(
+            report["white"]["average_wdl"],
+            serde_json::json!({"win": 400, "draw": 500, "loss": 100})
+        );
+        assert_eq!(report["white"]["average_expected_score"], 65.0);
+        // Black after 1... e5, seen from black: 400/400/200
+        assert_eq!(report["black"]["average_wdl"]["win"], 400);
+        assert_eq!(report["black"]["average_expected_score"], 60.0);
+
+        let evaluations = vec![cp(0), cp(10)];
+        let report = accuracy_report(&evaluations, true);
+        assert!(report.white.average_wdl.is_none());
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["white"].get("average_expected_score").is_none());
+    }
+}
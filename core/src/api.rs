@@ -1,38 +1,419 @@
-use crate::game::GameRepr;
+use crate::annotation::{AccuracyReport, AnnotationKind, AnnotationSettings};
+use crate::book::BookMove;
+use crate::clock::TimeControl;
+use crate::database::{
+    DatabaseGame, DatabaseRepr, DeletedGames, Duplicates, Explorer, ExportSummary, ImportOptions,
+    ImportSummary, MaintenanceAction, MaintenanceResult, ReindexSummary, SearchFilters,
+    SearchResults, SortColumn,
+};
+use crate::drill::{DrillStep, Side};
+use crate::engine::{Analysis, EngineConfig, EngineRepr};
+use crate::engine_match::{MatchEnd, MatchSettings};
+use crate::explorer::{CacheMode, Cached, CloudEval, ExplorerSource, OnlineExplorer};
+use crate::game::{
+    Annotation, Evaluation, Game, GameInfoUpdate, GameRepr, GameResult, LinkMode, Player,
+    Promotion, TreeRepr,
+};
+use crate::lichess::{
+    ImportTarget, LichessAccount, LichessConfig, LichessGameRow, LichessToken, StudyChapter,
+    UserGamesFilter,
+};
+use crate::move_format::{MoveFormat, Notation, PieceLocale};
+use crate::opponent::Opponent;
+use crate::puzzle::PuzzleAttempt;
+use crate::stats::Stats;
+use crate::tablebase::TablebaseProbe;
+use crate::transcript::TranscriptLine;
 use crate::{
-    errors::{Error, ErrorRepr},
+    errors::{Error, ErrorRepr, RequestEcho, WarningRepr},
     state::StateHandle,
 };
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 /// Only returns Err(Error) when it is not recoverable
 /// All other errors are returned in the form of Ok(Response)
-pub fn dispatch_request(request: Request, state: &StateHandle) -> Result<Response, Error> {
+pub async fn dispatch_request(request: Request, state: &StateHandle) -> Result<Response, Error> {
+    // Tokens are redacted from the debug output of requests
+    tracing::debug!(?request, "dispatching request");
     let result = match request {
-        Request::Play(PlayArgs { id, from, to }) => state.play(&id, from, to),
-        Request::NavigateBack(NavigateBackArgs { id, back }) => state.navigate_back(&id, back),
+        Request::Play(PlayArgs {
+            id,
+            from,
+            to,
+            promotion,
+            at_ms,
+        }) => state.play(&id, from, to, promotion, at_ms),
+        Request::NavigateBack(NavigateBackArgs { id, back, at_ms }) => {
+            state.navigate_back(&id, back, at_ms)
+        }
+        Request::NavigateForward(NavigateForwardArgs { id, forward, at_ms }) => {
+            state.navigate_forward(&id, forward, at_ms)
+        }
+        Request::NavigateTo(NavigateToArgs { id, ply, at_ms }) => {
+            state.navigate_to(&id, ply, at_ms)
+        }
+        Request::NavigateToEnd(NavigateToEndArgs { id, at_ms }) => {
+            state.navigate_to_end(&id, at_ms)
+        }
+        Request::LinkGames(LinkGamesArgs {
+            group_id,
+            game_ids,
+            mode,
+        }) => state.link_games(group_id, game_ids, mode),
+        Request::UnlinkGames(UnlinkGamesArgs { group_id }) => state.unlink_games(&group_id),
+        Request::SetMoveFormat(SetMoveFormatArgs {
+            notation,
+            piece_locale,
+        }) => state.set_move_format(MoveFormat {
+            notation,
+            piece_locale,
+        }),
+        Request::SetClock(SetClockArgs {
+            id,
+            time_control,
+            at_ms,
+        }) => state.set_clock(&id, time_control, at_ms),
+        Request::ClockTick(ClockTickArgs { id, at_ms }) => state.clock_tick(&id, at_ms),
+        Request::SetPuzzle(SetPuzzleArgs {
+            id,
+            solution_uci,
+            attempts_before_hint,
+            at_ms,
+        }) => state.set_puzzle(&id, solution_uci, attempts_before_hint, at_ms),
+        Request::SetComment(SetCommentArgs { id, comment }) => state.set_comment(&id, comment),
+        Request::SetGameInfo(SetGameInfoArgs { id, fields }) => state.set_game_info(&id, fields),
+        Request::SetResult(SetResultArgs { id, result }) => state.set_result(&id, result),
+        Request::SetPlayers(SetPlayersArgs { id, white, black }) => {
+            state.set_players(&id, white, black)
+        }
+        Request::SetEvaluation(SetEvaluationArgs { id, evaluation }) => {
+            state.set_evaluation(&id, evaluation)
+        }
+        Request::SetAnnotation(SetAnnotationArgs { id, annotation }) => {
+            state.set_annotation(&id, annotation)
+        }
+        Request::DeleteContinuations(DeleteContinuationsArgs { id }) => {
+            state.delete_continuations(&id)
+        }
+        Request::MarkRepertoireMove(MarkRepertoireMoveArgs { id, unmark }) => {
+            state.mark_repertoire_move(&id, !unmark)
+        }
+        Request::StartDrill(StartDrillArgs {
+            id,
+            color,
+            depth_limit,
+        }) => state.start_drill(&id, color.into(), depth_limit).await,
         Request::GetAllGames(_) => state.get_all_games(),
-        Request::NewGame(NewGameArgs { id }) => state.new_game_default(&id),
+        Request::GetStats(_) => state.get_stats(),
+        Request::NewGame(NewGameArgs {
+            id,
+            fen,
+            chess960,
+            pgn,
+        }) => match (pgn, fen) {
+            (Some(pgn), _) => state.new_game_pgn(&id, &pgn),
+            (None, Some(fen)) => state.new_game_fen(&id, fen, chess960),
+            (None, None) if chess960 => {
+                state.new_game_fen(&id, Game::default().initial_fen(), true)
+            }
+            (None, None) => state.new_game_default(&id),
+        },
+        Request::CloseGame(CloseGameArgs { id }) => state.close_game(&id),
+        Request::ExportPgn(ExportPgnArgs { id }) => state.export_pgn(&id),
+        Request::GetTree(GetTreeArgs { id }) => state.get_tree(&id),
+        Request::AddEngine(AddEngineArgs { engine_id, config }) => {
+            state.add_engine(&engine_id, config).await
+        }
+        Request::GetEngineLog(GetEngineLogArgs { engine_id }) => state.get_engine_log(&engine_id),
+        Request::SetEngineOption(SetEngineOptionArgs {
+            engine_id,
+            name,
+            value,
+        }) => state.set_engine_option(&engine_id, &name, &value).await,
+        Request::Analyze(AnalyzeArgs {
+            id,
+            engine_id,
+            depth,
+        }) => state.analyze(&id, engine_id, depth),
+        Request::StartAnalysis(StartAnalysisArgs { id, engine_id }) => {
+            state.start_analysis(&id, engine_id)
+        }
+        Request::StopAnalysis(StopAnalysisArgs { id }) => state.stop_analysis(&id),
+        Request::SetOpponent(SetOpponentArgs { id, opponent }) => state.set_opponent(&id, opponent),
+        Request::EngineMatch(EngineMatchArgs {
+            id,
+            white_engine_id,
+            black_engine_id,
+            movetime_ms,
+            max_plies,
+        }) => state.start_engine_match(
+            &id,
+            MatchSettings {
+                white_engine_id,
+                black_engine_id,
+                movetime_ms,
+                max_plies,
+            },
+        ),
+        Request::StopEngineMatch(StopEngineMatchArgs { id }) => state.stop_job(&id),
+        Request::AnnotateGame(AnnotateGameArgs {
+            id,
+            engine_id,
+            movetime_per_ply_ms,
+        }) => state.engine_id_or_default(engine_id).and_then(|engine_id| {
+            state.start_annotation(
+                &id,
+                AnnotationSettings {
+                    engine_id,
+                    movetime_per_ply_ms,
+                    kind: AnnotationKind::Full,
+                },
+            )
+        }),
+        Request::QuickEval(QuickEvalArgs {
+            id,
+            engine_id,
+            movetime_per_ply_ms,
+        }) => state.engine_id_or_default(engine_id).and_then(|engine_id| {
+            state.start_annotation(
+                &id,
+                AnnotationSettings {
+                    engine_id,
+                    movetime_per_ply_ms,
+                    kind: AnnotationKind::Quick,
+                },
+            )
+        }),
+        Request::StopAnnotation(StopAnnotationArgs { id }) => state.stop_job(&id),
+        Request::Shutdown(_) => state.shutdown().await,
+        Request::OpenDatabase(OpenDatabaseArgs {
+            db_id,
+            path,
+            create,
+        }) => state.open_database(db_id, path, create).await,
+        Request::CloseDatabase(CloseDatabaseArgs { db_id }) => state.close_database(db_id).await,
+        Request::GetDatabaseInfo(GetDatabaseInfoArgs { db_id }) => {
+            state.get_database_info(db_id).await
+        }
+        Request::ListDatabases(_) => state.list_databases().await,
+        Request::SetDefaultDatabase(SetDefaultDatabaseArgs { db_id }) => {
+            state.set_default_database(db_id).await
+        }
+        Request::DatabaseImportPgn(DatabaseImportPgnArgs {
+            db_id,
+            path,
+            skip_duplicates,
+        }) => {
+            state
+                .start_database_import(db_id, path, ImportOptions { skip_duplicates })
+                .await
+        }
+        Request::DatabaseSearchPosition(DatabaseSearchPositionArgs {
+            db_id,
+            fen,
+            limit,
+            offset,
+        }) => state.search_position(db_id, fen, limit, offset).await,
+        Request::DatabaseSearchText(DatabaseSearchTextArgs {
+            db_id,
+            query,
+            limit,
+            offset,
+        }) => {
+            state
+                .search_database_text(db_id, query, limit, offset)
+                .await
+        }
+        Request::DatabaseSearch(DatabaseSearchArgs {
+            db_id,
+            filters,
+            limit,
+            offset,
+        }) => state.search_database(db_id, filters, limit, offset).await,
+        Request::ExplorerFromDatabase(ExplorerFromDatabaseArgs { db_id, id, fen }) => {
+            state.explore_database(db_id, id, fen).await
+        }
+        Request::ProbeBook(ProbeBookArgs { id, fen, book_path }) => {
+            state.probe_book(id, fen, book_path)
+        }
+        Request::OnlineTablebase(OnlineTablebaseArgs { id, fen }) => {
+            state.probe_online_tablebase(id, fen).await
+        }
+        Request::LichessExplorer(LichessExplorerArgs {
+            id,
+            fen,
+            source,
+            cache,
+        }) => state.explore_lichess(id, fen, source, cache).await,
+        Request::LichessCloudEval(LichessCloudEvalArgs {
+            id,
+            fen,
+            multi_pv,
+            cache,
+        }) => state.lichess_cloud_eval(id, fen, multi_pv, cache).await,
+        Request::OpenGameFromDatabase(OpenGameFromDatabaseArgs {
+            db_id,
+            db_game_id,
+            as_id,
+        }) => {
+            state
+                .open_game_from_database(db_id, db_game_id, as_id)
+                .await
+        }
+        Request::DatabaseListGames(DatabaseListGamesArgs {
+            db_id,
+            sort_by,
+            descending,
+            limit,
+            offset,
+        }) => {
+            state
+                .list_database_games(db_id, sort_by, descending, limit, offset)
+                .await
+        }
+        Request::DatabaseDeleteGames(DatabaseDeleteGamesArgs { db_id, db_game_ids }) => {
+            state.delete_database_games(db_id, db_game_ids).await
+        }
+        Request::DatabaseUpdateHeaders(DatabaseUpdateHeadersArgs {
+            db_id,
+            db_game_id,
+            fields,
+        }) => {
+            state
+                .update_database_headers(db_id, db_game_id, fields)
+                .await
+        }
+        Request::DatabaseFindDuplicates(DatabaseFindDuplicatesArgs { db_id, limit }) => {
+            state.find_database_duplicates(db_id, limit).await
+        }
+        Request::DatabaseExportPgn(DatabaseExportPgnArgs {
+            db_id,
+            filter,
+            sort_by,
+            descending,
+            path,
+        }) => {
+            state
+                .start_database_export(db_id, path, filter, sort_by, descending)
+                .await
+        }
+        Request::StopDatabaseExport(StopDatabaseExportArgs { path }) => {
+            state.stop_database_export(&path)
+        }
+        Request::StopDatabaseImport(StopDatabaseImportArgs { db_id }) => {
+            state.stop_database_job(&db_id)
+        }
+        Request::DatabaseReindexPositions(DatabaseReindexPositionsArgs { db_id, check_only }) => {
+            state.start_database_reindex(db_id, check_only).await
+        }
+        Request::StopDatabaseReindex(StopDatabaseReindexArgs { db_id }) => {
+            state.stop_database_job(&db_id)
+        }
+        Request::DatabaseMaintenance(DatabaseMaintenanceArgs { db_id, actions }) => {
+            state.maintain_database(db_id, actions).await
+        }
+        Request::LichessImportGame(LichessImportGameArgs { game_or_url, as_id }) => {
+            state.import_lichess_game(game_or_url, as_id).await
+        }
+        Request::LichessImportStudy(LichessImportStudyArgs {
+            study_id,
+            as_prefix,
+        }) => state.import_lichess_study(study_id, as_prefix).await,
+        Request::LichessSetToken(LichessSetTokenArgs { token }) => {
+            state.set_lichess_token(token).await
+        }
+        Request::LichessClearToken(_) => state.clear_lichess_token(),
+        Request::SetLichessConfig(SetLichessConfigArgs { base_url }) => {
+            state.set_lichess_config(base_url)
+        }
+        Request::LichessListUserGames(LichessListUserGamesArgs {
+            username,
+            max,
+            perf_type,
+            since,
+            until,
+        }) => {
+            let filter = UserGamesFilter {
+                max,
+                perf_type,
+                since,
+                until,
+            };
+            state.list_lichess_user_games(username, filter).await
+        }
+        Request::LichessImportGames(LichessImportGamesArgs { game_ids, target }) => {
+            state.import_lichess_games(game_ids, target).await
+        }
+        Request::LichessPushStudyChapter(LichessPushStudyChapterArgs {
+            id,
+            study_id,
+            chapter_name,
+        }) => {
+            state
+                .push_lichess_study_chapter(id, study_id, chapter_name)
+                .await
+        }
+        Request::LichessFollowGame(LichessFollowGameArgs { game_id, as_id }) => {
+            state.follow_lichess_game(game_id, as_id).await
+        }
+        Request::LichessUnfollow(LichessUnfollowArgs { id }) => state.stop_job(&id),
+        Request::LichessImportPuzzle(LichessImportPuzzleArgs { puzzle_id, as_id }) => {
+            state.import_lichess_puzzle(puzzle_id, as_id).await
+        }
     };
 
-    handle_fatal_error(result)
+    let response = handle_fatal_error(result)?;
+    state.record_response(&response);
+    Ok(response)
+}
+
+pub fn initial_response(
+    games: Response,
+    engines: Vec<EngineRepr>,
+    warnings: Vec<WarningRepr>,
+) -> Response {
+    Response {
+        engines,
+        warnings,
+        ..games
+    }
 }
 
-pub fn response_from_error(error: Error) -> Response {
+pub fn response_from_error(error: impl Into<ErrorRepr>) -> Response {
     Response {
         error: Some(error.into()),
-        changed_games: Vec::new(),
+        ..Response::default()
     }
 }
 
 pub fn response_from_game(id: String, repr: GameRepr) -> Response {
-    let mut changed_games = Vec::new();
-    changed_games.push(ChangedGame { id, game: repr });
+    Response {
+        changed_games: vec![ChangedGame { id, game: repr }],
+        ..Response::default()
+    }
+}
 
+pub fn response_from_closed_game(id: String) -> Response {
     Response {
-        error: None,
-        changed_games,
+        closed_games: vec![id],
+        ..Response::default()
+    }
+}
+
+pub fn response_from_notification(notification: Notification) -> Response {
+    Response {
+        notification: Some(notification),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_engines(engines: Vec<EngineRepr>) -> Response {
+    Response {
+        engines,
+        ..Response::default()
     }
 }
 
@@ -44,16 +425,179 @@ pub fn response_from_games(
     for game in games {
         match game {
             Ok((id, repr)) => changed_games.push(ChangedGame { id, game: repr }),
-            Err(err) => return Ok(handle_fatal_error(Err(err))?),
+            Err(err) => return handle_fatal_error(Err(err)),
         }
     }
 
     Ok(Response {
         changed_games,
-        error: None,
+        ..Response::default()
     })
 }
 
+pub fn response_from_databases(databases: Vec<DatabaseRepr>) -> Response {
+    Response {
+        databases,
+        ..Response::default()
+    }
+}
+
+pub fn response_from_maintenance(
+    results: Vec<MaintenanceResult>,
+    database: DatabaseRepr,
+) -> Response {
+    Response {
+        maintenance: Some(results),
+        database: Some(database),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_database(database: DatabaseRepr) -> Response {
+    Response {
+        database: Some(database),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_search(results: SearchResults) -> Response {
+    Response {
+        search_results: Some(results),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_explorer(explorer: Explorer) -> Response {
+    Response {
+        explorer: Some(explorer),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_database_game(game: DatabaseGame) -> Response {
+    Response {
+        database_game: Some(game),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_deleted_games(deleted: DeletedGames) -> Response {
+    Response {
+        deleted_games: Some(deleted),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_duplicates(duplicates: Duplicates) -> Response {
+    Response {
+        duplicates: Some(duplicates),
+        ..Response::default()
+    }
+}
+
+/// Games created from the chapters of a study, with their names.
+pub fn response_from_study(chapters: Vec<(StudyChapter, GameRepr)>) -> Response {
+    let (study_chapters, changed_games) = chapters
+        .into_iter()
+        .map(|(chapter, game)| {
+            let id = chapter.id.clone();
+            (chapter, ChangedGame { id, game })
+        })
+        .unzip();
+    Response {
+        changed_games,
+        study_chapters,
+        ..Response::default()
+    }
+}
+
+pub fn response_from_lichess_account(account: LichessAccount) -> Response {
+    Response {
+        lichess_account: Some(account),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_stats(stats: Stats) -> Response {
+    Response {
+        stats: Some(stats),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_lichess_config(config: LichessConfig) -> Response {
+    Response {
+        lichess_config: Some(config),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_lichess_games(rows: Vec<LichessGameRow>) -> Response {
+    Response {
+        lichess_games: Some(rows),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_import_summary(summary: ImportSummary) -> Response {
+    Response {
+        import_summary: Some(summary),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_tree(tree: TreeRepr) -> Response {
+    Response {
+        tree: Some(tree),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_pgn(pgn: String) -> Response {
+    Response {
+        pgn: Some(pgn),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_book_moves(moves: Vec<BookMove>) -> Response {
+    Response {
+        book_moves: Some(moves),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_tablebase(probe: TablebaseProbe) -> Response {
+    Response {
+        tablebase: Some(probe),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_online_explorer(explorer: Cached<OnlineExplorer>) -> Response {
+    Response {
+        online_explorer: Some(explorer),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_cloud_eval(eval: Cached<CloudEval>) -> Response {
+    Response {
+        cloud_eval: Some(eval),
+        ..Response::default()
+    }
+}
+
+pub fn response_from_engine_log(engine_id: &str, lines: Vec<TranscriptLine>) -> Response {
+    Response {
+        engine_log: Some(EngineLog {
+            engine_id: engine_id.to_string(),
+            lines,
+        }),
+        ..Response::default()
+    }
+}
+
 /// Converts Err(Error) to Some(Response) as long as it is recoverable
 pub fn handle_fatal_error(result: Result<Response, Error>) -> Result<Response, Error> {
     match result {
@@ -64,19 +608,223 @@ pub fn handle_fatal_error(result: Result<Response, Error>) -> Result<Response, E
 }
 
 /// Response type to be serialized into JSON
-#[derive(Serialize, Debug)]
+/// Also used for notifications, which are sent without a matching request (by background tasks).
+#[derive(Serialize, Debug, Clone, Default)]
 #[serde(rename_all = "snake_case")]
 pub struct Response {
     error: Option<ErrorRepr>,
     changed_games: Vec<ChangedGame>,
+    /// Ids whose handles are now stale.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    closed_games: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    engines: Vec<EngineRepr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    engine_log: Option<EngineLog>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<DatabaseRepr>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    databases: Vec<DatabaseRepr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<Vec<MaintenanceResult>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    search_results: Option<SearchResults>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explorer: Option<Explorer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book_moves: Option<Vec<BookMove>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pgn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tree: Option<TreeRepr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tablebase: Option<TablebaseProbe>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_explorer: Option<Cached<OnlineExplorer>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cloud_eval: Option<Cached<CloudEval>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database_game: Option<DatabaseGame>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_games: Option<DeletedGames>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates: Option<Duplicates>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    study_chapters: Vec<StudyChapter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lichess_account: Option<LichessAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lichess_config: Option<LichessConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lichess_games: Option<Vec<LichessGameRow>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    import_summary: Option<ImportSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notification: Option<Notification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    puzzle_attempt: Option<PuzzleAttempt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drill: Option<DrillStep>,
+    /// Caveats of an operation that succeeded anyway.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<WarningRepr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<Stats>,
 }
 
-#[derive(Serialize, Debug)]
+impl Response {
+    pub fn error(&self) -> Option<&ErrorRepr> {
+        self.error.as_ref()
+    }
+
+    pub fn warnings(&self) -> &[WarningRepr] {
+        &self.warnings
+    }
+
+    pub fn with_warnings(mut self, warnings: impl IntoIterator<Item = WarningRepr>) -> Response {
+        self.warnings.extend(warnings);
+        self
+    }
+
+    /// Echoes `line` in the error of the response, if any.
+    pub fn echoing(self, line: &str, limit: usize) -> Response {
+        Response {
+            error: self
+                .error
+                .map(|error| error.with_request(RequestEcho::new(line, limit))),
+            ..self
+        }
+    }
+
+    pub fn with_puzzle_attempt(self, attempt: PuzzleAttempt) -> Response {
+        Response {
+            puzzle_attempt: Some(attempt),
+            ..self
+        }
+    }
+
+    pub fn with_drill(self, step: DrillStep) -> Response {
+        Response {
+            drill: Some(step),
+            ..self
+        }
+    }
+
+    pub fn with_notification(self, notification: Notification) -> Response {
+        Response {
+            notification: Some(notification),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
 pub struct ChangedGame {
     id: String,
     game: GameRepr,
 }
 
+/// Recent UCI lines exchanged with an engine, oldest first
+#[derive(Serialize, Debug, Clone)]
+pub struct EngineLog {
+    engine_id: String,
+    lines: Vec<TranscriptLine>,
+}
+
+/// Events reported by background tasks
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Notification {
+    EngineMatchMove {
+        id: String,
+        engine_id: String,
+        uci: String,
+        ply: u32,
+    },
+    EngineMatchFinished {
+        id: String,
+        result: GameResult,
+        reason: MatchEnd,
+    },
+    /// The engine opponent played `uci`, the game is responded along.
+    OpponentMove { id: String, uci: String },
+    /// The engine analyzing the game found a new best line for its current position.
+    AnalysisUpdate { id: String, analysis: Analysis },
+    /// The search asked by `Analyze` ended, without any analysis if it was stopped.
+    AnalysisFinished {
+        id: String,
+        analysis: Option<Analysis>,
+    },
+    /// Position `ply` of the line (0 is the starting position) was evaluated, out of `total` moves.
+    AnnotationProgress {
+        id: String,
+        ply: u32,
+        total: u32,
+        evaluation: Option<Evaluation>,
+    },
+    AnnotationFinished {
+        id: String,
+        stopped: bool,
+        report: Option<AccuracyReport>,
+    },
+    /// Sent after every batch of imported games. `path` is the PGN file's.
+    DatabaseImportProgress {
+        db_id: String,
+        path: String,
+        imported: u64,
+        skipped: u64,
+        duplicates: u64,
+    },
+    DatabaseImportFinished {
+        db_id: String,
+        path: String,
+        summary: ImportSummary,
+    },
+    DatabaseReindexProgress {
+        db_id: String,
+        games: u64,
+        inconsistent: u64,
+    },
+    DatabaseReindexFinished {
+        db_id: String,
+        summary: ReindexSummary,
+    },
+    DatabaseExportProgress {
+        db_id: String,
+        path: String,
+        exported: u64,
+    },
+    DatabaseExportFinished {
+        db_id: String,
+        path: String,
+        summary: ExportSummary,
+    },
+    /// A database of the previous session, sent at startup. `warning` tells why it couldn't be
+    /// reopened, otherwise the response carries its representation.
+    DatabaseReopened {
+        db_id: String,
+        path: String,
+        warning: Option<String>,
+    },
+    /// A thread panicked while changing the games. Games it may have left half changed are
+    /// closed, the others are still served.
+    GamesQuarantined { ids: Vec<String> },
+    /// A move of a followed lichess game, sent with the game's representation.
+    LichessFollowMove { id: String, uci: String },
+    /// `stopped` is true if following was stopped before the game was over.
+    LichessFollowFinished {
+        id: String,
+        result: GameResult,
+        stopped: bool,
+    },
+    /// Evaluations of every position of the line, starting position included, to draw a graph.
+    QuickEvalFinished {
+        id: String,
+        stopped: bool,
+        evaluations: Vec<Option<Evaluation>>,
+    },
+}
+
 /// Request type into which JSON from stdin is deserialized
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -84,8 +832,123 @@ pub struct ChangedGame {
 pub enum Request {
     Play(PlayArgs),
     NavigateBack(NavigateBackArgs),
+    /// Replays moves navigated back from, following the first continuation of each position.
+    NavigateForward(NavigateForwardArgs),
+    /// Jumps to a ply of the current line, or of its first continuation past the current move.
+    NavigateTo(NavigateToArgs),
+    /// Replays the first continuation of each position up to the end of the line.
+    NavigateToEnd(NavigateToEndArgs),
+    /// Navigating any of the games navigates the others too, see `LinkMode`. Responds with
+    /// every game that moved.
+    LinkGames(LinkGamesArgs),
+    UnlinkGames(UnlinkGamesArgs),
+    /// Sets how the `last_move` of games is written, for screen readers or other languages.
+    /// Stored moves and exported PGN stay in standard SAN.
+    SetMoveFormat(SetMoveFormatArgs),
+    /// Plays the game on the clock from its current position, whose side to move starts.
+    SetClock(SetClockArgs),
+    /// Counts the time spent so far, which can lose the game on time.
+    ClockTick(ClockTickArgs),
+    /// Solves a puzzle from the current position: moves played there are checked against the
+    /// solution, see `PuzzleAttempt`.
+    SetPuzzle(SetPuzzleArgs),
+    /// Comments the move reaching the current position. An empty comment removes it.
+    SetComment(SetCommentArgs),
+    /// Changes the title, result or main PGN tags of a game. Fields left out are kept.
+    SetGameInfo(SetGameInfoArgs),
+    /// Overrides the result, which checkmates and stalemates ending the main line set.
+    SetResult(SetResultArgs),
+    /// Names, ratings and titles of the players, written in the PGN tags.
+    SetPlayers(SetPlayersArgs),
+    /// Stores an evaluation of the current position, from white's point of view.
+    SetEvaluation(SetEvaluationArgs),
+    /// Annotates the move reaching the current position, as `blunder` or `white_better`.
+    SetAnnotation(SetAnnotationArgs),
+    /// Deletes every move played after the current position, which stays.
+    DeleteContinuations(DeleteContinuationsArgs),
+    /// Marks the move reaching the current position as its mover's repertoire move.
+    MarkRepertoireMove(MarkRepertoireMoveArgs),
+    /// Drills a side's repertoire moves from the current position, see `DrillStep`.
+    StartDrill(StartDrillArgs),
     GetAllGames(GetAllGamesArgs),
+    /// Counts of the requests, errors and warnings since the backend started.
+    GetStats(GetStatsArgs),
+    /// Responds with the new game only, the other games are unchanged.
     NewGame(NewGameArgs),
+    CloseGame(CloseGameArgs),
+    /// The game with its variations, comments and evaluations, responded as `pgn`.
+    ExportPgn(ExportPgnArgs),
+    /// Every move of a game with its annotation, evaluation and comment, responded as `tree`.
+    GetTree(GetTreeArgs),
+    AddEngine(AddEngineArgs),
+    GetEngineLog(GetEngineLogArgs),
+    /// Sends `setoption` to a running engine, like `Hash` or `Threads`. Options of engines yet
+    /// to start are given in their `EngineConfig`.
+    SetEngineOption(SetEngineOptionArgs),
+    /// Best move, principal variation and evaluation of a game's current position, searched in
+    /// the background and notified as `AnalysisFinished`.
+    Analyze(AnalyzeArgs),
+    /// Keeps analyzing the game's current position, whichever it is, reporting through
+    /// `AnalysisUpdate` notifications at most twice a second.
+    StartAnalysis(StartAnalysisArgs),
+    StopAnalysis(StopAnalysisArgs),
+    /// Lets an engine play one side of a game, replying to `Play` requests through
+    /// `OpponentMove` notifications. Navigated games wait for the next move played.
+    SetOpponent(SetOpponentArgs),
+    EngineMatch(EngineMatchArgs),
+    StopEngineMatch(StopEngineMatchArgs),
+    AnnotateGame(AnnotateGameArgs),
+    QuickEval(QuickEvalArgs),
+    /// Also stops quick evaluations.
+    StopAnnotation(StopAnnotationArgs),
+    /// Terminates the engines, the backend exits after responding.
+    Shutdown(ShutdownArgs),
+    OpenDatabase(OpenDatabaseArgs),
+    CloseDatabase(CloseDatabaseArgs),
+    GetDatabaseInfo(GetDatabaseInfoArgs),
+    ListDatabases(ListDatabasesArgs),
+    /// Database used by position searches and the explorer when they don't name one.
+    SetDefaultDatabase(SetDefaultDatabaseArgs),
+    DatabaseImportPgn(DatabaseImportPgnArgs),
+    StopDatabaseImport(StopDatabaseImportArgs),
+    DatabaseSearchPosition(DatabaseSearchPositionArgs),
+    DatabaseSearch(DatabaseSearchArgs),
+    DatabaseSearchText(DatabaseSearchTextArgs),
+    ExplorerFromDatabase(ExplorerFromDatabaseArgs),
+    ProbeBook(ProbeBookArgs),
+    OnlineTablebase(OnlineTablebaseArgs),
+    LichessExplorer(LichessExplorerArgs),
+    LichessCloudEval(LichessCloudEvalArgs),
+    OpenGameFromDatabase(OpenGameFromDatabaseArgs),
+    DatabaseListGames(DatabaseListGamesArgs),
+    DatabaseDeleteGames(DatabaseDeleteGamesArgs),
+    DatabaseUpdateHeaders(DatabaseUpdateHeadersArgs),
+    DatabaseFindDuplicates(DatabaseFindDuplicatesArgs),
+    DatabaseExportPgn(DatabaseExportPgnArgs),
+    StopDatabaseExport(StopDatabaseExportArgs),
+    DatabaseReindexPositions(DatabaseReindexPositionsArgs),
+    StopDatabaseReindex(StopDatabaseReindexArgs),
+    DatabaseMaintenance(DatabaseMaintenanceArgs),
+    /// Opens a lichess game from its URL or id.
+    LichessImportGame(LichessImportGameArgs),
+    /// Opens every chapter of a lichess study as a game.
+    LichessImportStudy(LichessImportStudyArgs),
+    /// Responds with the account the token belongs to and its scopes.
+    LichessSetToken(LichessSetTokenArgs),
+    LichessClearToken(LichessClearTokenArgs),
+    /// Points the lichess features at a self-hosted lila instance, or back at lichess.org.
+    SetLichessConfig(SetLichessConfigArgs),
+    /// Adds a game to a study, which needs a token with the `study:write` scope.
+    LichessPushStudyChapter(LichessPushStudyChapterArgs),
+    /// Most recent games of a user, to pick some for `LichessImportGames`.
+    LichessListUserGames(LichessListUserGamesArgs),
+    LichessImportGames(LichessImportGamesArgs),
+    /// Opens a lichess game that is being played and keeps playing its moves as notifications,
+    /// until the game is over or `LichessUnfollow` is sent. Meanwhile the game can't be changed.
+    LichessFollowGame(LichessFollowGameArgs),
+    LichessUnfollow(LichessUnfollowArgs),
+    /// Opens a lichess puzzle at the position to solve, after the opponent's move.
+    LichessImportPuzzle(LichessImportPuzzleArgs),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -93,19 +956,583 @@ pub struct PlayArgs {
     id: String,
     to: String,
     from: String,
+    /// Needed by pawn moves to the last rank, which fail with `PromotionRequired` without it.
+    #[serde(default)]
+    promotion: Option<Promotion>,
+    /// When the move was made, in milliseconds since the Unix epoch, for games on the clock.
+    /// The backend's time if absent.
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NavigateForwardArgs {
+    id: String,
+    forward: u16,
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NavigateToArgs {
+    id: String,
+    /// Moves from the starting position, clamped to the end of the line.
+    ply: usize,
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NavigateToEndArgs {
+    id: String,
+    #[serde(default)]
+    at_ms: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct NavigateBackArgs {
     id: String,
     back: u16,
+    /// Like `PlayArgs::at_ms`, leaving the clock's position pauses it.
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+/// Games of another group leave it. `group_id` loses the games it had before.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LinkGamesArgs {
+    group_id: String,
+    game_ids: Vec<String>,
+    mode: LinkMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct UnlinkGamesArgs {
+    group_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetMoveFormatArgs {
+    #[serde(default)]
+    notation: Notation,
+    #[serde(default)]
+    piece_locale: PieceLocale,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetClockArgs {
+    id: String,
+    /// Removes the clock when absent.
+    #[serde(default)]
+    time_control: Option<TimeControl>,
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ClockTickArgs {
+    id: String,
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+/// `solution_uci` holds the moves of both sides, the solver's first. The solution of the
+/// imported lichess puzzle when absent.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetPuzzleArgs {
+    id: String,
+    #[serde(default)]
+    solution_uci: Option<Vec<String>>,
+    /// Wrong attempts at a move before its piece is hinted, as many more for the whole move.
+    #[serde(default)]
+    attempts_before_hint: Option<u32>,
+    #[serde(default)]
+    at_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetCommentArgs {
+    id: String,
+    comment: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetGameInfoArgs {
+    id: String,
+    fields: GameInfoUpdate,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetResultArgs {
+    id: String,
+    result: GameResult,
+}
+
+/// A side left out keeps its player.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetPlayersArgs {
+    id: String,
+    #[serde(default)]
+    white: Option<Player>,
+    #[serde(default)]
+    black: Option<Player>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetEvaluationArgs {
+    id: String,
+    /// Removes the evaluation when not given.
+    #[serde(default)]
+    evaluation: Option<Evaluation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetAnnotationArgs {
+    id: String,
+    /// Removes the annotation when not given.
+    #[serde(default)]
+    annotation: Option<Annotation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DeleteContinuationsArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MarkRepertoireMoveArgs {
+    id: String,
+    /// Removes the mark instead.
+    #[serde(default)]
+    unmark: bool,
+}
+
+/// The opponent's moves are picked by their games in the default database, if there is one.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StartDrillArgs {
+    id: String,
+    color: Side,
+    /// Plies after which lines end.
+    #[serde(default)]
+    depth_limit: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct GetAllGamesArgs {}
 
-// TODO  more new game types (fen, pgn, path, etc.)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GetStatsArgs {}
+
+// TODO  more new game types (path, etc.)
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct NewGameArgs {
     id: String,
+    /// Starting position, the standard one if absent.
+    #[serde(default)]
+    fen: Option<String>,
+    /// Only needed for Chess960 positions whose castling rights look standard (KQkq with the king on e1).
+    #[serde(default)]
+    chess960: bool,
+    /// Game to open at its starting position, whose tags give the starting position instead of `fen`.
+    #[serde(default)]
+    pgn: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CloseGameArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExportPgnArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GetTreeArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AddEngineArgs {
+    engine_id: String,
+    #[serde(flatten)]
+    config: EngineConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GetEngineLogArgs {
+    engine_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetEngineOptionArgs {
+    engine_id: String,
+    name: String,
+    value: String,
+}
+
+/// `engine_id` is the default engine when absent.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AnalyzeArgs {
+    id: String,
+    #[serde(default)]
+    engine_id: Option<String>,
+    depth: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StartAnalysisArgs {
+    id: String,
+    #[serde(default)]
+    engine_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopAnalysisArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetOpponentArgs {
+    id: String,
+    /// Removes the opponent when not given.
+    #[serde(default)]
+    opponent: Option<Opponent>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct EngineMatchArgs {
+    id: String,
+    white_engine_id: String,
+    black_engine_id: String,
+    movetime_ms: u64,
+    max_plies: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopEngineMatchArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AnnotateGameArgs {
+    id: String,
+    /// The first engine registered when absent.
+    #[serde(default)]
+    engine_id: Option<String>,
+    movetime_per_ply_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct QuickEvalArgs {
+    id: String,
+    /// The first engine registered when absent.
+    #[serde(default)]
+    engine_id: Option<String>,
+    movetime_per_ply_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopAnnotationArgs {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ShutdownArgs {}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct OpenDatabaseArgs {
+    /// Id by which the other requests refer to the database.
+    db_id: String,
+    path: String,
+    /// Create a new database at `path` instead of opening an existing one.
+    #[serde(default)]
+    create: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CloseDatabaseArgs {
+    db_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GetDatabaseInfoArgs {
+    db_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ListDatabasesArgs {}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetDefaultDatabaseArgs {
+    db_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseImportPgnArgs {
+    db_id: String,
+    path: String,
+    #[serde(default)]
+    skip_duplicates: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopDatabaseImportArgs {
+    db_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseSearchPositionArgs {
+    #[serde(default)]
+    db_id: Option<String>,
+    fen: String,
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseSearchArgs {
+    db_id: String,
+    #[serde(flatten)]
+    filters: SearchFilters,
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+/// `query` is in SQLite's FTS5 syntax, plain words matching games containing all of them.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseSearchTextArgs {
+    db_id: String,
+    query: String,
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+/// The position is the current one of game `id`, or given by `fen`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExplorerFromDatabaseArgs {
+    #[serde(default)]
+    db_id: Option<String>,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    fen: Option<String>,
+}
+
+/// Probes the book at `book_path` for the current position of game `id`, or the one given by `fen`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ProbeBookArgs {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    fen: Option<String>,
+    book_path: String,
+}
+
+/// Looks up the current position of game `id`, or the one given by `fen`, in the lichess tablebase.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct OnlineTablebaseArgs {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    fen: Option<String>,
+}
+
+/// Looks up the current position of game `id`, or the one given by `fen`, in the lichess opening
+/// explorer.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessExplorerArgs {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    fen: Option<String>,
+    #[serde(default)]
+    source: ExplorerSource,
+    #[serde(default)]
+    cache: CacheMode,
+}
+
+/// Evaluation lichess shares of the current position of game `id`, or the one given by `fen`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessCloudEvalArgs {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    fen: Option<String>,
+    /// Lines evaluated.
+    #[serde(default = "default_multi_pv")]
+    multi_pv: u32,
+    #[serde(default)]
+    cache: CacheMode,
+}
+
+fn default_multi_pv() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct OpenGameFromDatabaseArgs {
+    db_id: String,
+    db_game_id: i64,
+    /// Id of the new game, `<db_id>-<db_game_id>` by default.
+    #[serde(default)]
+    as_id: Option<String>,
+}
+
+/// Games are listed in the order they were added when `sort_by` is missing.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseListGamesArgs {
+    db_id: String,
+    #[serde(default)]
+    sort_by: Option<SortColumn>,
+    #[serde(default)]
+    descending: bool,
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseDeleteGamesArgs {
+    db_id: String,
+    db_game_ids: Vec<i64>,
+}
+
+/// Tags set to `null` are removed.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseUpdateHeadersArgs {
+    db_id: String,
+    db_game_id: i64,
+    fields: HashMap<String, Option<String>>,
+}
+
+/// `limit` applies to the groups of exact and of near duplicates separately.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseFindDuplicatesArgs {
+    db_id: String,
+    limit: u32,
+}
+
+/// Exports the games matching `filter` to the PGN file at `path`, sorted like `DatabaseListGames`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseExportPgnArgs {
+    db_id: String,
+    #[serde(default)]
+    filter: SearchFilters,
+    #[serde(default)]
+    sort_by: Option<SortColumn>,
+    #[serde(default)]
+    descending: bool,
+    path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopDatabaseExportArgs {
+    path: String,
+}
+
+/// Rebuilds the indexes of the games whose entries are missing or stale. With `check_only`, they
+/// are only counted.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseReindexPositionsArgs {
+    db_id: String,
+    #[serde(default)]
+    check_only: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StopDatabaseReindexArgs {
+    db_id: String,
+}
+
+/// Actions run in the order given.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DatabaseMaintenanceArgs {
+    db_id: String,
+    actions: Vec<MaintenanceAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessImportGameArgs {
+    game_or_url: String,
+    #[serde(default)]
+    as_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessSetTokenArgs {
+    token: LichessToken,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessClearTokenArgs {}
+
+/// `base_url` is the address of a lila instance, like `http://localhost:9663`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SetLichessConfigArgs {
+    #[serde(default)]
+    base_url: Option<String>,
+}
+
+/// `since` and `until` are timestamps in milliseconds.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessListUserGamesArgs {
+    username: String,
+    max: u32,
+    #[serde(default)]
+    perf_type: Option<String>,
+    #[serde(default)]
+    since: Option<u64>,
+    #[serde(default)]
+    until: Option<u64>,
+}
+
+/// `target` is `"state"` (the default) or `{"db_id": ...}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessImportGamesArgs {
+    game_ids: Vec<String>,
+    #[serde(default)]
+    target: ImportTarget,
+}
+
+/// `game_id` is the game's id or URL. The game is opened as `as_id`, or `lichess-<game id>`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessFollowGameArgs {
+    game_id: String,
+    #[serde(default)]
+    as_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessUnfollowArgs {
+    id: String,
+}
+
+/// `puzzle_id` is the puzzle's id or URL. The game is opened as `as_id`, or
+/// `lichess-puzzle-<puzzle id>`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessImportPuzzleArgs {
+    puzzle_id: String,
+    #[serde(default)]
+    as_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessPushStudyChapterArgs {
+    id: String,
+    study_id: String,
+    chapter_name: String,
+}
+
+/// Chapters are opened as `<as_prefix>-1`, `<as_prefix>-2`, ... The prefix defaults to
+/// `lichess-<study id>`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LichessImportStudyArgs {
+    study_id: String,
+    #[serde(default)]
+    as_prefix: Option<String>,
 }
@@ -0,0 +1,265 @@
+use crate::api::{dispatch_request, response_from_notification, Notification, Request};
+use crate::errors::{Error, ErrorType};
+use crate::state::StateHandle;
+use crate::stdio::MessageWriter;
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Request streams of the `bench` command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scenario {
+    /// One ply back then forward again at the end of a game of `BenchConfig::plies`.
+    Navigation,
+    /// A new game per iteration. Games aren't closed, so they pile up.
+    NewGames,
+    /// All the games, out of `BenchConfig::games` games of 20 plies.
+    GetAll,
+    /// A notification per iteration written to the null device, as stdio buffers them.
+    Notifications,
+}
+
+/// Parameters of the `bench` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchConfig {
+    pub scenario: Scenario,
+    pub iterations: usize,
+    /// Length of the navigation game, to check its cost doesn't grow with it.
+    pub plies: usize,
+    /// Games open in the get-all scenario.
+    pub games: usize,
+    /// Print the results as a line of JSON rather than a table.
+    pub json: bool,
+}
+
+/// Latencies are in microseconds.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BenchResults {
+    pub scenario: &'static str,
+    pub requests: usize,
+    pub seconds: f64,
+    pub requests_per_second: f64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    /// Only known on Linux.
+    pub peak_rss_kb: Option<u64>,
+}
+
+impl Scenario {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scenario::Navigation => "navigation",
+            Scenario::NewGames => "new-games",
+            Scenario::GetAll => "get-all",
+            Scenario::Notifications => "notifications",
+        }
+    }
+}
+
+/// Moves of the knights out and back, which never ends the game.
+const SHUFFLE: [(&str, &str); 4] = [("g1", "f3"), ("g8", "f6"), ("f3", "g1"), ("f6", "g8")];
+
+fn play(id: &str, ply: usize) -> Value {
+    let (from, to) = SHUFFLE[ply % SHUFFLE.len()];
+    json!({"method": "play", "params": {"id": id, "from": from, "to": to}})
+}
+
+/// Untimed requests setting up the scenario.
+fn setup(config: &BenchConfig) -> Vec<Value> {
+    let new_game = |id: &str| json!({"method": "new_game", "params": {"id": id}});
+    match config.scenario {
+        Scenario::Navigation => std::iter::once(new_game("bench"))
+            .chain((0..config.plies).map(|ply| play("bench", ply)))
+            .collect(),
+        Scenario::NewGames | Scenario::Notifications => Vec::new(),
+        Scenario::GetAll => (0..config.games)
+            .flat_map(|game| {
+                let id = format!("bench-{}", game);
+                std::iter::once(new_game(&id)).chain((0..20).map(move |ply| play(&id, ply)))
+            })
+            .collect(),
+    }
+}
+
+/// Timed requests of iteration `i`.
+fn iteration(scenario: Scenario, i: usize, plies: usize) -> Vec<Value> {
+    match scenario {
+        Scenario::Navigation => vec![
+            json!({"method": "navigate_back", "params": {"id": "bench", "back": 1}}),
+            play("bench", plies.saturating_sub(1)),
+        ],
+        Scenario::NewGames => {
+            vec![json!({"method": "new_game", "params": {"id": format!("bench-{}", i)}})]
+        }
+        Scenario::GetAll => vec![json!({"method": "get_all_games", "params": {}})],
+        Scenario::Notifications => Vec::new(),
+    }
+}
+
+/// Dispatches a request like any transport would, failing on an error response.
+async fn dispatch(request: Value, state: &StateHandle) -> Result<Duration, Error> {
+    let request: Request = serde_json::from_value(request)?;
+    let start = Instant::now();
+    let response = dispatch_request(request, state).await?;
+    let elapsed = start.elapsed();
+    let response = serde_json::to_value(&response)?;
+    if !response["error"].is_null() {
+        return Err(Error::new(ErrorType::ChessRules)
+            .with_message(&format!("Benchmark request failed: {}", response["error"])));
+    }
+    Ok(elapsed)
+}
+
+/// Writes `count` notifications to the null device, timing each. Only a full buffer flushes them,
+/// the window of stdio needing its event loop.
+fn write_notifications(count: usize) -> Result<Vec<Duration>, Error> {
+    let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+    let mut writer = MessageWriter::new(OpenOptions::new().write(true).open(null)?);
+    let notification = response_from_notification(Notification::AnnotationProgress {
+        id: String::from("bench"),
+        ply: 1,
+        total: 100,
+        evaluation: None,
+    });
+    let mut latencies = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = Instant::now();
+        writer.notify(&notification)?;
+        latencies.push(start.elapsed());
+    }
+    writer.flush()?;
+    Ok(latencies)
+}
+
+/// Runs `config.iterations` of the scenario on a fresh state, timing every request.
+pub async fn bench(config: &BenchConfig) -> Result<BenchResults, Error> {
+    let (scenario, plies) = (config.scenario, config.plies);
+    let state = StateHandle::default();
+    for request in setup(config) {
+        dispatch(request, &state).await?;
+    }
+    let mut latencies = match scenario {
+        Scenario::Notifications => write_notifications(config.iterations)?,
+        _ => Vec::new(),
+    };
+    for i in 0..config.iterations {
+        for request in iteration(scenario, i, plies) {
+            latencies.push(dispatch(request, &state).await?);
+        }
+    }
+
+    let total: Duration = latencies.iter().sum();
+    latencies.sort();
+    let percentile = |p: f64| {
+        let index = ((latencies.len().max(1) - 1) as f64 * p).round() as usize;
+        latencies
+            .get(index)
+            .map_or(0, |latency| latency.as_micros() as u64)
+    };
+    Ok(BenchResults {
+        scenario: scenario.name(),
+        requests: latencies.len(),
+        seconds: total.as_secs_f64(),
+        requests_per_second: latencies.len() as f64 / total.as_secs_f64().max(1e-9),
+        p50_us: percentile(0.5),
+        p95_us: percentile(0.95),
+        p99_us: percentile(0.99),
+        peak_rss_kb: peak_rss_kb(),
+    })
+}
+
+/// Runs the scenario and writes its results to `out`.
+pub async fn run(config: &BenchConfig, out: &mut impl Write) -> Result<bool, Error> {
+    let results = bench(config).await?;
+    if config.json {
+        serde_json::to_writer(&mut *out, &results)?;
+        writeln!(out)?;
+        return Ok(true);
+    }
+    writeln!(out, "scenario    {}", results.scenario)?;
+    writeln!(out, "requests    {}", results.requests)?;
+    writeln!(out, "time        {:.3}s", results.seconds)?;
+    writeln!(
+        out,
+        "throughput  {:.0} requests/s",
+        results.requests_per_second
+    )?;
+    writeln!(out, "p50         {} µs", results.p50_us)?;
+    writeln!(out, "p95         {} µs", results.p95_us)?;
+    writeln!(out, "p99         {} µs", results.p99_us)?;
+    if let Some(rss) = results.peak_rss_kb {
+        writeln!(out, "peak RSS    {} kB", rss)?;
+    }
+    Ok(true)
+}
+
+fn peak_rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scenarios() {
+        let scenarios = [
+            Scenario::Navigation,
+            Scenario::NewGames,
+            Scenario::GetAll,
+            Scenario::Notifications,
+        ];
+        for &scenario in &scenarios {
+            let config = BenchConfig {
+                scenario,
+                iterations: 3,
+                plies: 100,
+                games: 10,
+                json: false,
+            };
+            let results = bench(&config).await.unwrap();
+            assert_eq!(results.scenario, scenario.name());
+            let per_iteration = if scenario == Scenario::Navigation {
+                2
+            } else {
+                1
+            };
+            assert_eq!(results.requests, 3 * per_iteration);
+            assert!(results.p50_us <= results.p95_us && results.p95_us <= results.p99_us);
+        }
+
+        let config = BenchConfig {
+            scenario: Scenario::GetAll,
+            iterations: 2,
+            plies: 100,
+            games: 40,
+            json: true,
+        };
+        let mut out = Vec::new();
+        assert!(run(&config, &mut out).await.unwrap());
+        let results: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(results["scenario"], "get-all");
+        assert_eq!(results["requests"], 2);
+
+        let mut table = Vec::new();
+        let config = BenchConfig {
+            json: false,
+            ..config
+        };
+        run(&config, &mut table).await.unwrap();
+        assert!(String::from_utf8(table).unwrap().contains("throughput"));
+    }
+}
@@ -1,6 +1,82 @@
-use clap::{App, AppSettings, Arg, ArgMatches};
+use crate::analyze::AnalyzeConfig;
+use crate::bench::{BenchConfig, Scenario};
+use crate::convert::{ConvertConfig, Format};
+use crate::engine::EngineConfig;
+use crate::explorer::DEFAULT_CACHE_TTL;
+use crate::game::{Game, DEFAULT_MAX_PLIES};
+use crate::logging::{LogConfig, LogFormat};
+use crate::perft::PerftConfig;
+use crate::state::DEFAULT_REQUEST_ECHO_LIMIT;
 
-pub fn parse() -> ArgMatches {
+use crate::errors::Error;
+
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind};
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What the command line asks of the backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub book: Option<PathBuf>,
+    pub session: Option<PathBuf>,
+    pub online_cache: Option<PathBuf>,
+    /// Age past which cached lichess answers are fetched again.
+    pub online_cache_ttl: Duration,
+    /// Engines started with the backend, by id. The first one is the default engine.
+    pub engines: Vec<(String, EngineConfig)>,
+    /// Where the HTTP API is served, if anywhere.
+    pub http: Option<SocketAddr>,
+    /// Requests are read from stdin and answered on stdout.
+    pub stdio: bool,
+    /// Unix socket served like stdio, one connection per client.
+    pub listen_unix: Option<PathBuf>,
+    /// TCP address served like stdio.
+    pub listen_tcp: Option<SocketAddr>,
+    /// `--listen-tcp` may accept connections from other machines.
+    pub allow_remote: bool,
+    /// Token the socket connections must send first.
+    pub auth_token: Option<String>,
+    pub auth_token_file: Option<PathBuf>,
+    /// Position of a game opened at startup, under `startup_id`.
+    pub fen: Option<String>,
+    pub startup_id: String,
+    pub log: LogConfig,
+    /// Bytes of a failed request line echoed in its error.
+    pub request_echo_limit: usize,
+    /// Unrecoverable errors are sent with a backtrace.
+    pub backtraces: bool,
+    /// Longest line a game can be played or imported to.
+    pub max_plies: usize,
+    /// File every request line is appended to.
+    pub record: Option<PathBuf>,
+    /// Recording whose requests are answered instead of stdin's.
+    pub replay: Option<PathBuf>,
+    /// Requests of the replay are spaced as recorded, this many times faster.
+    pub replay_speed: Option<f64>,
+    /// Set to annotate a PGN file instead of running as a backend.
+    pub analyze: Option<AnalyzeConfig>,
+    /// Set to count the nodes of the move tree instead of running as a backend.
+    pub perft: Option<PerftConfig>,
+    pub bench: Option<BenchConfig>,
+    /// Set to convert games between formats instead of running as a backend.
+    pub convert: Option<ConvertConfig>,
+}
+
+pub fn parse() -> Config {
+    let config = Config::from_matches(&app().get_matches());
+    if let Err(message) = config.check() {
+        match clap::Error::with_description(message, ErrorKind::ArgumentConflict) {
+            Ok(err) => err.exit(),
+            Err(_) => std::process::exit(2),
+        }
+    }
+    config
+}
+
+fn app() -> App<'static> {
     let about = "\nRust backend to a chess GUI frontend. Comunicates in JSON through stdin/out.\nContribute at https://github.com/AlexandreGauthier/bigchess";
 
     App::new("bigchess-core")
@@ -11,6 +87,860 @@ pub fn parse() -> ArgMatches {
                 .long("start")
                 .about("Start listening to STDIN"),
         )
+        .arg(
+            Arg::with_name("book")
+                .long("book")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("Polyglot opening book whose moves are shown with every game"),
+        )
+        .arg(
+            Arg::with_name("session")
+                .long("session")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("File where the open databases are remembered between sessions"),
+        )
+        .arg(
+            Arg::with_name("online-cache")
+                .long("online-cache")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("SQLite file caching the lichess explorer and cloud evaluations"),
+        )
+        .arg(
+            Arg::with_name("online-cache-ttl")
+                .long("online-cache-ttl")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|seconds| seconds.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                .about("Age past which cached lichess answers are fetched again [default: 86400]"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("PATH[:ID]")
+                .about("Start this UCI engine with the backend, the first one being the default [default id: the file name]"),
+        )
+        .arg(
+            Arg::with_name("engine-option")
+                .long("engine-option")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("NAME=VALUE")
+                .requires("engine")
+                .validator(|option| match option.split_once('=') {
+                    Some((name, _)) if !name.trim().is_empty() => Ok(()),
+                    _ => Err(String::from("expected NAME=VALUE, like Hash=256")),
+                })
+                .about("UCI option set on every engine of --engine"),
+        )
+        .arg(
+            Arg::with_name("http-port")
+                .long("http-port")
+                .takes_value(true)
+                .value_name("PORT")
+                .validator(|port| port.parse::<u16>())
+                .about("Also serve the API over HTTP on this port"),
+        )
+        .arg(
+            Arg::with_name("http-addr")
+                .long("http-addr")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .requires("http-port")
+                .validator(|addr| addr.parse::<IpAddr>())
+                .about("Address the HTTP API listens on [default: 127.0.0.1]"),
+        )
+        .arg(
+            Arg::with_name("no-stdio")
+                .long("no-stdio")
+                .about("Only serve the other transports, ignoring STDIN"),
+        )
+        .arg(
+            Arg::with_name("listen-unix")
+                .long("listen-unix")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("Also serve the stdio protocol on this Unix socket"),
+        )
+        .arg(
+            Arg::with_name("listen-tcp")
+                .long("listen-tcp")
+                .takes_value(true)
+                .value_name("ADDRESS:PORT")
+                .validator(|addr| addr.parse::<SocketAddr>())
+                .about("Also serve the stdio protocol on this TCP address"),
+        )
+        .arg(
+            Arg::with_name("allow-remote")
+                .long("allow-remote")
+                .requires("listen-tcp")
+                .about("Let --listen-tcp listen on an address other machines can reach"),
+        )
+        .arg(
+            Arg::with_name("auth-token")
+                .long("auth-token")
+                .takes_value(true)
+                .value_name("TOKEN")
+                .conflicts_with("auth-token-file")
+                .about("Token socket connections must send first, as {\"token\": \"...\"}"),
+        )
+        .arg(
+            Arg::with_name("auth-token-file")
+                .long("auth-token-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("File holding the token of --auth-token"),
+        )
+        .arg(
+            Arg::with_name("fen")
+                .long("fen")
+                .takes_value(true)
+                .value_name("FEN")
+                .validator(|fen| Game::from_fen(fen.to_string()))
+                .about("Start with a game at this position"),
+        )
+        .arg(
+            Arg::with_name("id")
+                .long("id")
+                .takes_value(true)
+                .value_name("ID")
+                .requires("fen")
+                .about("Id of the game opened by --fen [default: startup]"),
+        )
+        .arg(
+            Arg::with_name("request-echo-limit")
+                .long("request-echo-limit")
+                .takes_value(true)
+                .value_name("BYTES")
+                .validator(|bytes| bytes.parse::<usize>().map(|_| ()).map_err(|err| err.to_string()))
+                .about("Longest part of a failed request line echoed in its error [default: 256]"),
+        )
+        .arg(
+            Arg::with_name("backtraces")
+                .long("backtraces")
+                .about("Send a backtrace in the details of unrecoverable errors"),
+        )
+        .arg(
+            Arg::with_name("max-plies")
+                .long("max-plies")
+                .takes_value(true)
+                .value_name("PLIES")
+                .validator(|plies| plies.parse::<usize>().map(|_| ()).map_err(|err| err.to_string()))
+                .about("Longest line a game can be played or imported to [default: 10000]"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("Append every request received to this file, to replay it later"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with_all(&["http-port", "listen-unix", "listen-tcp", "no-stdio"])
+                .about("Answer the requests of a recording instead of STDIN, then exit"),
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .long("replay-speed")
+                .takes_value(true)
+                .value_name("FACTOR")
+                .requires("replay")
+                .validator(|factor| match factor.parse::<f64>() {
+                    Ok(factor) if factor > 0.0 => Ok(()),
+                    _ => Err(String::from("expected a positive factor, like 1 or 2.5")),
+                })
+                .about("Space the replayed requests as recorded, this many times faster [default: no waiting]"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .takes_value(true)
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .about(
+                    "Most detailed logs written, read from RUST_LOG if not given [default: warn]",
+                ),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .about("Write logs to this file, rotated daily, instead of STDERR"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_values(&["plain", "json"])
+                .default_value("plain")
+                .about("Format of the logs"),
+        )
+        .subcommand(
+            App::new("analyze")
+                .about("Annotate every game of a PGN file with an engine, without a frontend")
+                .arg(
+                    Arg::with_name("engine")
+                        .long("engine")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .required(true)
+                        .about("UCI engine evaluating the positions"),
+                )
+                .arg(
+                    Arg::with_name("movetime")
+                        .long("movetime")
+                        .takes_value(true)
+                        .value_name("MS")
+                        .default_value("200")
+                        .validator(|ms| ms.parse::<u64>())
+                        .about("Time spent on every position, in milliseconds"),
+                )
+                .arg(
+                    Arg::with_name("in")
+                        .long("in")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .required(true)
+                        .about("PGN file to annotate"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .required(true)
+                        .about("PGN file the annotated games are written to"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1")
+                        .validator(|n| match n.parse::<usize>() {
+                            Ok(0) => Err(String::from("at least one thread is needed")),
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .about("Games annotated in parallel, each with its own engine"),
+                ),
+        )
+        .subcommand(
+            App::new("perft")
+                .about("Count the positions reachable from a position, timing the move generation")
+                .arg(
+                    Arg::with_name("fen")
+                        .long("fen")
+                        .takes_value(true)
+                        .value_name("FEN")
+                        .validator(|fen| Game::from_fen(fen.to_string()))
+                        .about("Position counted from [default: the start position]"),
+                )
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("5")
+                        .validator(|n| match n.parse::<u32>() {
+                            Ok(0) => Err(String::from("the depth is at least 1")),
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .about("Plies counted"),
+                )
+                .arg(
+                    Arg::with_name("divide")
+                        .long("divide")
+                        .about("Also count the positions under each legal move"),
+                )
+                .arg(Arg::with_name("verify").long("verify").about(
+                    "Check the counts of the start position or Kiwipete against known values",
+                ))
+                .arg(Arg::with_name("hash").long("hash").about(
+                    "Time updating position hashes move by move against hashing every position",
+                )),
+        )
+        .subcommand(
+            App::new("bench")
+                .about("Time requests dispatched in process, without any IO")
+                .arg(
+                    Arg::with_name("scenario")
+                        .long("scenario")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["navigation", "new-games", "get-all", "notifications"])
+                        .about("Timed: moves back and forth, new games, getting all games, or writing notifications"),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1000")
+                        .validator(|n| match n.parse::<usize>() {
+                            Ok(0) => Err(String::from("at least one iteration is needed")),
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .about("Times the scenario's requests are sent"),
+                )
+                .arg(
+                    Arg::with_name("plies")
+                        .long("plies")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("100")
+                        .validator(|n| match n.parse::<usize>() {
+                            Ok(0) => Err(String::from("at least one ply is needed")),
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .about("Length of the game navigated in the navigation scenario"),
+                )
+                .arg(
+                    Arg::with_name("games")
+                        .long("games")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("10")
+                        .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|err| err.to_string()))
+                        .about("Games open in the get-all scenario"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .about("Print the results as a line of JSON"),
+                ),
+        )
+        .subcommand(
+            App::new("convert")
+                .about("Convert games between PGN, JSON, FEN lists and UCI move lists")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["pgn", "json"])
+                        .about("Format of the input, JSON holding one game per line"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["pgn", "json", "fens", "uci"])
+                        .about(
+                            "Format of the output, FEN lists and UCI moves following main lines",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("in")
+                        .long("in")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .default_value("-")
+                        .about("File read, - for STDIN"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .default_value("-")
+                        .about("File written, - for STDOUT"),
+                ),
+        )
         .setting(AppSettings::ArgRequiredElseHelp)
-        .get_matches()
+}
+
+impl Config {
+    fn from_matches(matches: &ArgMatches) -> Config {
+        let http = matches.value_of("http-port").map(|port| {
+            let addr = matches.value_of("http-addr").unwrap_or("127.0.0.1");
+            // Both were validated while parsing
+            SocketAddr::new(addr.parse().unwrap(), port.parse().unwrap())
+        });
+        Config {
+            book: matches.value_of("book").map(PathBuf::from),
+            session: matches.value_of("session").map(PathBuf::from),
+            online_cache: matches.value_of("online-cache").map(PathBuf::from),
+            // Validated while parsing
+            online_cache_ttl: matches
+                .value_of("online-cache-ttl")
+                .map_or(DEFAULT_CACHE_TTL, |seconds| {
+                    Duration::from_secs(seconds.parse().unwrap())
+                }),
+            engines: startup_engines(matches),
+            http,
+            stdio: !matches.is_present("no-stdio"),
+            // Validated while parsing
+            listen_unix: matches.value_of("listen-unix").map(PathBuf::from),
+            listen_tcp: matches
+                .value_of("listen-tcp")
+                .map(|addr| addr.parse().unwrap()),
+            allow_remote: matches.is_present("allow-remote"),
+            auth_token: matches.value_of("auth-token").map(String::from),
+            auth_token_file: matches.value_of("auth-token-file").map(PathBuf::from),
+            fen: matches.value_of("fen").map(String::from),
+            startup_id: matches.value_of("id").unwrap_or("startup").to_string(),
+            analyze: matches
+                .subcommand_matches("analyze")
+                .map(|analyze| AnalyzeConfig {
+                    engine: analyze.value_of("engine").unwrap_or_default().to_string(),
+                    // Validated while parsing
+                    movetime_ms: analyze
+                        .value_of("movetime")
+                        .unwrap_or("200")
+                        .parse()
+                        .unwrap(),
+                    input: PathBuf::from(analyze.value_of("in").unwrap_or_default()),
+                    output: PathBuf::from(analyze.value_of("out").unwrap_or_default()),
+                    threads: analyze.value_of("threads").unwrap_or("1").parse().unwrap(),
+                }),
+            perft: matches
+                .subcommand_matches("perft")
+                .map(|perft| PerftConfig {
+                    fen: perft.value_of("fen").map(String::from),
+                    // Validated while parsing
+                    depth: perft.value_of("depth").unwrap_or("5").parse().unwrap(),
+                    divide: perft.is_present("divide"),
+                    verify: perft.is_present("verify"),
+                    hash: perft.is_present("hash"),
+                }),
+            bench: matches
+                .subcommand_matches("bench")
+                .map(|bench| BenchConfig {
+                    scenario: match bench.value_of("scenario") {
+                        Some("new-games") => Scenario::NewGames,
+                        Some("get-all") => Scenario::GetAll,
+                        Some("notifications") => Scenario::Notifications,
+                        _ => Scenario::Navigation,
+                    },
+                    // Validated while parsing
+                    iterations: bench
+                        .value_of("iterations")
+                        .unwrap_or("1000")
+                        .parse()
+                        .unwrap(),
+                    plies: bench.value_of("plies").unwrap_or("100").parse().unwrap(),
+                    games: bench.value_of("games").unwrap_or("10").parse().unwrap(),
+                    json: bench.is_present("json"),
+                }),
+            convert: matches
+                .subcommand_matches("convert")
+                .map(|convert| ConvertConfig {
+                    from: format(convert.value_of("from")),
+                    to: format(convert.value_of("to")),
+                    input: convert.value_of("in").unwrap_or("-").to_string(),
+                    output: convert.value_of("out").unwrap_or("-").to_string(),
+                }),
+            request_echo_limit: matches
+                .value_of("request-echo-limit")
+                .map_or(DEFAULT_REQUEST_ECHO_LIMIT, |bytes| bytes.parse().unwrap()),
+            backtraces: matches.is_present("backtraces"),
+            max_plies: matches
+                .value_of("max-plies")
+                .map_or(DEFAULT_MAX_PLIES, |plies| plies.parse().unwrap()),
+            record: matches.value_of("record").map(PathBuf::from),
+            replay: matches.value_of("replay").map(PathBuf::from),
+            replay_speed: matches
+                .value_of("replay-speed")
+                .map(|factor| factor.parse().unwrap()),
+            log: LogConfig {
+                level: matches.value_of("log-level").map(String::from),
+                file: matches.value_of("log-file").map(PathBuf::from),
+                format: match matches.value_of("log-format") {
+                    Some("json") => LogFormat::Json,
+                    _ => LogFormat::Plain,
+                },
+            },
+        }
+    }
+
+    /// Combinations of arguments clap can't rule out.
+    fn check(&self) -> Result<(), String> {
+        let transports =
+            self.http.is_some() || self.listen_unix.is_some() || self.listen_tcp.is_some();
+        if !self.stdio && !transports {
+            return Err(String::from(
+                "--no-stdio needs another transport: --http-port, --listen-unix or --listen-tcp",
+            ));
+        }
+        match self.listen_tcp {
+            Some(addr) if !addr.ip().is_loopback() && !self.allow_remote => Err(format!(
+                "--listen-tcp {} accepts connections from other machines, add --allow-remote",
+                addr
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Token of `--auth-token` or of the file of `--auth-token-file`.
+    pub fn auth_token(&self) -> Result<Option<String>, Error> {
+        match &self.auth_token_file {
+            Some(path) => Ok(Some(std::fs::read_to_string(path)?.trim().to_string())),
+            None => Ok(self.auth_token.clone()),
+        }
+    }
+}
+
+/// Engines of `--engine`, named after their file when no id is given, with the options of
+/// `--engine-option`.
+fn startup_engines(matches: &ArgMatches) -> Vec<(String, EngineConfig)> {
+    let options: BTreeMap<String, String> = matches
+        .values_of("engine-option")
+        .into_iter()
+        .flatten()
+        .filter_map(|option| option.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut engines: Vec<(String, EngineConfig)> = Vec::new();
+    for spec in matches.values_of("engine").into_iter().flatten() {
+        let (path, id) = match spec.rsplit_once(':') {
+            // Not a drive letter or a colon inside the path
+            Some((path, id)) if !path.is_empty() && !id.is_empty() && !id.contains(['/', '\\']) => {
+                (path, id.to_string())
+            }
+            _ => (spec, engine_name(spec)),
+        };
+        let mut unique = id.clone();
+        let mut count = 1;
+        while engines.iter().any(|(existing, _)| *existing == unique) {
+            count += 1;
+            unique = format!("{}-{}", id, count);
+        }
+        let mut config = EngineConfig::new(path);
+        config.options = options.clone();
+        engines.push((unique, config));
+    }
+    engines
+}
+
+fn engine_name(path: &str) -> String {
+    Path::new(path).file_stem().map_or_else(
+        || String::from("engine"),
+        |stem| stem.to_string_lossy().into_owned(),
+    )
+}
+
+/// Validated while parsing
+fn format(name: Option<&str>) -> Format {
+    match name {
+        Some("json") => Format::Json,
+        Some("fens") => Format::Fens,
+        Some("uci") => Format::Uci,
+        _ => Format::Pgn,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Option<Config> {
+        let args = std::iter::once("bigchess-core").chain(args.iter().copied());
+        let matches = app().try_get_matches_from(args).ok()?;
+        let config = Config::from_matches(&matches);
+        config.check().ok()?;
+        Some(config)
+    }
+
+    #[test]
+    fn http() {
+        let stdio_only = config(&["--start"]).unwrap();
+        assert_eq!(stdio_only.http, None);
+        assert!(stdio_only.stdio);
+
+        let http = config(&["--http-port", "8080", "--no-stdio"]).unwrap();
+        assert_eq!(http.http, Some("127.0.0.1:8080".parse().unwrap()));
+        assert!(!http.stdio);
+        let http = config(&["--http-port", "8080", "--http-addr", "0.0.0.0"]).unwrap();
+        assert_eq!(http.http, Some("0.0.0.0:8080".parse().unwrap()));
+        assert!(http.stdio);
+
+        assert!(config(&["--http-port", "70000"]).is_none());
+        assert!(config(&["--http-port", "8080", "--http-addr", "localhost:1"]).is_none());
+        assert!(config(&["--no-stdio"]).is_none());
+    }
+
+    #[test]
+    fn engines() {
+        assert!(config(&["--start"]).unwrap().engines.is_empty());
+
+        let args = [
+            "--engine",
+            "/usr/bin/stockfish",
+            "--engine",
+            "/opt/lc0/lc0:leela",
+            "--engine",
+            "/usr/local/bin/stockfish.exe",
+            "--engine-option",
+            "Hash=256",
+            "--engine-option",
+            "Threads = 4",
+        ];
+        let engines = config(&args).unwrap().engines;
+        let ids: Vec<&str> = engines.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["stockfish", "leela", "stockfish-2"]);
+        assert_eq!(engines[1].1.path, "/opt/lc0/lc0");
+        assert_eq!(engines[2].1.path, "/usr/local/bin/stockfish.exe");
+        for (_, engine) in &engines {
+            assert_eq!(engine.options["Hash"], "256");
+            assert_eq!(engine.options["Threads"], "4");
+        }
+
+        let windows = config(&["--engine", "C:\\Engines\\komodo.exe"]).unwrap();
+        assert_eq!(windows.engines[0].1.path, "C:\\Engines\\komodo.exe");
+
+        assert!(config(&["--engine", "sf", "--engine-option", "Hash"]).is_none());
+        assert!(config(&["--engine-option", "Hash=256"]).is_none());
+    }
+
+    #[test]
+    fn sockets() {
+        let stdio_only = config(&["--start"]).unwrap();
+        assert_eq!(stdio_only.listen_unix, None);
+        assert_eq!(stdio_only.listen_tcp, None);
+
+        let args = [
+            "--listen-unix",
+            "/tmp/bigchess.sock",
+            "--listen-tcp",
+            "127.0.0.1:7788",
+            "--auth-token",
+            "secret",
+            "--no-stdio",
+        ];
+        let sockets = config(&args).unwrap();
+        assert_eq!(
+            sockets.listen_unix,
+            Some(PathBuf::from("/tmp/bigchess.sock"))
+        );
+        assert_eq!(sockets.listen_tcp, Some("127.0.0.1:7788".parse().unwrap()));
+        assert_eq!(sockets.auth_token().unwrap().as_deref(), Some("secret"));
+        assert!(!sockets.stdio);
+        assert!(config(&["--listen-unix", "/tmp/bigchess.sock", "--no-stdio"]).is_some());
+
+        // Remote addresses must be asked for
+        assert!(config(&["--listen-tcp", "0.0.0.0:7788"]).is_none());
+        assert!(config(&["--listen-tcp", "192.168.1.2:7788"]).is_none());
+        let remote = config(&["--listen-tcp", "0.0.0.0:7788", "--allow-remote"]).unwrap();
+        assert!(remote.allow_remote);
+        assert!(config(&["--allow-remote"]).is_none());
+        assert!(config(&["--listen-tcp", "localhost"]).is_none());
+
+        let token_file =
+            std::env::temp_dir().join(format!("bigchess-token-{}", std::process::id()));
+        std::fs::write(&token_file, "from file\n").unwrap();
+        let path = token_file.to_str().unwrap();
+        let args = ["--listen-tcp", "[::1]:7788", "--auth-token-file", path];
+        let from_file = config(&args).unwrap();
+        assert_eq!(
+            from_file.auth_token().unwrap().as_deref(),
+            Some("from file")
+        );
+        assert!(config(&["--auth-token", "a", "--auth-token-file", path]).is_none());
+        std::fs::remove_file(&token_file).unwrap();
+    }
+
+    #[test]
+    fn request_echo_limit() {
+        let default = config(&["--start"]).unwrap();
+        assert_eq!(default.request_echo_limit, DEFAULT_REQUEST_ECHO_LIMIT);
+        let none = config(&["--request-echo-limit", "0"]).unwrap();
+        assert_eq!(none.request_echo_limit, 0);
+        assert!(config(&["--request-echo-limit", "-1"]).is_none());
+        assert!(!default.backtraces);
+        assert!(config(&["--backtraces"]).unwrap().backtraces);
+        assert_eq!(default.max_plies, DEFAULT_MAX_PLIES);
+        assert_eq!(config(&["--max-plies", "500"]).unwrap().max_plies, 500);
+        assert!(config(&["--max-plies", "many"]).is_none());
+    }
+
+    #[test]
+    fn replay() {
+        let none = config(&["--start"]).unwrap();
+        assert_eq!(
+            (none.record, none.replay, none.replay_speed),
+            (None, None, None)
+        );
+
+        let record = config(&["--record", "session.txt"]).unwrap();
+        assert_eq!(record.record, Some(PathBuf::from("session.txt")));
+        let replay = config(&["--replay", "session.txt", "--replay-speed", "2.5"]).unwrap();
+        assert_eq!(replay.replay, Some(PathBuf::from("session.txt")));
+        assert_eq!(replay.replay_speed, Some(2.5));
+
+        assert!(config(&["--replay-speed", "2"]).is_none());
+        assert!(config(&["--replay", "session.txt", "--replay-speed", "0"]).is_none());
+        assert!(config(&["--replay", "session.txt", "--http-port", "8080"]).is_none());
+    }
+
+    #[test]
+    fn log() {
+        let default = config(&["--start"]).unwrap();
+        assert_eq!(default.log.level, None);
+        assert_eq!(default.log.file, None);
+        assert_eq!(default.log.format, LogFormat::Plain);
+
+        let args = [
+            "--log-level",
+            "debug",
+            "--log-file",
+            "/tmp/bigchess.log",
+            "--log-format",
+            "json",
+        ];
+        let log = config(&args).unwrap().log;
+        assert_eq!(log.level.as_deref(), Some("debug"));
+        assert_eq!(log.file, Some(PathBuf::from("/tmp/bigchess.log")));
+        assert_eq!(log.format, LogFormat::Json);
+
+        assert!(config(&["--log-level", "verbose"]).is_none());
+        assert!(config(&["--log-format", "xml"]).is_none());
+    }
+
+    #[test]
+    fn analyze() {
+        let args = [
+            "analyze",
+            "--engine",
+            "/usr/bin/stockfish",
+            "--in",
+            "games.pgn",
+            "--out",
+            "annotated.pgn",
+        ];
+        let analyze = config(&args).unwrap().analyze.unwrap();
+        assert_eq!(analyze.engine, "/usr/bin/stockfish");
+        assert_eq!(analyze.movetime_ms, 200);
+        assert_eq!(analyze.threads, 1);
+        assert_eq!(analyze.output, PathBuf::from("annotated.pgn"));
+
+        let threads = [&args[..], &["--threads", "4", "--movetime", "50"]].concat();
+        let analyze = config(&threads).unwrap().analyze.unwrap();
+        assert_eq!(analyze.threads, 4);
+        assert_eq!(analyze.movetime_ms, 50);
+
+        assert!(config(&[&args[..], &["--threads", "0"]].concat()).is_none());
+        assert!(config(&["analyze", "--in", "games.pgn"]).is_none());
+        assert_eq!(config(&["--start"]).unwrap().analyze, None);
+    }
+
+    #[test]
+    fn perft() {
+        let perft = config(&["perft"]).unwrap().perft.unwrap();
+        assert_eq!(perft.fen, None);
+        assert_eq!(perft.depth, 5);
+        assert!(!perft.divide && !perft.verify && !perft.hash);
+
+        let kiwipete = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+        let args = [
+            "perft", "--fen", kiwipete, "--depth", "3", "--divide", "--verify", "--hash",
+        ];
+        let perft = config(&args).unwrap().perft.unwrap();
+        assert_eq!(perft.fen.as_deref(), Some(kiwipete));
+        assert_eq!(perft.depth, 3);
+        assert!(perft.divide && perft.verify && perft.hash);
+
+        assert!(config(&["perft", "--depth", "0"]).is_none());
+        assert!(config(&["perft", "--fen", "not a position"]).is_none());
+        assert_eq!(config(&["--start"]).unwrap().perft, None);
+    }
+
+    #[test]
+    fn bench() {
+        let bench = config(&["bench", "--scenario", "get-all"])
+            .unwrap()
+            .bench
+            .unwrap();
+        assert_eq!(bench.scenario, Scenario::GetAll);
+        assert_eq!(bench.iterations, 1000);
+        assert_eq!(bench.plies, 100);
+        assert_eq!(bench.games, 10);
+        assert!(!bench.json);
+
+        let args = [
+            "bench",
+            "--scenario",
+            "new-games",
+            "--iterations",
+            "10",
+            "--plies",
+            "400",
+            "--games",
+            "500",
+            "--json",
+        ];
+        let bench = config(&args).unwrap().bench.unwrap();
+        assert_eq!(bench.scenario, Scenario::NewGames);
+        assert_eq!(bench.iterations, 10);
+        assert_eq!(bench.plies, 400);
+        assert_eq!(bench.games, 500);
+        assert!(bench.json);
+
+        assert!(config(&["bench"]).is_none());
+        assert!(config(&["bench", "--scenario", "navigation", "--iterations", "0"]).is_none());
+        assert_eq!(config(&["--start"]).unwrap().bench, None);
+    }
+
+    #[test]
+    fn convert() {
+        let args = [
+            "convert",
+            "--from",
+            "pgn",
+            "--to",
+            "json",
+            "--in",
+            "games.pgn",
+        ];
+        let convert = config(&args).unwrap().convert.unwrap();
+        assert_eq!(convert.from, Format::Pgn);
+        assert_eq!(convert.to, Format::Json);
+        assert_eq!(convert.input, "games.pgn");
+        assert_eq!(convert.output, "-");
+
+        let args = [
+            "convert",
+            "--from",
+            "json",
+            "--to",
+            "uci",
+            "--out",
+            "moves.txt",
+        ];
+        let convert = config(&args).unwrap().convert.unwrap();
+        assert_eq!(convert.to, Format::Uci);
+        assert_eq!(convert.input, "-");
+
+        assert!(config(&["convert", "--from", "fens", "--to", "pgn"]).is_none());
+        assert!(config(&["convert", "--to", "pgn"]).is_none());
+        assert_eq!(config(&["--start"]).unwrap().convert, None);
+    }
+
+    #[test]
+    fn fen() {
+        let endgame = "8/8/8/8/8/4k3/4p3/4K3 w - - 0 1";
+        let startup = config(&["--fen", endgame]).unwrap();
+        assert_eq!(startup.fen.as_deref(), Some(endgame));
+        assert_eq!(startup.startup_id, "startup");
+        let named = config(&["--fen", endgame, "--id", "pawn-ending"]).unwrap();
+        assert_eq!(named.startup_id, "pawn-ending");
+
+        // Both kings can't be in check
+        assert!(config(&["--fen", "4k3/8/8/8/8/8/8/4K2r w - - 0 1"]).is_some());
+        assert!(config(&["--fen", "4k2R/8/8/8/8/8/8/4K2r w - - 0 1"]).is_none());
+        assert!(config(&["--fen", "not a position"]).is_none());
+        assert!(config(&["--id", "pawn-ending"]).is_none());
+    }
 }
@@ -0,0 +1,203 @@
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Time each side gets for a game.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum TimeControl {
+    /// `base_ms` for the whole game, `increment_ms` added after each move.
+    Fischer { base_ms: u64, increment_ms: u64 },
+    /// `days` for every move.
+    Correspondence { days: u32 },
+}
+
+impl TimeControl {
+    /// Time control of a PGN `TimeControl` tag: `180+2`, `600`, or `1/259200` for a move every
+    /// three days. Unknown (`?`) and untimed (`-`) games have none.
+    pub fn from_pgn_tag(tag: &str) -> Option<TimeControl> {
+        if let Some(seconds) = tag.strip_prefix("1/") {
+            let seconds: u64 = seconds.parse().ok()?;
+            return Some(TimeControl::Correspondence {
+                days: u32::try_from(seconds / 86_400).ok()?.max(1),
+            });
+        }
+        let (base, increment) = tag.split_once('+').unwrap_or((tag, "0"));
+        let base: u64 = base.parse().ok()?;
+        let increment: u64 = increment.parse().ok()?;
+        Some(TimeControl::Fischer {
+            base_ms: base * 1000,
+            increment_ms: increment * 1000,
+        })
+    }
+
+    /// Time on each clock when the game starts.
+    fn initial_ms(self) -> u64 {
+        match self {
+            TimeControl::Fischer { base_ms, .. } => base_ms,
+            TimeControl::Correspondence { days } => u64::from(days) * DAY_MS,
+        }
+    }
+}
+
+/// Clocks of both sides, run by timestamps in milliseconds rather than by reading the time,
+/// so that they follow the frontend's moves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clock {
+    time_control: TimeControl,
+    /// Time left to white then black, as of `since` for the side to move.
+    remaining: [u64; 2],
+    /// Side whose time runs when the clock isn't paused.
+    turn: Color,
+    /// When `turn`'s time was last counted, `None` while paused.
+    since: Option<u64>,
+    /// Side whose time ran out, which stops the clock for good.
+    flagged: Option<Color>,
+}
+
+impl Clock {
+    /// Paused clock with full time on both sides, `turn` to move first.
+    pub fn new(time_control: TimeControl, turn: Color) -> Clock {
+        let initial = time_control.initial_ms();
+        Clock {
+            time_control,
+            remaining: [initial, initial],
+            turn,
+            since: None,
+            flagged: None,
+        }
+    }
+
+    pub fn remaining_ms(&self, color: Color) -> u64 {
+        self.remaining[color.fold(0, 1)]
+    }
+
+    pub fn flagged(&self) -> Option<Color> {
+        self.flagged
+    }
+
+    /// Runs `turn`'s time from `at`, unless it has run out.
+    pub fn start(&mut self, at: u64) {
+        if self.since.is_none() && self.flagged.is_none() {
+            self.since = Some(at);
+        }
+    }
+
+    pub fn pause(&mut self, at: u64) {
+        self.tick(at);
+        self.since = None;
+    }
+
+    /// Counts the time spent until `at`. Returns the side whose time ran out, if any.
+    pub fn tick(&mut self, at: u64) -> Option<Color> {
+        if let Some(since) = self.since {
+            let spent = at.saturating_sub(since);
+            let remaining = &mut self.remaining[self.turn.fold(0, 1)];
+            if spent >= *remaining {
+                *remaining = 0;
+                self.since = None;
+                self.flagged = Some(self.turn);
+            } else {
+                *remaining -= spent;
+                self.since = Some(at);
+            }
+        }
+        self.flagged
+    }
+
+    /// Ends the move of `turn` at `at`, giving it the increment, and hands the clock to the
+    /// other side. A move made once the time ran out doesn't count.
+    pub fn punch(&mut self, at: u64) -> Option<Color> {
+        if self.tick(at).is_some() {
+            return self.flagged;
+        }
+        let remaining = &mut self.remaining[self.turn.fold(0, 1)];
+        match self.time_control {
+            TimeControl::Fischer { increment_ms, .. } => *remaining += increment_ms,
+            TimeControl::Correspondence { .. } => *remaining = self.time_control.initial_ms(),
+        }
+        self.turn = !self.turn;
+        None
+    }
+
+    pub fn repr(&self) -> ClockRepr {
+        ClockRepr {
+            time_control: self.time_control,
+            white_ms: self.remaining_ms(Color::White),
+            black_ms: self.remaining_ms(Color::Black),
+            running: self.since.map(|_| side(self.turn)),
+            since_ms: self.since,
+            flagged: self.flagged.map(side),
+        }
+    }
+}
+
+/// State of the clocks. The running side has spent the time since `since_ms` on top.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClockRepr {
+    pub time_control: TimeControl,
+    pub white_ms: u64,
+    pub black_ms: u64,
+    /// `"white"` or `"black"`, absent while paused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub running: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since_ms: Option<u64>,
+    /// Side whose time ran out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flagged: Option<String>,
+}
+
+/// Milliseconds since the Unix epoch, for requests that don't give their time.
+pub fn now_ms() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_millis() as u64
+}
+
+fn side(color: Color) -> String {
+    String::from(color.fold("white", "black"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgn_tags() {
+        let blitz = TimeControl::Fischer {
+            base_ms: 180_000,
+            increment_ms: 2000,
+        };
+        assert_eq!(TimeControl::from_pgn_tag("180+2"), Some(blitz));
+        assert_eq!(
+            TimeControl::from_pgn_tag("600"),
+            Some(TimeControl::Fischer {
+                base_ms: 600_000,
+                increment_ms: 0
+            })
+        );
+        assert_eq!(
+            TimeControl::from_pgn_tag("1/259200"),
+            Some(TimeControl::Correspondence { days: 3 })
+        );
+        assert_eq!(TimeControl::from_pgn_tag("-"), None);
+        assert_eq!(TimeControl::from_pgn_tag("?"), None);
+    }
+
+    #[test]
+    fn correspondence() {
+        let mut clock = Clock::new(TimeControl::Correspondence { days: 2 }, Color::White);
+        clock.start(0);
+        assert_eq!(clock.punch(DAY_MS), None);
+        assert_eq!(clock.remaining_ms(Color::White), 2 * DAY_MS);
+        assert_eq!(clock.tick(3 * DAY_MS), Some(Color::Black));
+        assert_eq!(clock.repr().flagged.as_deref(), Some("black"));
+        assert_eq!(clock.repr().running, None);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::errors::{Error, ErrorType};
+use crate::game::{Game, SavedGame};
+use crate::pgn::PgnReader;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// Formats of the `convert` command. Only PGN and JSON can be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Pgn,
+    /// One `SavedGame` per line.
+    Json,
+    /// FEN of every position of the main line, a blank line after each game.
+    Fens,
+    /// Moves of the main line in UCI, one game per line.
+    Uci,
+}
+
+/// Parameters of the `convert` command. Paths are `-` for stdin and stdout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertConfig {
+    pub from: Format,
+    pub to: Format,
+    pub input: String,
+    pub output: String,
+}
+
+/// Converts the input file into the output file. Returns false if any game was skipped.
+pub fn run(config: &ConvertConfig) -> Result<bool, Error> {
+    let input: Box<dyn BufRead> = match config.input.as_str() {
+        "-" => Box::new(BufReader::new(std::io::stdin())),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let mut output: Box<dyn Write> = match config.output.as_str() {
+        "-" => Box::new(BufWriter::new(std::io::stdout())),
+        path => Box::new(BufWriter::new(File::create(path)?)),
+    };
+    let skipped = convert(config.from, config.to, input, &mut output)?;
+    output.flush()?;
+    Ok(skipped == 0)
+}
+
+/// Converts the games one at a time, warning on stderr about those which can't be read. Returns
+/// how many were skipped.
+pub fn convert<R: BufRead + 'static>(
+    from: Format,
+    to: Format,
+    input: R,
+    output: &mut impl Write,
+) -> Result<usize, Error> {
+    let games: Box<dyn Iterator<Item = Result<Game, Error>>> = match from {
+        Format::Pgn => Box::new(PgnReader::new(input).map(|pgn| Game::from_pgn(&pgn?))),
+        Format::Json => Box::new(
+            input
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| {
+                    let saved: SavedGame = serde_json::from_str(&line?)?;
+                    Game::from_saved(&saved)
+                }),
+        ),
+        Format::Fens | Format::Uci => {
+            return Err(Error::new(ErrorType::Parse)
+                .with_message("Only PGN and JSON games can be converted"))
+        }
+    };
+
+    let mut skipped = 0;
+    for (index, game) in games.enumerate() {
+        match game {
+            Ok(game) => write_game(&game, to, output)?,
+            Err(err) => {
+                skipped += 1;
+                eprintln!("warning: skipped game {}: {}", index + 1, err);
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+fn write_game(game: &Game, to: Format, output: &mut impl Write) -> Result<(), Error> {
+    match to {
+        Format::Pgn => game.to_pgn().write(output)?,
+        Format::Json => {
+            serde_json::to_writer(&mut *output, &game.to_saved())?;
+            writeln!(output)?;
+        }
+        Format::Fens => {
+            for position in game.positions(&game.main_line()) {
+                writeln!(output, "{}", shakmaty::fen::fen(&position))?;
+            }
+            writeln!(output)?;
+        }
+        // Games are read with the main line as their current line
+        Format::Uci => writeln!(output, "{}", game.uci_line().join(" "))?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMES: &str = r#"[White "Alice"]
+[Result "1-0"]
+
+{Miniature} 1. e4 {Best by test} e5 (1... c5 2. Nf3 (2. c3 {Alapin}) d6) 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0
+
+[White "Carol"]
+[FEN "8/8/8/4k3/8/8/4P3/4K3 w - - 0 40"]
+[SetUp "1"]
+
+40. e4 Kd4 *
+"#;
+
+    fn converted(from: Format, to: Format, input: &str) -> (String, usize) {
+        let mut output = Vec::new();
+        let input = std::io::Cursor::new(input.to_string());
+        let skipped = convert(from, to, input, &mut output).unwrap();
+        (String::from_utf8(output).unwrap(), skipped)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (pgn, _) = converted(Format::Pgn, Format::Pgn, GAMES);
+        let (json, skipped) = converted(Format::Pgn, Format::Json, GAMES);
+        assert_eq!(skipped, 0);
+        assert_eq!(json.lines().count(), 2);
+        assert!(json.contains(r#""comment":"Alapin""#));
+        let (back, skipped) = converted(Format::Json, Format::Pgn, &json);
+        assert_eq!(skipped, 0);
+        assert_eq!(back, pgn);
+        assert!(back.contains(
+            "{ Miniature } 1. e4 { Best by test } 1... e5 (1... c5 2. Nf3 (2. c3 { Alapin }) 2... d6)"
+        ));
+        assert!(back.contains("[FEN \"8/8/8/4k3/8/8/4P3/4K3 w - - 0 40\"]"));
+        assert_eq!(converted(Format::Json, Format::Json, &json).0, json);
+    }
+
+    #[test]
+    fn main_lines() {
+        let (fens, _) = converted(Format::Pgn, Format::Fens, GAMES);
+        let games: Vec<&str> = fens.split("\n\n").collect();
+        assert_eq!(games[0].lines().count(), 8);
+        assert!(games[0].starts_with("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1\n"));
+        assert_eq!(games[1].lines().count(), 3);
+
+        let (uci, _) = converted(Format::Pgn, Format::Uci, GAMES);
+        assert_eq!(uci, "e2e4 e7e5 d1h5 b8c6 f1c4 g8f6 h5f7\ne2e4 e5d4\n");
+    }
+
+    #[test]
+    fn skipped_games() {
+        let broken = format!("[White \"Erin\"]\n\n1. e4 e5 2. Ke3 *\n\n{}", GAMES);
+        let (pgn, skipped) = converted(Format::Pgn, Format::Pgn, &broken);
+        assert_eq!(skipped, 1);
+        assert!(!pgn.contains("Erin"));
+        assert!(pgn.contains("Carol"));
+
+        let (json, _) = converted(Format::Pgn, Format::Json, GAMES);
+        let broken = format!("{{\"version\": 1}}\n\nnot json\n{}", json);
+        let (uci, skipped) = converted(Format::Json, Format::Uci, &broken);
+        assert_eq!(skipped, 2);
+        assert_eq!(uci.lines().count(), 2);
+
+        let mut output = Vec::new();
+        assert!(convert(Format::Uci, Format::Pgn, std::io::empty(), &mut output).is_err());
+    }
+}
@@ -1,5 +1,2640 @@
+use crate::api::{
+    response_from_database, response_from_error, response_from_notification, Notification,
+};
+use crate::eco;
+use crate::endgame;
+use crate::errors::{Error, ErrorType};
+use crate::game::Game;
+use crate::hash;
+use crate::jobs::JobTicket;
+use crate::pgn::{tokenize, PgnGame, PgnReader, Token};
+use crate::state::StateHandle;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::Value;
+use rusqlite::{
+    params, params_from_iter, Connection, OpenFlags, OptionalExtension, Row, Transaction,
+};
 use serde::{Deserialize, Serialize};
+use shakmaty::san::San;
+use shakmaty::uci::Uci;
+use shakmaty::{Chess, Color, Position, Setup};
+use tokio::sync::oneshot::error::TryRecvError;
+
+/// Schema changes, in order. Migration `i` brings a database from version `i` to `i + 1`,
+/// the version being stored in SQLite's `user_version` pragma. Never edit a released migration.
+const MIGRATIONS: &[&str] = &[
+    // 1: games and the position index
+    "CREATE TABLE games (
+        id INTEGER PRIMARY KEY,
+        event TEXT,
+        site TEXT,
+        date TEXT,
+        round TEXT,
+        white TEXT,
+        black TEXT,
+        result TEXT NOT NULL DEFAULT '*',
+        white_elo INTEGER,
+        black_elo INTEGER,
+        eco TEXT,
+        -- Every tag pair of the game, as a JSON object
+        headers TEXT NOT NULL DEFAULT '{}',
+        movetext BLOB NOT NULL
+    );
+    CREATE TABLE positions (
+        hash INTEGER NOT NULL,
+        game_id INTEGER NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+        ply INTEGER NOT NULL
+    );
+    CREATE INDEX positions_hash ON positions(hash);
+    CREATE INDEX positions_game ON positions(game_id);",
+    // 2: duplicate detection during imports
+    "ALTER TABLE games ADD COLUMN moves_hash INTEGER;
+    CREATE INDEX games_moves_hash ON games(moves_hash);",
+    // 3: header search, case-insensitive so that LIKE can use the indexes
+    "CREATE INDEX games_white ON games(white COLLATE NOCASE);
+    CREATE INDEX games_black ON games(black COLLATE NOCASE);
+    CREATE INDEX games_event ON games(event COLLATE NOCASE);
+    CREATE INDEX games_eco ON games(eco COLLATE NOCASE);
+    CREATE INDEX games_date ON games(date);",
+    // 4: moves played from each position, for the opening explorer
+    "CREATE TABLE position_moves (
+        hash INTEGER NOT NULL,
+        game_id INTEGER NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+        san TEXT NOT NULL
+    );
+    CREATE INDEX position_moves_hash ON position_moves(hash, san);
+    CREATE INDEX position_moves_game ON position_moves(game_id);",
+    // 5: listing details
+    "ALTER TABLE games ADD COLUMN ply_count INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE games ADD COLUMN has_annotations INTEGER NOT NULL DEFAULT 0;",
+    // 6: exact duplicate detection, games imported earlier have no fingerprint
+    "ALTER TABLE games ADD COLUMN fingerprint INTEGER;
+    CREATE INDEX games_fingerprint ON games(fingerprint);",
+    // 7: full-text search, the rowid being the game's id
+    "CREATE VIRTUAL TABLE game_text USING fts5(comments, annotator);
+    CREATE TRIGGER games_delete_text AFTER DELETE ON games BEGIN
+        DELETE FROM game_text WHERE rowid = old.id;
+    END;",
+    // 8: facts about the database itself
+    "CREATE TABLE metadata (key TEXT PRIMARY KEY, value) WITHOUT ROWID;",
+    // 9: endgames reached by the main line, see `endgame::classify_endgame`. Games imported
+    // earlier get theirs when reindexed.
+    "CREATE TABLE game_endgames (
+        game_id INTEGER NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+        class TEXT NOT NULL
+    );
+    CREATE INDEX game_endgames_class ON game_endgames(class);
+    CREATE INDEX game_endgames_game ON game_endgames(game_id);",
+];
+
+/// Example games listed for each move of the explorer.
+const EXPLORER_EXAMPLES: u32 = 3;
+
+/// Games inserted per transaction during imports.
+const IMPORT_BATCH: usize = 500;
+
+/// Games per page of search results at most, so that searching the starting position stays cheap.
+const MAX_SEARCH_LIMIT: u32 = 200;
+
+/// Games sharing only their moves are near-duplicates from this length on, shorter lines are
+/// commonly played in unrelated games.
+const MIN_NEAR_DUPLICATE_PLIES: u32 = 30;
+
+/// Read connections kept open per database once a query is done with them.
+const IDLE_READERS: usize = 4;
+
+/// How long a statement waits for another connection's write to finish before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Warnings kept in an import summary. The count of skipped games stays exact.
+const MAX_IMPORT_WARNINGS: usize = 100;
+
+/// A collection of games stored in an SQLite file.
+/// All methods block on disk IO and must be called from `spawn_blocking`.
+#[derive(Debug)]
+pub struct Database {
+    path: PathBuf,
+    connection: Connection,
+}
+
+impl Database {
+    /// Opens an existing database, upgrading its schema if it was made by an older version.
+    pub fn open(path: &Path) -> Result<Database, Error> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let connection = Connection::open_with_flags(path, flags)?;
+        Database::init(path, connection)
+    }
+
+    /// Creates a new database. Fails if the file already exists.
+    pub fn create(path: &Path) -> Result<Database, Error> {
+        if path.exists() {
+            return Err(Error::new(ErrorType::IO)
+                .with_message(&format!("{} already exists", path.display())));
+        }
+        let connection = Connection::open(path)?;
+        Database::init(path, connection)
+    }
+
+    /// Connection for queries only, to a database already opened with `open` or `create`.
+    fn open_read_only(path: &Path) -> Result<Database, Error> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let connection = Connection::open_with_flags(path, flags)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Database {
+            path: path.to_path_buf(),
+            connection,
+        })
+    }
+
+    fn init(path: &Path, mut connection: Connection) -> Result<Database, Error> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        // Lets read connections query while a write is in progress
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&mut connection, MIGRATIONS)?;
+        Ok(Database {
+            path: path.to_path_buf(),
+            connection,
+        })
+    }
+
+    /// Games in which `position` occurs, with the ply of its first occurrence.
+    pub fn search_position(
+        &self,
+        position: &Chess,
+        limit: u32,
+        offset: u32,
+    ) -> Result<SearchResults, Error> {
+        let hash = hash::zobrist(position) as i64;
+        let total: i64 = self.connection.query_row(
+            "SELECT COUNT(*) FROM positions WHERE hash = ?1",
+            params![hash],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {}, positions.ply, games.headers, games.movetext
+            FROM positions JOIN games ON games.id = positions.game_id
+            WHERE positions.hash = ?1
+            ORDER BY games.id LIMIT ?2 OFFSET ?3",
+            GAME_COLUMNS
+        ))?;
+        let rows =
+            statement.query_map(params![hash, limit.min(MAX_SEARCH_LIMIT), offset], |row| {
+                let mut game = DatabaseGame::from_row(row)?;
+                game.ply = Some(row.get(GAME_COLUMN_COUNT)?);
+                let headers: String = row.get(GAME_COLUMN_COUNT + 1)?;
+                let movetext = movetext(row, GAME_COLUMN_COUNT + 2)?;
+                Ok((game, headers, movetext))
+            })?;
+
+        // Rules out hash collisions
+        let target = shakmaty::fen::epd(position);
+        let mut games = Vec::new();
+        for row in rows {
+            let (game, headers, movetext) = row?;
+            let ply = game.ply.unwrap_or_default() as usize;
+            let reached = stored_game(&headers, &movetext)
+                .and_then(|stored| stored.positions())
+                .map(|positions| {
+                    positions
+                        .get(ply)
+                        .is_some_and(|reached| shakmaty::fen::epd(reached) == target)
+                });
+            if let Ok(true) = reached {
+                games.push(game);
+            }
+        }
+        Ok(SearchResults {
+            total: total as u64,
+            games,
+        })
+    }
+
+    /// Games whose comments or annotator match `query`, in FTS5 syntax, best matches first.
+    /// Each game comes with an excerpt of the matching text, matches being put in «».
+    pub fn search_text(
+        &self,
+        query: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<SearchResults, Error> {
+        let total: i64 = self
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM game_text WHERE game_text MATCH ?1",
+                params![query],
+                |row| row.get(0),
+            )
+            .map_err(query_error)?;
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {}, snippet(game_text, -1, '«', '»', '…', 16)
+            FROM game_text JOIN games ON games.id = game_text.rowid
+            WHERE game_text MATCH ?1
+            ORDER BY rank, games.id LIMIT ?2 OFFSET ?3",
+            GAME_COLUMNS
+        ))?;
+        let games = statement
+            .query_map(params![query, limit.min(MAX_SEARCH_LIMIT), offset], |row| {
+                let mut game = DatabaseGame::from_row(row)?;
+                game.snippet = Some(row.get(GAME_COLUMN_COUNT)?);
+                Ok(game)
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(query_error)?;
+        Ok(SearchResults {
+            total: total as u64,
+            games,
+        })
+    }
+
+    /// Games matching every given filter, ordered as they were added.
+    pub fn search(
+        &self,
+        filters: &SearchFilters,
+        limit: u32,
+        offset: u32,
+    ) -> Result<SearchResults, Error> {
+        self.page(filters, "games.id", limit, offset)
+    }
+
+    /// Every game, sorted by `sort_by` or in the order they were added.
+    /// Games with equal values are sorted by id, so that pages never overlap.
+    pub fn list_games(
+        &self,
+        sort_by: Option<SortColumn>,
+        descending: bool,
+        limit: u32,
+        offset: u32,
+    ) -> Result<SearchResults, Error> {
+        let order = order_by(sort_by, descending);
+        self.page(&SearchFilters::default(), &order, limit, offset)
+    }
+
+    fn page(
+        &self,
+        filters: &SearchFilters,
+        order: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<SearchResults, Error> {
+        let (conditions, mut values) = filters.to_sql();
+        let total: i64 = self.connection.query_row(
+            &format!("SELECT COUNT(*) FROM games WHERE {}", conditions),
+            params_from_iter(&values),
+            |row| row.get(0),
+        )?;
+
+        values.push(Value::from(limit.min(MAX_SEARCH_LIMIT)));
+        values.push(Value::from(offset));
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {} FROM games WHERE {} ORDER BY {} LIMIT ?{} OFFSET ?{}",
+            GAME_COLUMNS,
+            conditions,
+            order,
+            values.len() - 1,
+            values.len()
+        ))?;
+        let games = statement
+            .query_map(params_from_iter(&values), DatabaseGame::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(SearchResults {
+            total: total as u64,
+            games,
+        })
+    }
+
+    /// Moves played from `position` in the database's games and how they scored, most played first.
+    pub fn explore(&self, position: &Chess) -> Result<Explorer, Error> {
+        let hash = hash::zobrist(position) as i64;
+        let opponent_elo = match position.turn() {
+            Color::White => "games.black_elo",
+            Color::Black => "games.white_elo",
+        };
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT position_moves.san, COUNT(*),
+                SUM(games.result = '1-0'), SUM(games.result = '1/2-1/2'), SUM(games.result = '0-1'),
+                AVG({})
+            FROM position_moves JOIN games ON games.id = position_moves.game_id
+            WHERE position_moves.hash = ?1
+            GROUP BY position_moves.san
+            ORDER BY COUNT(*) DESC, position_moves.san",
+            opponent_elo
+        ))?;
+        let mut moves = statement
+            .query_map(params![hash], |row| {
+                let games: i64 = row.get(1)?;
+                let percent = |count: i64| count as f64 * 100.0 / games as f64;
+                Ok(ExplorerMove {
+                    san: row.get(0)?,
+                    games: games as u64,
+                    white: percent(row.get(2)?),
+                    draw: percent(row.get(3)?),
+                    black: percent(row.get(4)?),
+                    average_opponent_elo: row
+                        .get::<_, Option<f64>>(5)?
+                        .map(|elo| elo.round() as u32),
+                    examples: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut examples = self.connection.prepare(&format!(
+            "SELECT {} FROM position_moves JOIN games ON games.id = position_moves.game_id
+            WHERE position_moves.hash = ?1 AND position_moves.san = ?2
+            ORDER BY MAX(IFNULL(games.white_elo, 0), IFNULL(games.black_elo, 0)) DESC, games.id
+            LIMIT ?3",
+            GAME_COLUMNS
+        ))?;
+        for explorer_move in &mut moves {
+            explorer_move.examples = examples
+                .query_map(
+                    params![hash, explorer_move.san, EXPLORER_EXAMPLES],
+                    DatabaseGame::from_row,
+                )?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+        }
+        Ok(Explorer {
+            games: moves.iter().map(|explorer_move| explorer_move.games).sum(),
+            moves,
+        })
+    }
+
+    /// Stored game `id` with its variations, ready to be analysed.
+    pub fn open_game(&self, id: i64) -> Result<Game, Error> {
+        let (headers, movetext): (String, Vec<u8>) = self
+            .connection
+            .query_row(
+                "SELECT headers, movetext FROM games WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, movetext(row, 1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| missing_game(id))?;
+        stored_game(&headers, &movetext)
+            .and_then(|pgn| Game::from_pgn(&pgn))
+            .map_err(|err| {
+                Error::new(ErrorType::Parse).with_message(&format!(
+                    "Game {} of the database is corrupt: {}",
+                    id,
+                    describe(&err)
+                ))
+            })
+    }
+
+    /// Deletes games along with their positions in the index. Ids without a game are reported
+    /// rather than failing the others.
+    pub fn delete_games(&mut self, ids: &[i64]) -> Result<DeletedGames, Error> {
+        let transaction = self.connection.transaction()?;
+        let mut deleted = DeletedGames::default();
+        {
+            let mut statement = transaction.prepare("DELETE FROM games WHERE id = ?1")?;
+            for &id in ids {
+                match statement.execute(params![id])? {
+                    0 => deleted.missing.push(id),
+                    _ => deleted.deleted.push(id),
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(deleted)
+    }
+
+    /// Sets the tag pairs of game `id`, removing those set to `None`. Moves are left untouched.
+    pub fn update_headers(
+        &mut self,
+        id: i64,
+        fields: HashMap<String, Option<String>>,
+    ) -> Result<DatabaseGame, Error> {
+        if fields.contains_key("FEN") || fields.contains_key("SetUp") {
+            return Err(Error::new(ErrorType::Database)
+                .with_message("The starting position of a stored game can't be changed"));
+        }
+        let transaction = self.connection.transaction()?;
+        let (headers, movetext): (String, Vec<u8>) = transaction
+            .query_row(
+                "SELECT headers, movetext FROM games WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, movetext(row, 1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| missing_game(id))?;
+        let mut headers: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&headers)?;
+        for (name, value) in fields {
+            match value {
+                Some(value) => headers.insert(name, serde_json::Value::from(value)),
+                None => headers.remove(&name),
+            };
+        }
+
+        let headers_json = serde_json::Value::Object(headers.clone()).to_string();
+        let game = stored_game(&headers_json, &movetext)?;
+        let start = game.start_position()?;
+        let fingerprint = fingerprint(&game, &start, &game.main_line()?);
+
+        let header = |name: &str| headers.get(name).and_then(|value| value.as_str());
+        let elo = |name| header(name).and_then(|elo| elo.parse::<i64>().ok());
+        // A game without ECO tag keeps the code it was classified with
+        let eco = header("ECO").filter(|eco| !eco.is_empty() && *eco != "?");
+        transaction.execute(
+            "UPDATE games SET event = ?1, site = ?2, date = ?3, round = ?4, white = ?5,
+                black = ?6, result = ?7, white_elo = ?8, black_elo = ?9,
+                eco = COALESCE(?10, eco), headers = ?11, fingerprint = ?12
+            WHERE id = ?13",
+            params![
+                header("Event"),
+                header("Site"),
+                header("Date"),
+                header("Round"),
+                header("White"),
+                header("Black"),
+                header("Result").unwrap_or("*"),
+                elo("WhiteElo"),
+                elo("BlackElo"),
+                eco,
+                headers_json,
+                fingerprint,
+                id,
+            ],
+        )?;
+        index_text(&transaction, id, &comments(&game)?, header("Annotator"))?;
+        let game = transaction.query_row(
+            &format!("SELECT {} FROM games WHERE id = ?1", GAME_COLUMNS),
+            params![id],
+            DatabaseGame::from_row,
+        )?;
+        transaction.commit()?;
+        Ok(game)
+    }
+
+    /// Groups of at most `limit` games stored more than once. Games with the same moves but
+    /// other players, date or result are grouped separately, as they may be distinct games.
+    pub fn find_duplicates(&self, limit: u32) -> Result<Duplicates, Error> {
+        let limit = limit.min(MAX_SEARCH_LIMIT);
+        let exact = self.duplicate_groups(
+            "SELECT fingerprint FROM games WHERE fingerprint IS NOT NULL
+            GROUP BY fingerprint HAVING COUNT(*) > 1 ORDER BY MIN(id) LIMIT ?1",
+            "fingerprint",
+            limit,
+        )?;
+        let near = self.duplicate_groups(
+            &format!(
+                "SELECT moves_hash FROM games WHERE ply_count >= {}
+                GROUP BY moves_hash HAVING COUNT(DISTINCT fingerprint) > 1
+                ORDER BY MIN(id) LIMIT ?1",
+                MIN_NEAR_DUPLICATE_PLIES
+            ),
+            "moves_hash",
+            limit,
+        )?;
+        Ok(Duplicates { exact, near })
+    }
+
+    /// Games sharing each value of `column` returned by `query`.
+    fn duplicate_groups(
+        &self,
+        query: &str,
+        column: &str,
+        limit: u32,
+    ) -> Result<Vec<Vec<DatabaseGame>>, Error> {
+        let keys = self
+            .connection
+            .prepare(query)?
+            .query_map(params![limit], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {} FROM games WHERE {} = ?1 ORDER BY id",
+            GAME_COLUMNS, column
+        ))?;
+        let mut groups = Vec::new();
+        for key in keys {
+            let games = statement
+                .query_map(params![key], DatabaseGame::from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            groups.push(games);
+        }
+        Ok(groups)
+    }
+
+    pub fn game_count(&self) -> Result<u64, Error> {
+        let count: i64 = self
+            .connection
+            .query_row("SELECT COUNT(*) FROM games", [], |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    pub fn repr(&self, id: &str) -> Result<DatabaseRepr, Error> {
+        let last_reindex: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT value FROM metadata WHERE key = 'last_reindex'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let game_count = self.game_count()?;
+        let mut table_rows = BTreeMap::new();
+        table_rows.insert(String::from("games"), game_count);
+        for table in COUNTED_TABLES.iter() {
+            let rows: i64 = self.connection.query_row(
+                &format!("SELECT COUNT(*) FROM {}", table),
+                [],
+                |row| row.get(0),
+            )?;
+            table_rows.insert(table.to_string(), rows as u64);
+        }
+        Ok(DatabaseRepr {
+            id: id.to_string(),
+            path: self.path.display().to_string(),
+            game_count,
+            size_bytes: std::fs::metadata(&self.path)?.len(),
+            last_reindex: last_reindex.map(|time| time as u64),
+            table_rows,
+            object_sizes: self.object_sizes().ok(),
+        })
+    }
+
+    /// Bytes used by each table and index. Fails if SQLite was built without the dbstat table.
+    fn object_sizes(&self) -> Result<BTreeMap<String, u64>, Error> {
+        let sizes = self
+            .connection
+            .prepare("SELECT name, SUM(pgsize) FROM dbstat GROUP BY name")?
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(sizes)
+    }
+
+    /// Runs `actions` in order, stopping at the first one failing.
+    pub fn maintain(
+        &mut self,
+        actions: &[MaintenanceAction],
+    ) -> Result<Vec<MaintenanceResult>, Error> {
+        let mut results = Vec::new();
+        for &action in actions {
+            let started = Instant::now();
+            let mut result = MaintenanceResult {
+                action,
+                duration_ms: 0,
+                messages: Vec::new(),
+                bytes_reclaimed: None,
+            };
+            match action {
+                MaintenanceAction::Vacuum => {
+                    let before = self.allocated_bytes()?;
+                    self.connection.execute_batch("VACUUM")?;
+                    result.bytes_reclaimed = Some(before.saturating_sub(self.allocated_bytes()?));
+                }
+                MaintenanceAction::IntegrityCheck => {
+                    result.messages = self
+                        .connection
+                        .prepare("PRAGMA integrity_check")?
+                        .query_map([], |row| row.get(0))?
+                        .collect::<rusqlite::Result<_>>()?;
+                }
+                MaintenanceAction::Analyze => self.connection.execute_batch("ANALYZE")?,
+            }
+            result.duration_ms = started.elapsed().as_millis() as u64;
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Size of the database pages, free ones excluded.
+    fn allocated_bytes(&self) -> Result<u64, Error> {
+        let pragma = |name| {
+            self.connection
+                .pragma_query_value(None, name, |row| row.get::<_, i64>(0))
+        };
+        let pages = pragma("page_count")? - pragma("freelist_count")?;
+        Ok((pages * pragma("page_size")?) as u64)
+    }
+}
+
+/// Open databases, keyed by an id chosen by the client. Queries run concurrently on a pool of read
+/// connections, while writes go through a single connection one at a time.
+/// Every method blocks and must be called from `spawn_blocking`.
+#[derive(Clone, Default)]
+pub struct DatabaseRegistry {
+    databases: Arc<Mutex<HashMap<String, Arc<PooledDatabase>>>>,
+}
+
+struct PooledDatabase {
+    writer: Mutex<Database>,
+    readers: Mutex<Vec<Database>>,
+}
+
+impl DatabaseRegistry {
+    /// Registers `database` under `id`, replacing any database previously registered with that id.
+    /// Queries still running on the replaced one complete first.
+    pub fn insert(&self, id: &str, database: Database) -> Result<(), Error> {
+        let pooled = PooledDatabase {
+            writer: Mutex::new(database),
+            readers: Mutex::new(Vec::new()),
+        };
+        let replaced = self
+            .databases
+            .lock()?
+            .insert(id.to_string(), Arc::new(pooled));
+        if let Some(replaced) = replaced {
+            drop(replaced.writer.lock()?);
+        }
+        Ok(())
+    }
+
+    /// Unregisters database `id` once the write in progress, if any, is done.
+    pub fn remove(&self, id: &str) -> Result<(), Error> {
+        let removed = self.databases.lock()?.remove(id);
+        match removed {
+            Some(removed) => {
+                drop(removed.writer.lock()?);
+                Ok(())
+            }
+            None => Err(not_open(id)),
+        }
+    }
+
+    pub fn ids(&self) -> Result<Vec<String>, Error> {
+        let mut ids: Vec<String> = self.databases.lock()?.keys().cloned().collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Runs `query` on a read connection of database `id`, opening one if all are busy.
+    pub fn read<T>(
+        &self,
+        id: &str,
+        query: impl FnOnce(&Database) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let pooled = self.get(id)?;
+        let idle = pooled.readers.lock()?.pop();
+        let reader = match idle {
+            Some(reader) => reader,
+            None => Database::open_read_only(&pooled.writer.lock()?.path)?,
+        };
+        let result = query(&reader);
+
+        let mut readers = pooled.readers.lock()?;
+        if readers.len() < IDLE_READERS {
+            readers.push(reader);
+        }
+        result
+    }
+
+    /// Runs `operation` on database `id` once the writes before it are done.
+    pub fn write<T>(
+        &self,
+        id: &str,
+        operation: impl FnOnce(&mut Database) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let pooled = self.get(id)?;
+        let mut writer = pooled.writer.lock()?;
+        operation(&mut writer)
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<PooledDatabase>, Error> {
+        match self.databases.lock()?.get(id) {
+            Some(pooled) => Ok(Arc::clone(pooled)),
+            None => Err(not_open(id)),
+        }
+    }
+}
+
+fn not_open(id: &str) -> Error {
+    Error::new(ErrorType::Database).with_message(&format!("No database is open as {}", id))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ImportOptions {
+    /// Leave out games with the same players, date and moves as a game already in the database.
+    pub skip_duplicates: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ImportSummary {
+    pub imported: u64,
+    /// Games which could not be read, explained in `warnings`.
+    pub skipped: u64,
+    pub duplicates: u64,
+    /// Games imported before the import was stopped are kept.
+    pub stopped: bool,
+    pub warnings: Vec<String>,
+}
+
+impl Database {
+    /// Imports every game of a PGN stream, `IMPORT_BATCH` games per transaction. Games are read one at a
+    /// time, so memory use doesn't depend on the size of the stream.
+    /// `progress` is called after each commit and `should_stop` before each game.
+    pub fn import_pgn<R: BufRead>(
+        &mut self,
+        input: R,
+        options: ImportOptions,
+        mut progress: impl FnMut(&ImportSummary),
+        mut should_stop: impl FnMut() -> bool,
+    ) -> Result<ImportSummary, Error> {
+        let mut summary = ImportSummary::default();
+        let mut games = PgnReader::new(input).enumerate().peekable();
+        while games.peek().is_some() && !summary.stopped {
+            let transaction = self.connection.transaction()?;
+            for (index, game) in games.by_ref().take(IMPORT_BATCH) {
+                if should_stop() {
+                    summary.stopped = true;
+                    break;
+                }
+                match insert_game(&transaction, &game?, options) {
+                    Ok(true) => summary.imported += 1,
+                    Ok(false) => summary.duplicates += 1,
+                    Err(err) => {
+                        summary.skipped += 1;
+                        if summary.warnings.len() < MAX_IMPORT_WARNINGS {
+                            summary.warnings.push(format!(
+                                "Game {}: {}",
+                                index + 1,
+                                describe(&err)
+                            ));
+                        }
+                    }
+                }
+            }
+            transaction.commit()?;
+            progress(&summary);
+        }
+        Ok(summary)
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Index tables whose rows are counted in `DatabaseRepr`, along with the games.
+const COUNTED_TABLES: [&str; 3] = ["positions", "position_moves", "game_text"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case")]
-pub struct DatabaseRepr {}
+pub enum MaintenanceAction {
+    /// Rebuilds the file without its free pages.
+    Vacuum,
+    IntegrityCheck,
+    /// Gathers the statistics the query planner uses to pick indexes.
+    Analyze,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceResult {
+    pub action: MaintenanceAction,
+    pub duration_ms: u64,
+    /// Problems found by an integrity check, or just "ok".
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_reclaimed: Option<u64>,
+}
+
+/// Games checked per transaction while reindexing.
+const REINDEX_BATCH: usize = 500;
+
+/// Games between two progress reports of an export.
+const EXPORT_BATCH: u64 = 500;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ReindexSummary {
+    pub games: u64,
+    /// Games whose positions, text or details didn't match their moves.
+    pub inconsistent: u64,
+    /// Games whose moves can't be read, explained in `warnings`. Their index entries are removed.
+    pub corrupt: u64,
+    /// Index entries of games which don't exist anymore.
+    pub orphans: u64,
+    pub stopped: bool,
+    pub warnings: Vec<String>,
+}
+
+/// Hash of a position, ply at which it occurs.
+type PositionRow = (i64, u32);
+/// Hash of a position, move played from it.
+type MoveRow = (i64, String);
+
+/// Every index entry of a game.
+#[derive(Debug, PartialEq)]
+struct GameIndex {
+    positions: Vec<PositionRow>,
+    moves: Vec<MoveRow>,
+    text: Option<(String, String)>,
+    /// Sorted labels.
+    endgames: Vec<String>,
+    ply_count: u32,
+    has_annotations: bool,
+    fingerprint: Option<i64>,
+}
+
+impl Database {
+    /// Compares the indexes of every game with what its moves and headers give, `REINDEX_BATCH`
+    /// games per transaction, and rebuilds the entries that differ unless `check_only`.
+    /// Reads aren't blocked meanwhile. `progress` is called after each batch and `should_stop` before.
+    pub fn reindex(
+        &mut self,
+        check_only: bool,
+        mut progress: impl FnMut(&ReindexSummary),
+        mut should_stop: impl FnMut() -> bool,
+    ) -> Result<ReindexSummary, Error> {
+        let mut summary = ReindexSummary::default();
+        let transaction = self.connection.transaction()?;
+        for table in &["positions", "position_moves", "game_endgames"] {
+            let orphans = format!("{} WHERE game_id NOT IN (SELECT id FROM games)", table);
+            summary.orphans += if check_only {
+                transaction.query_row(&format!("SELECT COUNT(*) FROM {}", orphans), [], |row| {
+                    row.get::<_, i64>(0)
+                })? as u64
+            } else {
+                transaction.execute(&format!("DELETE FROM {}", orphans), [])? as u64
+            };
+        }
+        transaction.commit()?;
+
+        let mut after = 0;
+        loop {
+            if should_stop() {
+                summary.stopped = true;
+                break;
+            }
+            match self.reindex_batch(after, check_only, &mut summary)? {
+                Some(last) => after = last,
+                None => break,
+            }
+            progress(&summary);
+        }
+
+        if !check_only && !summary.stopped {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            self.connection.execute(
+                "INSERT OR REPLACE INTO metadata (key, value) VALUES ('last_reindex', ?1)",
+                params![now as i64],
+            )?;
+        }
+        Ok(summary)
+    }
+
+    /// Reindexes the games following game `after`. Returns the id of the last one, `None` once
+    /// there are no games left.
+    fn reindex_batch(
+        &mut self,
+        after: i64,
+        check_only: bool,
+        summary: &mut ReindexSummary,
+    ) -> Result<Option<i64>, Error> {
+        let transaction = self.connection.transaction()?;
+        let games = transaction
+            .prepare("SELECT id, headers, movetext FROM games WHERE id > ?1 ORDER BY id LIMIT ?2")?
+            .query_map(params![after, REINDEX_BATCH as u32], |row| {
+                Ok((row.get(0)?, row.get(1)?, movetext(row, 2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(i64, String, Vec<u8>)>>>()?;
+
+        for (id, headers, movetext) in &games {
+            summary.games += 1;
+            let expected = stored_game(headers, movetext).and_then(|game| game_index(&game));
+            let stored = stored_index(&transaction, *id)?;
+            match expected {
+                Ok(expected) if expected == stored => {}
+                Ok(expected) => {
+                    summary.inconsistent += 1;
+                    if !check_only {
+                        write_index(&transaction, *id, &expected)?;
+                    }
+                }
+                Err(err) => {
+                    summary.corrupt += 1;
+                    if summary.warnings.len() < MAX_IMPORT_WARNINGS {
+                        summary
+                            .warnings
+                            .push(format!("Game {}: {}", id, describe(&err)));
+                    }
+                    if !check_only {
+                        write_index(&transaction, *id, &GameIndex::default())?;
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(games.last().map(|(id, _, _)| *id))
+    }
+}
+
+impl Default for GameIndex {
+    /// What is indexed of a game whose moves can't be read.
+    fn default() -> GameIndex {
+        GameIndex {
+            positions: Vec::new(),
+            moves: Vec::new(),
+            text: None,
+            endgames: Vec::new(),
+            ply_count: 0,
+            has_annotations: false,
+            fingerprint: None,
+        }
+    }
+}
+
+fn game_index(game: &PgnGame) -> Result<GameIndex, Error> {
+    let start = game.start_position()?;
+    let moves = game.main_line()?;
+    let (positions, next_moves) = position_rows(start.clone(), &moves);
+    let comments = comments(game)?;
+    let annotator = game.header("Annotator").unwrap_or_default();
+    let text = if comments.is_empty() && annotator.is_empty() {
+        None
+    } else {
+        Some((comments, annotator.to_string()))
+    };
+    let mut index = GameIndex {
+        positions,
+        moves: next_moves,
+        text,
+        endgames: endgames(start.clone(), &moves),
+        ply_count: moves.len() as u32,
+        has_annotations: game.has_annotations()?,
+        fingerprint: Some(fingerprint(game, &start, &moves)),
+    };
+    index.positions.sort_unstable();
+    index.moves.sort_unstable();
+    Ok(index)
+}
+
+fn stored_index(transaction: &Transaction, game_id: i64) -> Result<GameIndex, Error> {
+    let mut positions = transaction
+        .prepare_cached("SELECT hash, ply FROM positions WHERE game_id = ?1")?
+        .query_map(params![game_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut moves = transaction
+        .prepare_cached("SELECT hash, san FROM position_moves WHERE game_id = ?1")?
+        .query_map(params![game_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    positions.sort_unstable();
+    moves.sort_unstable();
+    let text = transaction
+        .query_row(
+            "SELECT comments, annotator FROM game_text WHERE rowid = ?1",
+            params![game_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let endgames = transaction
+        .prepare_cached("SELECT class FROM game_endgames WHERE game_id = ?1 ORDER BY class")?
+        .query_map(params![game_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let (ply_count, has_annotations, fingerprint) = transaction.query_row(
+        "SELECT ply_count, has_annotations, fingerprint FROM games WHERE id = ?1",
+        params![game_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    Ok(GameIndex {
+        positions,
+        moves,
+        text,
+        endgames,
+        ply_count,
+        has_annotations,
+        fingerprint,
+    })
+}
+
+/// Replaces every index entry of a game.
+fn write_index(transaction: &Transaction, game_id: i64, index: &GameIndex) -> Result<(), Error> {
+    transaction.execute("DELETE FROM positions WHERE game_id = ?1", params![game_id])?;
+    transaction.execute(
+        "DELETE FROM position_moves WHERE game_id = ?1",
+        params![game_id],
+    )?;
+    insert_position_rows(transaction, game_id, &index.positions, &index.moves)?;
+    transaction.execute(
+        "DELETE FROM game_endgames WHERE game_id = ?1",
+        params![game_id],
+    )?;
+    insert_endgames(transaction, game_id, &index.endgames)?;
+    let (comments, annotator) = index.text.clone().unwrap_or_default();
+    index_text(transaction, game_id, &comments, Some(&annotator))?;
+    transaction.execute(
+        "UPDATE games SET ply_count = ?1, has_annotations = ?2, fingerprint = ?3 WHERE id = ?4",
+        params![
+            index.ply_count,
+            index.has_annotations,
+            index.fingerprint,
+            game_id
+        ],
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ExportSummary {
+    pub exported: u64,
+    /// Games exported before the export was stopped are kept in the file.
+    pub stopped: bool,
+}
+
+impl Database {
+    /// Writes the games matching `filters` to `out` as PGN, sorted like `list_games`. Rows are read
+    /// one at a time, so memory use doesn't depend on the number of games.
+    /// `progress` is called every `EXPORT_BATCH` games and `should_stop` before each game.
+    pub fn export_pgn<W: Write>(
+        &self,
+        filters: &SearchFilters,
+        sort_by: Option<SortColumn>,
+        descending: bool,
+        out: &mut W,
+        mut progress: impl FnMut(&ExportSummary),
+        mut should_stop: impl FnMut() -> bool,
+    ) -> Result<ExportSummary, Error> {
+        let (conditions, values) = filters.to_sql();
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT headers, movetext FROM games WHERE {} ORDER BY {}",
+            conditions,
+            order_by(sort_by, descending)
+        ))?;
+        let mut rows = statement.query(params_from_iter(&values))?;
+        let mut summary = ExportSummary::default();
+        while let Some(row) = rows.next()? {
+            if should_stop() {
+                summary.stopped = true;
+                break;
+            }
+            let headers: String = row.get(0)?;
+            stored_game(&headers, &movetext(row, 1)?)?.write(out)?;
+            summary.exported += 1;
+            if summary.exported % EXPORT_BATCH == 0 {
+                progress(&summary);
+            }
+        }
+        out.flush()?;
+        Ok(summary)
+    }
+}
+
+/// Returns false if the game was left out as a duplicate.
+fn insert_game(
+    transaction: &Transaction,
+    game: &PgnGame,
+    options: ImportOptions,
+) -> Result<bool, Error> {
+    let start = game.start_position()?;
+    let moves = game.main_line()?;
+    let moves_hash = moves_hash(&start, &moves);
+    let comments = comments(game)?;
+    let fingerprint = fingerprint(game, &start, &moves);
+
+    if options.skip_duplicates {
+        let duplicate: bool = transaction.query_row(
+            "SELECT EXISTS (SELECT 1 FROM games WHERE fingerprint = ?1)",
+            params![fingerprint],
+            |row| row.get(0),
+        )?;
+        if duplicate {
+            return Ok(false);
+        }
+    }
+
+    let headers: serde_json::Map<String, serde_json::Value> = game
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), serde_json::Value::from(value.as_str())))
+        .collect();
+    let elo = |tag| game.header(tag).and_then(|elo| elo.parse::<i64>().ok());
+    let eco = match game.header("ECO") {
+        Some(eco) if !eco.is_empty() && eco != "?" => Some(eco.to_string()),
+        _ => eco::classify(&game.positions()?).map(|opening| opening.eco),
+    };
+    transaction.execute(
+        "INSERT INTO games (event, site, date, round, white, black, result, white_elo, black_elo,
+            eco, headers, movetext, moves_hash, ply_count, has_annotations, fingerprint)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        params![
+            game.header("Event"),
+            game.header("Site"),
+            game.header("Date"),
+            game.header("Round"),
+            game.header("White"),
+            game.header("Black"),
+            game.header("Result").unwrap_or("*"),
+            elo("WhiteElo"),
+            elo("BlackElo"),
+            eco,
+            serde_json::Value::Object(headers).to_string(),
+            game.movetext.trim().as_bytes(),
+            moves_hash,
+            moves.len() as u32,
+            game.has_annotations()?,
+            fingerprint,
+        ],
+    )?;
+    let game_id = transaction.last_insert_rowid();
+    index_text(transaction, game_id, &comments, game.header("Annotator"))?;
+    insert_endgames(transaction, game_id, &endgames(start.clone(), &moves))?;
+    index_positions(transaction, game_id, start, &moves)?;
+    Ok(true)
+}
+
+/// Labels of the endgames reached by a main line, sorted.
+fn endgames(mut position: Chess, moves: &[shakmaty::Move]) -> Vec<String> {
+    let class = |position: &Chess| endgame::classify_endgame(position).map(|c| c.to_string());
+    let mut classes: BTreeSet<String> = class(&position).into_iter().collect();
+    for m in moves {
+        position.play_unchecked(m);
+        classes.extend(class(&position));
+    }
+    classes.into_iter().collect()
+}
+
+fn insert_endgames(
+    transaction: &Transaction,
+    game_id: i64,
+    endgames: &[String],
+) -> Result<(), Error> {
+    let mut statement =
+        transaction.prepare_cached("INSERT INTO game_endgames (game_id, class) VALUES (?1, ?2)")?;
+    for class in endgames {
+        statement.execute(params![game_id, class])?;
+    }
+    Ok(())
+}
+
+/// Makes the comments and annotator of a game searchable, replacing what was indexed before.
+fn index_text(
+    transaction: &Transaction,
+    game_id: i64,
+    comments: &str,
+    annotator: Option<&str>,
+) -> Result<(), Error> {
+    transaction.execute("DELETE FROM game_text WHERE rowid = ?1", params![game_id])?;
+    let annotator = annotator.unwrap_or_default();
+    if !comments.is_empty() || !annotator.is_empty() {
+        transaction.execute(
+            "INSERT INTO game_text (rowid, comments, annotator) VALUES (?1, ?2, ?3)",
+            params![game_id, comments, annotator],
+        )?;
+    }
+    Ok(())
+}
+
+/// Every comment of the game, variations included, one per line.
+fn comments(game: &PgnGame) -> Result<String, Error> {
+    let comments: Vec<String> = tokenize(&game.movetext)?
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Comment(comment) if !comment.is_empty() => Some(comment),
+            _ => None,
+        })
+        .collect();
+    Ok(comments.join("\n"))
+}
+
+/// Full-text queries are written by users, SQLite rejecting them with a generic error.
+fn query_error(err: rusqlite::Error) -> Error {
+    match err {
+        rusqlite::Error::SqliteFailure(failure, Some(message))
+            if failure.code == rusqlite::ErrorCode::Unknown =>
+        {
+            Error::new(ErrorType::Parse).with_message(&message)
+        }
+        err => err.into(),
+    }
+}
+
+/// Adds the positions of a game's main line to the position index.
+fn index_positions(
+    transaction: &Transaction,
+    game_id: i64,
+    start: Chess,
+    moves: &[shakmaty::Move],
+) -> Result<(), Error> {
+    let (positions, next_moves) = position_rows(start, moves);
+    insert_position_rows(transaction, game_id, &positions, &next_moves)
+}
+
+/// Hashes of the positions of a main line with their ply, and with the move played from them.
+/// Each position is kept once, at its first occurrence.
+fn position_rows(
+    mut position: Chess,
+    moves: &[shakmaty::Move],
+) -> (Vec<PositionRow>, Vec<MoveRow>) {
+    let (mut positions, mut next_moves) = (Vec::new(), Vec::new());
+    let mut seen = HashSet::new();
+    for ply in 0..=moves.len() {
+        let hash = hash::zobrist(&position);
+        if !seen.insert(hash) {
+            if let Some(m) = moves.get(ply) {
+                position.play_unchecked(m);
+            }
+            continue;
+        }
+        positions.push((hash as i64, ply as u32));
+        if let Some(m) = moves.get(ply) {
+            next_moves.push((hash as i64, San::from_move(&position, m).to_string()));
+            position.play_unchecked(m);
+        }
+    }
+    (positions, next_moves)
+}
+
+fn insert_position_rows(
+    transaction: &Transaction,
+    game_id: i64,
+    positions: &[PositionRow],
+    next_moves: &[MoveRow],
+) -> Result<(), Error> {
+    let mut statement = transaction
+        .prepare_cached("INSERT INTO positions (hash, game_id, ply) VALUES (?1, ?2, ?3)")?;
+    for (hash, ply) in positions {
+        statement.execute(params![hash, game_id, ply])?;
+    }
+    let mut statement = transaction
+        .prepare_cached("INSERT INTO position_moves (hash, game_id, san) VALUES (?1, ?2, ?3)")?;
+    for (hash, san) in next_moves {
+        statement.execute(params![hash, game_id, san])?;
+    }
+    Ok(())
+}
+
+/// Movetext is written as a blob, but tools editing the file may store text.
+fn movetext(row: &Row, index: usize) -> rusqlite::Result<Vec<u8>> {
+    Ok(row.get_ref(index)?.as_bytes()?.to_vec())
+}
+
+/// Rebuilds a game from its row.
+fn stored_game(headers: &str, movetext: &[u8]) -> Result<PgnGame, Error> {
+    let headers: serde_json::Map<String, serde_json::Value> = serde_json::from_str(headers)?;
+    Ok(PgnGame {
+        headers: headers
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value.as_str()?.to_string())))
+            .collect(),
+        movetext: String::from_utf8_lossy(movetext).into_owned(),
+    })
+}
+
+/// FNV-1a hash of the moves in SAN, stable across versions unlike the standard library's hasher.
+fn moves_hash(start: &shakmaty::Chess, moves: &[shakmaty::Move]) -> i64 {
+    let mut position = start.clone();
+    let mut hash = FNV_OFFSET;
+    for m in moves {
+        let san = San::from_move(&position, m).to_string();
+        hash = fnv1a(hash, &san);
+        position.play_unchecked(m);
+    }
+    hash as i64
+}
+
+/// Identifies a game by its players, date, result and moves. Names are compared ignoring case
+/// and spacing, so that the same game exported by two sites usually has the same fingerprint.
+/// Unknown values (`?`, `????.??.??`, `*`) are the same as missing tags.
+fn fingerprint(game: &PgnGame, start: &Chess, moves: &[shakmaty::Move]) -> i64 {
+    let mut hash = FNV_OFFSET;
+    for tag in &["White", "Black", "Date", "Result"] {
+        let value = game
+            .header(tag)
+            .filter(|value| !value.chars().all(|c| "?.* ".contains(c)))
+            .unwrap_or_default();
+        let words: Vec<String> = value.split_whitespace().map(str::to_lowercase).collect();
+        hash = fnv1a(hash, &words.join(" "));
+    }
+    hash = fnv1a(hash, &shakmaty::fen::epd(start));
+    let mut position = start.clone();
+    for m in moves {
+        hash = fnv1a(hash, &Uci::from_move(&position, m).to_string());
+        position.play_unchecked(m);
+    }
+    hash as i64
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Adds `word` and a separator to an FNV-1a hash.
+fn fnv1a(mut hash: u64, word: &str) -> u64 {
+    for byte in word.bytes().chain(std::iter::once(b' ')) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn missing_game(id: i64) -> Error {
+    Error::new(ErrorType::Database)
+        .with_message(&format!("There is no game {} in the database", id))
+}
+
+pub fn describe(err: &Error) -> String {
+    match &err.source {
+        Some(source) => source.to_string(),
+        None => format!("{:?}", err.error_type),
+    }
+}
+
+/// Imports the PGN file at `path` into the open database, reporting progress through notifications.
+pub async fn import(
+    state: StateHandle,
+    db_id: String,
+    path: String,
+    options: ImportOptions,
+    ticket: JobTicket,
+) {
+    let JobTicket { token, mut stop } = ticket;
+    let notifier = state.clone();
+    let (database_id, pgn_path) = (db_id.clone(), path.clone());
+    let outcome = state
+        .write_database(&db_id, move |database| {
+            let input = BufReader::new(File::open(&pgn_path)?);
+            let summary = database.import_pgn(
+                input,
+                options,
+                |summary| {
+                    notifier.notify(response_from_notification(
+                        Notification::DatabaseImportProgress {
+                            db_id: database_id.clone(),
+                            path: pgn_path.clone(),
+                            imported: summary.imported,
+                            skipped: summary.skipped,
+                            duplicates: summary.duplicates,
+                        },
+                    ))
+                },
+                // Closed when the job was stopped along with every other one
+                || !matches!(stop.try_recv(), Err(TryRecvError::Empty)),
+            )?;
+            Ok((summary, database.repr(&database_id)?))
+        })
+        .await;
+    let _ = state.database_jobs().finish(&db_id, token);
+
+    match outcome {
+        Ok((summary, repr)) => state.notify(response_from_database(repr).with_notification(
+            Notification::DatabaseImportFinished {
+                db_id,
+                path,
+                summary,
+            },
+        )),
+        Err(err) => state.notify(response_from_error(err)),
+    }
+}
+
+/// Reindexes database `db_id`, reporting progress through notifications.
+pub async fn reindex(state: StateHandle, db_id: String, check_only: bool, ticket: JobTicket) {
+    let JobTicket { token, mut stop } = ticket;
+    let notifier = state.clone();
+    let database_id = db_id.clone();
+    let outcome = state
+        .write_database(&db_id, move |database| {
+            let summary = database.reindex(
+                check_only,
+                |summary| {
+                    notifier.notify(response_from_notification(
+                        Notification::DatabaseReindexProgress {
+                            db_id: database_id.clone(),
+                            games: summary.games,
+                            inconsistent: summary.inconsistent,
+                        },
+                    ))
+                },
+                || !matches!(stop.try_recv(), Err(TryRecvError::Empty)),
+            )?;
+            Ok((summary, database.repr(&database_id)?))
+        })
+        .await;
+    let _ = state.database_jobs().finish(&db_id, token);
+
+    match outcome {
+        Ok((summary, repr)) => state.notify(
+            response_from_database(repr)
+                .with_notification(Notification::DatabaseReindexFinished { db_id, summary }),
+        ),
+        Err(err) => state.notify(response_from_error(err)),
+    }
+}
+
+/// Exports the games of database `db_id` matching `filters` to the PGN file at `path`, reporting
+/// progress through notifications.
+pub async fn export(
+    state: StateHandle,
+    db_id: String,
+    path: String,
+    filters: SearchFilters,
+    sort_by: Option<SortColumn>,
+    descending: bool,
+    ticket: JobTicket,
+) {
+    let JobTicket { token, mut stop } = ticket;
+    let notifier = state.clone();
+    let (database_id, pgn_path) = (db_id.clone(), path.clone());
+    let outcome = state
+        .read_database(&db_id, move |database| {
+            let mut out = BufWriter::new(File::create(&pgn_path)?);
+            database.export_pgn(
+                &filters,
+                sort_by,
+                descending,
+                &mut out,
+                |summary| {
+                    notifier.notify(response_from_notification(
+                        Notification::DatabaseExportProgress {
+                            db_id: database_id.clone(),
+                            path: pgn_path.clone(),
+                            exported: summary.exported,
+                        },
+                    ))
+                },
+                || !matches!(stop.try_recv(), Err(TryRecvError::Empty)),
+            )
+        })
+        .await;
+    let _ = state.exports().finish(&path, token);
+
+    match outcome {
+        Ok(summary) => state.notify(response_from_notification(
+            Notification::DatabaseExportFinished {
+                db_id,
+                path,
+                summary,
+            },
+        )),
+        Err(err) => state.notify(response_from_error(err)),
+    }
+}
+
+/// Applies the migrations the database hasn't seen yet, each in its own transaction.
+fn migrate(connection: &mut Connection, migrations: &[&str]) -> Result<(), Error> {
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let version = version as usize;
+    if version > migrations.len() {
+        return Err(Error::new(ErrorType::Database).with_message(&format!(
+            "The database uses schema version {}, this version of bigchess only knows up to {}",
+            version,
+            migrations.len()
+        )));
+    }
+
+    for (index, migration) in migrations.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", (index + 1) as i64)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+const GAME_COLUMNS: &str =
+    "games.id, games.event, games.site, games.date, games.round, games.white,
+    games.black, games.result, games.white_elo, games.black_elo, games.eco, games.ply_count,
+    games.has_annotations";
+const GAME_COLUMN_COUNT: usize = 13;
+
+/// Header fields of a stored game, as listed in search results.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DatabaseGame {
+    pub id: i64,
+    pub event: Option<String>,
+    pub site: Option<String>,
+    pub date: Option<String>,
+    pub round: Option<String>,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub result: String,
+    pub white_elo: Option<i64>,
+    pub black_elo: Option<i64>,
+    pub eco: Option<String>,
+    /// Length of the main line.
+    pub ply_count: u32,
+    /// Whether the game has comments, NAGs or variations.
+    pub has_annotations: bool,
+    /// Ply at which the searched position occurs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ply: Option<u32>,
+    /// Text matching a full-text search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+impl DatabaseGame {
+    /// Reads the `GAME_COLUMNS` at the start of `row`.
+    fn from_row(row: &Row) -> rusqlite::Result<DatabaseGame> {
+        Ok(DatabaseGame {
+            id: row.get(0)?,
+            event: row.get(1)?,
+            site: row.get(2)?,
+            date: row.get(3)?,
+            round: row.get(4)?,
+            white: row.get(5)?,
+            black: row.get(6)?,
+            result: row.get(7)?,
+            white_elo: row.get(8)?,
+            black_elo: row.get(9)?,
+            eco: row.get(10)?,
+            ply_count: row.get(11)?,
+            has_annotations: row.get(12)?,
+            ply: None,
+            snippet: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortColumn {
+    Date,
+    White,
+    Black,
+    Event,
+    Eco,
+}
+
+/// What was played from a position.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Explorer {
+    /// Games continuing from the position.
+    pub games: u64,
+    pub moves: Vec<ExplorerMove>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExplorerMove {
+    pub san: String,
+    pub games: u64,
+    /// Percentages of the games won by white, drawn and won by black.
+    pub white: f64,
+    pub draw: f64,
+    pub black: f64,
+    /// Of the player facing the move, over the rated games.
+    pub average_opponent_elo: Option<u32>,
+    /// Games with the best rated player first.
+    pub examples: Vec<DatabaseGame>,
+}
+
+/// Header search criteria. Names match case-insensitively from their start ("carl" finds "Carlsen").
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct SearchFilters {
+    pub white: Option<String>,
+    pub black: Option<String>,
+    /// The players may be on either side.
+    pub ignore_colors: bool,
+    pub event: Option<String>,
+    pub eco_prefix: Option<String>,
+    /// PGN dates (`1851.06.21`), both bounds included.
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+    pub result: Option<String>,
+    /// Both players are rated at least this much.
+    pub min_elo: Option<u32>,
+    /// Label of an endgame the main line reaches, as `R+P vs R`.
+    pub endgame: Option<String>,
+}
+
+impl SearchFilters {
+    /// WHERE clause matching the filters, with its parameters.
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = vec![String::from("1")];
+        let mut values = Vec::new();
+        let mut param = |value: Value| {
+            values.push(value);
+            format!("?{}", values.len())
+        };
+
+        let white = self
+            .white
+            .as_deref()
+            .map(|name| param(prefix_pattern(name)));
+        let black = self
+            .black
+            .as_deref()
+            .map(|name| param(prefix_pattern(name)));
+        match (white, black) {
+            (Some(white), Some(black)) if self.ignore_colors => conditions.push(format!(
+                "(({} AND {}) OR ({} AND {}))",
+                like("white", &white),
+                like("black", &black),
+                like("white", &black),
+                like("black", &white)
+            )),
+            (Some(player), None) | (None, Some(player)) if self.ignore_colors => conditions.push(
+                format!("({} OR {})", like("white", &player), like("black", &player)),
+            ),
+            (white, black) => {
+                conditions.extend(white.map(|white| like("white", &white)));
+                conditions.extend(black.map(|black| like("black", &black)));
+            }
+        }
+        if let Some(event) = &self.event {
+            conditions.push(like("event", &param(prefix_pattern(event))));
+        }
+        if let Some(eco) = &self.eco_prefix {
+            conditions.push(like("eco", &param(prefix_pattern(eco))));
+        }
+        if let Some(date) = &self.date_from {
+            conditions.push(format!("date >= {}", param(Value::from(date.clone()))));
+        }
+        if let Some(date) = &self.date_to {
+            conditions.push(format!("date <= {}", param(Value::from(date.clone()))));
+        }
+        if let Some(result) = &self.result {
+            conditions.push(format!("result = {}", param(Value::from(result.clone()))));
+        }
+        if let Some(elo) = self.min_elo {
+            let elo = param(Value::from(elo));
+            conditions.push(format!("white_elo >= {} AND black_elo >= {}", elo, elo));
+        }
+        if let Some(endgame) = &self.endgame {
+            conditions.push(format!(
+                "id IN (SELECT game_id FROM game_endgames WHERE class = {})",
+                param(Value::from(endgame.clone()))
+            ));
+        }
+        (conditions.join(" AND "), values)
+    }
+}
+
+/// ORDER BY clause sorting on `sort_by`, then on the id so that the order is total.
+fn order_by(sort_by: Option<SortColumn>, descending: bool) -> String {
+    let direction = if descending { "DESC" } else { "ASC" };
+    // Collations match the indexes'
+    let column = match sort_by {
+        Some(SortColumn::Date) => "games.date",
+        Some(SortColumn::White) => "games.white COLLATE NOCASE",
+        Some(SortColumn::Black) => "games.black COLLATE NOCASE",
+        Some(SortColumn::Event) => "games.event COLLATE NOCASE",
+        Some(SortColumn::Eco) => "games.eco COLLATE NOCASE",
+        None => "games.id",
+    };
+    format!("{} {}, games.id {}", column, direction, direction)
+}
+
+fn like(column: &str, param: &str) -> String {
+    format!("{} LIKE {} ESCAPE '\\'", column, param)
+}
+
+/// LIKE pattern matching values starting with `prefix`.
+fn prefix_pattern(prefix: &str) -> Value {
+    let escaped = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    Value::from(format!("{}%", escaped))
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct DeletedGames {
+    pub deleted: Vec<i64>,
+    /// Requested ids without a game.
+    pub missing: Vec<i64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Duplicates {
+    /// Games with the same players, date, result and moves.
+    pub exact: Vec<Vec<DatabaseGame>>,
+    /// Games with the same moves only.
+    pub near: Vec<Vec<DatabaseGame>>,
+}
+
+/// One page of matching games.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SearchResults {
+    /// Matches on every page.
+    pub total: u64,
+    pub games: Vec<DatabaseGame>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct DatabaseRepr {
+    pub id: String,
+    pub path: String,
+    pub game_count: u64,
+    pub size_bytes: u64,
+    /// Seconds since the Unix epoch at the end of the last complete reindexing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_reindex: Option<u64>,
+    #[serde(default)]
+    pub table_rows: BTreeMap<String, u64>,
+    /// Bytes used by each table and index, when SQLite can tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_sizes: Option<BTreeMap<String, u64>>,
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Fresh path in the temporary directory, nothing exists there yet.
+    pub fn temp_database(test_name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "bigchess-{}-{}.sqlite",
+            test_name,
+            std::process::id()
+        ));
+        // Along with the write-ahead log of a previous run
+        for suffix in &["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    fn schema_version(path: &Path) -> i64 {
+        Connection::open(path)
+            .unwrap()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn create_and_reopen() {
+        let path = temp_database("create_and_reopen");
+        assert!(Database::open(&path).is_err());
+
+        let database = Database::create(&path).unwrap();
+        database
+            .connection
+            .execute(
+                "INSERT INTO games (white, black, movetext) VALUES ('Carlsen', 'Caruana', '1. e4')",
+                [],
+            )
+            .unwrap();
+        drop(database);
+        assert!(Database::create(&path).unwrap_err().is_type(ErrorType::IO));
+
+        let repr = Database::open(&path).unwrap().repr("main").unwrap();
+        assert_eq!((repr.id.as_str(), repr.game_count), ("main", 1));
+        assert!(repr.size_bytes > 0);
+        assert_eq!(schema_version(&path), MIGRATIONS.len() as i64);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/three_games.pgn"
+    );
+
+    fn import_fixture(database: &mut Database, skip_duplicates: bool) -> ImportSummary {
+        let input = BufReader::new(File::open(FIXTURE).unwrap());
+        let options = ImportOptions { skip_duplicates };
+        database
+            .import_pgn(input, options, |_| {}, || false)
+            .unwrap()
+    }
+
+    #[test]
+    fn import_pgn() {
+        let path = temp_database("import_pgn");
+        let mut database = Database::create(&path).unwrap();
+
+        let summary = import_fixture(&mut database, true);
+        assert_eq!((summary.imported, summary.skipped), (2, 1));
+        assert_eq!(summary.warnings.len(), 1);
+        assert!(summary.warnings[0].starts_with("Game 2:"));
+
+        let (white, elo, eco): (String, Option<i64>, String) = database
+            .connection
+            .query_row(
+                "SELECT white, white_elo, eco FROM games WHERE event = 'London'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (white.as_str(), elo, eco.as_str()),
+            ("Anderssen, Adolf", Some(2600), "C33")
+        );
+
+        let summary = import_fixture(&mut database, true);
+        assert_eq!((summary.imported, summary.duplicates), (0, 2));
+        assert_eq!(database.game_count().unwrap(), 2);
+        let summary = import_fixture(&mut database, false);
+        assert_eq!((summary.imported, summary.duplicates), (2, 0));
+        assert_eq!(database.game_count().unwrap(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn search_position() {
+        let path = temp_database("search_position");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+
+        let opening = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from("1. e4 e5 2. Nf3 d6 3. d4"),
+        };
+        let position = opening.positions().unwrap().pop().unwrap();
+        let results = database.search_position(&position, 10, 0).unwrap();
+        assert_eq!(results.total, 1);
+        assert_eq!(results.games.len(), 1);
+        assert_eq!(results.games[0].white.as_deref(), Some("Morphy, Paul"));
+        assert_eq!(results.games[0].ply, Some(5));
+
+        // The limit is enforced while the total counts every match
+        let results = database.search_position(&Chess::default(), 1, 0).unwrap();
+        assert_eq!((results.total, results.games.len()), (2, 1));
+        let next_page = database.search_position(&Chess::default(), 1, 1).unwrap();
+        assert_ne!(next_page.games[0].id, results.games[0].id);
+        assert_eq!(next_page.games[0].ply, Some(0));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn find_duplicates() {
+        let path = temp_database("find_duplicates");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+        import_fixture(&mut database, false);
+        let moves = "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O Be7 6. Re1 b5 7. Bb3 d6
+            8. c3 O-O 9. h3 Nb8 10. d4 Nbd7 11. Nbd2 Bb7 12. Bc2 Re8 13. Nf1 Bf8 14. Ng3 g6 15. a4 c6 *";
+        let games = format!(
+            "[White \"Karpov, Anatoly\"]\n[Black \"Spassky, Boris\"]\n\n{}\n\n\
+            [White \"karpov,  anatoly\"]\n[Black \"Spassky, Boris\"]\n\n{}\n\n\
+            [White \"Someone\"]\n[Black \"Someone else\"]\n\n{}\n",
+            moves, moves, moves
+        );
+        let summary = database
+            .import_pgn(
+                games.as_bytes(),
+                ImportOptions {
+                    skip_duplicates: true,
+                },
+                |_| {},
+                || false,
+            )
+            .unwrap();
+        // Names are normalized before hashing
+        assert_eq!((summary.imported, summary.duplicates), (2, 1));
+
+        let duplicates = database.find_duplicates(10).unwrap();
+        let ids = |groups: &[Vec<DatabaseGame>]| -> Vec<Vec<i64>> {
+            groups
+                .iter()
+                .map(|group| group.iter().map(|game| game.id).collect())
+                .collect()
+        };
+        assert_eq!(ids(&duplicates.exact), vec![vec![1, 3], vec![2, 4]]);
+        assert_eq!(ids(&duplicates.near), vec![vec![5, 6]]);
+        assert_eq!(database.find_duplicates(1).unwrap().exact.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn search_text() {
+        let path = temp_database("search_text");
+        let mut database = Database::create(&path).unwrap();
+        let games = r#"
+[White "Nimzowitsch, Aron"]
+[Annotator "Tartakower"]
+
+1. e4 {A quiet start} e6 (1... c5 2. Nf3 (2. c3 {Leads to zugzwang eventually}) d6) 2. d4 *
+
+[White "Someone"]
+
+1. d4 {Zugzwang is far away} d5 *
+
+[White "Someone else"]
+
+1. c4 c5 *
+"#;
+        database
+            .import_pgn(games.as_bytes(), ImportOptions::default(), |_| {}, || false)
+            .unwrap();
+
+        let results = database.search_text("zugzwang", 10, 0).unwrap();
+        assert_eq!(results.total, 2);
+        let ids: HashSet<i64> = results.games.iter().map(|game| game.id).collect();
+        assert_eq!(ids, [1, 2].iter().copied().collect());
+        let nested = results.games.iter().find(|game| game.id == 1).unwrap();
+        assert!(nested.snippet.as_deref().unwrap().contains("«zugzwang»"));
+        let results = database.search_text("annotator:tartakower", 10, 0).unwrap();
+        assert_eq!(results.total, 1);
+
+        let err = database.search_text("zugzwang AND (", 10, 0).unwrap_err();
+        assert!(err.is_type(ErrorType::Parse), "{:?}", err);
+
+        // Kept in sync with edits and deletions
+        let mut fields = HashMap::new();
+        fields.insert(String::from("Annotator"), Some(String::from("Kmoch")));
+        database.update_headers(1, fields).unwrap();
+        assert_eq!(database.search_text("tartakower", 10, 0).unwrap().total, 0);
+        assert_eq!(database.search_text("kmoch", 10, 0).unwrap().total, 1);
+        database.delete_games(&[2]).unwrap();
+        assert_eq!(database.search_text("zugzwang", 10, 0).unwrap().total, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn export_pgn() {
+        let path = temp_database("export_pgn");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+        let games =
+            "[White \"Morphy, Paul\"]\n[Black \"Amateur\"]\n\n1. d4 {Solid} d5 (1... Nf6) *\n";
+        database
+            .import_pgn(games.as_bytes(), ImportOptions::default(), |_| {}, || false)
+            .unwrap();
+
+        let filters = SearchFilters {
+            white: Some(String::from("morphy")),
+            ..SearchFilters::default()
+        };
+        let mut out = Vec::new();
+        let summary = database
+            .export_pgn(&filters, None, true, &mut out, |_| {}, || false)
+            .unwrap();
+        assert_eq!(summary.exported, 2);
+
+        let exported = temp_database("export_pgn_copy");
+        let mut copy = Database::create(&exported).unwrap();
+        let summary = copy
+            .import_pgn(out.as_slice(), ImportOptions::default(), |_| {}, || false)
+            .unwrap();
+        assert_eq!((summary.imported, summary.skipped), (2, 0));
+        // Newest first, as asked
+        let reimported = copy.list_games(None, false, 10, 0).unwrap().games;
+        assert_eq!(reimported[0].black.as_deref(), Some("Amateur"));
+        let fingerprint = |database: &Database, id: i64| -> i64 {
+            database
+                .connection
+                .query_row(
+                    "SELECT fingerprint FROM games WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        for (original, copied) in &[(3, 1), (1, 2)] {
+            assert_eq!(
+                fingerprint(&database, *original),
+                fingerprint(&copy, *copied)
+            );
+        }
+        assert_eq!(copy.search_text("solid", 10, 0).unwrap().total, 1);
+
+        let mut out = Vec::new();
+        let summary = database
+            .export_pgn(&filters, None, false, &mut out, |_| {}, || true)
+            .unwrap();
+        assert!(summary.stopped && out.is_empty());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&exported).unwrap();
+    }
+
+    #[test]
+    fn reindex() {
+        let path = temp_database("reindex");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+        let connection = &database.connection;
+        connection
+            .execute_batch(
+                "DELETE FROM positions WHERE game_id = 1;
+                UPDATE position_moves SET san = 'Qh5' WHERE game_id = 2;
+                UPDATE games SET fingerprint = NULL WHERE id = 2;
+                INSERT INTO games (id, movetext) VALUES (10, '1. e4 Ke5');
+                PRAGMA foreign_keys = OFF;
+                INSERT INTO positions (hash, game_id, ply) VALUES (42, 99, 0);
+                PRAGMA foreign_keys = ON;",
+            )
+            .unwrap();
+        let start_games = |database: &Database| {
+            database
+                .search_position(&Chess::default(), 10, 0)
+                .unwrap()
+                .total
+        };
+        assert_eq!(start_games(&database), 1);
+
+        let summary = database.reindex(true, |_| {}, || false).unwrap();
+        assert_eq!(
+            (
+                summary.games,
+                summary.inconsistent,
+                summary.corrupt,
+                summary.orphans
+            ),
+            (3, 2, 1, 1)
+        );
+        assert!(summary.warnings[0].starts_with("Game 10:"));
+        assert_eq!(start_games(&database), 1);
+        assert_eq!(database.repr("main").unwrap().last_reindex, None);
+
+        let summary = database.reindex(false, |_| {}, || false).unwrap();
+        assert_eq!((summary.inconsistent, summary.orphans), (2, 1));
+        assert_eq!(start_games(&database), 2);
+        let explorer = database.explore(&Chess::default()).unwrap();
+        assert!(explorer.moves.iter().all(|m| m.san == "e4"));
+        assert!(database.repr("main").unwrap().last_reindex.is_some());
+
+        let summary = database.reindex(true, |_| {}, || false).unwrap();
+        assert_eq!((summary.inconsistent, summary.orphans), (0, 0));
+        let stopped = database.reindex(false, |_| {}, || true).unwrap();
+        assert!(stopped.stopped);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn maintain() {
+        let path = temp_database("maintain");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+        database.delete_games(&[1]).unwrap();
+
+        let actions = [
+            MaintenanceAction::IntegrityCheck,
+            MaintenanceAction::Vacuum,
+            MaintenanceAction::Analyze,
+        ];
+        let results = database.maintain(&actions).unwrap();
+        let done: Vec<MaintenanceAction> = results.iter().map(|result| result.action).collect();
+        assert_eq!(done, actions);
+        assert_eq!(results[0].messages, vec![String::from("ok")]);
+        assert!(results[1].bytes_reclaimed.is_some());
+        assert!(results[2].messages.is_empty() && results[2].bytes_reclaimed.is_none());
+
+        let repr = database.repr("main").unwrap();
+        assert_eq!(repr.table_rows["games"], 1);
+        assert_eq!(repr.table_rows["game_text"], 0);
+        assert!(repr.table_rows["positions"] > repr.table_rows["position_moves"]);
+        let sizes = repr.object_sizes.unwrap();
+        assert!(sizes["games"] > 0 && sizes["positions_hash"] > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn maintain_from_state() {
+        let state = crate::state::StateHandle::default();
+        let path = temp_database("maintain_from_state");
+        let db_id = || String::from("main");
+        state
+            .open_database(db_id(), path.display().to_string(), true)
+            .await
+            .unwrap();
+
+        let actions = vec![MaintenanceAction::IntegrityCheck, MaintenanceAction::Vacuum];
+        let response = state.maintain_database(db_id(), actions).await.unwrap();
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(response["maintenance"][0]["action"], "integrity_check");
+        assert_eq!(response["maintenance"][0]["messages"][0], "ok");
+        assert!(response["maintenance"][1]["bytes_reclaimed"].is_u64());
+        assert!(response["maintenance"][1]["duration_ms"].is_u64());
+        assert_eq!(response["database"]["table_rows"]["games"], 0);
+
+        // Refused while an import is running
+        let _import = state.database_jobs().start("main").unwrap();
+        let err = state
+            .maintain_database(db_id(), vec![MaintenanceAction::Analyze])
+            .await
+            .unwrap_err();
+        assert!(err.is_type(ErrorType::Locked));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_games() {
+        let path = temp_database("delete_games");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+
+        let deleted = database.delete_games(&[1, 42]).unwrap();
+        assert_eq!(deleted.deleted, vec![1]);
+        assert_eq!(deleted.missing, vec![42]);
+        assert_eq!(database.game_count().unwrap(), 1);
+
+        let results = database.search_position(&Chess::default(), 10, 0).unwrap();
+        assert_eq!(results.total, 1);
+        assert_eq!(results.games[0].id, 2);
+        let orphans: i64 = database
+            .connection
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM positions WHERE game_id = 1)
+                    + (SELECT COUNT(*) FROM position_moves WHERE game_id = 1)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(orphans, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update_headers() {
+        let path = temp_database("update_headers");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+
+        let mut fields = HashMap::new();
+        fields.insert(String::from("White"), Some(String::from("Morphy, Paul C.")));
+        fields.insert(String::from("WhiteElo"), Some(String::from("2690")));
+        fields.insert(String::from("Site"), None);
+        let game = database.update_headers(1, fields).unwrap();
+        assert_eq!(game.white.as_deref(), Some("Morphy, Paul C."));
+        assert_eq!(game.white_elo, Some(2690));
+        assert_eq!(game.site, None);
+        assert_eq!(game.eco.as_deref(), Some("C41"));
+
+        let filters = SearchFilters {
+            white: Some(String::from("morphy, paul c")),
+            ..SearchFilters::default()
+        };
+        assert_eq!(database.search(&filters, 10, 0).unwrap().total, 1);
+        let stored = database.open_game(1).unwrap();
+        let repr = stored.get_repr();
+        assert!(repr.info.headers.iter().all(|(tag, _)| tag != "Site"));
+
+        assert!(database.update_headers(42, HashMap::new()).is_err());
+        let mut fields = HashMap::new();
+        fields.insert(String::from("FEN"), None);
+        assert!(database.update_headers(1, fields).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn search_endgames() {
+        let path = temp_database("search_endgames");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+        let pgn = "[FEN \"8/8/4k3/8/3RP3/8/4K3/r7 w - - 0 1\"]\n[SetUp \"1\"]\n\n\
+            1. Ke3 Re1+ 2. Kd2 Rxe4 *\n";
+        let options = ImportOptions {
+            skip_duplicates: false,
+        };
+        database
+            .import_pgn(std::io::Cursor::new(pgn), options, |_| {}, || false)
+            .unwrap();
+
+        let total = |database: &Database, endgame: &str| {
+            let filters = SearchFilters {
+                endgame: Some(String::from(endgame)),
+                ..SearchFilters::default()
+            };
+            database.search(&filters, 10, 0).unwrap().total
+        };
+        assert_eq!(total(&database, "R+P vs R"), 1);
+        assert_eq!(total(&database, "R vs R"), 1);
+        assert_eq!(total(&database, "pawn endgame"), 0);
+
+        // Games imported before the index are caught up by reindexing
+        database
+            .connection
+            .execute("DELETE FROM game_endgames", [])
+            .unwrap();
+        assert_eq!(total(&database, "R vs R"), 0);
+        let summary = database.reindex(false, |_| {}, || false).unwrap();
+        assert_eq!(summary.inconsistent, 1);
+        assert_eq!(total(&database, "R vs R"), 1);
+    }
+
+    #[test]
+    fn search() {
+        let path = temp_database("search");
+        let mut database = Database::create(&path).unwrap();
+        import_fixture(&mut database, false);
+        database
+            .connection
+            .execute(
+                "INSERT INTO games (event, date, white, black, result, white_elo, black_elo, movetext)
+                VALUES ('Casual_game', '1858.10.01', 'Duke Karl', 'Morphy, Paul', '0-1', 2100, 2700, '')",
+                [],
+            )
+            .unwrap();
+
+        let total = |filters: SearchFilters| database.search(&filters, 10, 0).unwrap().total;
+        let morphy = || Some(String::from("morphy"));
+        assert_eq!(total(SearchFilters::default()), 3);
+        assert_eq!(
+            total(SearchFilters {
+                white: morphy(),
+                ..SearchFilters::default()
+            }),
+            1
+        );
+        assert_eq!(
+            total(SearchFilters {
+                black: morphy(),
+                ignore_colors: true,
+                ..SearchFilters::default()
+            }),
+            2
+        );
+        assert_eq!(
+            total(SearchFilters {
+                white: morphy(),
+                black: Some(String::from("duke")),
+                ignore_colors: true,
+                ..SearchFilters::default()
+            }),
+            2
+        );
+        assert_eq!(
+            total(SearchFilters {
+                event: Some(String::from("casual_")),
+                ..SearchFilters::default()
+            }),
+            1
+        );
+        assert_eq!(
+            total(SearchFilters {
+                event: Some(String::from("c_")),
+                ..SearchFilters::default()
+            }),
+            0
+        );
+        assert_eq!(
+            total(SearchFilters {
+                eco_prefix: Some(String::from("c3")),
+                ..SearchFilters::default()
+            }),
+            1
+        );
+        assert_eq!(
+            total(SearchFilters {
+                date_from: Some(String::from("1858.01.01")),
+                date_to: Some(String::from("1858.12.31")),
+                ..SearchFilters::default()
+            }),
+            1
+        );
+        assert_eq!(
+            total(SearchFilters {
+                result: Some(String::from("1-0")),
+                ..SearchFilters::default()
+            }),
+            2
+        );
+        assert_eq!(
+            total(SearchFilters {
+                min_elo: Some(2000),
+                ..SearchFilters::default()
+            }),
+            1
+        );
+
+        let first_page = database.search(&SearchFilters::default(), 2, 0).unwrap();
+        let last_page = database.search(&SearchFilters::default(), 2, 2).unwrap();
+        assert_eq!((first_page.total, first_page.games.len()), (3, 2));
+        assert_eq!(last_page.games.len(), 1);
+        assert_eq!(last_page.games[0].white.as_deref(), Some("Duke Karl"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn explore() {
+        let path = temp_database("explore");
+        let mut database = Database::create(&path).unwrap();
+        let games = r#"
+[White "A"]
+[Black "B"]
+[Result "1-0"]
+[BlackElo "2000"]
+1. e4 e5 2. Nf3 1-0
+
+[White "C"]
+[Black "D"]
+[Result "1/2-1/2"]
+[BlackElo "2200"]
+1. e4 c5 1/2-1/2
+
+[White "E"]
+[Black "F"]
+[Result "0-1"]
+1. d4 d5 2. Nf3 Nf6 3. Nc3 0-1
+
+[White "G"]
+[Black "H"]
+[Result "1-0"]
+[BlackElo "2400"]
+1. e4 e5 1-0
+"#;
+        database
+            .import_pgn(games.as_bytes(), ImportOptions::default(), |_| {}, || false)
+            .unwrap();
+
+        // Classified on import, as the games have no ECO tag
+        let filters = SearchFilters {
+            eco_prefix: Some(String::from("C4")),
+            ..SearchFilters::default()
+        };
+        let classified = database.search(&filters, 10, 0).unwrap();
+        assert_eq!(classified.games[0].eco.as_deref(), Some("C40"));
+        assert_eq!(classified.total, 1);
+
+        let explorer = database.explore(&Chess::default()).unwrap();
+        assert_eq!(explorer.games, 4);
+        let e4 = &explorer.moves[0];
+        assert_eq!((e4.san.as_str(), e4.games), ("e4", 3));
+        assert_eq!(
+            (e4.white.round(), e4.draw.round(), e4.black),
+            (67.0, 33.0, 0.0)
+        );
+        assert_eq!(e4.average_opponent_elo, Some(2200));
+        assert_eq!(e4.examples.len(), 3);
+        assert_eq!(e4.examples[0].white.as_deref(), Some("G"));
+        let d4 = &explorer.moves[1];
+        assert_eq!((d4.san.as_str(), d4.games, d4.black), ("d4", 1, 100.0));
+        assert_eq!(d4.average_opponent_elo, None);
+
+        // Games which stop at the position don't count
+        let opening = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from("1. e4 e5"),
+        };
+        let position = opening.positions().unwrap().pop().unwrap();
+        let explorer = database.explore(&position).unwrap();
+        assert_eq!(explorer.games, 1);
+        assert_eq!(explorer.moves[0].san, "Nf3");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_game() {
+        let state = crate::state::StateHandle::default();
+        let path = temp_database("open_game");
+        let db_id = || String::from("main");
+        state
+            .open_database(db_id(), path.display().to_string(), true)
+            .await
+            .unwrap();
+        state
+            .write_database("main", |database| {
+                let input = BufReader::new(File::open(FIXTURE)?);
+                database.import_pgn(input, ImportOptions::default(), |_| {}, || false)?;
+                database.connection.execute(
+                    "INSERT INTO games (id, movetext) VALUES (10, '1. e4 Ke5')",
+                    [],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        let response = state
+            .open_game_from_database(db_id(), 2, None)
+            .await
+            .unwrap();
+        let response = serde_json::to_value(response).unwrap();
+        let opened = &response["changed_games"][0];
+        assert_eq!(opened["id"], "main-2");
+        assert_eq!(opened["game"]["info"]["db_id"], "main");
+        assert_eq!(opened["game"]["info"]["database_id"], 2);
+        assert_eq!(opened["game"]["info"]["opening"]["eco"], "C33");
+        assert!(state.navigate_back("main-2", 1, None).is_ok());
+
+        let response = state
+            .open_game_from_database(db_id(), 1, Some(String::from("morphy")))
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(response).unwrap()["changed_games"][0]["id"],
+            "morphy"
+        );
+
+        let err = state
+            .open_game_from_database(db_id(), 10, None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.is_type(ErrorType::Parse) && err.is_recoverable(),
+            "{:?}",
+            err
+        );
+        assert!(err.source.unwrap().to_string().contains("Game 10"));
+        let err = state
+            .open_game_from_database(db_id(), 11, None)
+            .await
+            .unwrap_err();
+        assert!(err.is_type(ErrorType::Database));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn list_games() {
+        let path = temp_database("list_games");
+        let mut database = Database::create(&path).unwrap();
+        let games = r#"
+[Event "Casual"]
+[Date "1858.??.??"]
+[White "Morphy, Paul"]
+[Black "anderssen, Adolf"]
+1. e4 e5 {Open game} 2. Nf3 *
+
+[Event "London"]
+[Date "1851.06.21"]
+[White "Anderssen, Adolf"]
+[Black "Kieseritzky, Lionel"]
+1. d4 *
+
+[Event "Casual"]
+[Date "1859.01.01"]
+[White "Bird, Henry"]
+[Black "Morphy, Paul"]
+1. f4 d5 (1... e5) *
+"#;
+        database
+            .import_pgn(games.as_bytes(), ImportOptions::default(), |_| {}, || false)
+            .unwrap();
+
+        let ids = |sort_by, descending| -> Vec<i64> {
+            let page = database.list_games(sort_by, descending, 10, 0).unwrap();
+            page.games.iter().map(|game| game.id).collect()
+        };
+        assert_eq!(ids(None, false), vec![1, 2, 3]);
+        assert_eq!(ids(Some(SortColumn::Date), false), vec![2, 1, 3]);
+        assert_eq!(ids(Some(SortColumn::White), false), vec![2, 3, 1]);
+        assert_eq!(ids(Some(SortColumn::Black), false), vec![1, 2, 3]);
+        assert_eq!(ids(Some(SortColumn::Black), true), vec![3, 2, 1]);
+        // Ties are broken by id, in the same direction
+        assert_eq!(ids(Some(SortColumn::Event), false), vec![1, 3, 2]);
+        assert_eq!(ids(Some(SortColumn::Event), true), vec![2, 3, 1]);
+        assert_eq!(ids(Some(SortColumn::Eco), false), vec![3, 2, 1]);
+
+        let first = database.list_games(None, false, 1, 0).unwrap();
+        assert_eq!((first.total, first.games[0].ply_count), (3, 3));
+        assert!(first.games[0].has_annotations);
+        let rest = database.list_games(None, false, 5, 1).unwrap();
+        assert_eq!(rest.games.len(), 2);
+        assert!(!rest.games[0].has_annotations);
+        assert!(rest.games[1].has_annotations);
+        let at_the_end = database.list_games(None, false, 10, 3).unwrap();
+        assert!(at_the_end.games.is_empty());
+        let past_the_end = database.list_games(None, false, 10, u32::MAX).unwrap();
+        assert!(past_the_end.games.is_empty());
+        assert_eq!(past_the_end.total, 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stop_import() {
+        let path = temp_database("stop_import");
+        let mut database = Database::create(&path).unwrap();
+        let input = BufReader::new(File::open(FIXTURE).unwrap());
+        let mut games_read = 0;
+        let summary = database
+            .import_pgn(
+                input,
+                ImportOptions::default(),
+                |_| {},
+                || {
+                    games_read += 1;
+                    games_read > 1
+                },
+            )
+            .unwrap();
+        assert!(summary.stopped);
+        assert_eq!(database.game_count().unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn upgrade() {
+        let path = temp_database("upgrade");
+        let migrations = [
+            "CREATE TABLE games (id INTEGER PRIMARY KEY, white TEXT);",
+            "ALTER TABLE games ADD COLUMN black TEXT;",
+        ];
+
+        let mut connection = Connection::open(&path).unwrap();
+        migrate(&mut connection, &migrations[..1]).unwrap();
+        connection
+            .execute("INSERT INTO games (white) VALUES ('Tal')", [])
+            .unwrap();
+        drop(connection);
+        assert_eq!(schema_version(&path), 1);
+
+        // Reopened by a newer version
+        let mut connection = Connection::open(&path).unwrap();
+        migrate(&mut connection, &migrations).unwrap();
+        let (white, black): (String, Option<String>) = connection
+            .query_row("SELECT white, black FROM games", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((white.as_str(), black), ("Tal", None));
+        assert_eq!(schema_version(&path), 2);
+
+        // Then by the older one again
+        let err = migrate(&mut connection, &migrations[..1]).unwrap_err();
+        assert!(err.is_type(ErrorType::Database));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_from_state() {
+        let state = crate::state::StateHandle::default();
+        let path = temp_database("open_from_state");
+        let path_string = path.display().to_string();
+        let db_id = || String::from("main");
+
+        assert!(state
+            .open_database(db_id(), path_string.clone(), false)
+            .await
+            .is_err());
+        let response = state
+            .open_database(db_id(), path_string, true)
+            .await
+            .unwrap();
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(response["database"]["game_count"], 0);
+
+        let info = state.get_database_info(db_id()).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(info).unwrap()["database"],
+            response["database"]
+        );
+        state.close_database(db_id()).await.unwrap();
+        let err = state.get_database_info(db_id()).await.unwrap_err();
+        assert!(err.is_type(ErrorType::Database));
+        assert!(state.close_database(db_id()).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_job() {
+        let state = crate::state::StateHandle::default();
+        let mut notifications = state.subscribe();
+        let path = temp_database("import_job");
+        let options = ImportOptions::default();
+        let db_id = || String::from("main");
+        assert!(state
+            .start_database_import(db_id(), String::from(FIXTURE), options)
+            .await
+            .is_err());
+
+        state
+            .open_database(db_id(), path.display().to_string(), true)
+            .await
+            .unwrap();
+        state
+            .start_database_import(db_id(), String::from(FIXTURE), options)
+            .await
+            .unwrap();
+        let progress = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(progress["notification"]["type"], "database_import_progress");
+        let finished = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(finished["notification"]["summary"]["imported"], 2);
+        assert_eq!(finished["notification"]["db_id"], "main");
+        assert_eq!(finished["database"]["game_count"], 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn several_databases() {
+        let state = crate::state::StateHandle::default();
+        let paths = [temp_database("several_a"), temp_database("several_b")];
+        for (db_id, path) in ["a", "b"].iter().zip(&paths) {
+            state
+                .open_database(db_id.to_string(), path.display().to_string(), true)
+                .await
+                .unwrap();
+        }
+        state
+            .write_database("b", |database| {
+                let input = BufReader::new(File::open(FIXTURE)?);
+                database.import_pgn(input, ImportOptions::default(), |_| {}, || false)
+            })
+            .await
+            .unwrap();
+
+        let search = |db_id: &str| {
+            let filters = SearchFilters {
+                white: Some(String::from("Morphy")),
+                ..SearchFilters::default()
+            };
+            state.search_database(db_id.to_string(), filters, 10, 0)
+        };
+        let in_a = serde_json::to_value(search("a").await.unwrap()).unwrap();
+        let in_b = serde_json::to_value(search("b").await.unwrap()).unwrap();
+        assert_eq!(in_a["search_results"]["total"], 0);
+        assert_eq!(in_b["search_results"]["total"], 1);
+
+        // Queries share the pool of read connections while a write is running
+        let (first, second, deleted) = tokio::join!(
+            state.read_database("b", |database| database.game_count()),
+            state.read_database("b", |database| database.game_count()),
+            state.write_database("b", |database| database.delete_games(&[1]))
+        );
+        assert_eq!(deleted.unwrap().deleted, vec![1]);
+        for count in &[first.unwrap(), second.unwrap()] {
+            assert!(*count == 1 || *count == 2);
+        }
+
+        let listed = serde_json::to_value(state.list_databases().await.unwrap()).unwrap();
+        assert_eq!(listed["databases"][0]["id"], "a");
+        assert_eq!(listed["databases"][1]["game_count"], 1);
+
+        state.close_database(String::from("b")).await.unwrap();
+        assert!(search("b").await.unwrap_err().is_type(ErrorType::Database));
+        assert!(search("a").await.is_ok());
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::api::{response_from_error, response_from_notification, Notification};
+use crate::engine::{position_command, Analysis, Engine};
+use crate::errors::{Error, ErrorType};
+use crate::game::san_line;
+use crate::jobs::JobTicket;
+use crate::state::StateHandle;
+
+use std::time::Duration;
+
+use shakmaty::Setup;
+use tokio::sync::{oneshot, OwnedMutexGuard};
+
+/// Deepest search an `Analyze` request can ask for.
+pub const MAX_DEPTH: u32 = 60;
+
+/// Time a search is given at most, whatever its depth.
+const BUDGET: Duration = Duration::from_secs(30);
+
+/// Searches the current position of game `id` to `depth` with `engine`, locked for the request.
+/// The result is notified as `AnalysisFinished`, without any analysis if it was stopped.
+pub async fn run(
+    state: StateHandle,
+    id: String,
+    engine: OwnedMutexGuard<Engine>,
+    depth: u32,
+    ticket: JobTicket,
+) {
+    let JobTicket { token, mut stop } = ticket;
+    let outcome = analyze(&state, &id, engine, depth, &mut stop).await;
+    let _ = state.analyses().finish(&id, token);
+    match outcome {
+        // The game was closed
+        Err(err) if err.is_type(ErrorType::BadHandle) || err.is_type(ErrorType::StaleHandle) => {}
+        Err(err) => state.notify(response_from_error(err.with_id(&id))),
+        Ok(analysis) => state.notify(response_from_notification(Notification::AnalysisFinished {
+            id,
+            analysis,
+        })),
+    }
+}
+
+async fn analyze(
+    state: &StateHandle,
+    id: &str,
+    mut engine: OwnedMutexGuard<Engine>,
+    depth: u32,
+    stop: &mut oneshot::Receiver<()>,
+) -> Result<Option<Analysis>, Error> {
+    // The game stays free to change: the line found is read from the position searched
+    let (fen, moves, chess960, position) = state.with_game(id, |game| {
+        Ok((
+            game.initial_fen(),
+            game.uci_line(),
+            game.is_chess960(),
+            game.current_position(),
+        ))
+    })?;
+    engine.restore_options().await?;
+    engine.set_chess960(chess960).await?;
+    let command = position_command(&fen, &moves);
+    let go = format!("go depth {} movetime {}", depth, BUDGET.as_millis());
+    let best_move = tokio::select! {
+        best_move = engine.best_move(&command, &go, BUDGET) => best_move?,
+        _ = &mut *stop => {
+            engine.stop().await?;
+            return Ok(None);
+        }
+    };
+    drop(engine);
+
+    let pv = match best_move.pv.first() {
+        Some(first) if *first == best_move.uci => best_move.pv,
+        _ => vec![best_move.uci],
+    };
+    let pv = san_line(&position, &pv);
+    Ok(Some(Analysis {
+        best_move: pv.first().cloned().ok_or_else(|| {
+            Error::new(ErrorType::Engine).with_message("The engine's best move is illegal")
+        })?,
+        pv,
+        evaluation: best_move.evaluation.map(|e| e.for_white(position.turn())),
+    }))
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    White,
+    Black,
+}
+
+impl From<Side> for Color {
+    fn from(side: Side) -> Color {
+        match side {
+            Side::White => Color::White,
+            Side::Black => Color::Black,
+        }
+    }
+}
+
+/// Progress of a drill of the repertoire moves of one side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drill {
+    color: Color,
+    /// Plies after which lines end, counted from where the drill started.
+    depth_limit: Option<u32>,
+    plies: u32,
+    hits: u32,
+    misses: u32,
+    streak: u32,
+    best_streak: u32,
+    /// Database games of the opponent's moves (SAN) by Zobrist key of the position.
+    frequencies: HashMap<u64, Vec<(String, u64)>>,
+}
+
+impl Drill {
+    pub fn new(
+        color: Color,
+        depth_limit: Option<u32>,
+        frequencies: HashMap<u64, Vec<(String, u64)>>,
+    ) -> Drill {
+        Drill {
+            color,
+            depth_limit,
+            plies: 0,
+            hits: 0,
+            misses: 0,
+            streak: 0,
+            best_streak: 0,
+            frequencies,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    pub fn depth_reached(&self) -> bool {
+        self.depth_limit.is_some_and(|limit| self.plies >= limit)
+    }
+
+    pub fn played(&mut self) {
+        self.plies += 1;
+    }
+
+    pub fn hit(&mut self) {
+        self.played();
+        self.hits += 1;
+        self.streak += 1;
+        self.best_streak = self.best_streak.max(self.streak);
+    }
+
+    pub fn miss(&mut self, expected: String) -> DrillStep {
+        self.misses += 1;
+        self.streak = 0;
+        DrillStep::Miss {
+            expected,
+            misses: self.misses,
+        }
+    }
+
+    pub fn line_end(&self, reply: Option<String>) -> DrillStep {
+        DrillStep::LineEnd {
+            reply,
+            hits: self.hits,
+            misses: self.misses,
+            best_streak: self.best_streak,
+        }
+    }
+
+    pub fn streak(&self) -> u32 {
+        self.streak
+    }
+
+    /// Picks one of the opponent's `moves` from the position of `key`, given as their SAN and
+    /// the misses recorded after them. Moves are weighted by how often they were played in the
+    /// database, then by how often the reply to them was missed, so weak spots come back.
+    pub fn pick(&self, key: u64, moves: &[(String, u32)]) -> Option<usize> {
+        let frequencies = self.frequencies.get(&key);
+        let weights = moves.iter().map(|(san, misses)| {
+            let games = frequencies
+                .and_then(|frequencies| frequencies.iter().find(|(played, _)| played == san))
+                .map_or(0, |&(_, games)| games);
+            (games + 1) * (u64::from(*misses) + 1)
+        });
+        let index = WeightedIndex::new(weights).ok()?;
+        Some(index.sample(&mut rand::thread_rng()))
+    }
+}
+
+/// Outcome of starting a drill or of a move played during one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "result")]
+pub enum DrillStep {
+    /// The repertoire move is expected, after the opponent's `reply` (SAN) if they had to move.
+    Ready {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply: Option<String>,
+    },
+    /// The repertoire move was played, then the opponent's `reply`.
+    Hit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply: Option<String>,
+        streak: u32,
+    },
+    /// The move wasn't played. `expected` is the repertoire move in SAN.
+    Miss { expected: String, misses: u32 },
+    /// No repertoire move is marked from the position, after the opponent's `reply` if any, or
+    /// the depth limit was reached: the drill is over.
+    LineEnd {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply: Option<String>,
+        hits: u32,
+        misses: u32,
+        best_streak: u32,
+    },
+}
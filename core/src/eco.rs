@@ -0,0 +1,121 @@
+use crate::hash;
+use crate::pgn::{self, Token};
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use shakmaty::san::San;
+use shakmaty::{Chess, Position};
+
+/// Canonical opening lines: ECO code, name and moves, separated by tabs.
+const TABLE: &str = include_str!("../data/eco.tsv");
+
+/// Opening of a game, from the Encyclopaedia of Chess Openings classification.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Opening {
+    pub eco: String,
+    /// Ruy Lopez
+    pub name: String,
+    /// Morphy Defense
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variation: Option<String>,
+}
+
+/// Opening of the deepest of `positions` found in the table. Transpositions are recognized.
+pub fn classify(positions: &[Chess]) -> Option<Opening> {
+    let table = openings();
+    positions
+        .iter()
+        .rev()
+        .find_map(|position| table.get(&hash::zobrist(position)))
+        .cloned()
+}
+
+/// Openings by the hash of the position their line leads to, built on first use.
+fn openings() -> &'static HashMap<u64, Opening> {
+    static OPENINGS: OnceLock<HashMap<u64, Opening>> = OnceLock::new();
+    OPENINGS.get_or_init(|| {
+        let mut openings = HashMap::new();
+        for row in TABLE.lines().skip(1) {
+            let mut columns = row.split('\t');
+            let (eco, full_name, moves) = match (columns.next(), columns.next(), columns.next()) {
+                (Some(eco), Some(full_name), Some(moves)) => (eco, full_name, moves),
+                _ => panic!("Malformed ECO table row: {}", row),
+            };
+            let (name, variation) = match full_name.split_once(": ") {
+                Some((name, variation)) => (name, Some(variation.to_string())),
+                None => (full_name, None),
+            };
+            // Transposing lines keep the first classification
+            openings.entry(line_hash(moves)).or_insert(Opening {
+                eco: eco.to_string(),
+                name: name.to_string(),
+                variation,
+            });
+        }
+        openings
+    })
+}
+
+fn line_hash(moves: &str) -> u64 {
+    let mut position = Chess::default();
+    let tokens = pgn::tokenize(moves).expect("The ECO table's lines are valid movetext");
+    for token in tokens {
+        if let Token::San(san) = token {
+            let m = san
+                .parse::<San>()
+                .ok()
+                .and_then(|parsed| parsed.to_move(&position).ok())
+                .unwrap_or_else(|| panic!("Illegal move {} in the ECO table", san));
+            position.play_unchecked(&m);
+        }
+    }
+    hash::zobrist(&position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pgn::PgnGame;
+
+    fn classify_line(moves: &str) -> Option<Opening> {
+        let game = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from(moves),
+        };
+        classify(&game.positions().unwrap())
+    }
+
+    #[test]
+    fn classification() {
+        assert_eq!(openings().len(), TABLE.lines().count() - 1);
+
+        let ruy_lopez =
+            classify_line("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O b5 6. Bb3").unwrap();
+        assert_eq!(ruy_lopez.eco, "C78");
+        assert_eq!(ruy_lopez.name, "Ruy Lopez");
+        assert_eq!(ruy_lopez.variation.as_deref(), Some("Morphy Defense"));
+
+        let najdorf =
+            classify_line("1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 6. Be3 e5 7. Nb3")
+                .unwrap();
+        assert_eq!(najdorf.eco, "B90");
+        assert_eq!(
+            najdorf.variation.as_deref(),
+            Some("Najdorf Variation, English Attack")
+        );
+
+        // Transposition into the Najdorf
+        let najdorf = classify_line("1. e4 c5 2. Nf3 a6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 d6").unwrap();
+        assert_eq!(najdorf.eco, "B90");
+
+        let sideline = classify_line("1. b4 e5 2. Bb2 Bxb4 3. Bxe5 Nf6").unwrap();
+        assert_eq!(
+            (sideline.eco.as_str(), sideline.name.as_str()),
+            ("A00", "Polish Opening")
+        );
+        assert_eq!(classify_line(""), None);
+    }
+}
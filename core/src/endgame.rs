@@ -0,0 +1,140 @@
+use std::fmt;
+
+use shakmaty::{Bitboard, Color, MaterialSide, Position};
+
+/// Material of the pieces on the board, kings and pawns apart, up to which positions are
+/// endgames: two queens against one at most.
+const ENDGAME_MATERIAL: u32 = 27;
+
+/// Kind of endgame, the same whichever side has the material.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndgameClass {
+    /// Kings and pawns only.
+    Pawns,
+    /// A bishop each, on squares of different colors, and pawns.
+    OppositeColoredBishops,
+    /// Material of the stronger side then of the other, as `R+P vs R`.
+    Material(String, String),
+}
+
+impl fmt::Display for EndgameClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndgameClass::Pawns => write!(f, "pawn endgame"),
+            EndgameClass::OppositeColoredBishops => write!(f, "opposite-colored bishops"),
+            EndgameClass::Material(stronger, weaker) => write!(f, "{} vs {}", stronger, weaker),
+        }
+    }
+}
+
+/// Class of `position` if it is an endgame.
+pub fn classify_endgame(position: &impl Position) -> Option<EndgameClass> {
+    let board = position.board();
+    let material = board.material();
+    let (white, black) = (&material.white, &material.black);
+    if piece_value(white) + piece_value(black) > ENDGAME_MATERIAL {
+        return None;
+    }
+
+    let no_pieces = |side: &MaterialSide| piece_value(side) == 0;
+    let lone_bishop = |side: &MaterialSide| piece_value(side) == 3 && side.bishops == 1;
+    if no_pieces(white) && no_pieces(black) && white.pawns + black.pawns > 0 {
+        return Some(EndgameClass::Pawns);
+    }
+    if lone_bishop(white) && lone_bishop(black) {
+        let dark = board.bishops() & Bitboard::DARK_SQUARES;
+        if dark.count() == 1 {
+            return Some(EndgameClass::OppositeColoredBishops);
+        }
+    }
+
+    let (white, black) = (signature(white), signature(black));
+    let stronger = match value(&material.white).cmp(&value(&material.black)) {
+        std::cmp::Ordering::Greater => Color::White,
+        std::cmp::Ordering::Less => Color::Black,
+        // Written the same way for either side
+        std::cmp::Ordering::Equal if white <= black => Color::White,
+        std::cmp::Ordering::Equal => Color::Black,
+    };
+    Some(match stronger {
+        Color::White => EndgameClass::Material(white, black),
+        Color::Black => EndgameClass::Material(black, white),
+    })
+}
+
+/// Pieces from the queens down, then the count of pawns: `Q+B+B`, `R+2P`, `K` for a bare king.
+fn signature(side: &MaterialSide) -> String {
+    let pieces = [
+        (side.queens, "Q"),
+        (side.rooks, "R"),
+        (side.bishops, "B"),
+        (side.knights, "N"),
+    ];
+    let mut parts: Vec<String> = pieces
+        .iter()
+        .flat_map(|&(count, letter)| std::iter::repeat_n(String::from(letter), count.into()))
+        .collect();
+    match side.pawns {
+        0 => (),
+        1 => parts.push(String::from("P")),
+        pawns => parts.push(format!("{}P", pawns)),
+    }
+    if parts.is_empty() {
+        String::from("K")
+    } else {
+        parts.join("+")
+    }
+}
+
+fn piece_value(side: &MaterialSide) -> u32 {
+    9 * u32::from(side.queens)
+        + 5 * u32::from(side.rooks)
+        + 3 * u32::from(side.bishops)
+        + 3 * u32::from(side.knights)
+}
+
+fn value(side: &MaterialSide) -> u32 {
+    piece_value(side) + u32::from(side.pawns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::fen::Fen;
+
+    #[test]
+    fn classes() {
+        let cases = [
+            ("8/8/4k3/8/3RP3/8/4K3/r7 w - - 0 1", Some("R+P vs R")),
+            // Colors flipped
+            ("R7/4k3/8/3rp3/8/4K3/8/8 w - - 0 1", Some("R+P vs R")),
+            ("8/5p2/4k3/8/3P4/4K3/6P1/8 w - - 0 1", Some("pawn endgame")),
+            (
+                "8/5p2/3bk3/8/3P4/4K3/4B3/8 w - - 0 1",
+                Some("opposite-colored bishops"),
+            ),
+            ("8/5p2/2b1k3/8/3P4/4K3/4B3/8 w - - 0 1", Some("B+P vs B+P")),
+            ("8/4k3/8/8/8/8/4K3/8 w - - 0 1", Some("K vs K")),
+            // Three minor pieces
+            ("8/4k3/8/3q4/8/2BNB3/4K3/8 w - - 0 1", Some("B+B+N vs Q")),
+            ("8/4k3/8/3q4/8/2BNB3/4K3/8 b - - 0 1", Some("B+B+N vs Q")),
+            ("8/4k3/2n5/3b4/8/2B1N3/4K3/8 b - - 0 1", Some("B+N vs B+N")),
+            // Promoted queens
+            ("Q7/4k3/8/8/8/8/1q2K1Q1/8 w - - 0 1", Some("Q+Q vs Q")),
+            ("Q7/4k3/8/8/8/8/1q2K1Q1/3r4 w - - 0 1", None),
+            ("8/4k1P1/8/8/8/8/4K3/q7 w - - 0 1", Some("Q vs P")),
+            ("Q7/8/4k3/8/8/8/4K3/8 b - - 0 1", Some("Q vs K")),
+            ("r1bqk2r/8/8/8/8/8/8/R2QK2R w KQkq - 0 1", None),
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                None,
+            ),
+        ];
+        for (fen, class) in &cases {
+            let fen: Fen = fen.parse().unwrap();
+            let position: shakmaty::Chess = fen.position().unwrap();
+            let label = classify_endgame(&position).map(|class| class.to_string());
+            assert_eq!(label.as_deref(), *class, "{}", fen);
+        }
+    }
+}
@@ -1,5 +1,1103 @@
-use serde::{Serialize, Deserialize};
+use crate::errors::{Error, ErrorType};
+use crate::game::{Evaluation, Score, Wdl};
+use crate::supervisor::{kill_process_group, Supervisor};
+use crate::transcript::{Direction, Transcript};
 
-#[derive(Serialize, Deserialize, Debug)]
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time::timeout;
+
+/// Time given to an engine to answer `uci`, `isready` and `stop`.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Extra time given to an engine on top of its search budget before it is considered unresponsive.
+const SEARCH_GRACE: Duration = Duration::from_secs(5);
+
+/// Time given to an engine to exit after `quit` before it is killed.
+const QUIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Shared handle to a running engine. The async mutex is held for the whole duration of a search.
+pub type EngineHandle = Arc<tokio::sync::Mutex<Engine>>;
+
+/// How to launch a UCI engine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Let the engine think during its opponent's time, when it supports it.
+    #[serde(default)]
+    pub ponder: bool,
+    /// Have the engine report win/draw/loss statistics, when it supports it.
+    #[serde(default = "default_show_wdl")]
+    pub show_wdl: bool,
+    /// Number of UCI lines kept in the engine's transcript.
+    #[serde(default = "default_transcript_size")]
+    pub transcript_size: usize,
+    /// File the transcript is also appended to.
+    #[serde(default)]
+    pub transcript_file: Option<String>,
+    /// UCI options set after the handshake, like `{"Hash": "256"}`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, String>,
+}
+
+impl EngineConfig {
+    /// Engine at `path` started without arguments, other settings being the defaults of requests.
+    pub fn new(path: &str) -> EngineConfig {
+        EngineConfig {
+            path: path.to_string(),
+            args: Vec::new(),
+            ponder: false,
+            show_wdl: default_show_wdl(),
+            transcript_size: default_transcript_size(),
+            transcript_file: None,
+            options: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_show_wdl() -> bool {
+    true
+}
+
+fn default_transcript_size() -> usize {
+    2000
+}
+
+/// A UCI engine process and the pipes used to talk to it.
+pub struct Engine {
+    config: EngineConfig,
+    /// Name given by the engine in its `id name` line.
+    name: Option<String>,
+    /// Names of the options advertised during the handshake.
+    options: Vec<String>,
+    /// Advertised default values, by option name.
+    defaults: HashMap<String, String>,
+    /// Options changed for a single user of the engine, like an opponent's `Skill Level`, until
+    /// `restore_options`. Never kept in the config.
+    overrides: BTreeMap<String, String>,
+    /// Current value of `UCI_Chess960`.
+    chess960: bool,
+    search: Search,
+    /// Every line sent to and received from the engine.
+    transcript: Transcript,
+    /// Only taken when the engine quits.
+    process: Option<Process>,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+/// Where the engine stands between a `go` command and its `bestmove`.
+#[derive(Debug, Clone, PartialEq)]
+enum Search {
+    Idle,
+    Searching,
+    /// Thinking on the opponent's time about the stored `position` command, until `ponderhit` or `stop`.
+    Pondering(String),
+}
+
+/// Answer to a `go` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BestMove {
+    pub uci: String,
+    pub ponder: Option<String>,
+    /// Last exact score reported during the search, from the side to move's point of view.
+    pub evaluation: Option<Evaluation>,
+    /// Principal variation reported with that score, in UCI notation.
+    pub pv: Vec<String>,
+}
+
+/// Engine's view of a game's current position. Moves are in SAN, the evaluation from white's
+/// point of view.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub best_move: String,
+    pub pv: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evaluation: Option<Evaluation>,
+}
+
+impl Engine {
+    /// Spawns the engine and waits for it to complete the `uci`/`isready` handshake.
+    pub async fn start(config: EngineConfig) -> Result<Engine, Error> {
+        let mut command = std::process::Command::new(&config.path);
+        command
+            .args(&config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        // Own process group: the engine can be found and killed even if the backend dies abruptly
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = Command::from(command).kill_on_drop(true).spawn()?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+        let transcript =
+            Transcript::new(config.transcript_size, config.transcript_file.as_deref())?;
+        let mut engine = Engine {
+            config,
+            name: None,
+            options: Vec::new(),
+            defaults: HashMap::new(),
+            overrides: BTreeMap::new(),
+            chess960: false,
+            search: Search::Idle,
+            transcript,
+            process: Some(Process { child, stdin }),
+            stdout,
+        };
+
+        engine.send("uci").await?;
+        loop {
+            let line = timeout(RESPONSE_TIMEOUT, engine.read_line()).await??;
+            if line == "uciok" {
+                break;
+            } else if let Some(name) = line.strip_prefix("id name ") {
+                engine.name = Some(name.to_string());
+            } else if let Some(option) = parse_option_name(&line) {
+                if let Some(default) = parse_option_default(&line) {
+                    engine.defaults.insert(option.clone(), default);
+                }
+                engine.options.push(option);
+            }
+        }
+        if engine.can_ponder() {
+            engine.send("setoption name Ponder value true").await?;
+        }
+        if engine.config.show_wdl && engine.supports_option("UCI_ShowWDL") {
+            engine.send("setoption name UCI_ShowWDL value true").await?;
+        }
+        for (name, value) in engine.config.options.clone() {
+            engine
+                .send(&format!("setoption name {} value {}", name, value))
+                .await?;
+        }
+        engine.is_ready().await?;
+
+        Ok(engine)
+    }
+
+    pub async fn is_ready(&mut self) -> Result<(), Error> {
+        self.send("isready").await?;
+        timeout(RESPONSE_TIMEOUT, self.read_until("readyok")).await??;
+        Ok(())
+    }
+
+    /// Tells the engine that following searches belong to another game.
+    pub async fn new_game(&mut self) -> Result<(), Error> {
+        self.send("ucinewgame").await?;
+        self.is_ready().await
+    }
+
+    /// Searches `position` (a full `position ...` command) with the `go ...` command `go`.
+    /// The engine has `budget` plus a grace period to answer.
+    ///
+    /// If the engine was pondering on that exact position, the search continues with `ponderhit`.
+    /// Pondering on any other position is stopped first.
+    pub async fn best_move(
+        &mut self,
+        position: &str,
+        go: &str,
+        budget: Duration,
+    ) -> Result<BestMove, Error> {
+        match &self.search {
+            Search::Pondering(pondered) if pondered == position => {
+                self.send("ponderhit").await?;
+            }
+            _ => {
+                self.stop().await?;
+                self.send(position).await?;
+                self.send(go).await?;
+            }
+        }
+
+        self.search = Search::Searching;
+        let best_move = timeout(budget + SEARCH_GRACE, self.read_bestmove()).await??;
+        self.search = Search::Idle;
+        Ok(best_move)
+    }
+
+    /// Starts thinking on the opponent's time about `position`, normally the engine's last move
+    /// followed by the `ponder` move it predicted. `go` is the command the real search will use.
+    /// Does nothing unless pondering is enabled for this engine and supported by it.
+    pub async fn ponder(&mut self, position: &str, go: &str) -> Result<(), Error> {
+        if !self.can_ponder() {
+            return Ok(());
+        }
+
+        self.stop().await?;
+        self.send(position).await?;
+        self.send(&go.replacen("go", "go ponder", 1)).await?;
+        self.search = Search::Pondering(position.to_string());
+        Ok(())
+    }
+
+    /// Stops pondering, leaving any other search running.
+    pub async fn stop_pondering(&mut self) -> Result<(), Error> {
+        if self.is_pondering() {
+            self.stop().await?;
+        }
+        Ok(())
+    }
+
+    pub fn is_pondering(&self) -> bool {
+        matches!(self.search, Search::Pondering(_))
+    }
+
+    /// Searches `position` until stopped, for an analysis whose progress is read with `read_info`.
+    pub async fn go_infinite(&mut self, position: &str) -> Result<(), Error> {
+        self.stop().await?;
+        self.send(position).await?;
+        self.send("go infinite").await?;
+        self.search = Search::Searching;
+        Ok(())
+    }
+
+    /// Next exact score reported by the running search, with its principal variation. `None`
+    /// once the search ended on its own, like in a mated position.
+    pub async fn read_info(&mut self) -> Result<Option<(Evaluation, Vec<String>)>, Error> {
+        while self.search == Search::Searching {
+            let line = self.read_line().await?;
+            match line.split_whitespace().next() {
+                Some("info") => {
+                    if let Some(evaluation) = parse_info_evaluation(&line) {
+                        return Ok(Some((evaluation, parse_info_pv(&line))));
+                    }
+                }
+                Some("bestmove") => self.search = Search::Idle,
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Interrupts the current search or pondering, if any, discarding its result.
+    pub async fn stop(&mut self) -> Result<(), Error> {
+        if self.search != Search::Idle {
+            self.send("stop").await?;
+            timeout(RESPONSE_TIMEOUT, self.read_until("bestmove")).await??;
+            self.search = Search::Idle;
+        }
+        Ok(())
+    }
+
+    /// Switches the engine between standard chess and Chess960 (castling sent as king-takes-rook).
+    /// Fails if Chess960 is asked from an engine without the `UCI_Chess960` option.
+    pub async fn set_chess960(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled == self.chess960 {
+            return Ok(());
+        }
+        if !self.supports_option("UCI_Chess960") {
+            let name = self.name.as_deref().unwrap_or(&self.config.path);
+            return Err(Error::new(ErrorType::Engine).with_message(&format!(
+                "{} does not support Chess960 (no UCI_Chess960 option)",
+                name
+            )));
+        }
+
+        self.stop().await?;
+        self.send(&format!("setoption name UCI_Chess960 value {}", enabled))
+            .await?;
+        self.chess960 = enabled;
+        self.is_ready().await
+    }
+
+    /// Sets UCI option `name`, also kept in the config. Engines ignore options they don't know,
+    /// so they are sent anyway: returns whether the engine advertised it.
+    pub async fn set_option(&mut self, name: &str, value: &str) -> Result<bool, Error> {
+        self.stop().await?;
+        self.send(&format!("setoption name {} value {}", name, value))
+            .await?;
+        self.is_ready().await?;
+        self.config
+            .options
+            .insert(name.to_string(), value.to_string());
+        self.overrides.remove(name);
+        Ok(self.supports_option(name))
+    }
+
+    /// Sets UCI option `name` until `restore_options`, without keeping it in the config. Unlike
+    /// `set_option`, nothing is sent to engines that didn't advertise it: returns whether it was
+    /// set. Setting the value already overriding the option leaves pondering alone.
+    pub async fn override_option(&mut self, name: &str, value: &str) -> Result<bool, Error> {
+        if !self.supports_option(name) {
+            return Ok(false);
+        }
+        if self.overrides.get(name).map(String::as_str) == Some(value) {
+            return Ok(true);
+        }
+
+        self.stop().await?;
+        self.send(&format!("setoption name {} value {}", name, value))
+            .await?;
+        self.is_ready().await?;
+        self.overrides.insert(name.to_string(), value.to_string());
+        Ok(true)
+    }
+
+    /// Puts the options changed by `override_option` back to their configured value, or else
+    /// their advertised default.
+    pub async fn restore_options(&mut self) -> Result<(), Error> {
+        if self.overrides.is_empty() {
+            return Ok(());
+        }
+
+        self.stop().await?;
+        for name in std::mem::take(&mut self.overrides).into_keys() {
+            let value = self
+                .config
+                .options
+                .get(&name)
+                .or_else(|| self.defaults.get(&name));
+            if let Some(value) = value.cloned() {
+                self.send(&format!("setoption name {} value {}", name, value))
+                    .await?;
+            }
+        }
+        self.is_ready().await
+    }
+
+    /// Option names are case insensitive in UCI.
+    pub fn supports_option(&self, name: &str) -> bool {
+        self.options.iter().any(|o| o.eq_ignore_ascii_case(name))
+    }
+
+    fn can_ponder(&self) -> bool {
+        self.config.ponder && self.supports_option("Ponder")
+    }
+
+    pub fn repr(&self, id: &str) -> EngineRepr {
+        EngineRepr {
+            id: id.to_string(),
+            name: self.name.clone(),
+            path: self.config.path.clone(),
+        }
+    }
+
+    pub fn transcript(&self) -> Transcript {
+        self.transcript.clone()
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.process.as_ref().map(|process| process.child.id())
+    }
+
+    /// Asks the engine to exit, killing it if it doesn't within `QUIT_TIMEOUT`.
+    /// The engine can't be used afterwards.
+    pub async fn quit(&mut self) {
+        if let Some(process) = self.process.take() {
+            self.transcript.record(Direction::Sent, "quit");
+            process.quit().await;
+        }
+    }
+
+    async fn send(&mut self, command: &str) -> Result<(), Error> {
+        self.transcript.record(Direction::Sent, command);
+        let stdin = match &mut self.process {
+            Some(process) => &mut process.stdin,
+            None => return Err(Error::new(ErrorType::Engine).with_message("The engine has quit")),
+        };
+        stdin.write_all(command.as_bytes()).await?;
+        stdin.write_all(b"\n").await?;
+        stdin.flush().await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        match self.stdout.next_line().await? {
+            Some(line) => {
+                self.transcript.record(Direction::Received, &line);
+                Ok(line.trim().to_string())
+            }
+            None => {
+                Err(Error::new(ErrorType::Engine).with_message("The engine exited unexpectedly"))
+            }
+        }
+    }
+
+    /// Reads a search's output until its `bestmove`, keeping the last score reported on the way.
+    async fn read_bestmove(&mut self) -> Result<BestMove, Error> {
+        let mut evaluation = None;
+        let mut pv = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            match line.split_whitespace().next() {
+                Some("info") => {
+                    if let Some(reported) = parse_info_evaluation(&line) {
+                        evaluation = Some(reported);
+                        pv = parse_info_pv(&line);
+                    }
+                }
+                Some("bestmove") => {
+                    return Ok(BestMove {
+                        evaluation,
+                        pv,
+                        ..parse_bestmove(&line)?
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads lines until one starts with `token`, which is returned.
+    async fn read_until(&mut self, token: &str) -> Result<String, Error> {
+        loop {
+            let line = self.read_line().await?;
+            if line.split_whitespace().next() == Some(token) {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if let Some(process) = self.process.take() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(process.quit());
+                }
+                // Killed right away by `kill_on_drop`
+                Err(_) => drop(process),
+            }
+        }
+    }
+}
+
+impl Process {
+    async fn quit(mut self) {
+        let _ = self.stdin.write_all(b"quit\n").await;
+        let _ = self.stdin.flush().await;
+        if timeout(QUIT_TIMEOUT, &mut self.child).await.is_err() {
+            let _ = self.child.kill();
+            let _ = self.child.await;
+        }
+    }
+}
+
+/// Extracts `Hash` from `option name Hash type spin default 16 min 1 max 33554432`.
+fn parse_option_name(line: &str) -> Option<String> {
+    let rest = line.strip_prefix("option name ")?;
+    let name = match rest.find(" type ") {
+        Some(end) => &rest[..end],
+        None => rest,
+    };
+    Some(name.trim().to_string())
+}
+
+/// Extracts `16` from `option name Hash type spin default 16 min 1 max 33554432`.
+fn parse_option_default(line: &str) -> Option<String> {
+    let rest = &line[line.find(" type ")?..];
+    let rest = &rest[rest.find(" default ")? + " default ".len()..];
+    let end = [" min ", " max ", " var "]
+        .iter()
+        .filter_map(|keyword| rest.find(keyword))
+        .min()
+        .unwrap_or(rest.len());
+    Some(rest[..end].trim().to_string())
+}
+
+/// Parses `bestmove e2e4 [ponder e7e5]`.
+fn parse_bestmove(line: &str) -> Result<BestMove, Error> {
+    let mut tokens = line.split_whitespace().skip(1);
+    let uci = match tokens.next() {
+        Some(m) if m != "(none)" && m != "0000" => m.to_string(),
+        _ => {
+            return Err(
+                Error::new(ErrorType::Engine).with_message("The engine found no move to play")
+            )
+        }
+    };
+    let ponder = match (tokens.next(), tokens.next()) {
+        (Some("ponder"), Some(m)) => Some(m.to_string()),
+        _ => None,
+    };
+
+    Ok(BestMove {
+        uci,
+        ponder,
+        evaluation: None,
+        pv: Vec::new(),
+    })
+}
+
+/// Extracts depth, score and win/draw/loss statistics from
+/// `info depth 20 seldepth 31 score cp 35 wdl 223 698 79 nodes 1234 pv e2e4 ...`.
+/// Bounds (`score cp 35 lowerbound`) are not exact scores and are ignored.
+fn parse_info_evaluation(line: &str) -> Option<Evaluation> {
+    let mut tokens = line.split_whitespace().skip(1);
+    let mut depth = 0;
+    let mut score = None;
+    let mut wdl = None;
+    while let Some(token) = tokens.next() {
+        match token {
+            "depth" => depth = tokens.next()?.parse().ok()?,
+            "score" => {
+                score = match (tokens.next()?, tokens.next()?.parse().ok()?) {
+                    ("cp", cp) => Some(Score::Cp(cp)),
+                    ("mate", moves) => Some(Score::Mate(moves)),
+                    _ => None,
+                }
+            }
+            "wdl" => {
+                let mut stat = || -> Option<u32> { tokens.next()?.parse().ok() };
+                wdl = Wdl::normalized(stat()?, stat()?, stat()?);
+            }
+            "lowerbound" | "upperbound" => return None,
+            // Everything after is free text or moves
+            "pv" | "string" => break,
+            _ => {}
+        }
+    }
+    Some(Evaluation {
+        score: score?,
+        depth,
+        wdl,
+    })
+}
+
+/// Moves after `pv` in an `info` line, unless they are free text of `info string`.
+fn parse_info_pv(line: &str) -> Vec<String> {
+    let mut tokens = line.split_whitespace().skip(1);
+    while let Some(token) = tokens.next() {
+        match token {
+            "pv" => return tokens.map(String::from).collect(),
+            "string" => break,
+            _ => {}
+        }
+    }
+    Vec::new()
+}
+
+/// Builds the `position` command for a game starting at `fen` followed by `moves`.
+pub fn position_command(fen: &str, moves: &[String]) -> String {
+    if moves.is_empty() {
+        format!("position fen {}", fen)
+    } else {
+        format!("position fen {} moves {}", fen, moves.join(" "))
+    }
+}
+
+/// Running engines, keyed by the id given when they were added.
+/// Representations and transcripts are kept outside of the engines' locks so that busy engines can
+/// still be listed and inspected.
+#[derive(Clone, Default)]
+pub struct EngineRegistry {
+    engines: Arc<Mutex<HashMap<String, RegisteredEngine>>>,
+    /// Records engine processes so they can be reaped if the backend dies abruptly.
+    supervisor: Option<Supervisor>,
+    /// Id of the first engine registered, used by requests which don't name one.
+    default: Arc<Mutex<Option<String>>>,
+}
+
+struct RegisteredEngine {
+    repr: EngineRepr,
+    transcript: Transcript,
+    pid: Option<u32>,
+    handle: EngineHandle,
+}
+
+impl EngineRegistry {
+    /// Registers `engine` under `id`, replacing (and killing) any engine previously registered with that id.
+    pub fn insert(&self, id: &str, engine: Engine) -> Result<(), Error> {
+        let registered = RegisteredEngine {
+            repr: engine.repr(id),
+            transcript: engine.transcript(),
+            pid: engine.pid(),
+            handle: Arc::new(tokio::sync::Mutex::new(engine)),
+        };
+        if let (Some(supervisor), Some(pid)) = (&self.supervisor, registered.pid) {
+            supervisor.register(pid)?;
+        }
+
+        self.default.lock()?.get_or_insert_with(|| id.to_string());
+        let replaced = self.engines.lock()?.insert(id.to_string(), registered);
+        if let (Some(supervisor), Some(pid)) = (&self.supervisor, replaced.and_then(|r| r.pid)) {
+            supervisor.unregister(pid)?;
+        }
+        Ok(())
+    }
+
+    pub fn with_supervisor(supervisor: Supervisor) -> EngineRegistry {
+        EngineRegistry {
+            supervisor: Some(supervisor),
+            ..EngineRegistry::default()
+        }
+    }
+
+    /// Makes every engine quit. Engines still busy after `RESPONSE_TIMEOUT` are killed.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let engines: Vec<RegisteredEngine> = self.engines.lock()?.drain().map(|(_, r)| r).collect();
+        for registered in engines {
+            match timeout(RESPONSE_TIMEOUT, registered.handle.lock()).await {
+                Ok(mut engine) => engine.quit().await,
+                Err(_) => {
+                    if let Some(pid) = registered.pid {
+                        kill_process_group(pid);
+                    }
+                }
+            }
+            if let (Some(supervisor), Some(pid)) = (&self.supervisor, registered.pid) {
+                supervisor.unregister(pid)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<EngineHandle, Error> {
+        self.with_registered(id, |registered| Arc::clone(&registered.handle))
+    }
+
+    /// `id`, or the default engine when none is given.
+    pub fn id_or_default(&self, id: Option<String>) -> Result<String, Error> {
+        let id = match id {
+            Some(id) => Some(id),
+            None => self.default.lock()?.clone(),
+        };
+        id.ok_or_else(|| Error::new(ErrorType::Engine).with_message("No engine registered yet"))
+    }
+
+    pub fn transcript(&self, id: &str) -> Result<Transcript, Error> {
+        self.with_registered(id, |registered| registered.transcript.clone())
+    }
+
+    pub fn reprs(&self) -> Result<Vec<EngineRepr>, Error> {
+        let mut reprs: Vec<EngineRepr> = self
+            .engines
+            .lock()?
+            .values()
+            .map(|registered| registered.repr.clone())
+            .collect();
+        reprs.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(reprs)
+    }
+
+    fn with_registered<T>(
+        &self,
+        id: &str,
+        operation: impl FnOnce(&RegisteredEngine) -> T,
+    ) -> Result<T, Error> {
+        match self.engines.lock()?.get(id) {
+            Some(registered) => Ok(operation(registered)),
+            None => Err(Error::new(ErrorType::Engine)
+                .with_message(&format!("No engine registered as {}", id))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
-pub struct EngineRepr {}
\ No newline at end of file
+pub struct EngineRepr {
+    pub id: String,
+    pub name: Option<String>,
+    pub path: String,
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::path::{Path, PathBuf};
+
+    /// Scripted UCI engine answering each `go` with the next move given as argument.
+    pub const MOCK_ENGINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/mock_engine.sh");
+
+    pub fn mock_engine(moves: &[&str]) -> EngineConfig {
+        EngineConfig {
+            path: String::from(MOCK_ENGINE),
+            args: moves.iter().map(|m| m.to_string()).collect(),
+            ponder: false,
+            show_wdl: true,
+            transcript_size: default_transcript_size(),
+            transcript_file: None,
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Mock engine appending the commands it receives to a fresh file, whose path is returned.
+    pub fn logged_mock_engine(test_name: &str, moves: &[&str]) -> (EngineConfig, PathBuf) {
+        let log =
+            std::env::temp_dir().join(format!("bigchess-{}-{}.log", test_name, std::process::id()));
+        let _ = std::fs::remove_file(&log);
+
+        let mut config = mock_engine(moves);
+        config.args.insert(0, format!("--log={}", log.display()));
+        (config, log)
+    }
+
+    pub fn received_commands(log: &Path) -> Vec<String> {
+        std::fs::read_to_string(log)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn start_position() -> String {
+        position_command(&crate::game::Game::default().initial_fen(), &[])
+    }
+
+    pub fn after(moves: &[&str]) -> String {
+        let moves: Vec<String> = moves.iter().map(|m| m.to_string()).collect();
+        position_command(&crate::game::Game::default().initial_fen(), &moves)
+    }
+
+    #[test]
+    fn option_parsing() {
+        assert_eq!(
+            parse_option_name("option name Ponder type check default false").as_deref(),
+            Some("Ponder")
+        );
+        assert_eq!(
+            parse_option_name("option name Skill Level type spin default 20 min 0 max 20")
+                .as_deref(),
+            Some("Skill Level")
+        );
+        assert_eq!(parse_option_name("id name Stockfish"), None);
+        assert_eq!(
+            parse_option_default("option name Skill Level type spin default 20 min 0 max 20")
+                .as_deref(),
+            Some("20")
+        );
+        assert_eq!(
+            parse_option_default("option name Style type combo default Normal var Solid var Risky")
+                .as_deref(),
+            Some("Normal")
+        );
+        assert_eq!(
+            parse_option_default("option name Clear Hash type button"),
+            None
+        );
+    }
+
+    #[test]
+    fn bestmove_parsing() {
+        assert_eq!(
+            parse_bestmove("bestmove e2e4 ponder e7e5").unwrap(),
+            BestMove {
+                uci: String::from("e2e4"),
+                ponder: Some(String::from("e7e5")),
+                evaluation: None,
+                pv: Vec::new(),
+            }
+        );
+        assert_eq!(parse_bestmove("bestmove a7a8q").unwrap().ponder, None);
+        assert!(parse_bestmove("bestmove (none)").is_err());
+    }
+
+    #[test]
+    fn info_parsing() {
+        assert_eq!(
+            parse_info_evaluation("info depth 20 seldepth 31 score cp -35 nodes 1234 pv e2e4"),
+            Some(Evaluation {
+                score: Score::Cp(-35),
+                depth: 20,
+                wdl: None,
+            })
+        );
+        assert_eq!(
+            parse_info_evaluation("info depth 30 score cp 41 wdl 223 698 79 nodes 9 pv d2d4")
+                .and_then(|e| e.wdl),
+            Some(Wdl {
+                win: 223,
+                draw: 698,
+                loss: 79
+            })
+        );
+        assert_eq!(
+            parse_info_evaluation("info depth 30 wdl 0 0 1000 score mate -2").and_then(|e| e.wdl),
+            Some(Wdl {
+                win: 0,
+                draw: 0,
+                loss: 1000
+            })
+        );
+        assert_eq!(
+            parse_info_evaluation("info depth 7 score mate 3 pv d1h5").map(|e| e.score),
+            Some(Score::Mate(3))
+        );
+        assert_eq!(
+            parse_info_evaluation("info depth 20 score cp 40 lowerbound"),
+            None
+        );
+        assert_eq!(
+            parse_info_evaluation("info currmove e2e4 currmovenumber 1"),
+            None
+        );
+        assert_eq!(parse_info_evaluation("info string score cp 10"), None);
+
+        assert_eq!(
+            parse_info_pv("info depth 2 score cp 5 pv e2e4 e7e5"),
+            vec!["e2e4", "e7e5"]
+        );
+        assert!(parse_info_pv("info string pv e2e4").is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_and_search() {
+        let mut engine = Engine::start(mock_engine(&["e2e4"])).await.unwrap();
+        assert_eq!(engine.repr("mock").name.as_deref(), Some("Mock Engine"));
+
+        let best = engine
+            .best_move(
+                &start_position(),
+                "go movetime 10",
+                Duration::from_millis(10),
+            )
+            .await
+            .unwrap();
+        assert_eq!(best.uci, "e2e4");
+        assert_eq!(best.evaluation.unwrap().score, Score::Cp(0));
+    }
+
+    #[tokio::test]
+    async fn transcript() {
+        let mut config = mock_engine(&["e2e4"]);
+        config.transcript_size = 4;
+        let mut engine = Engine::start(config).await.unwrap();
+        let sent = |lines: &[crate::transcript::TranscriptLine]| -> Vec<String> {
+            lines
+                .iter()
+                .filter(|l| l.direction == Direction::Sent)
+                .map(|l| l.line.clone())
+                .collect()
+        };
+
+        engine
+            .best_move(
+                &start_position(),
+                "go movetime 10",
+                Duration::from_millis(10),
+            )
+            .await
+            .unwrap();
+        let lines = engine.transcript().lines().unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            sent(&lines),
+            vec![start_position(), String::from("go movetime 10")]
+        );
+        assert_eq!(lines[3].direction, Direction::Received);
+        assert_eq!(lines[3].line, "bestmove e2e4");
+    }
+
+    #[tokio::test]
+    async fn wdl() {
+        let search = |config: EngineConfig| async {
+            let mut engine = Engine::start(config).await.unwrap();
+            let best = engine
+                .best_move(
+                    &start_position(),
+                    "go movetime 10",
+                    Duration::from_millis(10),
+                )
+                .await
+                .unwrap();
+            best.evaluation.unwrap()
+        };
+
+        let evaluation = search(mock_engine(&["e2e4@35=600,300,100"])).await;
+        assert_eq!(evaluation.score, Score::Cp(35));
+        assert_eq!(
+            evaluation.wdl,
+            Some(Wdl {
+                win: 600,
+                draw: 300,
+                loss: 100
+            })
+        );
+
+        let mut config = mock_engine(&["e2e4@35=600,300,100"]);
+        config.show_wdl = false;
+        assert_eq!(search(config).await.wdl, None);
+    }
+
+    #[tokio::test]
+    async fn ponder_hit() {
+        let (mut config, log) = logged_mock_engine("ponder_hit", &["e2e4:e7e5", "g1f3"]);
+        config.ponder = true;
+        let mut engine = Engine::start(config).await.unwrap();
+        let budget = Duration::from_millis(10);
+
+        let best = engine
+            .best_move(&start_position(), "go movetime 10", budget)
+            .await
+            .unwrap();
+        assert_eq!(best.ponder.as_deref(), Some("e7e5"));
+        engine
+            .ponder(&after(&["e2e4", "e7e5"]), "go movetime 10")
+            .await
+            .unwrap();
+
+        // The opponent played the predicted move
+        let best = engine
+            .best_move(&after(&["e2e4", "e7e5"]), "go movetime 10", budget)
+            .await
+            .unwrap();
+        assert_eq!(best.uci, "g1f3");
+
+        let commands = received_commands(&log);
+        assert!(commands.contains(&String::from("setoption name Ponder value true")));
+        let pondering = commands.iter().position(|c| c == "go ponder movetime 10");
+        assert_eq!(
+            &commands[pondering.unwrap() - 1..],
+            &[
+                after(&["e2e4", "e7e5"]),
+                String::from("go ponder movetime 10"),
+                String::from("ponderhit")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ponder_miss() {
+        let (mut config, log) = logged_mock_engine("ponder_miss", &["e2e4:e7e5", "g1f3"]);
+        config.ponder = true;
+        let mut engine = Engine::start(config).await.unwrap();
+        let budget = Duration::from_millis(10);
+
+        engine
+            .best_move(&start_position(), "go movetime 10", budget)
+            .await
+            .unwrap();
+        engine
+            .ponder(&after(&["e2e4", "e7e5"]), "go movetime 10")
+            .await
+            .unwrap();
+
+        // The opponent played something else
+        let best = engine
+            .best_move(&after(&["e2e4", "c7c5"]), "go movetime 10", budget)
+            .await
+            .unwrap();
+        assert_eq!(best.uci, "g1f3");
+
+        let commands = received_commands(&log);
+        let stop = commands.iter().position(|c| c == "stop").unwrap();
+        assert_eq!(
+            &commands[stop..],
+            &[
+                String::from("stop"),
+                after(&["e2e4", "c7c5"]),
+                String::from("go movetime 10")
+            ]
+        );
+        assert!(!commands.contains(&String::from("ponderhit")));
+    }
+
+    #[tokio::test]
+    async fn ponder_disabled() {
+        let (config, log) = logged_mock_engine("ponder_disabled", &["e2e4:e7e5", "g1f3"]);
+        let mut engine = Engine::start(config).await.unwrap();
+        let budget = Duration::from_millis(10);
+
+        engine
+            .best_move(&start_position(), "go movetime 10", budget)
+            .await
+            .unwrap();
+        engine
+            .ponder(&after(&["e2e4", "e7e5"]), "go movetime 10")
+            .await
+            .unwrap();
+        engine
+            .best_move(&after(&["e2e4", "e7e5"]), "go movetime 10", budget)
+            .await
+            .unwrap();
+
+        let commands = received_commands(&log);
+        assert!(!commands.iter().any(|c| c.contains("ponder")));
+    }
+
+    fn is_running(pid: u32) -> bool {
+        std::path::Path::new(&format!("/proc/{}", pid)).exists()
+    }
+
+    async fn wait_for_exit(pid: u32) {
+        for _ in 0..100 {
+            if !is_running(pid) {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("Engine process {} is still running", pid);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn quit_on_drop() {
+        let engine = Engine::start(mock_engine(&[])).await.unwrap();
+        let pid = engine.pid().unwrap();
+        assert!(is_running(pid));
+        drop(engine);
+        wait_for_exit(pid).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn shutdown_during_search() {
+        let registry = EngineRegistry::default();
+        let mut engine = Engine::start(mock_engine(&["wait"])).await.unwrap();
+        let pid = engine.pid().unwrap();
+        engine.send("go infinite").await.unwrap();
+        engine.search = Search::Searching;
+        registry.insert("searching", engine).unwrap();
+
+        registry.shutdown().await.unwrap();
+        wait_for_exit(pid).await;
+        assert!(registry.reprs().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn options_and_default() {
+        let registry = EngineRegistry::default();
+        assert!(registry.id_or_default(None).is_err());
+
+        let (mut config, log) = logged_mock_engine("options", &[]);
+        config
+            .options
+            .insert(String::from("Hash"), String::from("256"));
+        config
+            .options
+            .insert(String::from("Threads"), String::from("4"));
+        registry
+            .insert("first", Engine::start(config).await.unwrap())
+            .unwrap();
+        let commands = received_commands(&log);
+        let hash = commands
+            .iter()
+            .position(|c| c == "setoption name Hash value 256")
+            .unwrap();
+        assert!(commands.contains(&String::from("setoption name Threads value 4")));
+        assert!(hash < commands.iter().rposition(|c| c == "isready").unwrap());
+
+        let second = Engine::start(mock_engine(&[])).await.unwrap();
+        registry.insert("second", second).unwrap();
+        assert_eq!(registry.id_or_default(None).unwrap(), "first");
+        let named = registry.id_or_default(Some(String::from("second")));
+        assert_eq!(named.unwrap(), "second");
+        registry.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bad_path() {
+        let config = EngineConfig {
+            path: String::from("/nonexistent/engine"),
+            ..mock_engine(&[])
+        };
+        let err = Engine::start(config).await.err().unwrap();
+        assert!(err.is_type(ErrorType::IO));
+    }
+}
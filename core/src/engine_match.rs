@@ -0,0 +1,330 @@
+use crate::api::{response_from_error, response_from_notification, Notification};
+use crate::engine::{position_command, EngineHandle};
+use crate::errors::Error;
+use crate::game::{GameOver, GameResult};
+use crate::jobs::JobTicket;
+use crate::state::StateHandle;
+
+use std::time::Duration;
+
+use serde::Serialize;
+use shakmaty::Setup;
+use tokio::sync::oneshot;
+
+/// Parameters of an engine match, see `Request::EngineMatch`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchSettings {
+    pub white_engine_id: String,
+    pub black_engine_id: String,
+    pub movetime_ms: u64,
+    /// Maximum number of moves (by either side) played during the match.
+    pub max_plies: u32,
+}
+
+/// Why an engine match ended.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchEnd {
+    GameOver(GameOver),
+    MoveLimit,
+    Stopped,
+}
+
+/// Drives the match on game `id` until its end, reporting every move and the outcome as notifications.
+pub async fn run(state: StateHandle, id: String, settings: MatchSettings, ticket: JobTicket) {
+    let JobTicket { token, mut stop } = ticket;
+    let outcome = play_match(&state, &id, &settings, &mut stop).await;
+    let _ = state.jobs().finish(&id, token);
+
+    let notification = outcome.and_then(|reason| {
+        let result = state.with_game(&id, |game| Ok(game.result()))?;
+        Ok(Notification::EngineMatchFinished {
+            id: id.clone(),
+            result,
+            reason,
+        })
+    });
+
+    match notification {
+        Ok(notification) => state.notify(response_from_notification(notification)),
+        Err(err) => state.notify(response_from_error(err.with_id(&id))),
+    }
+}
+
+async fn play_match(
+    state: &StateHandle,
+    id: &str,
+    settings: &MatchSettings,
+    stop: &mut oneshot::Receiver<()>,
+) -> Result<MatchEnd, Error> {
+    let white = state.engines().get(&settings.white_engine_id)?;
+    let black = state.engines().get(&settings.black_engine_id)?;
+    let chess960 = state.with_game(id, |game| Ok(game.is_chess960()))?;
+    for handle in &[&white, &black] {
+        let mut engine = handle.lock().await;
+        engine.restore_options().await?;
+        engine.new_game().await?;
+        engine.set_chess960(chess960).await?;
+    }
+
+    let end = play_moves(state, id, settings, [&white, &black], stop).await;
+
+    // An engine may still be pondering on a reply that won't come
+    white.lock().await.stop().await?;
+    black.lock().await.stop().await?;
+    end
+}
+
+async fn play_moves(
+    state: &StateHandle,
+    id: &str,
+    settings: &MatchSettings,
+    [white, black]: [&EngineHandle; 2],
+    stop: &mut oneshot::Receiver<()>,
+) -> Result<MatchEnd, Error> {
+    let budget = Duration::from_millis(settings.movetime_ms);
+    let go = format!("go movetime {}", settings.movetime_ms);
+    let mut ply = 0;
+
+    loop {
+        let (fen, mut moves, white_to_move, game_over) = state.with_game(id, |game| {
+            let white_to_move = game.current_position().turn() == shakmaty::Color::White;
+            Ok((
+                game.initial_fen(),
+                game.uci_line(),
+                white_to_move,
+                game.game_over(),
+            ))
+        })?;
+
+        if let Some(game_over) = game_over {
+            // Mates were recorded as they were played, draws the rules allow to claim weren't
+            if !matches!(game_over, GameOver::Checkmate | GameOver::Stalemate) {
+                state.with_game(id, |game| {
+                    game.set_result(GameResult::Draw);
+                    Ok(())
+                })?;
+            }
+            return Ok(MatchEnd::GameOver(game_over));
+        }
+        if ply >= settings.max_plies {
+            return Ok(MatchEnd::MoveLimit);
+        }
+
+        let (engine_id, handle) = if white_to_move {
+            (&settings.white_engine_id, white)
+        } else {
+            (&settings.black_engine_id, black)
+        };
+        let mut engine = handle.lock().await;
+        let position = position_command(&fen, &moves);
+        let best_move = tokio::select! {
+            best_move = engine.best_move(&position, &go, budget) => best_move?,
+            _ = &mut *stop => {
+                engine.stop().await?;
+                return Ok(MatchEnd::Stopped);
+            }
+        };
+        if let Some(expected_reply) = &best_move.ponder {
+            moves.push(best_move.uci.clone());
+            moves.push(expected_reply.clone());
+            engine.ponder(&position_command(&fen, &moves), &go).await?;
+        }
+        drop(engine);
+
+        ply += 1;
+        let response = state.play_uci(id, &best_move.uci)?;
+        state.notify(response.with_notification(Notification::EngineMatchMove {
+            id: id.to_string(),
+            engine_id: engine_id.clone(),
+            uci: best_move.uci,
+            ply,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Response;
+    use crate::engine::tests::{logged_mock_engine, mock_engine, received_commands};
+    use crate::game::GameResult;
+
+    use serde_json::Value;
+    use tokio::sync::broadcast;
+
+    async fn setup(white: &[&str], black: &[&str]) -> (StateHandle, broadcast::Receiver<Response>) {
+        let state = StateHandle::default();
+        let notifications = state.subscribe();
+        state.new_game_default("g1").unwrap();
+        state.add_engine("white", mock_engine(white)).await.unwrap();
+        state.add_engine("black", mock_engine(black)).await.unwrap();
+        (state, notifications)
+    }
+
+    fn settings(max_plies: u32) -> MatchSettings {
+        MatchSettings {
+            white_engine_id: String::from("white"),
+            black_engine_id: String::from("black"),
+            movetime_ms: 10,
+            max_plies,
+        }
+    }
+
+    /// Collects notifications until the match ends, returns the moves and the final notification.
+    async fn collect(notifications: &mut broadcast::Receiver<Response>) -> (Vec<Value>, Value) {
+        let mut moves = Vec::new();
+        loop {
+            let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+            let notification = response["notification"].clone();
+            match notification["type"].as_str() {
+                Some("engine_match_move") => moves.push(notification),
+                Some("engine_match_finished") => return (moves, notification),
+                _ => panic!("Unexpected notification {}", response),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn scholars_mate() {
+        let (state, mut notifications) =
+            setup(&["e2e4", "f1c4", "d1h5", "h5f7"], &["e7e5", "b8c6", "g8f6"]).await;
+        state.start_engine_match("g1", settings(100)).unwrap();
+
+        let (moves, finished) = collect(&mut notifications).await;
+        let played: Vec<&str> = moves.iter().map(|m| m["uci"].as_str().unwrap()).collect();
+        assert_eq!(
+            played,
+            vec!["e2e4", "e7e5", "f1c4", "b8c6", "d1h5", "g8f6", "h5f7"]
+        );
+        assert_eq!(moves[1]["engine_id"], "black");
+        assert_eq!(finished["result"], "1-0");
+        assert_eq!(finished["reason"]["game_over"], "checkmate");
+
+        let result = state.with_game("g1", |game| Ok(game.result())).unwrap();
+        assert_eq!(result, GameResult::WhiteWins);
+    }
+
+    #[tokio::test]
+    async fn pondering_engine() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        state.new_game_default("g1").unwrap();
+
+        // White always predicts black's reply correctly
+        let (mut white, log) = logged_mock_engine(
+            "pondering_engine",
+            &["e2e4:e7e5", "f1c4:b8c6", "d1h5:g8f6", "h5f7"],
+        );
+        white.ponder = true;
+        state.add_engine("white", white).await.unwrap();
+        let black = mock_engine(&["e7e5", "b8c6", "g8f6"]);
+        state.add_engine("black", black).await.unwrap();
+
+        state.start_engine_match("g1", settings(100)).unwrap();
+        let (moves, finished) = collect(&mut notifications).await;
+        assert_eq!(moves.len(), 7);
+        assert_eq!(finished["result"], "1-0");
+
+        let commands = received_commands(&log);
+        let count = |command: &str| commands.iter().filter(|c| *c == command).count();
+        assert_eq!(count("go movetime 10"), 1);
+        assert_eq!(count("go ponder movetime 10"), 3);
+        assert_eq!(count("ponderhit"), 3);
+    }
+
+    #[tokio::test]
+    async fn chess960() {
+        // Double Fischer random: the kings sit on b1 and b8, castling rights on both wings
+        let fen = "rk5r/pppppppp/8/8/8/8/PPPPPPPP/RK5R w KQkq - 0 1";
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        state.new_game_fen("g1", String::from(fen), false).unwrap();
+        state
+            .add_engine("white", mock_engine(&["b1h1"]))
+            .await
+            .unwrap();
+        let (black, log) = logged_mock_engine("chess960", &["b8h8"]);
+        state.add_engine("black", black).await.unwrap();
+
+        state.start_engine_match("g1", settings(2)).unwrap();
+        let (moves, _) = collect(&mut notifications).await;
+        assert_eq!(moves.len(), 2);
+
+        let commands = received_commands(&log);
+        assert!(commands.contains(&String::from("setoption name UCI_Chess960 value true")));
+        assert!(commands.contains(&format!("position fen {} moves b1h1", fen)));
+        let fen = state
+            .with_game("g1", |game| Ok(game.current_fen()))
+            .unwrap();
+        assert_eq!(fen, "r4rk1/pppppppp/8/8/8/8/PPPPPPPP/R4RK1 w - - 2 2");
+    }
+
+    #[tokio::test]
+    async fn chess960_unsupported() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let fen = "rk5r/pppppppp/8/8/8/8/PPPPPPPP/RK5R w KQkq - 0 1";
+        state.new_game_fen("g1", String::from(fen), false).unwrap();
+        let mut white = mock_engine(&["b1h1"]);
+        white.args.insert(0, String::from("--bare"));
+        state.add_engine("white", white).await.unwrap();
+        state.add_engine("black", mock_engine(&[])).await.unwrap();
+
+        state.start_engine_match("g1", settings(2)).unwrap();
+        let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(response["error"]["type"], "Engine");
+        assert_eq!(response["error"]["game_id"], "g1");
+    }
+
+    #[tokio::test]
+    async fn threefold_adjudication() {
+        let (state, mut notifications) = setup(
+            &["g1f3", "f3g1", "g1f3", "f3g1"],
+            &["g8f6", "f6g8", "g8f6", "f6g8"],
+        )
+        .await;
+        state.start_engine_match("g1", settings(100)).unwrap();
+
+        let (moves, finished) = collect(&mut notifications).await;
+        assert_eq!(moves.len(), 8);
+        assert_eq!(finished["result"], "1/2-1/2");
+        assert_eq!(finished["reason"]["game_over"], "threefold_repetition");
+    }
+
+    #[tokio::test]
+    async fn move_limit() {
+        let (state, mut notifications) = setup(&["e2e4", "g1f3"], &["e7e5", "b8c6"]).await;
+        state.start_engine_match("g1", settings(3)).unwrap();
+
+        // A second match can't run on the same game at the same time
+        assert!(state.start_engine_match("g1", settings(3)).is_err());
+
+        let (moves, finished) = collect(&mut notifications).await;
+        assert_eq!(moves.len(), 3);
+        assert_eq!(finished["result"], "*");
+        assert_eq!(finished["reason"], "move_limit");
+    }
+
+    #[tokio::test]
+    async fn stop() {
+        let (state, mut notifications) = setup(&["e2e4", "wait"], &["e7e5"]).await;
+        state.start_engine_match("g1", settings(100)).unwrap();
+
+        for _ in 0..2 {
+            notifications.recv().await.unwrap();
+        }
+        state.stop_job("g1").unwrap();
+
+        let (moves, finished) = collect(&mut notifications).await;
+        assert!(moves.is_empty());
+        assert_eq!(finished["reason"], "stopped");
+        let fen = state
+            .with_game("g1", |game| Ok(game.current_fen()))
+            .unwrap();
+        assert_eq!(
+            fen,
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"
+        );
+    }
+}
@@ -1,5 +1,4 @@
 use serde::Serialize;
-use serde_json;
 
 // Error types
 use shakmaty::fen::ParseFenError;
@@ -8,16 +7,28 @@ use shakmaty::san::SanError;
 use shakmaty::uci::ParseUciError;
 use shakmaty::IllegalMoveError;
 use shakmaty::PositionError;
+use std::backtrace::Backtrace;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{fmt::Display, sync::PoisonError};
 use tokio::io;
+use warp::http::StatusCode;
+
+/// Whether unrecoverable errors capture a backtrace, off unless configured.
+static CAPTURE_BACKTRACES: AtomicBool = AtomicBool::new(false);
+
+pub fn capture_backtraces(enabled: bool) {
+    CAPTURE_BACKTRACES.store(enabled, Ordering::Relaxed);
+}
 
 #[derive(Debug)]
 /// Custom error type
 /// These errors end up being converted to ErrorRepr objects, wrapped inside Response objects, serialized to json and outputed to stdout.
 pub struct Error {
     pub error_type: ErrorType,
-    pub source: Option<Box<dyn std::error::Error + 'static>>,
+    pub source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
     pub id: Option<String>,
+    /// Only captured for unrecoverable errors, when enabled with `capture_backtraces`.
+    pub backtrace: Option<Backtrace>,
 }
 
 impl Error {
@@ -30,40 +41,79 @@ impl Error {
     }
 
     pub fn new(error_type: ErrorType) -> Error {
+        let unrecoverable = error_type == ErrorType::PoisonedHandle;
+        let backtrace = if unrecoverable && CAPTURE_BACKTRACES.load(Ordering::Relaxed) {
+            Some(Backtrace::force_capture())
+        } else {
+            None
+        };
         Error {
             error_type,
             source: None,
             id: None,
+            backtrace,
         }
     }
 
-    pub fn with_id(self, id: &String) -> Self {
+    /// Attaches a human readable explanation for errors that don't come from another crate.
+    pub fn with_message(self, message: &str) -> Self {
         Error {
-            error_type: self.error_type,
-            source: self.source,
-            id: Some(id.clone()),
+            source: Some(Box::from(message)),
+            ..self
         }
     }
+
+    pub fn with_id(self, id: &str) -> Self {
+        Error {
+            id: Some(id.to_string()),
+            ..self
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.error_type.status_code()
+    }
+
+    /// The error as the JSON of a response, with its status.
+    pub fn to_http_reply(&self) -> warp::reply::WithStatus<warp::reply::Json> {
+        let status = self.status_code();
+        let response = crate::api::response_from_error(self);
+        warp::reply::with_status(warp::reply::json(&response), status)
+    }
+
+    /// The underlying errors, each followed by its own source.
+    pub fn chain(&self) -> String {
+        let mut chain = Vec::new();
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+        chain.join(": ")
+    }
 }
 
+/// The human readable message, then the underlying error if any.
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let source = match &self.source {
-            None => String::from("No underlying error"),
-            Some(e) => format!("{:?}", e),
-        };
-        write!(
-            f,
-            "{} Comes from {}",
-            human_readable_message(&self.error_type),
-            source
-        )
+        f.write_str(&human_readable_message(&self.error_type))?;
+        match &self.source {
+            Some(source) => write!(f, " {}", source),
+            None => Ok(()),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.source {
+            Some(source) => Some(source.as_ref()),
+            None => None,
+        }
+    }
+}
 
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Serialize, PartialEq, Clone, Copy)]
 pub enum ErrorType {
     Deserialize,
     Parse,
@@ -71,31 +121,232 @@ pub enum ErrorType {
     BadHandle,
     StaleHandle,
     PoisonedHandle,
+    Locked,
+    Engine,
+    Database,
     IO,
+    Network,
+    Unauthorized,
+    /// A move that isn't written in SAN at all.
+    Notation,
+    /// A SAN move that several legal moves match.
+    Ambiguous,
+    /// Input past a configured limit, like a line too deep.
+    LimitExceeded,
+    /// A pawn move to the last rank that doesn't say which piece it promotes to.
+    PromotionRequired,
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+impl ErrorType {
+    /// Status of the HTTP replies failing with this type of error.
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            ErrorType::Deserialize
+            | ErrorType::Parse
+            | ErrorType::Notation
+            | ErrorType::Ambiguous
+            | ErrorType::PromotionRequired => StatusCode::BAD_REQUEST,
+            ErrorType::ChessRules => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorType::LimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorType::BadHandle => StatusCode::NOT_FOUND,
+            ErrorType::StaleHandle => StatusCode::GONE,
+            ErrorType::Locked => StatusCode::LOCKED,
+            ErrorType::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorType::Network => StatusCode::BAD_GATEWAY,
+            ErrorType::Engine => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorType::PoisonedHandle | ErrorType::Database | ErrorType::IO => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Stable number of the type, for frontends that would rather not match on names.
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorType::Deserialize => 1,
+            ErrorType::Parse => 2,
+            ErrorType::ChessRules => 3,
+            ErrorType::BadHandle => 4,
+            ErrorType::StaleHandle => 5,
+            ErrorType::PoisonedHandle => 6,
+            ErrorType::Locked => 7,
+            ErrorType::Engine => 8,
+            ErrorType::Database => 9,
+            ErrorType::IO => 10,
+            ErrorType::Network => 11,
+            ErrorType::Unauthorized => 12,
+            ErrorType::Notation => 13,
+            ErrorType::Ambiguous => 14,
+            ErrorType::LimitExceeded => 15,
+            ErrorType::PromotionRequired => 16,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct ErrorRepr {
     #[serde(rename = "type")]
     error_type: ErrorType,
+    code: u16,
     message: String,
     underlying_error: Option<String>,
     game_id: Option<String>,
+    /// Backtrace of an unrecoverable error, when they are captured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+    /// Request that failed, when it came as a line of the protocol.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<RequestEcho>,
 }
 
-impl From<Error> for ErrorRepr {
-    fn from(e: Error) -> ErrorRepr {
+/// Kinds of caveats an operation can succeed with.
+#[derive(Debug, Serialize, PartialEq, Clone, Copy)]
+pub enum WarningType {
+    /// An engine given on the command line could not be started.
+    Engine,
+    /// A game of an import could not be read and was left out.
+    SkippedGame,
+    /// The session could not be saved.
+    Session,
+    /// A game was left out, a thread having panicked while changing it.
+    PoisonedGame,
+    /// An option was set that the engine doesn't advertise, which it probably ignores.
+    UnknownEngineOption,
+}
+
+impl WarningType {
+    pub fn code(self) -> u16 {
+        match self {
+            WarningType::Engine => 1,
+            WarningType::SkippedGame => 2,
+            WarningType::Session => 3,
+            WarningType::PoisonedGame => 4,
+            WarningType::UnknownEngineOption => 5,
+        }
+    }
+}
+
+/// Like `ErrorRepr`, for an operation that succeeded anyway.
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct WarningRepr {
+    #[serde(rename = "type")]
+    warning_type: WarningType,
+    code: u16,
+    message: String,
+    game_id: Option<String>,
+}
+
+impl WarningRepr {
+    pub fn warning_type(&self) -> WarningType {
+        self.warning_type
+    }
+
+    pub fn new(warning_type: WarningType, message: &str) -> WarningRepr {
+        WarningRepr {
+            warning_type,
+            code: warning_type.code(),
+            message: message.to_string(),
+            game_id: None,
+        }
+    }
+
+    pub fn with_game_id(self, id: &str) -> WarningRepr {
+        WarningRepr {
+            game_id: Some(id.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Methods whose request lines carry secrets, never echoed nor recorded.
+const REDACTED_METHODS: &[&str] = &["lichess_set_token"];
+
+/// Whether `line` may carry a secret. Malformed lines are searched too, a typo mustn't leak a
+/// token.
+pub fn carries_secret(line: &str) -> bool {
+    REDACTED_METHODS
+        .iter()
+        .any(|redacted| line.contains(redacted))
+}
+
+/// Request line echoed in an error, so that the frontend can tell which request failed.
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct RequestEcho {
+    /// Absent when the line isn't a request.
+    method: Option<String>,
+    /// Absent when the request carries a secret.
+    line: Option<String>,
+    truncated: bool,
+}
+
+impl RequestEcho {
+    /// Keeps at most `limit` bytes of `line`.
+    pub fn new(line: &str, limit: usize) -> RequestEcho {
+        let method = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|request| Some(request.get("method")?.as_str()?.to_string()));
+        if carries_secret(line) {
+            return RequestEcho {
+                method,
+                line: None,
+                truncated: false,
+            };
+        }
+        let mut end = line.len().min(limit);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        RequestEcho {
+            method,
+            line: Some(line[..end].to_string()),
+            truncated: end < line.len(),
+        }
+    }
+}
+
+impl ErrorRepr {
+    pub fn error_type(&self) -> ErrorType {
+        self.error_type
+    }
+
+    /// Message followed by the underlying error.
+    pub fn describe(&self) -> String {
+        match &self.underlying_error {
+            Some(underlying) => format!("{} {}", self.message, underlying),
+            None => self.message.clone(),
+        }
+    }
+
+    pub fn with_request(self, request: RequestEcho) -> ErrorRepr {
+        ErrorRepr {
+            request: Some(request),
+            ..self
+        }
+    }
+}
+
+impl From<&Error> for ErrorRepr {
+    fn from(e: &Error) -> ErrorRepr {
         let message = human_readable_message(&e.error_type);
         ErrorRepr {
             error_type: e.error_type,
-            underlying_error: Some(format!("{:?}", e.source)),
-            game_id: e.id,
+            code: e.error_type.code(),
+            underlying_error: Some(e.chain()).filter(|chain| !chain.is_empty()),
+            details: e.backtrace.as_ref().map(|backtrace| backtrace.to_string()),
+            game_id: e.id.clone(),
             message,
+            request: None,
         }
     }
 }
 
+impl From<Error> for ErrorRepr {
+    fn from(e: Error) -> ErrorRepr {
+        ErrorRepr::from(&e)
+    }
+}
+
 fn human_readable_message(err_type: &ErrorType) -> String {
     let message = match err_type {
         ErrorType::Deserialize => "Could not parse JSON from stdin.",
@@ -104,7 +355,16 @@ fn human_readable_message(err_type: &ErrorType) -> String {
         ErrorType::BadHandle => "Tried to use an invalid handle to a game or the inner state.",
         ErrorType::StaleHandle => "Tried to use an expired handle to a game.",
         ErrorType::PoisonedHandle => "Unrecoverable error: A thread crashed while holding a lock to the program state.",
-        ErrorType::IO => "IO operation failed."
+        ErrorType::Locked => "The game is busy with a background task (engine match, ...) that must be stopped first.",
+        ErrorType::Engine => "The chess engine failed or did not respond as expected.",
+        ErrorType::Database => "The game database could not be read or written.",
+        ErrorType::IO => "IO operation failed.",
+        ErrorType::Network => "A request to an online service (lichess, ...) failed.",
+        ErrorType::Unauthorized => "The connection must start with the authentication token of the backend.",
+        ErrorType::Notation => "The move is not written in standard algebraic notation.",
+        ErrorType::Ambiguous => "Several legal moves match this move, specify the file or rank of the piece moving.",
+        ErrorType::LimitExceeded => "The input goes past a limit of the backend.",
+        ErrorType::PromotionRequired => "The pawn reaches the last rank, choose the piece it promotes to."
     };
 
     String::from(message)
@@ -123,9 +383,8 @@ fn human_readable_message(err_type: &ErrorType) -> String {
 ///impl From<serde_json::Error> for Error {
 ///    fn from(e: serde_json::Error) -> Error {
 ///        Error {
-///            error_type: ErrorType::Deserialize,
 ///            source: Some(Box::from(e)),
-///            id: None
+///            ..Error::new(ErrorType::Deserialize)
 ///        }
 ///    }
 ///}
@@ -133,9 +392,8 @@ fn human_readable_message(err_type: &ErrorType) -> String {
 ///impl From<othercrate::DeserializationError> for Error {
 ///    fn from(e: othercrate::DeserializationError) -> Error {
 ///        Error {
-///            error_type: ErrorType::Deserialize,
 ///            source: Some(Box::from(e)),
-///            id: None
+///            ..Error::new(ErrorType::Deserialize)
 ///        }
 ///    }
 ///}
@@ -146,9 +404,8 @@ macro_rules! conversion_boilerplate {
                 impl From<$e> for Error {
                     fn from(e: $e) -> Error {
                         Error {
-                            error_type: $t,
                             source: Some(Box::from(e)),
-                            id: None
+                            ..Error::new($t)
                         }
                     }
                 }
@@ -160,10 +417,20 @@ macro_rules! conversion_boilerplate {
 /// And access to the inner state is not necessary anyways since we panic after throwing this error.
 impl<T> From<PoisonError<T>> for Error {
     fn from(_: PoisonError<T>) -> Error {
+        Error::new(ErrorType::PoisonedHandle)
+    }
+}
+
+/// Ambiguous moves are told apart from illegal ones.
+impl From<SanError> for Error {
+    fn from(e: SanError) -> Error {
+        let error_type = match e {
+            SanError::AmbiguousSan => ErrorType::Ambiguous,
+            SanError::IllegalSan => ErrorType::ChessRules,
+        };
         Error {
-            error_type: ErrorType::PoisonedHandle,
-            source: None,
-            id: None,
+            source: Some(Box::from(e)),
+            ..Error::new(error_type)
         }
     }
 }
@@ -175,17 +442,73 @@ conversion_boilerplate! {
 
     ErrorType::Parse => [
         ParseUciError,
-        ParseFenError,
+        ParseFenError
+    ],
+
+    ErrorType::Notation => [
         ParseSanError
     ],
 
     ErrorType::ChessRules => [
         IllegalMoveError,
-        PositionError,
-        SanError
+        PositionError
+    ],
+
+    ErrorType::Engine => [
+        tokio::time::Elapsed
+    ],
+
+    ErrorType::Database => [
+        rusqlite::Error,
+        tokio::task::JoinError
     ],
 
     ErrorType::IO => [
     io::Error
+    ],
+
+    ErrorType::Network => [
+        reqwest::Error
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn source_chain() {
+        let io_error = io::Error::other("disk full");
+        let err = Error::from(io_error).with_id("g1");
+        let mut chain = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(underlying) = source {
+            chain.push(underlying.to_string());
+            source = underlying.source();
+        }
+        assert_eq!(chain, vec!["IO operation failed. disk full", "disk full"]);
+        assert_eq!(err.chain(), "disk full");
+
+        let repr = ErrorRepr::from(err);
+        assert_eq!(repr.underlying_error.as_deref(), Some("disk full"));
+        assert_eq!(repr.game_id.as_deref(), Some("g1"));
+        assert!(ErrorRepr::from(Error::new(ErrorType::Locked))
+            .underlying_error
+            .is_none());
+    }
+
+    #[test]
+    fn backtraces() {
+        let poisoned = || ErrorRepr::from(Error::from(PoisonError::new(())));
+        assert_eq!(poisoned().details, None);
+
+        capture_backtraces(true);
+        let captured = poisoned();
+        let recoverable = ErrorRepr::from(Error::new(ErrorType::IO));
+        capture_backtraces(false);
+        assert!(captured.details.is_some());
+        assert_eq!(recoverable.details, None);
+        assert_eq!(poisoned().details, None);
+    }
+}
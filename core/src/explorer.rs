@@ -0,0 +1,459 @@
+use crate::errors::{Error, ErrorType};
+use crate::lichess;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const EXPLORER_URL: &str = "https://explorer.lichess.ovh";
+pub const CLOUD_EVAL_URL: &str = "https://lichess.org";
+/// Age past which cached answers are fetched again, unless configured otherwise.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Answers kept by the cache, the oldest fetched are dropped first.
+pub const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// How a lookup uses the cache.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheMode {
+    /// Answers from the cache while they are fresh, and when the network fails.
+    #[default]
+    Prefer,
+    /// Always asks lichess, updating the cache.
+    Bypass,
+    /// Never asks lichess, whatever the age of the cached answer: works offline.
+    Only,
+}
+
+/// Games explored by the lichess explorer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExplorerSource {
+    /// Games played on lichess.
+    #[default]
+    Lichess,
+    /// Over the board games between masters.
+    Masters,
+}
+
+/// Games of the lichess explorer reaching a position, and the moves played from it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnlineExplorer {
+    pub white: u64,
+    pub draws: u64,
+    pub black: u64,
+    pub moves: Vec<OnlineExplorerMove>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnlineExplorerMove {
+    pub uci: String,
+    pub san: String,
+    pub white: u64,
+    pub draws: u64,
+    pub black: u64,
+    #[serde(default, alias = "averageRating")]
+    pub average_rating: Option<u32>,
+}
+
+/// Evaluation of a position shared by lichess, from white's point of view.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CloudEval {
+    pub depth: u32,
+    pub knodes: u64,
+    pub pvs: Vec<CloudPv>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CloudPv {
+    /// UCI moves, separated by spaces.
+    pub moves: String,
+    #[serde(default)]
+    pub cp: Option<i32>,
+    #[serde(default)]
+    pub mate: Option<i32>,
+}
+
+/// Answer of a lookup, flagged with its age when it comes from the cache.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Cached<T> {
+    #[serde(flatten)]
+    pub value: T,
+    pub from_cache: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_s: Option<u64>,
+}
+
+/// Answers of lichess in an SQLite file, by endpoint, FEN and parameters. Cloning shares the file.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    connection: Arc<Mutex<Connection>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl ResponseCache {
+    /// `$XDG_CACHE_HOME/bigchess/lichess-cache.sqlite`, or the equivalent of the platform.
+    pub fn default_path() -> Option<PathBuf> {
+        let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(cache_dir.join("bigchess").join("lichess-cache.sqlite"))
+    }
+
+    pub fn open(path: &Path, ttl: Duration) -> Result<ResponseCache, Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        ResponseCache::with_connection(Connection::open(path)?, ttl)
+    }
+
+    /// Cache lost when the backend exits.
+    pub fn in_memory(ttl: Duration) -> Result<ResponseCache, Error> {
+        ResponseCache::with_connection(Connection::open_in_memory()?, ttl)
+    }
+
+    fn with_connection(connection: Connection, ttl: Duration) -> Result<ResponseCache, Error> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS responses (
+                key TEXT PRIMARY KEY,
+                body TEXT NOT NULL,
+                fetched_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS responses_fetched_at ON responses (fetched_at);",
+        )?;
+        Ok(ResponseCache {
+            connection: Arc::new(Mutex::new(connection)),
+            ttl,
+            max_entries: MAX_CACHE_ENTRIES,
+        })
+    }
+
+    /// Body stored under `key`, with its age in seconds.
+    fn get(&self, key: &str) -> Result<Option<(String, u64)>, Error> {
+        let cached: Option<(String, i64)> = self
+            .connection
+            .lock()?
+            .query_row(
+                "SELECT body, fetched_at FROM responses WHERE key = ?1",
+                params![key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(cached.map(|(body, fetched_at)| (body, now_s().saturating_sub(fetched_at as u64))))
+    }
+
+    /// Stores `body` under `key`, dropping the oldest answers past `max_entries`.
+    fn put(&self, key: &str, body: &str) -> Result<(), Error> {
+        let connection = self.connection.lock()?;
+        connection.execute(
+            "INSERT OR REPLACE INTO responses (key, body, fetched_at) VALUES (?1, ?2, ?3)",
+            params![key, body, now_s() as i64],
+        )?;
+        connection.execute(
+            "DELETE FROM responses WHERE key NOT IN
+                (SELECT key FROM responses ORDER BY fetched_at DESC, rowid DESC LIMIT ?1)",
+            params![self.max_entries as i64],
+        )?;
+        Ok(())
+    }
+}
+
+fn now_s() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Client of the lichess opening explorer and cloud evaluations, asking the cache first.
+#[derive(Debug, Clone)]
+pub struct ExplorerClient {
+    http: reqwest::Client,
+    explorer_url: String,
+    cloud_eval_url: String,
+    cache: ResponseCache,
+}
+
+impl ExplorerClient {
+    pub fn new(cache: ResponseCache) -> ExplorerClient {
+        ExplorerClient {
+            http: lichess::http_client(),
+            explorer_url: EXPLORER_URL.to_string(),
+            cloud_eval_url: CLOUD_EVAL_URL.to_string(),
+            cache,
+        }
+    }
+
+    /// Client of the server at `base_url` for both services, for tests.
+    #[cfg(test)]
+    pub fn with_base_url(base_url: &str, cache: ResponseCache) -> ExplorerClient {
+        let base_url = base_url.trim_end_matches('/').to_string();
+        ExplorerClient {
+            http: lichess::http_client(),
+            explorer_url: base_url.clone(),
+            cloud_eval_url: base_url,
+            cache,
+        }
+    }
+
+    pub async fn explore(
+        &self,
+        fen: &str,
+        source: ExplorerSource,
+        mode: CacheMode,
+    ) -> Result<Cached<OnlineExplorer>, Error> {
+        let endpoint = match source {
+            ExplorerSource::Lichess => "lichess",
+            ExplorerSource::Masters => "masters",
+        };
+        let url = format!("{}/{}", self.explorer_url, endpoint);
+        let key = format!("explorer/{}?fen={}", endpoint, fen);
+        self.lookup(&key, &url, &[("fen", fen.to_string())], mode)
+            .await
+    }
+
+    /// Fails with `ErrorType::Network` for positions lichess has no evaluation of.
+    pub async fn cloud_eval(
+        &self,
+        fen: &str,
+        multi_pv: u32,
+        mode: CacheMode,
+    ) -> Result<Cached<CloudEval>, Error> {
+        let url = format!("{}/api/cloud-eval", self.cloud_eval_url);
+        let key = format!("cloud-eval?fen={}&multiPv={}", fen, multi_pv);
+        let query = [("fen", fen.to_string()), ("multiPv", multi_pv.to_string())];
+        self.lookup(&key, &url, &query, mode).await
+    }
+
+    /// Stale answers are still given when lichess can't be reached.
+    async fn lookup<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        url: &str,
+        query: &[(&str, String)],
+        mode: CacheMode,
+    ) -> Result<Cached<T>, Error> {
+        let cached = match mode {
+            CacheMode::Bypass => None,
+            CacheMode::Prefer | CacheMode::Only => self.cache.get(key)?,
+        };
+        match (mode, cached) {
+            (CacheMode::Only, Some(cached)) => from_cache(cached),
+            (CacheMode::Only, None) => Err(Error::new(ErrorType::Network)
+                .with_message("The answer isn't cached and the cache only was asked for")),
+            (CacheMode::Prefer, Some(cached)) if cached.1 < self.cache.ttl.as_secs() => {
+                from_cache(cached)
+            }
+            (_, cached) => match self.fetch(url, query).await {
+                Ok(body) => {
+                    let value = serde_json::from_str(&body)?;
+                    self.cache.put(key, &body)?;
+                    Ok(Cached {
+                        value,
+                        from_cache: false,
+                        age_s: None,
+                    })
+                }
+                Err(err) => match cached {
+                    Some(cached) => from_cache(cached),
+                    None => Err(err),
+                },
+            },
+        }
+    }
+
+    async fn fetch(&self, url: &str, query: &[(&str, String)]) -> Result<String, Error> {
+        Ok(self
+            .http
+            .get(url)
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+}
+
+fn from_cache<T: DeserializeOwned>((body, age_s): (String, u64)) -> Result<Cached<T>, Error> {
+    Ok(Cached {
+        value: serde_json::from_str(&body)?,
+        from_cache: true,
+        age_s: Some(age_s),
+    })
+}
+
+impl Default for ExplorerClient {
+    fn default() -> ExplorerClient {
+        let cache = ResponseCache::in_memory(DEFAULT_CACHE_TTL)
+            .expect("An in-memory database always opens");
+        ExplorerClient::new(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::dispatch_request;
+    use crate::state::StateHandle;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn explorer_json() -> serde_json::Value {
+        serde_json::json!({
+            "white": 10, "draws": 5, "black": 7,
+            "moves": [
+                {"uci": "e2e4", "san": "e4", "white": 6, "draws": 2, "black": 4, "averageRating": 2210},
+                {"uci": "d2d4", "san": "d4", "white": 4, "draws": 3, "black": 3, "averageRating": 2250},
+            ],
+            "topGames": [],
+        })
+    }
+
+    #[tokio::test]
+    async fn explore() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/lichess"))
+            .and(query_param("fen", START))
+            .respond_with(ResponseTemplate::new(200).set_body_json(explorer_json()))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let cache = ResponseCache::in_memory(DEFAULT_CACHE_TTL).unwrap();
+        let client = ExplorerClient::with_base_url(&server.uri(), cache.clone());
+
+        let fetched = client
+            .explore(START, ExplorerSource::Lichess, CacheMode::Prefer)
+            .await
+            .unwrap();
+        assert!(!fetched.from_cache);
+        assert_eq!(fetched.value.white, 10);
+        assert_eq!(fetched.value.moves[0].san, "e4");
+        assert_eq!(fetched.value.moves[1].average_rating, Some(2250));
+
+        // The second identical request doesn't reach the server
+        let cached = client
+            .explore(START, ExplorerSource::Lichess, CacheMode::Prefer)
+            .await
+            .unwrap();
+        assert!(cached.from_cache);
+        assert_eq!(cached.age_s, Some(0));
+        assert_eq!(cached.value, fetched.value);
+        let json = serde_json::to_value(&cached).unwrap();
+        assert_eq!(json["from_cache"], true);
+        assert_eq!(json["moves"][0]["uci"], "e2e4");
+
+        // Unless asked to
+        let bypassed = client
+            .explore(START, ExplorerSource::Lichess, CacheMode::Bypass)
+            .await
+            .unwrap();
+        assert!(!bypassed.from_cache);
+
+        // Offline, the cache still answers, and only it
+        let offline = ExplorerClient::with_base_url("http://127.0.0.1:9", cache);
+        let stale = offline
+            .explore(START, ExplorerSource::Lichess, CacheMode::Only)
+            .await
+            .unwrap();
+        assert!(stale.from_cache);
+        let masters = offline
+            .explore(START, ExplorerSource::Masters, CacheMode::Only)
+            .await;
+        assert!(masters.unwrap_err().is_type(ErrorType::Network));
+    }
+
+    #[tokio::test]
+    async fn stale_answers() {
+        let server = MockServer::start().await;
+        let eval = serde_json::json!({
+            "fen": START, "knodes": 13683, "depth": 22,
+            "pvs": [{"moves": "e2e4 e7e5", "cp": 18}, {"moves": "d2d4 d7d5", "cp": 15}],
+        });
+        Mock::given(method("GET"))
+            .and(path("/api/cloud-eval"))
+            .and(query_param("multiPv", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(eval))
+            .expect(2)
+            .mount(&server)
+            .await;
+        // Every answer is stale at once
+        let cache = ResponseCache::in_memory(Duration::from_secs(0)).unwrap();
+        let client = ExplorerClient::with_base_url(&server.uri(), cache.clone());
+
+        let eval = client
+            .cloud_eval(START, 2, CacheMode::Prefer)
+            .await
+            .unwrap();
+        assert_eq!(eval.value.depth, 22);
+        assert_eq!(eval.value.pvs[0].cp, Some(18));
+        assert!(
+            !client
+                .cloud_eval(START, 2, CacheMode::Prefer)
+                .await
+                .unwrap()
+                .from_cache
+        );
+
+        // Given rather than an error when lichess can't be reached
+        let offline = ExplorerClient::with_base_url("http://127.0.0.1:9", cache);
+        let stale = offline
+            .cloud_eval(START, 2, CacheMode::Prefer)
+            .await
+            .unwrap();
+        assert!(stale.from_cache);
+        assert_eq!(stale.value, eval.value);
+        assert!(offline
+            .cloud_eval(START, 1, CacheMode::Prefer)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn request() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/masters"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(explorer_json()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let cache = ResponseCache::in_memory(DEFAULT_CACHE_TTL).unwrap();
+        let state = StateHandle::default()
+            .with_explorer(ExplorerClient::with_base_url(&server.uri(), cache));
+        state.new_game_default("g1").unwrap();
+
+        let request = serde_json::json!({
+            "method": "lichess_explorer",
+            "params": {"id": "g1", "source": "masters"},
+        });
+        for from_cache in &[false, true] {
+            let request = serde_json::from_value(request.clone()).unwrap();
+            let response = dispatch_request(request, &state).await.unwrap();
+            let response = serde_json::to_value(response).unwrap();
+            assert_eq!(response["online_explorer"]["from_cache"], *from_cache);
+            assert_eq!(response["online_explorer"]["black"], 7);
+        }
+    }
+
+    #[test]
+    fn bounded() {
+        let mut cache = ResponseCache::in_memory(DEFAULT_CACHE_TTL).unwrap();
+        cache.max_entries = 2;
+        for key in &["a", "b", "c"] {
+            cache.put(key, "{}").unwrap();
+        }
+        assert!(cache.get("a").unwrap().is_none());
+        assert!(cache.get("b").unwrap().is_some());
+        assert!(cache.get("c").unwrap().is_some());
+    }
+}
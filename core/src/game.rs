@@ -1,11 +1,25 @@
+use crate::annotation::AccuracyReport;
+use crate::book::BookMove;
+use crate::clock::{Clock, ClockRepr, TimeControl};
+use crate::drill::{Drill, DrillStep};
+use crate::eco::{self, Opening};
+use crate::endgame;
 use crate::errors::{Error, ErrorType};
+use crate::hash;
+use crate::opponent::Opponent;
+use crate::pgn::{self, PgnGame, Token};
+use crate::puzzle::{PuzzleAttempt, Solving};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
 
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use shakmaty::san::{San, SanPlus};
 use shakmaty::uci::Uci;
-use shakmaty::Position;
+use shakmaty::{Position, Setup, Square};
 
 #[derive(Default, Debug)]
 pub struct Game {
@@ -17,244 +31,2995 @@ pub struct Game {
     initial_position: shakmaty::Chess,
     /// Tree of moves played or analysed during the game.
     game_tree: GameTree,
+    /// Node of `game_tree` reached by `current_line`.
+    current_node: NodeId,
+    /// Chess960 game: castling moves are exchanged with engines as king-takes-rook.
+    chess960: bool,
+    /// Set for games opened from a lichess puzzle.
+    puzzle: Option<PuzzleData>,
+    /// Position reached by each move of `current_line`, so that reprs don't replay the line.
+    /// Zobrist keys are updated along, see `hash::update`.
+    position_stack: Vec<(shakmaty::Chess, u64)>,
+    /// Opening of the main line, updated as moves extend it.
+    opening: Option<Opening>,
+    /// Clocks of a game being played, see `start_clock`.
+    clock: Option<Clock>,
+    /// Node of the last move played on the clock, the only one where it runs.
+    clock_node: NodeId,
+    /// Puzzle being solved, see `start_solving`.
+    solving: Option<Solving>,
+    /// Node where the solver's next move is checked against the solution.
+    solving_node: NodeId,
+    /// Repertoire drill going on, see `start_drill`.
+    drill: Option<Drill>,
+    /// Node where the drilled side's repertoire move is expected.
+    drill_node: NodeId,
+    /// Group of games navigating along with this one.
+    link: Option<Link>,
+    /// Engine replying to the moves played, see `opponent`.
+    opponent: Option<Opponent>,
 }
 
-#[derive(Default, Debug)]
+/// Index of a node in the arena of its `GameTree`.
+type NodeId = u32;
+
+/// Moves played or analysed during a game. Nodes are stored in one arena, which is compacted
+/// when lines are removed.
+#[derive(Debug)]
 struct GameTree {
-    /// Standard algebraic notation for the current move. Is `None` if this GameTree represents the starting position.
+    /// `nodes[0]` is the starting position.
+    nodes: Vec<Node>,
+}
+
+#[derive(Default, Debug)]
+struct Node {
+    /// Standard algebraic notation for the current move. Is `None` if this node represents the starting position.
     san: Option<SanPlus>,
+    /// Is `None` for the starting position.
+    parent: Option<NodeId>,
     /// `lines[0]` represents the main line, `lines[1..n]` are sidelines.
-    lines: Vec<GameTree>,
+    lines: Vec<NodeId>,
     /// Move annotation like ?? for blunders and ! for critical moves.
     annotation: Option<Annotation>,
-    /// Engine evaluation in tenths of pawns (evaluation = +10 -> 1 pawn advantage for white);
-    evaluation: Option<i16>,
+    /// Engine evaluation of the position reached, from white's point of view.
+    evaluation: Option<Evaluation>,
+    /// Text comment following the move, as in PGN `{...}` blocks.
+    comment: Option<String>,
+    /// Side whose repertoire this move is, at most one move of each node.
+    repertoire_for: Option<shakmaty::Color>,
+    /// Times the repertoire move from the position reached was missed in drills.
+    misses: u32,
+    /// FEN of the position reached, formatted on first use. Nodes keep their position, so it
+    /// stays valid.
+    fen: OnceLock<String>,
+}
+
+/// Tags written by `to_pgn` for an annotated game.
+const ACCURACY_TAGS: [&str; 4] = ["WhiteAccuracy", "WhiteACPL", "BlackAccuracy", "BlackACPL"];
+
+/// Longest line unless configured otherwise, see `set_max_plies`.
+pub const DEFAULT_MAX_PLIES: usize = 10_000;
+
+/// Longest line that can be played or imported.
+static MAX_PLIES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_PLIES);
+
+pub fn set_max_plies(plies: usize) {
+    MAX_PLIES.store(plies, Ordering::Relaxed);
+}
+
+/// Refuses a line of `plies` past the configured limit.
+fn check_plies(plies: usize) -> Result<(), Error> {
+    let max = MAX_PLIES.load(Ordering::Relaxed);
+    if plies > max {
+        return Err(Error::new(ErrorType::LimitExceeded)
+            .with_message(&format!("Lines are limited to {} plies", max)));
+    }
+    Ok(())
+}
+
+/// Largest score in centipawns of a stored evaluation, mates apart.
+const MAX_CP: i32 = 32_700;
+
+/// Longest comment of a move, in bytes.
+const MAX_COMMENT_BYTES: usize = 10 * 1024;
+
+fn check_comment(comment: &str) -> Result<(), Error> {
+    if comment.len() > MAX_COMMENT_BYTES {
+        return Err(Error::new(ErrorType::LimitExceeded).with_message(&format!(
+            "Comments are limited to {} bytes",
+            MAX_COMMENT_BYTES
+        )));
+    }
+    Ok(())
 }
 
 impl Game {
-    pub fn play(&mut self, from: &String, to: &String) -> Result<(), Error> {
-        let san = self.find_or_create_branch(&from, &to, &self.current_line.clone())?;
+    pub fn play(
+        &mut self,
+        from: &str,
+        to: &str,
+        promotion: Option<Promotion>,
+    ) -> Result<(), Error> {
+        check_plies(self.current_line.len() + 1)?;
+        let position = self.current_position();
+        let mov = fromto_to_move(from, to, promotion, &position)?;
+        let node = branch_for_move(&mut self.game_tree, self.current_node, position, &mov);
+        self.push_move(node);
+        self.record_mate();
+        Ok(())
+    }
+
+    /// Sets the result once the main line ends in checkmate or stalemate.
+    fn record_mate(&mut self) {
+        let position = self.cached_position();
+        let game_over = if position.is_checkmate() {
+            GameOver::Checkmate
+        } else if position.is_stalemate() {
+            GameOver::Stalemate
+        } else {
+            return;
+        };
+        if self.game_tree.ends_main_line(self.current_node) {
+            self.game_info.result = self.game_over_result(game_over);
+        }
+    }
+
+    /// Plays a move in UCI notation (e2e4, e7e8q), as sent by chess engines.
+    pub fn play_uci(&mut self, uci: &str) -> Result<(), Error> {
+        check_plies(self.current_line.len() + 1)?;
+        let position = self.current_position();
+        let mov = uci_to_move(uci, &position)?;
+        let node = branch_for_move(&mut self.game_tree, self.current_node, position, &mov);
+        self.push_move(node);
+        self.record_mate();
+        Ok(())
+    }
+
+    /// SAN of `moves` (UCI) played from the current position, up to the first illegal one.
+    pub fn san_line(&self, moves: &[String]) -> Vec<String> {
+        san_line(self.cached_position(), moves)
+    }
+
+    /// Plays a move in SAN notation (Nf3, exd8=Q+).
+    pub fn play_san(&mut self, san: &str) -> Result<(), Error> {
+        check_plies(self.current_line.len() + 1)?;
+        let parsed_san: SanPlus = san.parse()?;
+        let position = self.current_position();
+        let mov = parsed_san.san.to_move(&position)?;
+        let node = branch_for_move(&mut self.game_tree, self.current_node, position, &mov);
+        self.push_move(node);
+        self.record_mate();
+        Ok(())
+    }
+
+    /// Extends `current_line` with the move of `node`, a line of the current node, keeping the
+    /// cached positions and opening in step.
+    fn push_move(&mut self, node: NodeId) {
+        let san = self.game_tree.san(node).clone();
+        let before = self.cached_position();
+        let m = san_to_move(&san, before).expect("Tried to compute an invalid line");
+        let mut position = before.clone();
+        position.play_unchecked(&m);
+        let key = hash::update(self.position_hash(), before, &m, &position);
+        // Only the new deepest position of the main line can change its opening
+        let opening = if self.game_tree.ends_main_line(node) {
+            eco::classify(std::slice::from_ref(&position))
+        } else {
+            None
+        };
         self.current_line.push(san);
+        self.current_node = node;
+        self.position_stack.push((position, key));
+        if opening.is_some() {
+            self.opening = opening;
+        }
+    }
+
+    /// Moves to the end of `line`, a line of the tree, replaying it once.
+    fn set_line(&mut self, line: Vec<SanPlus>) -> Result<(), Error> {
+        self.current_node = self.game_tree.find(&line)?;
+        self.position_stack.clear();
+        let mut position = self.initial_position.clone();
+        let mut key = hash::zobrist(&position);
+        for san in &line {
+            let m = san_to_move(san, &position).expect("Tried to compute an invalid line");
+            let before = position.clone();
+            position.play_unchecked(&m);
+            key = hash::update(key, &before, &m, &position);
+            self.position_stack.push((position.clone(), key));
+        }
+        self.current_line = line;
+        Ok(())
+    }
+
+    pub fn navigate_back(&mut self, back: u16) {
+        let new_length = self.current_line.len().saturating_sub(back as usize);
+        for _ in new_length..self.current_line.len() {
+            self.current_node = self.game_tree.parent(self.current_node);
+        }
+        self.current_line.truncate(new_length);
+        self.position_stack.truncate(new_length);
+    }
+
+    /// Replays up to `forward` moves of the main continuation, stopping at the end of the line.
+    pub fn navigate_forward(&mut self, forward: u16) {
+        for _ in 0..forward {
+            match self.game_tree.node(self.current_node).lines.first() {
+                Some(&child) => self.push_move(child),
+                None => break,
+            }
+        }
+    }
+
+    /// Moves to the position after `ply` moves of the current line, extended by its main
+    /// continuation. Plies past its end stop at the end, 0 is the starting position.
+    pub fn navigate_to(&mut self, ply: usize) {
+        while self.current_line.len() > ply {
+            self.current_node = self.game_tree.parent(self.current_node);
+            self.current_line.pop();
+            self.position_stack.pop();
+        }
+        while self.current_line.len() < ply {
+            match self.game_tree.node(self.current_node).lines.first() {
+                Some(&child) => self.push_move(child),
+                None => break,
+            }
+        }
+    }
+
+    /// Follows the main continuation of the current position to the end of its line.
+    pub fn navigate_to_end(&mut self) {
+        while let Some(&child) = self.game_tree.node(self.current_node).lines.first() {
+            self.push_move(child);
+        }
+    }
+
+    pub fn navigate_to_start(&mut self) {
+        self.current_node = GameTree::ROOT;
+        self.current_line.clear();
+        self.position_stack.clear();
+    }
+
+    pub fn get_repr(&self) -> GameRepr {
+        let current_position = self.cached_position();
+        let last_move = self
+            .current_line
+            .last()
+            .map(|san| (san, self.previous_position()));
+        #[cfg(test)]
+        debug_assert_eq!(
+            self.opening,
+            eco::classify(&self.positions(&self.main_line()))
+        );
+        GameRepr {
+            available_moves: AvailableMoves::of(current_position),
+            fen: self
+                .node_fen(self.current_node, current_position)
+                .to_string(),
+            moves: self.current_line.iter().map(ToString::to_string).collect(),
+            ply: self.current_line.len(),
+            position_hash: format!("{:016x}", self.position_hash()),
+            is_takes: is_takes(last_move),
+            is_check: current_position.is_check(),
+            endgame: endgame::classify_endgame(current_position).map(|class| class.to_string()),
+            accuracy: self.game_info.accuracy.clone(),
+            book_moves: Vec::new(),
+            info: GameInfoRepr {
+                opening: self.opening.clone(),
+                headers: self.game_info.headers.clone(),
+                db_id: self.game_info.db_id.clone(),
+                database_id: self.game_info.database_id,
+                lichess: self.game_info.lichess.clone(),
+                title: self.game_info.game_title.clone(),
+                puzzle: self.puzzle.clone(),
+                time_control: self.game_info.time_control,
+                result: self.game_info.result,
+                white: self.game_info.players.0.clone(),
+                black: self.game_info.players.1.clone(),
+            },
+            clock: self.clock.as_ref().map(Clock::repr),
+            solving: self.solving(),
+            drilling: self.drilling(),
+            repertoire_move: self
+                .repertoire_move(current_position.turn())
+                .filter(|_| !self.drilling())
+                .map(|node| self.game_tree.san(node).to_string()),
+            link: self.link.clone(),
+            opponent: self.opponent.clone(),
+            last_move: last_move.map(|(san, _)| san.to_string()),
+            last_move_annotation: self.game_tree.node(self.current_node).annotation,
+            evaluation: self.game_tree.node(self.current_node).evaluation,
+            game_over: self.game_over(),
+            comment: self.game_tree.node(self.current_node).comment.clone(),
+        }
+    }
+
+    /// Move that led to the current position, with the position it was played in.
+    pub fn last_move(&self) -> Option<(&shakmaty::Chess, shakmaty::Move)> {
+        let san = self.current_line.last()?;
+        let position = self.previous_position();
+        Some((position, san_to_move(san, position).ok()?))
+    }
+
+    /// Castling rights only possible in Chess960 (rooks not in the corners, ...) make a Chess960 game.
+    pub fn from_fen(fen_string: String) -> Result<Game, Error> {
+        let mut game = Game::default();
+        let setup: shakmaty::fen::Fen = fen_string.parse()?;
+        game.initial_position = setup.position()?;
+        game.chess960 = game.initial_position.castles().is_chess960();
+        game.opening = game.classify_opening();
+        Ok(game)
+    }
+
+    /// Game tree of a PGN game, variations included. The current position is the end of the main line.
+    pub fn from_pgn(pgn: &PgnGame) -> Result<Game, Error> {
+        let mut game = Game::default();
+        game.initial_position = pgn.start_position()?;
+        game.chess960 = game.initial_position.castles().is_chess960()
+            || pgn.header("Variant") == Some("Chess960");
+
+        // Position after each move of the current line
+        let mut line: Vec<shakmaty::Chess> = Vec::new();
+        let mut node = GameTree::ROOT;
+        // Where to resume once each open variation ends
+        let mut resume = Vec::new();
+        for token in pgn::tokenize(&pgn.movetext)? {
+            match token {
+                Token::San(san) => {
+                    check_plies(line.len() + 1)?;
+                    let position = line.last().unwrap_or(&game.initial_position).clone();
+                    let m = san.parse::<San>()?.to_move(&position)?;
+                    let mut after = position.clone();
+                    after.play_unchecked(&m);
+                    node = branch_for_move(&mut game.game_tree, node, position, &m);
+                    line.push(after);
+                }
+                Token::StartVariation => {
+                    // A variation replaces the move before it
+                    resume.push((line.clone(), node));
+                    line.pop();
+                    node = game.game_tree.parent(node);
+                }
+                Token::EndVariation => {
+                    let (resumed_line, resumed_node) = resume.pop().ok_or_else(|| {
+                        Error::new(ErrorType::Parse)
+                            .with_message("Unbalanced variation in movetext")
+                    })?;
+                    line = resumed_line;
+                    node = resumed_node;
+                }
+                Token::Comment(comment) if !comment.is_empty() => {
+                    let node = game.game_tree.node_mut(node);
+                    let comment = match node.comment.take() {
+                        Some(previous) => format!("{} {}", previous, comment),
+                        None => comment,
+                    };
+                    check_comment(&comment)?;
+                    node.comment = Some(comment);
+                }
+                Token::Nag(nag) if node != GameTree::ROOT => {
+                    if let Some(annotation) = Annotation::from_nag(nag) {
+                        game.game_tree.node_mut(node).annotation = Some(annotation);
+                    }
+                }
+                Token::Nag(_) | Token::Comment(_) | Token::Result(_) => {}
+            }
+        }
+
+        game.set_line(game.main_line())?;
+        game.opening = game.classify_opening();
+        game.game_info.result = match pgn.header("Result") {
+            Some("1-0") => GameResult::WhiteWins,
+            Some("0-1") => GameResult::BlackWins,
+            Some("1/2-1/2") => GameResult::Draw,
+            _ => GameResult::Unknown,
+        };
+        game.game_info.time_control = pgn
+            .header("TimeControl")
+            .and_then(TimeControl::from_pgn_tag);
+        game.game_info.headers = pgn.headers.clone();
+        game.players_from_headers();
+        Ok(game)
+    }
+
+    /// PGN of the whole tree: sidelines become variations and evaluations `[%eval]` comments, in
+    /// front of the comments of the moves.
+    /// Imported tags are kept, the result and starting position are set from the game, and so is
+    /// the accuracy of the players once the game was annotated.
+    pub fn to_pgn(&self) -> PgnGame {
+        let accuracy = self.game_info.accuracy.as_ref();
+        let mut headers: Vec<(String, String)> = self
+            .game_info
+            .headers
+            .iter()
+            .filter(|(name, _)| !["Result", "FEN", "SetUp"].contains(&name.as_str()))
+            .filter(|(name, _)| accuracy.is_none() || !ACCURACY_TAGS.contains(&name.as_str()))
+            .cloned()
+            .collect();
+        headers.push((
+            String::from("Result"),
+            self.game_info.result.tag().to_string(),
+        ));
+        if let Some(accuracy) = accuracy {
+            for (color, player) in &[("White", &accuracy.white), ("Black", &accuracy.black)] {
+                if let Some(percent) = player.accuracy {
+                    headers.push((format!("{}Accuracy", color), percent.to_string()));
+                }
+                if let Some(loss) = player.average_centipawn_loss {
+                    headers.push((format!("{}ACPL", color), loss.to_string()));
+                }
+            }
+        }
+        if self.initial_fen() != fen(&shakmaty::Chess::default()) {
+            headers.push((String::from("FEN"), self.initial_fen()));
+            headers.push((String::from("SetUp"), String::from("1")));
+        }
+
+        let mut movetext = String::new();
+        write_comments(self.game_tree.node(GameTree::ROOT), &mut movetext);
+        write_movetext(
+            &self.game_tree,
+            GameTree::ROOT,
+            &self.initial_position,
+            &mut movetext,
+        );
+        movetext.push(' ');
+        movetext.push_str(self.game_info.result.tag());
+        PgnGame {
+            headers,
+            movetext: movetext.trim_start().to_string(),
+        }
+    }
+
+    /// Whole game in the versioned format games are saved in.
+    pub fn to_saved(&self) -> SavedGame {
+        let start = fen(&shakmaty::Chess::default());
+        let root = self.game_tree.node(GameTree::ROOT);
+        SavedGame {
+            version: SAVED_GAME_VERSION,
+            headers: self.game_info.headers.clone(),
+            fen: Some(self.initial_fen()).filter(|fen| *fen != start),
+            chess960: self.chess960,
+            result: self.game_info.result,
+            accuracy: self.game_info.accuracy.clone(),
+            tree: SavedNode {
+                evaluation: root.evaluation,
+                comment: root.comment.clone(),
+                misses: root.misses,
+                ..SavedNode::default()
+            },
+            moves: save_lines(&self.game_tree),
+        }
+    }
+
+    /// Whole tree, laid out like saved games.
+    pub fn tree_repr(&self) -> TreeRepr {
+        let saved = self.to_saved();
+        TreeRepr {
+            start: saved.tree,
+            moves: saved.moves,
+        }
+    }
+
+    /// Game of a saved tree, whose moves are checked against the rules. The current position is
+    /// the end of the main line.
+    pub fn from_saved(saved: &SavedGame) -> Result<Game, Error> {
+        if saved.version > SAVED_GAME_VERSION {
+            return Err(Error::new(ErrorType::Parse).with_message(&format!(
+                "Game saved in format version {}, only versions up to {} can be read",
+                saved.version, SAVED_GAME_VERSION
+            )));
+        }
+        let mut game = match &saved.fen {
+            Some(fen) => Game::from_fen(fen.clone())?,
+            None => Game::default(),
+        };
+        game.chess960 |= saved.chess960;
+        let root = game.game_tree.node_mut(GameTree::ROOT);
+        root.evaluation = saved.tree.evaluation;
+        root.comment = saved.tree.comment.clone();
+        root.misses = saved.tree.misses;
+        if saved.version < 2 {
+            load_lines(
+                &mut game.game_tree,
+                GameTree::ROOT,
+                &saved.tree,
+                &game.initial_position,
+            )?;
+        } else {
+            load_moves(&mut game.game_tree, &saved.moves, &game.initial_position)?;
+        }
+        game.set_line(game.main_line())?;
+        game.opening = game.classify_opening();
+        game.game_info.headers = saved.headers.clone();
+        game.players_from_headers();
+        game.game_info.result = saved.result;
+        game.game_info.accuracy = saved.accuracy.clone();
+        Ok(game)
+    }
+
+    /// Records the database and row the game was opened from.
+    pub fn with_database_id(self, db_id: &str, database_id: i64) -> Game {
+        Game {
+            game_info: GameInfo {
+                db_id: Some(db_id.to_string()),
+                database_id: Some(database_id),
+                ..self.game_info
+            },
+            ..self
+        }
+    }
+
+    pub fn with_title(self, title: &str) -> Game {
+        Game {
+            game_info: GameInfo {
+                game_title: title.to_string(),
+                ..self.game_info
+            },
+            ..self
+        }
+    }
+
+    pub fn set_lichess_chapter(&mut self, chapter: LichessChapter) {
+        self.game_info
+            .lichess
+            .get_or_insert_with(Lichess::default)
+            .chapter = Some(chapter);
+    }
+
+    pub fn with_puzzle(self, puzzle: PuzzleData) -> Game {
+        Game {
+            puzzle: Some(puzzle),
+            ..self
+        }
+    }
+
+    /// Puzzle the game was opened from, solution included.
+    #[cfg(test)]
+    pub fn puzzle(&self) -> Option<&PuzzleData> {
+        self.puzzle.as_ref()
+    }
+
+    pub fn with_lichess(self, lichess: Lichess) -> Game {
+        Game {
+            game_info: GameInfo {
+                lichess: Some(lichess),
+                ..self.game_info
+            },
+            ..self
+        }
+    }
+
+    /// Marks the game as Chess960 even if its starting position looks like a standard one.
+    pub fn with_chess960(self, chess960: bool) -> Game {
+        Game {
+            chess960: self.chess960 || chess960,
+            ..self
+        }
+    }
+
+    pub fn is_chess960(&self) -> bool {
+        self.chess960
+    }
+
+    pub fn current_position(&self) -> shakmaty::Chess {
+        self.cached_position().clone()
+    }
+
+    fn cached_position(&self) -> &shakmaty::Chess {
+        let position = self
+            .position_stack
+            .last()
+            .map_or(&self.initial_position, |(position, _)| position);
+        #[cfg(test)]
+        {
+            // Compared without allocating, for tests counting allocations
+            let key =
+                |pos: &shakmaty::Chess| (hash::zobrist(pos), pos.halfmoves(), pos.fullmoves());
+            let replayed = shakmaty_position(&self.initial_position, &self.current_line);
+            debug_assert_eq!(key(position), key(&replayed));
+            debug_assert_eq!(self.position_hash(), key(position).0);
+            debug_assert_eq!(
+                self.game_tree.find(&self.current_line).ok(),
+                Some(self.current_node)
+            );
+        }
+        position
+    }
+
+    /// Zobrist key of the current position, as computed by `hash::zobrist`.
+    pub fn position_hash(&self) -> u64 {
+        match self.position_stack.last() {
+            Some((_, key)) => *key,
+            None => hash::zobrist(&self.initial_position),
+        }
+    }
+
+    /// Position before the last move of `current_line`.
+    fn previous_position(&self) -> &shakmaty::Chess {
+        match self.position_stack.len() {
+            0 | 1 => &self.initial_position,
+            len => &self.position_stack[len - 2].0,
+        }
+    }
+
+    pub fn current_fen(&self) -> String {
+        self.node_fen(self.current_node, self.cached_position())
+            .to_string()
+    }
+
+    pub fn initial_fen(&self) -> String {
+        self.node_fen(GameTree::ROOT, &self.initial_position)
+            .to_string()
+    }
+
+    /// FEN of `node`, reached at `position`, only formatted the first time.
+    fn node_fen(&self, node: NodeId, position: &shakmaty::Chess) -> &str {
+        let memo = self.game_tree.node(node).fen.get_or_init(|| fen(position));
+        #[cfg(test)]
+        debug_assert_eq!(memo, &fen(position));
+        memo
+    }
+
+    /// Moves of `current_line` in UCI notation, as expected by `position ... moves` engine commands.
+    /// Castling is written king-takes-rook (e1h1) in Chess960 games, e1g1 otherwise.
+    pub fn uci_line(&self) -> Vec<String> {
+        let mut position = self.initial_position.clone();
+        let mut moves = Vec::with_capacity(self.current_line.len());
+        for san in &self.current_line {
+            let m = san
+                .san
+                .to_move(&position)
+                .expect("Tried to compute an invalid line");
+            let uci = if self.chess960 {
+                Uci::from_chess960(&m)
+            } else {
+                Uci::from_move(&position, &m)
+            };
+            moves.push(uci.to_string());
+            position.play_unchecked(&m);
+        }
+        moves
+    }
+
+    pub fn link(&self) -> Option<&Link> {
+        self.link.as_ref()
+    }
+
+    pub fn set_link(&mut self, link: Option<Link>) {
+        self.link = link;
+    }
+
+    pub fn opponent(&self) -> Option<&Opponent> {
+        self.opponent.as_ref()
+    }
+
+    pub fn set_opponent(&mut self, opponent: Option<Opponent>) {
+        self.opponent = opponent;
+    }
+
+    /// Whether the engine opponent, if any, is the one to move in a game still going on.
+    pub fn opponent_to_move(&self) -> bool {
+        let turn = self.cached_position().turn();
+        self.opponent
+            .as_ref()
+            .is_some_and(|opponent| shakmaty::Color::from(opponent.side) == turn)
+            && self.game_over().is_none()
+    }
+
+    /// Moves to the first node of the tree reaching `position`, the shallowest one, unless the
+    /// current position is already the same. Returns whether the game moved.
+    pub fn go_to_position(&mut self, position: &shakmaty::Chess) -> bool {
+        let key = hash::zobrist(position);
+        if self.position_hash() == key {
+            return false;
+        }
+        let mut pending = VecDeque::from(vec![(GameTree::ROOT, self.initial_position.clone())]);
+        while let Some((node, position)) = pending.pop_front() {
+            if hash::zobrist(&position) == key {
+                let line = self.game_tree.line_to(node);
+                return self.set_line(line).is_ok();
+            }
+            for &line in &self.game_tree.node(node).lines {
+                let m = san_to_move(self.game_tree.san(line), &position)
+                    .expect("Tried to compute an invalid line");
+                let mut after = position.clone();
+                after.play_unchecked(&m);
+                pending.push_back((line, after));
+            }
+        }
+        false
+    }
+
+    /// Moves leading to the current position.
+    pub fn line(&self) -> Vec<SanPlus> {
+        self.current_line.clone()
+    }
+
+    /// Moves of the main line, which starts with the first move played from each position.
+    pub fn main_line(&self) -> Vec<SanPlus> {
+        let mut line = Vec::new();
+        let mut node = self.game_tree.node(GameTree::ROOT);
+        while let Some(&child) = node.lines.first() {
+            node = self.game_tree.node(child);
+            line.extend(node.san.clone());
+        }
+        line
+    }
+
+    /// Deepest known opening of the main line.
+    pub fn classify_opening(&self) -> Option<Opening> {
+        eco::classify(&self.positions(&self.main_line()))
+    }
+
+    /// Every position of `line`, starting position included.
+    pub fn positions(&self, line: &[SanPlus]) -> Vec<shakmaty::Chess> {
+        line_positions(&self.initial_position, line)
+    }
+
+    /// Evaluations stored along `line`, starting position included.
+    pub fn evaluations(&self, line: &[SanPlus]) -> Result<Vec<Option<Evaluation>>, Error> {
+        let tree = &self.game_tree;
+        let mut node = GameTree::ROOT;
+        let mut evaluations = vec![tree.node(node).evaluation];
+        for san in line {
+            node = tree
+                .child(node, san)
+                .ok_or_else(|| Error::new(ErrorType::ChessRules))?;
+            evaluations.push(tree.node(node).evaluation);
+        }
+        Ok(evaluations)
+    }
+
+    /// Stores the evaluation of the position reached by `line`.
+    pub fn set_evaluation(
+        &mut self,
+        line: &[SanPlus],
+        evaluation: Evaluation,
+    ) -> Result<(), Error> {
+        let node = self.game_tree.find(line)?;
+        self.game_tree.node_mut(node).evaluation = Some(evaluation);
+        Ok(())
+    }
+
+    /// Stores the evaluation of the current position, or removes it. Scores past 327 pawns are
+    /// rejected, as are WDL probabilities not summing to 1000.
+    pub fn evaluate_current(&mut self, evaluation: Option<Evaluation>) -> Result<(), Error> {
+        if let Some(evaluation) = evaluation {
+            if matches!(evaluation.score, Score::Cp(cp) if cp.abs() > MAX_CP) {
+                return Err(Error::new(ErrorType::Parse)
+                    .with_message(&format!("Evaluations are limited to {} centipawns", MAX_CP)));
+            }
+            if evaluation
+                .wdl
+                .is_some_and(|wdl| wdl.win + wdl.draw + wdl.loss != 1000)
+            {
+                return Err(
+                    Error::new(ErrorType::Parse).with_message("WDL probabilities must sum to 1000")
+                );
+            }
+        }
+        self.game_tree.node_mut(self.current_node).evaluation = evaluation;
+        Ok(())
+    }
+
+    /// Annotates the move reaching the current position, or removes its annotation.
+    pub fn annotate_current(&mut self, annotation: Option<Annotation>) -> Result<(), Error> {
+        if self.current_line.is_empty() {
+            return Err(Error::new(ErrorType::ChessRules)
+                .with_message("The starting position has no move to annotate"));
+        }
+        self.game_tree.node_mut(self.current_node).annotation = annotation;
+        Ok(())
+    }
+
+    /// Annotates the move reaching the end of `line`.
+    pub fn set_annotation(
+        &mut self,
+        line: &[SanPlus],
+        annotation: Annotation,
+    ) -> Result<(), Error> {
+        let node = self.game_tree.find(line)?;
+        self.game_tree.node_mut(node).annotation = Some(annotation);
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn play_san(&mut self, san: String) -> Result<(), Error> {
-        let parsed_san: San = san.parse()?;
-        let current_position = self.current_position();
-        let mov = parsed_san.to_move(&current_position)?;
-        self.play(&mov.from().unwrap().to_string(), &mov.to().to_string())
+    pub fn set_accuracy(&mut self, accuracy: AccuracyReport) {
+        self.game_info.accuracy = Some(accuracy);
+    }
+
+    pub fn result(&self) -> GameResult {
+        self.game_info.result
+    }
+
+    pub fn set_result(&mut self, result: GameResult) {
+        self.game_info.result = result;
+    }
+
+    /// Changes the fields given by `update`. Tags set to an empty string are removed.
+    pub fn update_info(&mut self, update: GameInfoUpdate) -> Result<(), Error> {
+        if let Some(date) = update.date.as_deref().filter(|date| !date.is_empty()) {
+            check_pgn_date(date)?;
+        }
+        if let Some(title) = update.title {
+            self.game_info.game_title = title;
+        }
+        if let Some(result) = update.result {
+            self.game_info.result = result;
+        }
+        let tags = [
+            ("Event", update.event),
+            ("Site", update.site),
+            ("Date", update.date),
+            ("Round", update.round),
+        ];
+        for (tag, value) in tags.iter() {
+            if let Some(value) = value {
+                self.set_header(tag, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the players given, keeping the other, and their tags along.
+    pub fn set_players(
+        &mut self,
+        white: Option<Player>,
+        black: Option<Player>,
+    ) -> Result<(), Error> {
+        for player in white.iter().chain(black.iter()) {
+            if player.rating.is_some_and(|rating| rating > MAX_RATING) {
+                return Err(Error::new(ErrorType::Parse)
+                    .with_message(&format!("Ratings are limited to {}", MAX_RATING)));
+            }
+        }
+        for (color, player) in [("White", white), ("Black", black)] {
+            let player = match player {
+                Some(player) => player,
+                None => continue,
+            };
+            self.set_header(color, &player.name);
+            let rating = player.rating.map(|rating| rating.to_string());
+            self.set_header(&format!("{}Elo", color), &rating.unwrap_or_default());
+            let title = player.title.clone().unwrap_or_default();
+            self.set_header(&format!("{}Title", color), &title);
+            match color {
+                "White" => self.game_info.players.0 = Some(player),
+                _ => self.game_info.players.1 = Some(player),
+            }
+        }
+        Ok(())
+    }
+
+    fn players_from_headers(&mut self) {
+        let headers = &self.game_info.headers;
+        self.game_info.players = (
+            Player::from_headers(headers, "White"),
+            Player::from_headers(headers, "Black"),
+        );
+    }
+
+    fn set_header(&mut self, tag: &str, value: &str) {
+        let headers = &mut self.game_info.headers;
+        match headers.iter().position(|(name, _)| name == tag) {
+            Some(index) if value.is_empty() => {
+                headers.remove(index);
+            }
+            Some(index) => headers[index].1 = value.to_string(),
+            None if value.is_empty() => {}
+            None => headers.push((tag.to_string(), value.to_string())),
+        }
+    }
+
+    /// Plays the game on the clock from the current position, the side to move's time running
+    /// from `at` (milliseconds). Moves played from that position on are timed, elsewhere the
+    /// clock is paused.
+    pub fn start_clock(&mut self, time_control: TimeControl, at: u64) {
+        let mut clock = Clock::new(time_control, self.cached_position().turn());
+        clock.start(at);
+        self.game_info.time_control = Some(time_control);
+        self.clock = Some(clock);
+        self.clock_node = self.current_node;
+    }
+
+    pub fn stop_clock(&mut self) {
+        self.clock = None;
+    }
+
+    /// Plays a move at `at` (milliseconds). Played from the position of the clock, the move
+    /// stops the mover's time, unless it already ran out which loses the game.
+    pub fn play_timed(
+        &mut self,
+        from: &str,
+        to: &str,
+        promotion: Option<Promotion>,
+        at: u64,
+    ) -> Result<(), Error> {
+        let timed = self.clock.is_some() && self.current_node == self.clock_node;
+        self.play(from, to, promotion)?;
+        if !timed {
+            self.sync_clock(at);
+            return Ok(());
+        }
+        if let Some(clock) = &mut self.clock {
+            match clock.punch(at) {
+                Some(flagged) => {
+                    let before = self.previous_position().clone();
+                    self.flag_fell(&before, flagged);
+                }
+                None => self.clock_node = self.current_node,
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the clock if the current position is the one of the clock, pauses it otherwise.
+    pub fn sync_clock(&mut self, at: u64) {
+        let live = self.current_node == self.clock_node;
+        if let Some(clock) = &mut self.clock {
+            if live {
+                clock.start(at);
+            } else {
+                clock.pause(at);
+            }
+        }
+        self.tick_clock(at);
+    }
+
+    /// Counts the time spent until `at`, ending the game if it ran out.
+    pub fn tick_clock(&mut self, at: u64) {
+        let flagged = match &mut self.clock {
+            Some(clock) if clock.flagged().is_none() => clock.tick(at),
+            _ => None,
+        };
+        if let Some(flagged) = flagged {
+            let position = self.cached_position().clone();
+            self.flag_fell(&position, flagged);
+        }
+    }
+
+    /// Solves the puzzle of the current position: moves played from there are checked against
+    /// `solution` (UCI, both sides), or the imported puzzle's. Hints come after
+    /// `attempts_before_hint` wrong attempts at a move.
+    pub fn start_solving(
+        &mut self,
+        solution: Option<Vec<String>>,
+        attempts_before_hint: u32,
+        at: u64,
+    ) -> Result<(), Error> {
+        let solution = solution
+            .or_else(|| self.puzzle.as_ref().map(|puzzle| puzzle.solution.clone()))
+            .unwrap_or_default();
+        if solution.is_empty() {
+            return Err(Error::new(ErrorType::Parse).with_message("The puzzle has no solution"));
+        }
+        let mut position = self.current_position();
+        for uci in &solution {
+            let mov = uci_to_move(uci, &position)?;
+            position.play_unchecked(&mov);
+        }
+        self.solving = Some(Solving::new(solution, attempts_before_hint, at));
+        self.solving_node = self.current_node;
+        Ok(())
+    }
+
+    /// Whether moves played now are attempts at the puzzle.
+    pub fn solving(&self) -> bool {
+        self.solving.is_some() && self.current_node == self.solving_node
+    }
+
+    /// Plays the solver's move if it is the solution's, then the opponent's reply. Wrong moves
+    /// aren't played. Solving ends with the last move of the solution.
+    pub fn play_puzzle(
+        &mut self,
+        from: &str,
+        to: &str,
+        promotion: Option<Promotion>,
+        at: u64,
+    ) -> Result<PuzzleAttempt, Error> {
+        let mut solving = self.solving.take().ok_or_else(|| {
+            Error::new(ErrorType::ChessRules).with_message("No puzzle is being solved")
+        })?;
+        let attempt = self.attempt_puzzle(&mut solving, from, to, promotion, at);
+        if !solving.solved() {
+            self.solving = Some(solving);
+            self.solving_node = self.current_node;
+        }
+        attempt
+    }
+
+    fn attempt_puzzle(
+        &mut self,
+        solving: &mut Solving,
+        from: &str,
+        to: &str,
+        promotion: Option<Promotion>,
+        at: u64,
+    ) -> Result<PuzzleAttempt, Error> {
+        let promotion_char = promotion.map(Promotion::uci_char);
+        let expected = match solving.expected(from, to, promotion_char) {
+            Some(expected) => expected.to_string(),
+            None => {
+                // Illegal moves are errors rather than attempts
+                fromto_to_move(from, to, promotion, &self.current_position())?;
+                return Ok(solving.reject());
+            }
+        };
+        self.play_uci(&expected)?;
+        if let Some(reply) = solving.advance() {
+            let reply = reply.to_string();
+            self.play_uci(&reply)?;
+            if !solving.solved() {
+                return Ok(PuzzleAttempt::Correct {
+                    reply: self.game_tree.san(self.current_node).to_string(),
+                });
+            }
+        }
+        Ok(solving.summary(at))
+    }
+
+    /// Comments the move reaching the current position, or the starting position. An empty
+    /// comment removes it.
+    pub fn set_comment(&mut self, comment: String) -> Result<(), Error> {
+        check_comment(&comment)?;
+        self.game_tree.node_mut(self.current_node).comment =
+            Some(comment).filter(|c| !c.is_empty());
+        Ok(())
+    }
+
+    /// Removes every move played from the current position, sidelines included. From the
+    /// starting position, the tree is emptied. When the main line is cut short, its result goes
+    /// with it, unless the new end is a mate.
+    pub fn truncate_from_current(&mut self) {
+        let had_lines = !self.game_tree.node(self.current_node).lines.is_empty();
+        let new_ids = self.game_tree.remove_lines(self.current_node);
+        let moved = |id: NodeId| new_ids[id as usize].unwrap_or(GameTree::REMOVED);
+        self.current_node = moved(self.current_node);
+        self.clock_node = moved(self.clock_node);
+        self.solving_node = moved(self.solving_node);
+        self.drill_node = moved(self.drill_node);
+        if had_lines && self.game_tree.ends_main_line(self.current_node) {
+            self.game_info.result = GameResult::Unknown;
+            self.record_mate();
+        }
+        self.opening = self.classify_opening();
+    }
+
+    /// Marks the move reaching the current position as its mover's repertoire move, in place of
+    /// any other from the previous position, or unmarks it.
+    pub fn mark_repertoire(&mut self, marked: bool) -> Result<(), Error> {
+        if self.current_line.is_empty() {
+            return Err(Error::new(ErrorType::ChessRules)
+                .with_message("The starting position has no move to mark"));
+        }
+        let color = self.previous_position().turn();
+        let parent = self.game_tree.parent(self.current_node);
+        for line in self.game_tree.node(parent).lines.clone() {
+            self.game_tree.node_mut(line).repertoire_for = None;
+        }
+        if marked {
+            self.game_tree.node_mut(self.current_node).repertoire_for = Some(color);
+        }
+        Ok(())
+    }
+
+    /// Marked move of `color`'s repertoire from the current position.
+    fn repertoire_move(&self, color: shakmaty::Color) -> Option<NodeId> {
+        self.game_tree
+            .node(self.current_node)
+            .lines
+            .iter()
+            .copied()
+            .find(|&line| self.game_tree.node(line).repertoire_for == Some(color))
+    }
+
+    /// Positions of the tree from the current one where the opponent of `color` is to move,
+    /// within `depth_limit` plies.
+    pub fn drill_positions(
+        &self,
+        color: shakmaty::Color,
+        depth_limit: Option<u32>,
+    ) -> Vec<shakmaty::Chess> {
+        let mut positions = Vec::new();
+        let mut pending = vec![(self.current_node, self.cached_position().clone(), 0)];
+        while let Some((node, position, depth)) = pending.pop() {
+            if depth_limit.is_some_and(|limit| depth >= limit) {
+                continue;
+            }
+            let lines = &self.game_tree.node(node).lines;
+            if lines.is_empty() {
+                continue;
+            }
+            for &line in lines {
+                let m = san_to_move(self.game_tree.san(line), &position)
+                    .expect("Tried to compute an invalid line");
+                let mut after = position.clone();
+                after.play_unchecked(&m);
+                pending.push((line, after, depth + 1));
+            }
+            if position.turn() != color {
+                positions.push(position);
+            }
+        }
+        positions
+    }
+
+    /// Drills the repertoire from the current position: the opponent's moves are picked among
+    /// the lines of the tree, and the drilled side is expected to play its marked moves.
+    pub fn start_drill(&mut self, mut drill: Drill) -> DrillStep {
+        let reply = self.drill_reply(&mut drill);
+        if self.drill_line_over(&drill) {
+            return drill.line_end(reply);
+        }
+        self.drill = Some(drill);
+        self.drill_node = self.current_node;
+        DrillStep::Ready { reply }
+    }
+
+    /// Whether moves played now are checked against the repertoire.
+    pub fn drilling(&self) -> bool {
+        self.drill.is_some() && self.current_node == self.drill_node
+    }
+
+    /// Plays the move if it is the repertoire's, then the opponent's reply. Other moves aren't
+    /// played and count as misses of the position. The drill ends with its line.
+    pub fn play_drill(
+        &mut self,
+        from: &str,
+        to: &str,
+        promotion: Option<Promotion>,
+    ) -> Result<DrillStep, Error> {
+        let mut drill = self.drill.take().ok_or_else(|| {
+            Error::new(ErrorType::ChessRules).with_message("No drill is going on")
+        })?;
+        let step = self.drill_attempt(&mut drill, from, to, promotion);
+        match step {
+            Ok(DrillStep::LineEnd { .. }) => (),
+            _ => {
+                self.drill = Some(drill);
+                self.drill_node = self.current_node;
+            }
+        }
+        step
+    }
+
+    fn drill_attempt(
+        &mut self,
+        drill: &mut Drill,
+        from: &str,
+        to: &str,
+        promotion: Option<Promotion>,
+    ) -> Result<DrillStep, Error> {
+        let position = self.current_position();
+        let played = fromto_to_move(from, to, promotion, &position)?;
+        let expected = match self.repertoire_move(drill.color()) {
+            Some(expected) => expected,
+            None => return Ok(drill.line_end(None)),
+        };
+        let expected_san = self.game_tree.san(expected);
+        if san_to_move(expected_san, &position)? != played {
+            let expected_san = expected_san.to_string();
+            self.game_tree.node_mut(self.current_node).misses += 1;
+            return Ok(drill.miss(expected_san));
+        }
+        self.push_move(expected);
+        drill.hit();
+        let reply = self.drill_reply(drill);
+        if self.drill_line_over(drill) {
+            return Ok(drill.line_end(reply));
+        }
+        Ok(DrillStep::Hit {
+            reply,
+            streak: drill.streak(),
+        })
+    }
+
+    /// Plays one of the opponent's lines if they are to move, returning its SAN.
+    fn drill_reply(&mut self, drill: &mut Drill) -> Option<String> {
+        if self.cached_position().turn() == drill.color() || drill.depth_reached() {
+            return None;
+        }
+        let lines = self.game_tree.node(self.current_node).lines.clone();
+        let moves: Vec<(String, u32)> = lines
+            .iter()
+            .map(|&line| {
+                let node = self.game_tree.node(line);
+                (self.game_tree.san(line).to_string(), node.misses)
+            })
+            .collect();
+        let picked = drill.pick(self.position_hash(), &moves)?;
+        self.push_move(lines[picked]);
+        drill.played();
+        Some(moves[picked].0.clone())
+    }
+
+    fn drill_line_over(&self, drill: &Drill) -> bool {
+        drill.depth_reached() || self.repertoire_move(drill.color()).is_none()
+    }
+
+    /// Loses the game on time for `flagged`, drawn when the opponent can't mate in `position`.
+    fn flag_fell(&mut self, position: &shakmaty::Chess, flagged: shakmaty::Color) {
+        self.game_info.result = if position.has_insufficient_material(!flagged) {
+            GameResult::Draw
+        } else {
+            flagged.fold(GameResult::BlackWins, GameResult::WhiteWins)
+        };
+    }
+
+    /// Checks whether the current position ends the game by the rules.
+    /// Threefold repetition and the fifty-move rule are treated as automatic draws.
+    pub fn game_over(&self) -> Option<GameOver> {
+        let current = self.cached_position();
+        let keys: Vec<u64> = std::iter::once(hash::zobrist(&self.initial_position))
+            .chain(self.position_stack.iter().map(|(_, key)| *key))
+            .collect();
+
+        if current.is_checkmate() {
+            Some(GameOver::Checkmate)
+        } else if current.is_stalemate() {
+            Some(GameOver::Stalemate)
+        } else if current.is_insufficient_material() {
+            Some(GameOver::InsufficientMaterial)
+        } else if current.halfmoves() >= 100 {
+            Some(GameOver::FiftyMoveRule)
+        } else if is_threefold_repetition(&keys) {
+            Some(GameOver::ThreefoldRepetition)
+        } else {
+            None
+        }
+    }
+
+    /// Result implied by `game_over`, the side to move being the one who got mated.
+    pub fn game_over_result(&self, game_over: GameOver) -> GameResult {
+        match (game_over, self.current_position().turn()) {
+            (GameOver::Checkmate, shakmaty::Color::White) => GameResult::BlackWins,
+            (GameOver::Checkmate, shakmaty::Color::Black) => GameResult::WhiteWins,
+            _ => GameResult::Draw,
+        }
+    }
+}
+
+impl Default for GameTree {
+    fn default() -> GameTree {
+        GameTree {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl GameTree {
+    const ROOT: NodeId = 0;
+    /// Stands for a node removed from the tree, equal to none of its nodes.
+    const REMOVED: NodeId = NodeId::MAX;
+
+    fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id as usize]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id as usize]
+    }
+
+    /// Move leading to `id`, which isn't the root.
+    fn san(&self, id: NodeId) -> &SanPlus {
+        self.node(id)
+            .san
+            .as_ref()
+            .expect("Only the root has no move")
+    }
+
+    /// Node before `id`, the root being its own parent.
+    fn parent(&self, id: NodeId) -> NodeId {
+        self.node(id).parent.unwrap_or(GameTree::ROOT)
+    }
+
+    /// Line of `id` starting with `san`.
+    fn child(&self, id: NodeId, san: &SanPlus) -> Option<NodeId> {
+        self.node(id)
+            .lines
+            .iter()
+            .copied()
+            .find(|&child| self.node(child).san.as_ref() == Some(san))
+    }
+
+    /// Node reached by playing `line` from the starting position.
+    fn find(&self, line: &[SanPlus]) -> Result<NodeId, Error> {
+        line.iter().try_fold(GameTree::ROOT, |node, san| {
+            self.child(node, san)
+                .ok_or_else(|| Error::new(ErrorType::ChessRules))
+        })
+    }
+
+    /// Moves from the starting position to `id`.
+    fn line_to(&self, mut id: NodeId) -> Vec<SanPlus> {
+        let mut line = Vec::new();
+        while let Some(parent) = self.node(id).parent {
+            line.push(self.san(id).clone());
+            id = parent;
+        }
+        line.reverse();
+        line
+    }
+
+    /// Removes every line of `id`, then renumbers the nodes left in tree order, so that the arena
+    /// only holds those. Returns the new id of each node, `None` for those removed.
+    fn remove_lines(&mut self, id: NodeId) -> Vec<Option<NodeId>> {
+        self.node_mut(id).lines.clear();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![GameTree::ROOT];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.node(node).lines.iter().rev());
+        }
+        let mut new_ids = vec![None; self.nodes.len()];
+        for (new_id, old_id) in order.iter().enumerate() {
+            new_ids[*old_id as usize] = Some(new_id as NodeId);
+        }
+        let new_id = |old_id: NodeId| new_ids[old_id as usize].expect("Kept nodes link kept nodes");
+        let mut old_nodes: Vec<Option<Node>> = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.nodes = order
+            .iter()
+            .map(|old_id| {
+                let mut node = old_nodes[*old_id as usize]
+                    .take()
+                    .expect("Nodes are reached once");
+                node.parent = node.parent.map(new_id);
+                for line in &mut node.lines {
+                    *line = new_id(*line);
+                }
+                node
+            })
+            .collect();
+        new_ids
+    }
+
+    /// Line of `parent` starting with `san`, added after the others if there is none yet.
+    fn add_line(&mut self, parent: NodeId, san: SanPlus) -> NodeId {
+        if let Some(child) = self.child(parent, &san) {
+            return child;
+        }
+        let id = self.nodes.len() as NodeId;
+        self.nodes.push(Node {
+            san: Some(san),
+            parent: Some(parent),
+            ..Node::default()
+        });
+        self.node_mut(parent).lines.push(id);
+        id
+    }
+
+    /// Whether `id` is the last move of the main line.
+    fn ends_main_line(&self, mut id: NodeId) -> bool {
+        if !self.node(id).lines.is_empty() {
+            return false;
+        }
+        while let Some(parent) = self.node(id).parent {
+            if self.node(parent).lines.first() != Some(&id) {
+                return false;
+            }
+            id = parent;
+        }
+        true
+    }
+}
+
+/// Line of `parent` for `mov`, played at `pos`, added to the tree unless already there.
+fn branch_for_move(
+    tree: &mut GameTree,
+    parent: NodeId,
+    pos: shakmaty::Chess,
+    mov: &shakmaty::Move,
+) -> NodeId {
+    tree.add_line(parent, SanPlus::from_move(pos, mov))
+}
+
+/// Part of a line left to write.
+struct PendingLine {
+    /// Written from its move on when `opens`, otherwise from the moves following it.
+    node: NodeId,
+    /// Before the move of `node` when `opens`, after it otherwise.
+    position: shakmaty::Chess,
+    /// Starts a variation, whose parenthesis is opened.
+    opens: bool,
+    /// Ends a variation, whose parenthesis is closed.
+    closes: bool,
+}
+
+/// Appends the moves following `node`, reached at `position`, with their sidelines. Variations
+/// wait on a stack rather than recursing, however deep they nest.
+fn write_movetext(tree: &GameTree, node: NodeId, position: &shakmaty::Chess, out: &mut String) {
+    let mut pending = vec![PendingLine {
+        node,
+        position: position.clone(),
+        opens: false,
+        closes: false,
+    }];
+    'lines: while let Some(PendingLine {
+        mut node,
+        mut position,
+        opens,
+        closes,
+    }) = pending.pop()
+    {
+        if opens {
+            out.push_str(" (");
+            write_move(tree.node(node), &position, true, out);
+            position = shakmaty_position(&position, tree.node(node).san.iter());
+        }
+        // Black moves need their number after a comment or a variation
+        let mut interrupted = true;
+        while let Some((&main, sidelines)) = tree.node(node).lines.split_first() {
+            let main_node = tree.node(main);
+            write_move(main_node, &position, interrupted, out);
+            interrupted = main_node.evaluation.is_some() || main_node.comment.is_some();
+            let after = shakmaty_position(&position, main_node.san.iter());
+            if !sidelines.is_empty() {
+                // The rest of the line comes after its sidelines, in order
+                pending.push(PendingLine {
+                    node: main,
+                    position: after,
+                    opens: false,
+                    closes,
+                });
+                for &id in sidelines.iter().rev() {
+                    pending.push(PendingLine {
+                        node: id,
+                        position: position.clone(),
+                        opens: true,
+                        closes: true,
+                    });
+                }
+                continue 'lines;
+            }
+            position = after;
+            node = main;
+        }
+        if closes {
+            out.push(')');
+        }
+    }
+}
+
+fn write_move(node: &Node, position: &shakmaty::Chess, numbered: bool, out: &mut String) {
+    let san = match &node.san {
+        Some(san) => san,
+        None => return,
+    };
+    if !out.is_empty() && !out.ends_with('(') {
+        out.push(' ');
+    }
+    match position.turn() {
+        shakmaty::Color::White => out.push_str(&format!("{}. ", position.fullmoves())),
+        shakmaty::Color::Black if numbered => {
+            out.push_str(&format!("{}... ", position.fullmoves()))
+        }
+        shakmaty::Color::Black => {}
+    }
+    out.push_str(&san.to_string());
+    if let Some(annotation) = node.annotation {
+        out.push_str(&format!(" ${}", annotation.nag()));
+    }
+    write_comments(node, out);
+}
+
+/// Main line of the tree, each move holding the lines played instead of it. Lines are saved one
+/// after the other then put in place, so that nested variations don't recurse.
+fn save_lines(tree: &GameTree) -> Vec<SavedNode> {
+    // Each line with its place: line, move and variation it belongs in
+    type Place = (usize, usize, usize);
+    let mut lines: Vec<(Vec<SavedNode>, Option<Place>)> = Vec::new();
+    let mut pending = Vec::new();
+    if let Some(&first) = tree.node(GameTree::ROOT).lines.first() {
+        pending.push((first, None));
+    }
+    while let Some((first, place)) = pending.pop() {
+        let index = lines.len();
+        let mut moves = Vec::new();
+        let mut next = Some(first);
+        while let Some(id) = next {
+            let node = tree.node(id);
+            // A variation's siblings are already variations of the main move
+            let variations = match place {
+                Some(_) if id == first => &[][..],
+                _ => &tree.node(tree.parent(id)).lines[1..],
+            };
+            for (slot, &variation) in variations.iter().enumerate() {
+                pending.push((variation, Some((index, moves.len(), slot))));
+            }
+            moves.push(SavedNode {
+                san: node.san.as_ref().map(|san| san.to_string()),
+                evaluation: node.evaluation,
+                comment: node.comment.clone(),
+                annotation: node.annotation,
+                repertoire: node.repertoire_for.is_some(),
+                misses: node.misses,
+                lines: Vec::new(),
+                variations: vec![Vec::new(); variations.len()],
+            });
+            next = node.lines.first().copied();
+        }
+        lines.push((moves, place));
+    }
+    // Lines only belong in earlier ones
+    while let Some((moves, place)) = lines.pop() {
+        match place {
+            Some((line, index, slot)) => lines[line].0[index].variations[slot] = moves,
+            None => return moves,
+        }
+    }
+    Vec::new()
+}
+
+/// Plays the main line of a saved game from `position` with the variations of each move, which
+/// wait on a stack rather than recursing.
+fn load_moves(
+    tree: &mut GameTree,
+    moves: &[SavedNode],
+    position: &shakmaty::Chess,
+) -> Result<(), Error> {
+    // Lines with the node they start from, its position and its plies
+    let mut pending = vec![(GameTree::ROOT, position.clone(), moves, 0)];
+    while let Some((mut parent, mut position, moves, mut plies)) = pending.pop() {
+        for saved in moves {
+            check_plies(plies + 1)?;
+            let m = saved_move(saved, &position)?;
+            let id = branch_for_move(tree, parent, position.clone(), &m);
+            let node = tree.node_mut(id);
+            node.evaluation = saved.evaluation;
+            node.annotation = saved.annotation;
+            node.comment = saved.comment.clone();
+            node.repertoire_for = Some(position.turn()).filter(|_| saved.repertoire);
+            node.misses = saved.misses;
+            // Reversed to be added in order
+            for variation in saved.variations.iter().rev() {
+                pending.push((parent, position.clone(), variation, plies));
+            }
+            position.play_unchecked(&m);
+            parent = id;
+            plies += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the lines of `saved` to `parent`, reached at `position`, as saved by version 1. These
+/// recurse, but serde_json bounds their nesting when reading them.
+fn load_lines(
+    tree: &mut GameTree,
+    parent: NodeId,
+    saved: &SavedNode,
+    position: &shakmaty::Chess,
+) -> Result<(), Error> {
+    for child in &saved.lines {
+        let m = saved_move(child, position)?;
+        let mut after = position.clone();
+        after.play_unchecked(&m);
+        let id = branch_for_move(tree, parent, position.clone(), &m);
+        let node = tree.node_mut(id);
+        node.evaluation = child.evaluation;
+        node.comment = child.comment.clone();
+        load_lines(tree, id, child, &after)?;
+    }
+    Ok(())
+}
+
+fn saved_move(saved: &SavedNode, position: &shakmaty::Chess) -> Result<shakmaty::Move, Error> {
+    let san = saved
+        .san
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorType::Parse).with_message("Saved move without its SAN"))?;
+    Ok(san.parse::<SanPlus>()?.san.to_move(position)?)
+}
+
+/// Evaluation then comment of `node`, each in its own block.
+fn write_comments(node: &Node, out: &mut String) {
+    if let Some(evaluation) = node.evaluation {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(&eval_comment(evaluation));
+    }
+    if let Some(comment) = &node.comment {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        // A closing brace would end the block early
+        out.push_str(&format!("{{ {} }}", comment.replace('}', "")));
+    }
+}
+
+/// Evaluation in the lichess format: `{ [%eval 0.25] }` in pawns, `{ [%eval #-3] }` for mates.
+fn eval_comment(evaluation: Evaluation) -> String {
+    match evaluation.score {
+        Score::Cp(cp) => format!("{{ [%eval {:.2}] }}", f64::from(cp) / 100.0),
+        Score::Mate(moves) => format!("{{ [%eval #{}] }}", moves),
+    }
+}
+
+fn shakmaty_position<'a, I>(starting_position: &shakmaty::Chess, line: I) -> shakmaty::Chess
+where
+    I: IntoIterator<Item = &'a SanPlus>,
+{
+    let mut position = starting_position.clone();
+    for san in line {
+        let m = san
+            .san
+            .to_move(&position)
+            .expect("Tried to compute an invalid line");
+        position.play_unchecked(&m);
+    }
+    position
+}
+
+/// SAN of `moves` (UCI) played from `position`, up to the first illegal one.
+pub fn san_line(position: &shakmaty::Chess, moves: &[String]) -> Vec<String> {
+    let mut position = position.clone();
+    let mut line = Vec::new();
+    for uci in moves {
+        let m = match uci_to_move(uci, &position) {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        line.push(SanPlus::from_move_and_play_unchecked(&mut position, &m).to_string());
+    }
+    line
+}
+
+fn uci_to_move(uci: &str, pos: &shakmaty::Chess) -> Result<shakmaty::Move, Error> {
+    let m = uci.parse::<Uci>()?;
+    Ok(m.to_move(pos)?)
+}
+
+/// Move from square `from` to square `to`, as played on a board. A promotion piece given for
+/// another move makes it illegal.
+fn fromto_to_move(
+    from: &str,
+    to: &str,
+    promotion: Option<Promotion>,
+    pos: &shakmaty::Chess,
+) -> Result<shakmaty::Move, Error> {
+    let uci = format!("{}{}", from, to);
+    match promotion {
+        Some(promotion) => uci_to_move(&format!("{}{}", uci, promotion.uci_char()), pos),
+        None => {
+            uci_to_move(&uci, pos).map_err(|err| match uci_to_move(&format!("{}q", uci), pos) {
+                Ok(_) => Error::new(ErrorType::PromotionRequired),
+                Err(_) => err,
+            })
+        }
+    }
+}
+
+/// Every position of `line`, starting position included.
+fn line_positions(starting_position: &shakmaty::Chess, line: &[SanPlus]) -> Vec<shakmaty::Chess> {
+    let mut positions = Vec::with_capacity(line.len() + 1);
+    positions.push(starting_position.clone());
+    for san in line {
+        let last = positions.last().unwrap();
+        positions.push(shakmaty_position(last, std::iter::once(san)));
+    }
+    positions
+}
+
+/// Whether the last of `keys` already occurred twice. Zobrist keys cover the placement, side to
+/// move, castling rights and relevant en passant square, as the rules require.
+fn is_threefold_repetition(keys: &[u64]) -> bool {
+    let current = keys.last().unwrap();
+    keys.iter().filter(|key| *key == current).count() >= 3
+}
+
+fn san_to_move(san: &SanPlus, pos: &shakmaty::Chess) -> Result<shakmaty::Move, Error> {
+    Ok(san.san.to_move(pos)?)
+}
+
+/// Assessment of a move, or of the position it reaches, written as a NAG in PGN.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Annotation {
+    /// `!`
+    Good,
+    /// `?`
+    Mistake,
+    /// `!!`
+    Brilliant,
+    /// `??`
+    Blunder,
+    /// `!?`
+    Interesting,
+    /// `?!`
+    Inaccuracy,
+    /// `=`
+    Equal,
+    /// `⩲`
+    WhiteSlightlyBetter,
+    /// `⩱`
+    BlackSlightlyBetter,
+    /// `±`
+    WhiteBetter,
+    /// `∓`
+    BlackBetter,
+    /// `+-`
+    WhiteWinning,
+    /// `-+`
+    BlackWinning,
+}
+
+const ANNOTATION_NAGS: [(Annotation, u8); 13] = [
+    (Annotation::Good, 1),
+    (Annotation::Mistake, 2),
+    (Annotation::Brilliant, 3),
+    (Annotation::Blunder, 4),
+    (Annotation::Interesting, 5),
+    (Annotation::Inaccuracy, 6),
+    (Annotation::Equal, 10),
+    (Annotation::WhiteSlightlyBetter, 14),
+    (Annotation::BlackSlightlyBetter, 15),
+    (Annotation::WhiteBetter, 16),
+    (Annotation::BlackBetter, 17),
+    (Annotation::WhiteWinning, 18),
+    (Annotation::BlackWinning, 19),
+];
+
+impl Annotation {
+    fn from_nag(nag: u8) -> Option<Annotation> {
+        ANNOTATION_NAGS
+            .iter()
+            .find(|&&(_, code)| code == nag)
+            .map(|&(annotation, _)| annotation)
+    }
+
+    pub fn nag(self) -> u8 {
+        ANNOTATION_NAGS
+            .iter()
+            .find(|&&(annotation, _)| annotation == self)
+            .map(|&(_, code)| code)
+            .expect("every annotation has a NAG")
+    }
+}
+
+/// Highest rating a player can be given.
+const MAX_RATING: u16 = 4000;
+
+/// One side's player, written in the `White`, `WhiteElo` and `WhiteTitle` tags (or Black's).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Player {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u16>,
+    /// As `GM` or `FM`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl Player {
+    /// Player of `color` (`White` or `Black`) named in `headers`. Ratings that aren't numbers,
+    /// like `?`, are left out.
+    fn from_headers(headers: &[(String, String)], color: &str) -> Option<Player> {
+        let tag = |suffix: &str| {
+            let name = format!("{}{}", color, suffix);
+            headers
+                .iter()
+                .find(|(tag, _)| *tag == name)
+                .map(|(_, value)| value.clone())
+        };
+        Some(Player {
+            name: tag("")?,
+            rating: tag("Elo").and_then(|rating| rating.parse().ok()),
+            title: tag("Title").filter(|title| !title.is_empty() && title != "-"),
+        })
+    }
+}
+
+/// Links of the game with lichess.org.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Lichess {
+    /// Set for games played on lichess.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game: Option<LichessGame>,
+    /// Set once the game was pushed to a study.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chapter: Option<LichessChapter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LichessGame {
+    pub game_id: String,
+    /// Rating category: bullet, blitz, chess960, ...
+    pub perf: Option<String>,
+    pub rated: bool,
+    pub white: LichessPlayer,
+    pub black: LichessPlayer,
+}
+
+/// Lichess puzzle. The solution is never serialized, so that representations don't give it away.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PuzzleData {
+    pub puzzle_id: String,
+    /// Lichess game the puzzle comes from.
+    pub game_id: String,
+    pub rating: u32,
+    pub themes: Vec<String>,
+    /// Moves of both sides in UCI notation, from the puzzle's position.
+    #[serde(skip_serializing, default)]
+    pub solution: Vec<String>,
+}
+
+/// Group of games whose navigation is mirrored, see `LinkGames`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub group_id: String,
+    pub mode: LinkMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkMode {
+    /// The other games move as many plies.
+    Ply,
+    /// The other games jump to the position reached, if their tree has it.
+    Position,
+}
+
+/// Piece a pawn promotes to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Promotion {
+    Queen,
+    Rook,
+    Bishop,
+    Knight,
+}
+
+impl Promotion {
+    fn uci_char(self) -> char {
+        match self {
+            Promotion::Queen => 'q',
+            Promotion::Rook => 'r',
+            Promotion::Bishop => 'b',
+            Promotion::Knight => 'n',
+        }
+    }
+}
+
+/// Study chapter holding a copy of the game.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LichessChapter {
+    pub study_id: String,
+    pub chapter_id: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LichessPlayer {
+    pub name: String,
+    pub rating: Option<u32>,
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct GameInfo {
+    players: (Option<Player>, Option<Player>),
+    game_title: String,
+    lichess: Option<Lichess>,
+    result: GameResult,
+    /// Summary of the last completed annotation.
+    accuracy: Option<AccuracyReport>,
+    /// From the `TimeControl` tag of an imported game, or the clock it was played on.
+    time_control: Option<TimeControl>,
+    /// PGN tag pairs of an imported game, in file order.
+    headers: Vec<(String, String)>,
+    /// Database and row the game was opened from.
+    db_id: Option<String>,
+    database_id: Option<i64>,
+}
+
+/// Engine evaluation of a position.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evaluation {
+    pub score: Score,
+    /// Depth of the search which produced the score.
+    pub depth: u32,
+    /// Reported by engines with the `UCI_ShowWDL` option.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wdl: Option<Wdl>,
+}
+
+/// Win, draw and loss probabilities in permille, summing to 1000.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wdl {
+    pub win: u32,
+    pub draw: u32,
+    pub loss: u32,
+}
+
+impl Wdl {
+    /// Scales raw engine statistics (usually already permille) so that they sum to 1000.
+    pub fn normalized(win: u32, draw: u32, loss: u32) -> Option<Wdl> {
+        let (win, draw, loss) = (u64::from(win), u64::from(draw), u64::from(loss));
+        let total = win + draw + loss;
+        if total == 0 {
+            return None;
+        }
+        let win = (win * 1000 + total / 2) / total;
+        // Rounding both up may go past 1000, which the draw then can't make up for
+        let loss = ((loss * 1000 + total / 2) / total).min(1000 - win);
+        Some(Wdl {
+            win: win as u32,
+            draw: (1000 - win - loss) as u32,
+            loss: loss as u32,
+        })
+    }
+
+    /// Same statistics from the other side's point of view.
+    pub fn flipped(self) -> Wdl {
+        Wdl {
+            win: self.loss,
+            draw: self.draw,
+            loss: self.win,
+        }
+    }
+
+    /// Points expected in percent, a draw counting half a win.
+    pub fn expected_score(self) -> f64 {
+        f64::from(2 * self.win + self.draw) / 20.0
+    }
+}
+
+/// Point of view depends on the context: side to move for raw engine output, white once stored in the tree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Score {
+    /// Advantage in centipawns (+100 -> one pawn up).
+    Cp(i32),
+    /// Mate in that many moves, negative when getting mated.
+    Mate(i32),
+}
+
+impl Evaluation {
+    /// Converts an evaluation made from the point of view of `side` to white's point of view.
+    pub fn for_white(self, side: shakmaty::Color) -> Evaluation {
+        if side == shakmaty::Color::White {
+            return self;
+        }
+        let score = match self.score {
+            Score::Cp(cp) => Score::Cp(-cp),
+            Score::Mate(moves) => Score::Mate(-moves),
+        };
+        Evaluation {
+            score,
+            wdl: self.wdl.map(Wdl::flipped),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GameResult {
+    #[serde(rename = "1-0")]
+    WhiteWins,
+    #[serde(rename = "0-1")]
+    BlackWins,
+    #[serde(rename = "1/2-1/2")]
+    Draw,
+    #[serde(rename = "*")]
+    #[default]
+    Unknown,
+}
+
+impl GameResult {
+    /// Value of the PGN `Result` tag.
+    pub fn tag(self) -> &'static str {
+        match self {
+            GameResult::WhiteWins => "1-0",
+            GameResult::BlackWins => "0-1",
+            GameResult::Draw => "1/2-1/2",
+            GameResult::Unknown => "*",
+        }
+    }
+}
+
+/// Reasons for which the rules end a game.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GameOver {
+    Checkmate,
+    Stalemate,
+    InsufficientMaterial,
+    FiftyMoveRule,
+    ThreefoldRepetition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameRepr {
+    pub available_moves: AvailableMoves,
+    pub fen: String,
+    /// SAN of the moves leading to the position.
+    #[serde(default)]
+    pub moves: Vec<String>,
+    /// Length of `moves`, 0 at the starting position.
+    #[serde(default)]
+    pub ply: usize,
+    /// Zobrist key of the position in hexadecimal, as javascript numbers can't hold it.
+    #[serde(default)]
+    pub position_hash: String,
+    pub is_takes: bool,
+    pub is_check: bool,
+    /// Label of the endgame on the board, as `R+P vs R` or `opposite-colored bishops`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endgame: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<AccuracyReport>,
+    #[serde(default)]
+    pub info: GameInfoRepr,
+    /// Moves of the default opening book from the current position.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub book_moves: Vec<BookMove>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockRepr>,
+    /// Moves played from this position are attempts at a puzzle.
+    #[serde(default)]
+    pub solving: bool,
+    /// Moves played from this position are checked against the repertoire, see `DrillStep`.
+    #[serde(default)]
+    pub drilling: bool,
+    /// Repertoire move of the side to move, kept hidden during drills.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repertoire_move: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<Link>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opponent: Option<Opponent>,
+    /// Move that led to the position, written in the session's `MoveFormat`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_move: Option<String>,
+    /// Annotation of `last_move`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_move_annotation: Option<Annotation>,
+    /// Evaluation of the position, from white's point of view.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evaluation: Option<Evaluation>,
+    /// Why the rules end the game in this position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_over: Option<GameOver>,
+    /// Comment of `last_move`, or of the starting position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+/// Version of `SavedGame`, raised whenever older versions can't read it.
+pub const SAVED_GAME_VERSION: u32 = 2;
+
+/// A game as written to disk: its whole tree and what it was imported with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedGame {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    /// Starting position, unless it is the standard one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fen: Option<String>,
+    #[serde(default)]
+    pub chess960: bool,
+    #[serde(default)]
+    pub result: GameResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<AccuracyReport>,
+    /// Starting position. Its lines are the moves played from it up to version 1.
+    pub tree: SavedNode,
+    /// Main line from version 2, whose moves hold their variations: only these nest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moves: Vec<SavedNode>,
+}
+
+/// Tree of a game, see `Request::GetTree`. Moves hold the lines played instead of them, so only
+/// variations nest, however long the main line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TreeRepr {
+    /// Comment and evaluation of the starting position.
+    pub start: SavedNode,
+    /// Main line, the first move played from each position.
+    pub moves: Vec<SavedNode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SavedNode {
+    /// Move reaching the node, `None` for the starting position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub san: Option<String>,
+    /// From white's point of view.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evaluation: Option<Evaluation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<Annotation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Move of the mover's repertoire.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub repertoire: bool,
+    /// Misses of the repertoire move from the position reached, in drills.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub misses: u32,
+    /// Main line first, up to version 1.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lines: Vec<SavedNode>,
+    /// Lines played instead of this move, from version 2.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variations: Vec<Vec<SavedNode>>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Partial change of a game's information, see `Game::update_info`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct GameInfoUpdate {
+    pub title: Option<String>,
+    pub event: Option<String>,
+    pub site: Option<String>,
+    /// As `2020.03.??`: year, month and day with unknown digits written `?`.
+    pub date: Option<String>,
+    pub round: Option<String>,
+    pub result: Option<GameResult>,
+}
+
+/// Fails unless `date` is written `YYYY.MM.DD`, digits or `?`.
+fn check_pgn_date(date: &str) -> Result<(), Error> {
+    let parts: Vec<&str> = date.split('.').collect();
+    let well_formed = parts
+        .iter()
+        .map(|part| part.len())
+        .eq([4, 2, 2].iter().copied())
+        && parts
+            .iter()
+            .all(|part| part.chars().all(|c| c.is_ascii_digit() || c == '?'));
+    if !well_formed {
+        return Err(Error::new(ErrorType::Parse).with_message(&format!(
+            "Dates are written YYYY.MM.DD with ? for unknown digits, not {}",
+            date
+        )));
+    }
+    Ok(())
+}
+
+/// Textual information about the game.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GameInfoRepr {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opening: Option<Opening>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub db_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database_id: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lichess: Option<Lichess>,
+    /// Chapter name of a game imported from a study.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub puzzle: Option<PuzzleData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_control: Option<TimeControl>,
+    #[serde(default)]
+    pub result: GameResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub white: Option<Player>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub black: Option<Player>,
+}
+
+/// Legal moves of a position, serialized by origin square as `{"e2": ["e3", "e4"], ...}`.
+/// Promotions appear once per piece, as they always have.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AvailableMoves(Vec<(Square, Square)>);
+
+impl AvailableMoves {
+    fn of(position: &shakmaty::Chess) -> AvailableMoves {
+        let mut moves: Vec<(Square, Square)> = position
+            .legals()
+            .iter()
+            .filter_map(|m| Some((m.from()?, m.to())))
+            .collect();
+        // Stable, so targets stay in the order moves were generated
+        moves.sort_by_key(|(from, _)| *from);
+        AvailableMoves(moves)
+    }
+}
+
+/// Targets of the moves from one square.
+struct Targets<'a>(&'a [(Square, Square)]);
+
+impl Serialize for Targets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|(_, to)| square_name(*to)))
+    }
+}
+
+impl Serialize for AvailableMoves {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for moves in self.0.chunk_by(|a, b| a.0 == b.0) {
+            map.serialize_entry(square_name(moves[0].0), &Targets(moves))?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AvailableMoves {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let by_origin = HashMap::<String, Vec<String>>::deserialize(deserializer)?;
+        let square = |name: &str| name.parse::<Square>().map_err(D::Error::custom);
+        let mut moves = Vec::new();
+        for (from, targets) in &by_origin {
+            let from = square(from)?;
+            for to in targets {
+                moves.push((from, square(to)?));
+            }
+        }
+        moves.sort_by_key(|(from, _)| *from);
+        Ok(AvailableMoves(moves))
+    }
+}
+
+/// Names of the squares, so that serializing moves doesn't allocate them.
+#[rustfmt::skip]
+const SQUARE_NAMES: [&str; 64] = [
+    "a1", "b1", "c1", "d1", "e1", "f1", "g1", "h1",
+    "a2", "b2", "c2", "d2", "e2", "f2", "g2", "h2",
+    "a3", "b3", "c3", "d3", "e3", "f3", "g3", "h3",
+    "a4", "b4", "c4", "d4", "e4", "f4", "g4", "h4",
+    "a5", "b5", "c5", "d5", "e5", "f5", "g5", "h5",
+    "a6", "b6", "c6", "d6", "e6", "f6", "g6", "h6",
+    "a7", "b7", "c7", "d7", "e7", "f7", "g7", "h7",
+    "a8", "b8", "c8", "d8", "e8", "f8", "g8", "h8",
+];
+
+fn square_name(square: Square) -> &'static str {
+    SQUARE_NAMES[usize::from(square)]
+}
+
+fn fen(pos: &shakmaty::Chess) -> String {
+    shakmaty::fen::fen(pos).to_string()
+}
+
+fn is_takes(maybe_last: Option<(&SanPlus, &shakmaty::Chess)>) -> bool {
+    match maybe_last {
+        None => false,
+        Some((san, pos)) => san_to_move(san, pos).unwrap().is_capture(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the bytes allocated by each test thread.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocated_by(f: impl FnOnce()) -> usize {
+        let before = ALLOCATED.with(Cell::get);
+        f();
+        ALLOCATED.with(Cell::get) - before
+    }
+
+    #[test]
+    fn play_allocations() {
+        let shuffle = [("g1", "f3"), ("g8", "f6"), ("f3", "g1"), ("f6", "g8")];
+        let mut game = Game::default();
+        let replay_last = |game: &mut Game| {
+            let (from, to) = shuffle[(game.line().len() - 1) % shuffle.len()];
+            allocated_by(|| {
+                game.navigate_back(1);
+                game.play(from, to, None).unwrap();
+            })
+        };
+        for ply in 0..20 {
+            let (from, to) = shuffle[ply % shuffle.len()];
+            game.play(from, to, None).unwrap();
+        }
+        let short_line = replay_last(&mut game);
+        for ply in 20..1000 {
+            let (from, to) = shuffle[ply % shuffle.len()];
+            game.play(from, to, None).unwrap();
+        }
+        // Playing a move doesn't copy the line leading to it
+        assert_eq!(replay_last(&mut game), short_line);
+    }
+
+    #[test]
+    fn from_pgn() {
+        let pgn = PgnGame {
+            headers: vec![(String::from("Result"), String::from("1-0"))],
+            movetext: String::from(
+                "1. e4 e5 (1... c5 2. Nf3 (2. c3) d6) 2. Nf3 {Main line} Nc6 $1 1-0",
+            ),
+        };
+        let game = Game::from_pgn(&pgn).unwrap();
+        let sans: Vec<String> = game.main_line().iter().map(|san| san.to_string()).collect();
+        assert_eq!(sans, vec!["e4", "e5", "Nf3", "Nc6"]);
+        assert_eq!(game.line(), game.main_line());
+        assert_eq!(game.result(), GameResult::WhiteWins);
+
+        let tree = &game.game_tree;
+        let after_e4 = tree.node(tree.node(GameTree::ROOT).lines[0]);
+        assert_eq!(after_e4.lines.len(), 2);
+        let sicilian = tree.node(after_e4.lines[1]);
+        assert_eq!(sicilian.lines.len(), 2);
+        assert_eq!(tree.node(sicilian.lines[0]).lines.len(), 1);
+
+        let unbalanced = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from("1. e4 e5) 2. Nf3"),
+        };
+        assert!(Game::from_pgn(&unbalanced).is_err());
+    }
+
+    #[test]
+    fn comments() {
+        let pgn = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from(
+                "{Opening} 1. e4 {Best by test} {really} e5 (1... c5 {Sicilian}) 2. Nf3 *",
+            ),
+        };
+        let game = Game::from_pgn(&pgn).unwrap();
+        let tree = &game.game_tree;
+        let root = tree.node(GameTree::ROOT);
+        assert_eq!(root.comment.as_deref(), Some("Opening"));
+        let e4 = tree.node(root.lines[0]);
+        assert_eq!(e4.comment.as_deref(), Some("Best by test really"));
+        assert_eq!(tree.node(e4.lines[1]).comment.as_deref(), Some("Sicilian"));
+        assert_eq!(
+            game.to_pgn().movetext,
+            "{ Opening } 1. e4 { Best by test really } 1... e5 (1... c5 { Sicilian }) 2. Nf3 *"
+        );
+    }
+
+    #[test]
+    fn update_info() {
+        let pgn = PgnGame {
+            headers: vec![
+                (String::from("Event"), String::from("Casual")),
+                (String::from("Site"), String::from("Paris")),
+            ],
+            movetext: String::from("1. e4 *"),
+        };
+        let mut game = Game::from_pgn(&pgn).unwrap();
+        let update: GameInfoUpdate = serde_json::from_str(
+            r#"{"event": "Club championship", "site": "", "date": "2021.??.??", "result": "1-0"}"#,
+        )
+        .unwrap();
+        game.update_info(update).unwrap();
+        let info = game.get_repr().info;
+        assert_eq!(
+            info.headers,
+            vec![
+                (String::from("Event"), String::from("Club championship")),
+                (String::from("Date"), String::from("2021.??.??")),
+            ]
+        );
+        assert_eq!(info.result, GameResult::WhiteWins);
+        assert_eq!(game.to_pgn().header("Result"), Some("1-0"));
+
+        game.update_info(GameInfoUpdate {
+            title: Some(String::from("Round one")),
+            ..GameInfoUpdate::default()
+        })
+        .unwrap();
+        let info = game.get_repr().info;
+        assert_eq!(info.title, "Round one");
+        assert_eq!(info.headers.len(), 2);
+
+        for date in &["2021.3.01", "2021-03-01", "21.03.01", "2021.03.0x"] {
+            let update = GameInfoUpdate {
+                date: Some(date.to_string()),
+                event: Some(String::from("Changed")),
+                ..GameInfoUpdate::default()
+            };
+            let err = game.update_info(update).unwrap_err();
+            assert!(err.is_type(ErrorType::Parse), "{}", date);
+        }
+        assert_eq!(game.to_pgn().header("Event"), Some("Club championship"));
+    }
+
+    #[test]
+    fn players() {
+        let pgn = PgnGame {
+            headers: vec![
+                (String::from("White"), String::from("Carlsen, Magnus")),
+                (String::from("Black"), String::from("Anonymous")),
+                (String::from("WhiteElo"), String::from("2862")),
+                (String::from("WhiteTitle"), String::from("GM")),
+                (String::from("BlackElo"), String::from("?")),
+            ],
+            movetext: String::from("1. e4 *"),
+        };
+        let mut game = Game::from_pgn(&pgn).unwrap();
+        let info = game.get_repr().info;
+        let carlsen = Player {
+            name: String::from("Carlsen, Magnus"),
+            rating: Some(2862),
+            title: Some(String::from("GM")),
+        };
+        assert_eq!(info.white, Some(carlsen.clone()));
+        assert_eq!(info.black.unwrap().rating, None);
+
+        let black = Player {
+            name: String::from("Nepomniachtchi, Ian"),
+            rating: Some(2782),
+            title: None,
+        };
+        game.set_players(None, Some(black.clone())).unwrap();
+        let exported = game.to_pgn();
+        assert_eq!(exported.header("White"), Some("Carlsen, Magnus"));
+        assert_eq!(exported.header("Black"), Some("Nepomniachtchi, Ian"));
+        assert_eq!(exported.header("BlackElo"), Some("2782"));
+        let info = Game::from_pgn(&exported).unwrap().get_repr().info;
+        assert_eq!(info.white, Some(carlsen));
+        assert_eq!(info.black, Some(black));
+
+        let too_strong = Player {
+            rating: Some(4001),
+            ..Player::default()
+        };
+        let err = game.set_players(Some(too_strong), None).unwrap_err();
+        assert!(err.is_type(ErrorType::Parse));
+    }
+
+    #[test]
+    fn evaluate_current() {
+        let cp = |cp| Evaluation {
+            score: Score::Cp(cp),
+            depth: 0,
+            wdl: None,
+        };
+        let mut game = Game::default();
+        game.evaluate_current(Some(cp(20))).unwrap();
+        game.play_san("e4").unwrap();
+        game.evaluate_current(Some(cp(35))).unwrap();
+        game.play_san("e5").unwrap();
+        assert_eq!(game.get_repr().evaluation, None);
+
+        game.navigate_back(1);
+        assert_eq!(game.get_repr().evaluation, Some(cp(35)));
+        game.navigate_to_start();
+        assert_eq!(game.get_repr().evaluation, Some(cp(20)));
+        game.evaluate_current(None).unwrap();
+        assert_eq!(game.get_repr().evaluation, None);
+        game.navigate_forward(1);
+        assert_eq!(game.get_repr().evaluation, Some(cp(35)));
+
+        let err = game.evaluate_current(Some(cp(-40_000))).unwrap_err();
+        assert!(err.is_type(ErrorType::Parse));
+        let wdl = Evaluation {
+            wdl: Some(Wdl {
+                win: 500,
+                draw: 0,
+                loss: 0,
+            }),
+            ..cp(0)
+        };
+        assert!(game.evaluate_current(Some(wdl)).is_err());
+        assert_eq!(game.get_repr().evaluation, Some(cp(35)));
+    }
+
+    #[test]
+    fn annotate_current() {
+        let mut game = Game::default();
+        assert!(game.annotate_current(Some(Annotation::Good)).is_err());
+        game.play_san("e4").unwrap();
+        game.annotate_current(Some(Annotation::WhiteBetter))
+            .unwrap();
+        let repr = serde_json::to_value(game.get_repr()).unwrap();
+        assert_eq!(repr["last_move_annotation"], "white_better");
+        assert_eq!(game.to_pgn().movetext, "1. e4 $16 *");
+        game.annotate_current(None).unwrap();
+        assert_eq!(game.get_repr().last_move_annotation, None);
+    }
+
+    #[test]
+    fn set_comment() {
+        let mut game = Game::default();
+        game.play_san("e4").unwrap();
+        game.set_comment(String::from("Best by test")).unwrap();
+        assert_eq!(game.get_repr().comment.as_deref(), Some("Best by test"));
+        game.set_comment(String::from("Replaced")).unwrap();
+        game.play_san("e5").unwrap();
+        assert_eq!(game.get_repr().comment, None);
+        assert_eq!(game.to_pgn().movetext, "1. e4 { Replaced } 1... e5 *");
+
+        game.navigate_back(1);
+        game.set_comment(String::new()).unwrap();
+        assert_eq!(game.to_pgn().movetext, "1. e4 e5 *");
+
+        let long = "a".repeat(MAX_COMMENT_BYTES + 1);
+        let err = game.set_comment(long.clone()).unwrap_err();
+        assert!(err.is_type(ErrorType::LimitExceeded));
+        let pgn = PgnGame {
+            headers: Vec::new(),
+            movetext: format!("1. e4 {{{}}} *", long),
+        };
+        assert!(Game::from_pgn(&pgn).is_err());
+    }
+
+    #[test]
+    fn tree_repr() {
+        let pgn = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from(
+                "{Start} 1. e4 e5 (1... c5 $1 2. Nf3 (2. c3 d5) 2... d6) (1... e6) 2. Nf3 *",
+            ),
+        };
+        let game = Game::from_pgn(&pgn).unwrap();
+        let tree = serde_json::to_value(game.tree_repr()).unwrap();
+        assert_eq!(tree["start"]["comment"], "Start");
+        let moves = tree["moves"].as_array().unwrap();
+        let sans = |line: &serde_json::Value| -> Vec<String> {
+            let line = line.as_array().unwrap();
+            line.iter()
+                .map(|node| node["san"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(sans(&tree["moves"]), ["e4", "e5", "Nf3"]);
+        // The sidelines of e5, in order
+        let variations = moves[1]["variations"].as_array().unwrap();
+        assert_eq!(variations.len(), 2);
+        assert_eq!(sans(&variations[0]), ["c5", "Nf3", "d6"]);
+        assert_eq!(variations[0][0]["annotation"], "good");
+        assert_eq!(sans(&variations[1]), ["e6"]);
+        let nested = variations[0][1]["variations"].as_array().unwrap();
+        assert_eq!(nested.len(), 1);
+        assert_eq!(sans(&nested[0]), ["c3", "d5"]);
+        assert!(moves[0].get("variations").is_none());
     }
 
-    fn find_or_create_branch(
-        &mut self,
-        from: &String,
-        to: &String,
-        line: &Vec<SanPlus>,
-    ) -> Result<SanPlus, Error> {
-        let branch = traverse_down(&mut self.game_tree, line.as_slice())?;
-        let pos = shakmaty_position(&self.initial_position, line);
-        let mov = fromto_to_move(from, to, &pos)?;
-        let san = SanPlus::from_move(pos, &mov);
-
-        let existing_branch = branch
-            .lines
-            .iter()
-            .position(|elem| elem.san.as_ref() == Some(&san));
+    #[test]
+    fn saved() {
+        let pgn = PgnGame {
+            headers: vec![
+                (String::from("White"), String::from("Alice")),
+                (String::from("Result"), String::from("0-1")),
+            ],
+            movetext: String::from("1. e4 {Main} e5 (1... c5 2. Nf3 (2. c3)) 2. Qh5?! Nc6 0-1"),
+        };
+        let mut game = Game::from_pgn(&pgn).unwrap();
+        let evaluation = Evaluation {
+            score: Score::Mate(-3),
+            depth: 12,
+            wdl: None,
+        };
+        game.set_evaluation(&game.main_line(), evaluation).unwrap();
 
-        if existing_branch.is_none() {
-            insert_branch(&mut branch.lines, san.clone());
-        }
-        Ok(san)
+        let saved = game.to_saved();
+        assert_eq!(saved.version, SAVED_GAME_VERSION);
+        assert_eq!(saved.fen, None);
+        let json = serde_json::to_string(&saved).unwrap();
+        let loaded: SavedGame = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, saved);
+        let loaded = Game::from_saved(&loaded).unwrap();
+        assert_eq!(loaded.to_pgn(), game.to_pgn());
+        assert!(loaded.to_pgn().movetext.contains("2. Qh5 $6 Nc6"));
+        assert_eq!(loaded.line(), game.main_line());
+
+        let endgame = Game::from_fen(String::from("8/8/8/4k3/8/8/4P3/4K3 b - - 0 40")).unwrap();
+        let saved = endgame.to_saved();
+        assert_eq!(saved.fen, Some(endgame.initial_fen()));
+        assert_eq!(
+            Game::from_saved(&saved).unwrap().initial_fen(),
+            endgame.initial_fen()
+        );
+
+        let mut newer = game.to_saved();
+        newer.version = SAVED_GAME_VERSION + 1;
+        assert!(Game::from_saved(&newer).is_err());
+        let mut illegal = game.to_saved();
+        illegal.moves[0].san = Some(String::from("e5"));
+        assert!(Game::from_saved(&illegal).is_err());
     }
 
-    pub fn navigate_back(&mut self, back: u16) {
-        let new_length = self.current_line.len().saturating_sub(back as usize);
-        self.current_line.truncate(new_length);
+    #[test]
+    fn to_pgn_accuracy() {
+        let mut game = Game::default();
+        let mut report = AccuracyReport::default();
+        report.white.accuracy = Some(91.5);
+        report.white.average_centipawn_loss = Some(18);
+        game.set_accuracy(report);
+
+        let exported = game.to_pgn();
+        assert_eq!(exported.header("WhiteAccuracy"), Some("91.5"));
+        assert_eq!(exported.header("WhiteACPL"), Some("18"));
+        assert_eq!(exported.header("BlackAccuracy"), None);
     }
 
-    pub fn get_repr(&self) -> GameRepr {
-        let (maybe_last, current_position) = last_and_current_position(self);
-        GameRepr {
-            available_moves: available_moves(&current_position),
-            fen: fen(&current_position),
-            is_takes: is_takes(maybe_last),
-            is_check: current_position.is_check(),
-        }
+    #[test]
+    fn to_pgn() {
+        let pgn = PgnGame {
+            headers: vec![
+                (String::from("White"), String::from("Alice")),
+                (String::from("Result"), String::from("1-0")),
+            ],
+            movetext: String::from(
+                "1. e4 e5 (1... c5 2. Nf3 (2. c3) d6) 2. Nf3! {Main line} Nc6 $146 $16 1-0",
+            ),
+        };
+        let mut game = Game::from_pgn(&pgn).unwrap();
+        let e4 = game.main_line()[..1].to_vec();
+        let evaluation = Evaluation {
+            score: Score::Cp(25),
+            depth: 20,
+            wdl: None,
+        };
+        game.set_evaluation(&e4, evaluation).unwrap();
+
+        let exported = game.to_pgn();
+        assert_eq!(
+            exported.movetext,
+            "1. e4 { [%eval 0.25] } 1... e5 (1... c5 2. Nf3 (2. c3) 2... d6) 2. Nf3 $1 { Main line } 2... Nc6 $16 1-0"
+        );
+        assert_eq!(exported.header("White"), Some("Alice"));
+        assert_eq!(exported.header("Result"), Some("1-0"));
+        assert_eq!(
+            Game::from_pgn(&exported).unwrap().main_line(),
+            game.main_line()
+        );
+
+        let endgame = Game::from_fen(String::from("8/8/8/4k3/8/8/4P3/4K3 b - - 0 40")).unwrap();
+        let exported = endgame.to_pgn();
+        assert_eq!(exported.header("SetUp"), Some("1"));
+        assert_eq!(exported.movetext, "*");
     }
 
-    pub fn from_fen(fen_string: String) -> Result<Game, Error> {
+    #[test]
+    fn play() {
         let mut game = Game::default();
-        let setup: shakmaty::fen::Fen = fen_string.parse()?;
-        game.initial_position = setup.position()?;
-        Ok(game)
+
+        // Scholar's mate
+        for (from, to) in &[
+            ("e2", "e4"),
+            ("e7", "e5"),
+            ("f1", "c4"),
+            ("b8", "c6"),
+            ("d1", "h5"),
+            ("g8", "f6"),
+            ("h5", "f7"),
+        ] {
+            game.play(from, to, None).unwrap();
+        }
+        let current_pos = game.current_position();
+        assert!(current_pos.is_checkmate());
+        assert_eq!(
+            &*fen(&current_pos),
+            "r1bqkb1r/pppp1Qpp/2n2n2/4p3/2B1P3/8/PPPP1PPP/RNB1K1NR b KQkq - 0 4"
+        )
     }
 
-    pub fn current_position(&self) -> shakmaty::Chess {
-        shakmaty_position(&self.initial_position, &self.current_line)
+    #[test]
+    fn play_promotions() {
+        let fen = "3r3k/4P3/8/8/8/8/8/4K3 w - - 0 1";
+        let played = |from: &str, to: &str, promotion: Option<Promotion>| {
+            let mut game = Game::from_fen(String::from(fen)).unwrap();
+            game.play(from, to, promotion)
+                .map(|_| game.game_tree.san(game.current_node).to_string())
+        };
+        assert_eq!(played("e7", "e8", Some(Promotion::Knight)).unwrap(), "e8=N");
+        assert_eq!(
+            played("e7", "d8", Some(Promotion::Rook)).unwrap(),
+            "exd8=R+"
+        );
+        assert_eq!(
+            played("e7", "d8", Some(Promotion::Queen)).unwrap(),
+            "exd8=Q+"
+        );
+        let error_type = |result: Result<String, Error>| result.unwrap_err().error_type;
+        assert_eq!(
+            error_type(played("e7", "e8", None)),
+            ErrorType::PromotionRequired
+        );
+        assert_eq!(error_type(played("e7", "f8", None)), ErrorType::ChessRules);
+        assert_eq!(
+            error_type(played("e1", "e2", Some(Promotion::Queen))),
+            ErrorType::ChessRules
+        );
+        assert_eq!(played("e1", "e2", None).unwrap(), "Ke2");
     }
 
-    pub fn current_fen(&self) -> String {
-        fen(&self.current_position())
+    /// The recursive tree games were stored in before the arena.
+    #[derive(Default)]
+    struct RecursiveTree {
+        san: Option<SanPlus>,
+        lines: Vec<RecursiveTree>,
     }
-}
 
-fn traverse_down<'a>(tree: &'a mut GameTree, line: &[SanPlus]) -> Result<&'a mut GameTree, Error> {
-    match line.split_first() {
-        None => Ok(tree),
-        Some((san, tail)) => {
-            let child = tree
+    impl RecursiveTree {
+        fn play(&mut self, line: &[SanPlus], san: SanPlus) {
+            let mut node = self;
+            for played in line {
+                node = node
+                    .lines
+                    .iter_mut()
+                    .find(|child| child.san.as_ref() == Some(played))
+                    .unwrap();
+            }
+            if !node
                 .lines
-                .iter_mut()
-                .find(|pos| pos.san.as_ref() == Some(san));
-            match child {
-                None => Err(Error::new(ErrorType::ChessRules)),
-                Some(game) => traverse_down(game, tail),
+                .iter()
+                .any(|child| child.san.as_ref() == Some(&san))
+            {
+                node.lines.push(RecursiveTree {
+                    san: Some(san),
+                    lines: Vec::new(),
+                });
             }
         }
-    }
-}
 
-fn shakmaty_position<'a, I>(starting_position: &shakmaty::Chess, line: I) -> shakmaty::Chess
-where
-    I: IntoIterator<Item = &'a SanPlus>,
-{
-    let mut position = starting_position.clone();
-    for san in line {
-        let m = san
-            .san
-            .to_move(&position)
-            .expect("Tried to compute an invalid line");
-        position.play_unchecked(&m);
+        fn save(&self) -> SavedNode {
+            SavedNode {
+                san: self.san.as_ref().map(SanPlus::to_string),
+                lines: self.lines.iter().map(RecursiveTree::save).collect(),
+                ..SavedNode::default()
+            }
+        }
     }
-    position
-}
-
-fn fromto_to_move(
-    from: &String,
-    to: &String,
-    pos: &shakmaty::Chess,
-) -> Result<shakmaty::Move, Error> {
-    let m = format!("{}{}", from, to).parse::<Uci>()?;
-    Ok(m.to_move(pos)?)
-}
 
-fn san_to_move(san: &SanPlus, pos: &shakmaty::Chess) -> Result<shakmaty::Move, Error> {
-    Ok(san.san.to_move(pos)?)
-}
+    #[test]
+    fn arena_matches_recursive_tree() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
 
-fn insert_branch(vec: &mut Vec<GameTree>, san: SanPlus) {
-    vec.push(GameTree {
-        san: Some(san),
-        lines: Vec::new(),
-        annotation: None,
-        evaluation: None,
-    });
-}
+        let mut rng = StdRng::seed_from_u64(1237);
+        for _ in 0..20 {
+            let mut game = Game::default();
+            let mut reference = RecursiveTree::default();
+            let mut line = Vec::new();
+            for _ in 0..300 {
+                let position = game.current_position();
+                let legals = position.legals();
+                if legals.is_empty() || rng.gen_bool(0.3) {
+                    let back = rng.gen_range(1, 6);
+                    game.navigate_back(back);
+                    line.truncate(line.len().saturating_sub(usize::from(back)));
+                } else {
+                    let m = &legals[rng.gen_range(0, legals.len())];
+                    let uci = Uci::from_move(&position, m).to_string();
+                    game.play_uci(&uci).unwrap();
+                    let san = SanPlus::from_move(position, m);
+                    reference.play(&line, san.clone());
+                    line.push(san);
+                }
+                assert_eq!(game.line(), line);
+            }
 
-// TODO
-#[derive(Debug)]
-enum Annotation {}
+            let saved = game.to_saved();
+            let version_1 = SavedGame {
+                version: 1,
+                tree: reference.save(),
+                moves: Vec::new(),
+                ..saved.clone()
+            };
+            assert_eq!(Game::from_saved(&version_1).unwrap().to_saved(), saved);
+            let loaded = Game::from_saved(&saved).unwrap();
+            assert_eq!(loaded.to_saved(), saved);
+            assert_eq!(loaded.line(), loaded.main_line());
+            let from_pgn = Game::from_pgn(&game.to_pgn()).unwrap();
+            assert_eq!(from_pgn.to_saved().moves, saved.moves);
+        }
+    }
 
-// TODO
-#[derive(Default, Debug, PartialEq, Eq)]
-struct Player {}
+    #[test]
+    fn deep_lines() {
+        // Knights going back and forth, added to the tree past the limit of played lines
+        let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+        let line: Vec<SanPlus> = (0..20_000)
+            .map(|ply| shuffle[ply % 4].parse().unwrap())
+            .collect();
+        let mut game = Game::default();
+        let mut node = GameTree::ROOT;
+        for san in &line {
+            node = game.game_tree.add_line(node, san.clone());
+        }
+        game.set_line(line.clone()).unwrap();
+        game.opening = game.classify_opening();
+        let error = game.play_san("Nf3").unwrap_err();
+        assert_eq!(error.error_type, ErrorType::LimitExceeded);
 
-// TODO
-#[derive(Default, Debug, PartialEq, Eq)]
-struct Lichess {}
+        game.navigate_back(19_999);
+        assert_eq!(game.line().len(), 1);
+        assert_eq!(game.get_repr().fen, game.current_fen());
+        let pgn = game.to_pgn();
+        assert!(pgn.movetext.ends_with("10000. Ng1 Ng8 *"));
+        let saved = game.to_saved();
+        let json = serde_json::to_string(&saved).unwrap();
+        assert_eq!(serde_json::from_str::<SavedGame>(&json).unwrap(), saved);
 
-#[derive(Default, Debug, PartialEq, Eq)]
-struct GameInfo {
-    players: (Option<Player>, Option<Player>),
-    game_title: String,
-    lichess: Option<Lichess>,
-}
+        let error = Game::from_pgn(&pgn).unwrap_err();
+        assert_eq!(error.error_type, ErrorType::LimitExceeded);
+        let error = Game::from_saved(&saved).unwrap_err();
+        assert_eq!(error.error_type, ErrorType::LimitExceeded);
+        let mut within = saved;
+        within.moves.truncate(DEFAULT_MAX_PLIES);
+        assert_eq!(
+            Game::from_saved(&within).unwrap().line(),
+            &line[..DEFAULT_MAX_PLIES]
+        );
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GameRepr {
-    pub available_moves: HashMap<String, Vec<String>>,
-    pub fen: String,
-    pub is_takes: bool,
-    pub is_check: bool,
-}
+    #[test]
+    fn fen_memo() {
+        let mut game = Game::default();
+        for uci in &["g1f3", "g8f6", "b1c3"] {
+            game.play_uci(uci).unwrap();
+        }
+        let node = game.current_node;
+        assert!(game.game_tree.node(node).fen.get().is_none());
+        let repr = game.get_repr();
+        assert_eq!(game.game_tree.node(node).fen.get(), Some(&repr.fen));
 
-fn last_and_current_position(game: &Game) -> (Option<(SanPlus, shakmaty::Chess)>, shakmaty::Chess) {
-    match game.current_line.split_last() {
-        Some((last_move, line)) => {
-            let last_pos = shakmaty_position(&game.initial_position, line);
-            let current_pos = shakmaty_position(&last_pos, std::iter::once(last_move));
-            (Some((last_move.clone(), last_pos)), current_pos)
+        // Replayed moves reach the same node, transpositions their own
+        game.navigate_back(3);
+        for uci in &["g1f3", "g8f6", "b1c3"] {
+            game.play_uci(uci).unwrap();
         }
-        None => (None, game.initial_position.clone()),
+        assert_eq!(game.current_node, node);
+        assert_eq!(game.current_fen(), repr.fen);
+        game.navigate_back(3);
+        for uci in &["b1c3", "g8f6", "g1f3"] {
+            game.play_uci(uci).unwrap();
+        }
+        assert_ne!(game.current_node, node);
+        assert!(game.game_tree.node(game.current_node).fen.get().is_none());
+        assert_eq!(game.get_repr().fen, repr.fen);
     }
-}
 
-fn available_moves(position: &shakmaty::Chess) -> HashMap<String, Vec<String>> {
-    let mut map = HashMap::with_capacity(32);
-    let legal_moves = position.legals();
-    for m in legal_moves {
-        insert_move(&m, &mut map);
-    }
-    map
-}
+    #[test]
+    fn clock() {
+        let mut game = Game::default();
+        let time_control = TimeControl::Fischer {
+            base_ms: 60_000,
+            increment_ms: 2000,
+        };
+        game.start_clock(time_control, 0);
+        let clock = |game: &Game| game.get_repr().clock.unwrap();
+        game.play_timed("e2", "e4", None, 5000).unwrap();
+        game.play_timed("e7", "e5", None, 8000).unwrap();
+        assert_eq!(
+            (clock(&game).white_ms, clock(&game).black_ms),
+            (57_000, 59_000)
+        );
+        assert_eq!(clock(&game).running.as_deref(), Some("white"));
 
-fn insert_move(chess_move: &shakmaty::Move, map: &mut HashMap<String, Vec<String>>) {
-    let from = chess_move.from().unwrap().to_string();
-    let to = chess_move.to().to_string();
-    insert_from_to(from, to, map);
-}
+        // Paused away from the played position, running again once back
+        game.navigate_back(1);
+        game.sync_clock(9000);
+        assert_eq!(clock(&game).running, None);
+        game.tick_clock(50_000);
+        game.play_timed("e7", "e5", None, 60_000).unwrap();
+        assert_eq!(clock(&game).white_ms, 56_000);
+        assert_eq!(clock(&game).since_ms, Some(60_000));
+        game.play_timed("g1", "f3", None, 70_000).unwrap();
+        assert_eq!(clock(&game).white_ms, 48_000);
+        assert_eq!(game.result(), GameResult::Unknown);
 
-fn insert_from_to(from: String, to: String, map: &mut HashMap<String, Vec<String>>) {
-    match map.get_mut(&from) {
-        None => {
-            let mut vec = Vec::with_capacity(30);
-            vec.push(to);
-            map.insert(from, vec);
+        game.tick_clock(130_000);
+        assert_eq!(clock(&game).black_ms, 0);
+        assert_eq!(clock(&game).flagged.as_deref(), Some("black"));
+        assert_eq!(game.result(), GameResult::WhiteWins);
+        let json = serde_json::to_value(game.get_repr()).unwrap();
+        assert_eq!(json["clock"]["time_control"]["type"], "fischer");
+        assert_eq!(json["info"]["time_control"]["increment_ms"], 2000);
+
+        // No mating material left to win on time with
+        let fen = String::from("4k3/8/8/8/8/8/8/4K2Q w - - 0 1");
+        let mut game = Game::from_fen(fen).unwrap();
+        game.start_clock(time_control, 0);
+        game.play_timed("h1", "h5", None, 61_000).unwrap();
+        assert_eq!(clock(&game).flagged.as_deref(), Some("white"));
+        assert_eq!(game.result(), GameResult::Draw);
+    }
+
+    #[test]
+    fn available_moves_json() {
+        // Castling, en passant, promotions and a pinned piece
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "3r4/1P3k2/8/8/8/8/4B3/4K3 w - - 0 1",
+        ];
+        for fen_string in &fens {
+            let position: shakmaty::Chess = fen_string
+                .parse::<shakmaty::fen::Fen>()
+                .unwrap()
+                .position()
+                .unwrap();
+            // The map of strings available moves were sent as before
+            let mut expected: HashMap<String, Vec<String>> = HashMap::new();
+            for m in &position.legals() {
+                expected
+                    .entry(m.from().unwrap().to_string())
+                    .or_default()
+                    .push(m.to().to_string());
+            }
+            let moves = AvailableMoves::of(&position);
+            let json = serde_json::to_value(&moves).unwrap();
+            assert_eq!(json, serde_json::to_value(&expected).unwrap());
+            let parsed: AvailableMoves = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed, moves);
         }
-        Some(vec) => {
-            vec.push(to);
+        let json = serde_json::json!({"e2": ["e9"]});
+        assert!(serde_json::from_value::<AvailableMoves>(json).is_err());
+    }
+
+    #[test]
+    fn cached_positions() {
+        // Reprs check the cache against a replay of the line
+        let mut game = Game::default();
+        for san in &["e4", "e5", "Nf3", "Nc6", "Bb5"] {
+            game.play_san(san).unwrap();
+            game.get_repr();
         }
-    };
-}
+        assert_eq!(game.get_repr().info.opening.unwrap().name, "Ruy Lopez");
 
-fn fen(pos: &shakmaty::Chess) -> String {
-    shakmaty::fen::fen(pos).to_string()
-}
+        game.navigate_back(3);
+        assert_eq!(game.line().len(), 2);
+        game.play("f1", "c4", None).unwrap();
+        let repr = game.get_repr();
+        assert!(!repr.is_takes);
+        // A sideline leaves the opening of the main line
+        assert_eq!(repr.info.opening.unwrap().name, "Ruy Lopez");
 
-fn is_takes(maybe_last: Option<(SanPlus, shakmaty::Chess)>) -> bool {
-    match maybe_last {
-        None => false,
-        Some((san, pos)) => san_to_move(&san, &pos).unwrap().is_capture(),
+        game.navigate_back(10);
+        assert_eq!(game.get_repr().fen, game.initial_fen());
+        game.play("d2", "d4", None).unwrap();
+        game.play("e7", "e5", None).unwrap();
+        game.play("d4", "e5", None).unwrap();
+        let repr = game.get_repr();
+        assert!(repr.is_takes);
+        let key = hash::zobrist(&game.current_position());
+        assert_eq!(repr.position_hash, format!("{:016x}", key));
+        assert_eq!(game.game_over(), None);
+
+        let game = Game::from_saved(&game.to_saved()).unwrap();
+        assert_eq!(game.line().len(), 5);
+        assert_eq!(game.get_repr().info.opening.unwrap().name, "Ruy Lopez");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn play() {
+    fn san_errors() {
         let mut game = Game::default();
-
-        // Scholar's mate
-        for (from, to) in &[
-            ("e2", "e4"),
-            ("e7", "e5"),
-            ("f1", "c4"),
-            ("b8", "c6"),
-            ("d1", "h5"),
-            ("g8", "f6"),
-            ("h5", "f7"),
-        ] {
-            game.play(&String::from(from.to_owned()), &String::from(to.to_owned()))
-                .unwrap();
+        for san in &["d4", "d5", "Nf3", "Nc6"] {
+            game.play_san(san).unwrap();
         }
-        let (_, current_pos) = last_and_current_position(&game);
-        assert!(current_pos.is_checkmate());
-        assert_eq!(
-            &*fen(&current_pos),
-            "r1bqkb1r/pppp1Qpp/2n2n2/4p3/2B1P3/8/PPPP1PPP/RNB1K1NR b KQkq - 0 4"
-        )
+        let fen = game.current_fen();
+        let error_type = |game: &mut Game, san| game.play_san(san).unwrap_err().error_type;
+        assert_eq!(error_type(&mut game, "not a move"), ErrorType::Notation);
+        assert_eq!(error_type(&mut game, "Nd2"), ErrorType::Ambiguous);
+        assert_eq!(error_type(&mut game, "Qxh7"), ErrorType::ChessRules);
+        assert_eq!(game.current_fen(), fen);
+        game.play_san("Nbd2").unwrap();
     }
 
     #[test]
@@ -268,31 +3033,30 @@ mod tests {
             "Rd8", "Rxd7", "Rxd7", "Rd1", "Qe6", "Bxd7+", "Nxd7", "Qb8+", "Nxb8", "Rd8#",
         ];
         for san in opera_game {
-            let parsed: SanPlus = san.parse().unwrap();
-            game.play_san(String::from(parsed.san.to_string())).unwrap();
+            game.play_san(san).unwrap();
         }
 
         assert_eq!(
-            (&game).current_fen(),
+            game.current_fen(),
             "1n1Rkb1r/p4ppp/4q3/4p1B1/4P3/8/PPP2PPP/2K5 b k - 1 17"
         );
 
         // Should not change position
         game.navigate_back(0);
         assert_eq!(
-            (&game).current_fen(),
+            game.current_fen(),
             "1n1Rkb1r/p4ppp/4q3/4p1B1/4P3/8/PPP2PPP/2K5 b k - 1 17"
         );
 
         game.navigate_back(1);
         assert_eq!(
-            (&game).current_fen(),
+            game.current_fen(),
             "1n2kb1r/p4ppp/4q3/4p1B1/4P3/8/PPP2PPP/2KR4 w k - 0 17"
         );
 
         game.navigate_back(5);
         assert_eq!(
-            (&game).current_fen(),
+            game.current_fen(),
             "4kb1r/p2rqppp/5n2/1B2p1B1/4P3/1Q6/PPP2PPP/2KR4 b k - 1 14"
         );
 
@@ -300,6 +3064,253 @@ mod tests {
         assert_eq!(game.current_fen(), Game::default().current_fen());
     }
 
+    #[test]
+    fn navigate_forward() {
+        let mut game = Game::default();
+        for san in &["e4", "e5", "Nf3", "Nc6"] {
+            game.play_san(san).unwrap();
+        }
+        game.navigate_back(3);
+        // Sidelines don't change which continuation is followed
+        game.play_san("c5").unwrap();
+        game.play_san("Nf3").unwrap();
+        game.navigate_back(2);
+
+        game.navigate_forward(2);
+        assert_eq!(game.line(), game.main_line()[..3].to_vec());
+        game.navigate_forward(0);
+        assert_eq!(game.line().len(), 3);
+        game.navigate_forward(100);
+        assert_eq!(game.line(), game.main_line());
+        assert_eq!(game.position_hash(), hash::zobrist(game.cached_position()));
+
+        // From a sideline, its own continuation
+        game.navigate_back(3);
+        game.play_san("c5").unwrap();
+        game.navigate_forward(5);
+        let line: Vec<String> = game.line().iter().map(ToString::to_string).collect();
+        assert_eq!(line, ["e4", "c5", "Nf3"]);
+    }
+
+    #[test]
+    fn navigate_to() {
+        let mut game = Game::default();
+        for san in &["e4", "e5", "Nf3", "Nc6"] {
+            game.play_san(san).unwrap();
+        }
+        game.navigate_to(1);
+        game.play_san("c5").unwrap();
+
+        game.navigate_to(0);
+        assert!(game.line().is_empty());
+        game.navigate_to(3);
+        assert_eq!(game.line(), game.main_line()[..3].to_vec());
+        assert_eq!(game.position_hash(), hash::zobrist(game.cached_position()));
+        game.navigate_to(100);
+        assert_eq!(game.line(), game.main_line());
+        game.navigate_to(2);
+        assert_eq!(game.line(), game.main_line()[..2].to_vec());
+
+        // Within a sideline, jumps stay in it
+        game.navigate_to(1);
+        game.play_san("c5").unwrap();
+        game.play_san("Nf3").unwrap();
+        game.navigate_to(2);
+        let line: Vec<String> = game.line().iter().map(ToString::to_string).collect();
+        assert_eq!(line, ["e4", "c5"]);
+        game.navigate_to(10);
+        assert_eq!(game.line().len(), 3);
+    }
+
+    #[test]
+    fn truncate_from_current() {
+        let pgn = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from("1. e4 e5 (1... c5 2. Nf3) 2. Nf3 Nc6 *"),
+        };
+        let mut game = Game::from_pgn(&pgn).unwrap();
+        game.navigate_to(2);
+        game.truncate_from_current();
+        assert_eq!(game.line().len(), 2);
+        game.navigate_to_end();
+        assert_eq!(game.line().len(), 2);
+        assert_eq!(game.to_pgn().movetext, "1. e4 e5 (1... c5 2. Nf3) *");
+
+        game.navigate_to(1);
+        game.truncate_from_current();
+        assert_eq!(game.to_pgn().movetext, "1. e4 *");
+        game.navigate_to_start();
+        game.truncate_from_current();
+        assert!(game.main_line().is_empty());
+        assert_eq!(game.to_pgn().movetext, "*");
+        game.play_san("d4").unwrap();
+        assert_eq!(game.to_pgn().movetext, "1. d4 *");
+
+        // Removed nodes don't pile up in the arena
+        for _ in 0..3 {
+            game.play_san("d5").unwrap();
+            game.play_san("c4").unwrap();
+            game.navigate_back(2);
+            game.truncate_from_current();
+        }
+        assert_eq!(game.game_tree.nodes.len(), 2);
+        game.play_san("Nf6").unwrap();
+        assert_eq!(game.to_pgn().movetext, "1. d4 Nf6 *");
+
+        // Nor does the result of the removed end
+        for san in &["c4", "e6", "Nc3", "Bb4"] {
+            game.play_san(san).unwrap();
+        }
+        game.set_result(GameResult::WhiteWins);
+        game.navigate_back(1);
+        game.truncate_from_current();
+        assert_eq!(game.result(), GameResult::Unknown);
+    }
+
+    #[test]
+    fn navigate_to_end() {
+        let mut game = Game::default();
+        for san in &["e4", "e5", "Nf3"] {
+            game.play_san(san).unwrap();
+        }
+        // The latest line is a sideline, the main one is still followed
+        game.navigate_back(2);
+        game.play_san("c5").unwrap();
+        game.play_san("Nf3").unwrap();
+        game.navigate_to(1);
+
+        game.navigate_to_end();
+        assert_eq!(game.line(), game.main_line());
+        assert_eq!(game.position_hash(), hash::zobrist(game.cached_position()));
+        // Already at the end
+        game.navigate_to_end();
+        assert_eq!(game.line(), game.main_line());
+    }
+
+    #[test]
+    fn game_over() {
+        let mut game = Game::default();
+        assert_eq!(game.game_over(), None);
+
+        // Knights going back and forth: the starting position occurs for the third time
+        for uci in &["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1"] {
+            game.play_uci(uci).unwrap();
+            assert_eq!(game.game_over(), None);
+        }
+        game.play_uci("f6g8").unwrap();
+        assert_eq!(game.game_over(), Some(GameOver::ThreefoldRepetition));
+        assert_eq!(
+            game.game_over_result(GameOver::ThreefoldRepetition),
+            GameResult::Draw
+        );
+
+        // Fool's mate
+        let mut game = Game::default();
+        for uci in &["f2f3", "e7e5", "g2g4", "d8h4"] {
+            game.play_uci(uci).unwrap();
+        }
+        assert_eq!(game.game_over(), Some(GameOver::Checkmate));
+        assert_eq!(
+            game.game_over_result(GameOver::Checkmate),
+            GameResult::BlackWins
+        );
+    }
+
+    #[test]
+    fn repr_moves() {
+        let mut game = Game::default();
+        let repr = serde_json::to_value(game.get_repr()).unwrap();
+        assert_eq!(repr["moves"], serde_json::json!([]));
+        assert_eq!(repr["ply"], 0);
+
+        for san in &["e4", "e5", "Nf3", "Nc6", "Bb5"] {
+            game.play_san(san).unwrap();
+        }
+        game.navigate_back(2);
+        let repr = serde_json::to_value(game.get_repr()).unwrap();
+        assert_eq!(repr["moves"], serde_json::json!(["e4", "e5", "Nf3"]));
+        assert_eq!(repr["ply"], 3);
+        game.play_san("d5").unwrap();
+        assert_eq!(game.get_repr().moves, ["e4", "e5", "Nf3", "d5"]);
+    }
+
+    #[test]
+    fn play_records_mate() {
+        let mut game = Game::default();
+        for (from, to) in &[("f2", "f3"), ("e7", "e5"), ("g2", "g4"), ("b8", "c6")] {
+            game.play(from, to, None).unwrap();
+        }
+        // Mates in sidelines don't end the game
+        game.navigate_back(1);
+        game.play("d8", "h4", None).unwrap();
+        assert_eq!(game.get_repr().game_over, Some(GameOver::Checkmate));
+        assert_eq!(game.result(), GameResult::Unknown);
+
+        game.navigate_back(1);
+        game.truncate_from_current();
+        game.play("d8", "h4", None).unwrap();
+        let repr = serde_json::to_value(game.get_repr()).unwrap();
+        assert_eq!(repr["game_over"], "checkmate");
+        assert_eq!(repr["info"]["result"], "0-1");
+        assert_eq!(game.to_pgn().movetext, "1. f3 e5 2. g4 Qh4# 0-1");
+        assert_eq!(game.to_pgn().header("Result"), Some("0-1"));
+        // Results can still be set by hand
+        game.set_result(GameResult::Unknown);
+        assert_eq!(game.to_pgn().header("Result"), Some("*"));
+
+        let mut game = Game::from_fen(String::from("7k/8/6K1/8/8/8/5Q2/8 w - - 0 1")).unwrap();
+        game.play("f2", "f7", None).unwrap();
+        assert_eq!(game.get_repr().game_over, Some(GameOver::Stalemate));
+        assert_eq!(game.result(), GameResult::Draw);
+
+        // Whatever the notation
+        let mut game = Game::default();
+        for san in &["e4", "e5", "Bc4", "Nc6", "Qh5", "Nf6"] {
+            game.play_san(san).unwrap();
+        }
+        game.play_uci("h5f7").unwrap();
+        assert_eq!(game.result(), GameResult::WhiteWins);
+        let mut game = Game::default();
+        for san in &["f3", "e5", "g4", "Qh4#"] {
+            game.play_san(san).unwrap();
+        }
+        assert_eq!(game.result(), GameResult::BlackWins);
+    }
+
+    #[test]
+    fn uci_line() {
+        let fen = String::from("4k3/1P6/8/8/8/8/8/4K2R w K - 0 1");
+        let mut game = Game::from_fen(fen).unwrap();
+        for uci in &["e1g1", "e8d7", "b7b8n"] {
+            game.play_uci(uci).unwrap();
+        }
+        assert_eq!(game.uci_line(), vec!["e1g1", "e8d7", "b7b8n"]);
+        assert_eq!(game.initial_fen(), "4k3/1P6/8/8/8/8/8/4K2R w K - 0 1");
+    }
+
+    #[test]
+    fn chess960_castling() {
+        // King between the rooks, but not on e1
+        let fen = String::from("rk5r/pppppppp/8/8/8/8/PPPPPPPP/RK5R w KQkq - 0 1");
+        let mut game = Game::from_fen(fen).unwrap();
+        assert!(game.is_chess960());
+
+        game.play_uci("b1h1").unwrap();
+        assert_eq!(game.uci_line(), vec!["b1h1"]);
+        assert_eq!(
+            game.current_fen(),
+            "rk5r/pppppppp/8/8/8/8/PPPPPPPP/R4RK1 b kq - 1 1"
+        );
+
+        // Standard looking Chess960 starting position (518)
+        let mut game = Game::default().with_chess960(true);
+        for uci in &["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "f8c5", "e1h1"] {
+            game.play_uci(uci).unwrap();
+        }
+        assert_eq!(game.uci_line().last().unwrap(), "e1h1");
+        assert!(!Game::default().is_chess960());
+    }
+
     #[test]
     // TODO - incomplete
     fn game_repr() {
@@ -308,7 +3319,61 @@ mod tests {
         let fen = String::from("r3r1k1/p2q1ppp/np3n2/3p4/P1pP4/2PQP3/1B2NPPP/R4RK1 w - - 0 15");
         let game = Game::from_fen(fen).unwrap();
         let g = game.get_repr();
-        assert_eq!(g.is_check, false);
-        assert_eq!(g.is_takes, false)
+        assert!(!g.is_check);
+        assert!(!g.is_takes)
+    }
+
+    #[test]
+    fn evaluations() {
+        let mut game = Game::default();
+        for uci in &["e2e4", "e7e5"] {
+            game.play_uci(uci).unwrap();
+        }
+        let line = game.line();
+        let evaluation = Evaluation {
+            score: Score::Cp(-25),
+            depth: 12,
+            wdl: None,
+        };
+        game.set_evaluation(&line[..1], evaluation).unwrap();
+        assert_eq!(
+            game.evaluations(&line).unwrap(),
+            vec![None, Some(evaluation), None]
+        );
+
+        // Engines score from the side to move's point of view
+        let black = Evaluation {
+            score: Score::Mate(3),
+            depth: 1,
+            wdl: Wdl::normalized(900, 100, 0),
+        };
+        let white = black.for_white(shakmaty::Color::Black);
+        assert_eq!(white.score, Score::Mate(-3));
+        assert_eq!(
+            white.wdl,
+            Some(Wdl {
+                win: 0,
+                draw: 100,
+                loss: 900
+            })
+        );
+        assert_eq!(
+            Wdl::normalized(1, 1, 1),
+            Some(Wdl {
+                win: 333,
+                draw: 334,
+                loss: 333
+            })
+        );
+        assert_eq!(
+            Wdl::normalized(1, 0, 1999),
+            Some(Wdl {
+                win: 1,
+                draw: 0,
+                loss: 999
+            })
+        );
+        let huge = Wdl::normalized(u32::MAX, u32::MAX, 0).unwrap();
+        assert_eq!(huge.win + huge.draw + huge.loss, 1000);
     }
 }
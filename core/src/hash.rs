@@ -0,0 +1,198 @@
+use shakmaty::{CastlingSide, Chess, Color, Move, Piece, Role, Setup, Square};
+
+// Layout of the key table: 12 pieces on 64 squares, castling rights by rook square,
+// en passant file and side to move.
+const CASTLING: usize = 12 * 64;
+const EN_PASSANT: usize = CASTLING + 64;
+const WHITE_TO_MOVE: usize = EN_PASSANT + 8;
+const KEY_COUNT: usize = WHITE_TO_MOVE + 1;
+
+/// Hashes are stored in game databases: changing the seed or the layout invalidates their index.
+const SEED: u64 = 0x6269_6763_6865_7373;
+static KEYS: [u64; KEY_COUNT] = generate_keys();
+
+/// 64-bit zobrist key of a position. Equal positions in the repetition sense have equal keys.
+pub fn zobrist(position: &Chess) -> u64 {
+    let mut hash = castling_and_en_passant(position);
+    for (square, piece) in position.board().pieces() {
+        hash ^= piece_key(piece, square);
+    }
+    if position.turn() == Color::White {
+        hash ^= KEYS[WHITE_TO_MOVE];
+    }
+    hash
+}
+
+/// Key of `after`, reached by playing `m` in `before` whose key is `hash`. Only the squares the
+/// move touches are hashed again, rather than the whole board.
+pub fn update(hash: u64, before: &Chess, m: &Move, after: &Chess) -> u64 {
+    let color = before.turn();
+    let piece = |role| Piece { color, role };
+    let moved = match *m {
+        Move::Normal {
+            role,
+            from,
+            capture,
+            to,
+            promotion,
+        } => {
+            let captured = capture.map_or(0, |role| {
+                piece_key(
+                    Piece {
+                        color: !color,
+                        role,
+                    },
+                    to,
+                )
+            });
+            piece_key(piece(role), from)
+                ^ captured
+                ^ piece_key(piece(promotion.unwrap_or(role)), to)
+        }
+        Move::EnPassant { from, to } => {
+            let captured = Piece {
+                color: !color,
+                role: Role::Pawn,
+            };
+            piece_key(piece(Role::Pawn), from)
+                ^ piece_key(piece(Role::Pawn), to)
+                ^ piece_key(captured, to.with_rank_of(from))
+        }
+        Move::Castle { king, rook } => {
+            let side = CastlingSide::from_queen_side(rook < king);
+            piece_key(piece(Role::King), king)
+                ^ piece_key(piece(Role::Rook), rook)
+                ^ piece_key(piece(Role::King), side.king_to(color))
+                ^ piece_key(piece(Role::Rook), side.rook_to(color))
+        }
+        Move::Put { role, to } => piece_key(piece(role), to),
+    };
+    hash ^ moved
+        ^ castling_and_en_passant(before)
+        ^ castling_and_en_passant(after)
+        ^ KEYS[WHITE_TO_MOVE]
+}
+
+fn castling_and_en_passant(position: &Chess) -> u64 {
+    let mut hash = 0;
+    for rook in position.castling_rights() {
+        hash ^= KEYS[CASTLING + usize::from(rook)];
+    }
+    if let Some(square) = position.ep_square() {
+        hash ^= KEYS[EN_PASSANT + usize::from(square.file())];
+    }
+    hash
+}
+
+fn piece_key(piece: Piece, square: Square) -> u64 {
+    KEYS[piece_index(piece) * 64 + usize::from(square)]
+}
+
+fn piece_index(piece: Piece) -> usize {
+    (piece.role as usize - 1) * 2 + piece.color as usize
+}
+
+/// splitmix64, which is good enough for zobrist keys and simple enough to run at compile time.
+const fn generate_keys() -> [u64; KEY_COUNT] {
+    let mut keys = [0; KEY_COUNT];
+    let mut state = SEED;
+    let mut i = 0;
+    while i < KEY_COUNT {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        keys[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shakmaty::uci::Uci;
+    use shakmaty::Position;
+
+    fn from_fen(fen: &str) -> Chess {
+        fen.parse::<shakmaty::fen::Fen>()
+            .unwrap()
+            .position()
+            .unwrap()
+    }
+
+    fn play(moves: &[&str]) -> Chess {
+        let mut position = Chess::default();
+        for uci in moves {
+            let m = uci.parse::<Uci>().unwrap().to_move(&position).unwrap();
+            position.play_unchecked(&m);
+        }
+        position
+    }
+
+    /// Checks the key of every position under `position` against a full rehash.
+    fn check_updates(position: &Chess, hash: u64, depth: u32) -> usize {
+        assert_eq!(hash, zobrist(position));
+        if depth == 0 {
+            return 1;
+        }
+        let mut nodes = 1;
+        for m in &position.legals() {
+            let mut after = position.clone();
+            after.play_unchecked(m);
+            nodes += check_updates(&after, update(hash, position, m, &after), depth - 1);
+        }
+        nodes
+    }
+
+    #[test]
+    fn incremental_updates() {
+        // Castling both ways, en passant, promotions with and without captures
+        for fen in &[
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+        ] {
+            let position = from_fen(fen);
+            assert!(check_updates(&position, zobrist(&position), 3) > 1000);
+        }
+        let chess960 =
+            from_fen("bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9");
+        assert!(chess960.castles().is_chess960());
+        check_updates(&chess960, zobrist(&chess960), 3);
+    }
+
+    #[test]
+    fn transpositions() {
+        let start = zobrist(&Chess::default());
+        assert_eq!(start, zobrist(&play(&["g1f3", "g8f6", "f3g1", "f6g8"])));
+        assert_eq!(
+            zobrist(&play(&["e2e4", "e7e5", "g1f3"])),
+            zobrist(&play(&["g1f3", "e7e5", "e2e4"]))
+        );
+
+        // Same pieces, but castling rights were lost
+        let moved_king = play(&["e2e4", "e7e5", "e1e2", "e8e7", "e2e1", "e7e8"]);
+        let castling_rights = play(&["e2e4", "e7e5"]);
+        assert_eq!(moved_king.board(), castling_rights.board());
+        assert_ne!(zobrist(&castling_rights), zobrist(&moved_king));
+        // En passant only counts when a capture is possible
+        assert_eq!(
+            zobrist(&from_fen(
+                "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"
+            )),
+            zobrist(&from_fen(
+                "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+            ))
+        );
+        assert_ne!(
+            zobrist(&from_fen(
+                "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 3"
+            )),
+            zobrist(&from_fen(
+                "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq - 0 3"
+            ))
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::errors::{Error, ErrorType};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+/// Long running background tasks (engine matches, ...) attached to a game, or to another resource
+/// such as a file. At most one task runs per game, and it can be stopped through the game's id.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    running: Arc<Mutex<HashMap<String, Job>>>,
+    next_token: Arc<AtomicU64>,
+}
+
+struct Job {
+    token: u64,
+    stop: oneshot::Sender<()>,
+}
+
+/// Given to a task when it starts. `stop` resolves when the task is asked to stop.
+#[derive(Debug)]
+pub struct JobTicket {
+    pub token: u64,
+    pub stop: oneshot::Receiver<()>,
+}
+
+impl Jobs {
+    /// Registers a new task for the game `id`. Fails with `ErrorType::Locked` if one is already running.
+    pub fn start(&self, id: &str) -> Result<JobTicket, Error> {
+        let mut running = self.running.lock()?;
+        if running.contains_key(id) {
+            return Err(Error::new(ErrorType::Locked).with_id(id));
+        }
+
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        running.insert(
+            id.to_string(),
+            Job {
+                token,
+                stop: sender,
+            },
+        );
+        Ok(JobTicket {
+            token,
+            stop: receiver,
+        })
+    }
+
+    /// Asks the task running on game `id` to stop. Returns false if there was none.
+    pub fn stop(&self, id: &str) -> Result<bool, Error> {
+        match self.running.lock()?.remove(id) {
+            Some(job) => {
+                // The task may already be finishing on its own
+                let _ = job.stop.send(());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn is_running(&self, id: &str) -> Result<bool, Error> {
+        Ok(self.running.lock()?.contains_key(id))
+    }
+
+    /// Asks every running task to stop.
+    pub fn stop_all(&self) -> Result<(), Error> {
+        for (_, job) in self.running.lock()?.drain() {
+            let _ = job.stop.send(());
+        }
+        Ok(())
+    }
+
+    /// Unregisters a task once it's done. Does nothing if it was stopped and replaced in the meantime.
+    pub fn finish(&self, id: &str, token: u64) -> Result<(), Error> {
+        let mut running = self.running.lock()?;
+        if running.get(id).map(|job| job.token) == Some(token) {
+            running.remove(id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_job_per_game() {
+        let jobs = Jobs::default();
+        let first = jobs.start("g1").unwrap();
+        assert!(jobs.start("g1").unwrap_err().is_type(ErrorType::Locked));
+        assert!(jobs.start("g2").is_ok());
+
+        assert!(jobs.stop("g1").unwrap());
+        assert!(!jobs.stop("g1").unwrap());
+        let second = jobs.start("g1").unwrap();
+
+        // The first job finishing late must not unregister the second one
+        jobs.finish("g1", first.token).unwrap();
+        assert!(jobs.start("g1").is_err());
+        jobs.finish("g1", second.token).unwrap();
+        assert!(jobs.start("g1").is_ok());
+    }
+}
@@ -0,0 +1,1533 @@
+use crate::api::{
+    response_from_error, response_from_game, response_from_notification, Notification, Response,
+};
+use crate::eco::Opening;
+use crate::errors::{Error, ErrorType};
+use crate::game::{Game, GameResult, LichessChapter, LichessGame, LichessPlayer, PuzzleData};
+use crate::jobs::JobTicket;
+use crate::pgn::{PgnGame, PgnReader};
+use crate::state::StateHandle;
+
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use shakmaty::fen::fen;
+use shakmaty::san::SanPlus;
+use shakmaty::uci::Uci;
+use shakmaty::{Chess, Position};
+
+pub const LICHESS_URL: &str = "https://lichess.org";
+/// Sent with every request, as the lichess API etiquette asks.
+pub const USER_AGENT: &str = concat!(
+    "bigchess/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/AlexandreGauthier/bigchess)"
+);
+/// Lichess asks to wait a full minute after a 429 Too Many Requests.
+const FIRST_BACKOFF: Duration = Duration::from_secs(60);
+/// Requests that would wait longer than this for the backoff to end fail instead.
+const MAX_QUEUE_WAIT: Duration = Duration::from_secs(120);
+/// Delay before reconnecting to a followed game, doubled after every failure up to `LAST_RETRY`.
+const FIRST_RETRY: Duration = Duration::from_millis(500);
+const LAST_RETRY: Duration = Duration::from_secs(30);
+
+/// Personal access token, hidden from `Debug` output so that it never ends up in a log.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct LichessToken(String);
+
+impl fmt::Debug for LichessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LichessToken([redacted])")
+    }
+}
+
+/// Account a token belongs to.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LichessAccount {
+    pub username: String,
+    /// What the token allows, like `study:read`.
+    pub scopes: Vec<String>,
+}
+
+/// Server the lichess features talk to, lichess.org or a self-hosted lila instance.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LichessConfig {
+    pub base_url: String,
+    pub user_agent: String,
+}
+
+/// Client of the lichess API, through which every lichess request goes. Cloning shares the
+/// connection pool, the server, the token and the backoff.
+#[derive(Debug, Clone)]
+pub struct LichessClient {
+    http: reqwest::Client,
+    base_url: Arc<RwLock<String>>,
+    /// Sent with every request when set.
+    token: Arc<RwLock<Option<LichessToken>>>,
+    backoff: Arc<Mutex<Backoff>>,
+    first_backoff: Duration,
+    max_queue_wait: Duration,
+}
+
+/// Delay imposed on every request after lichess answered 429 Too Many Requests.
+#[derive(Debug, Default)]
+struct Backoff {
+    /// Requests are held until then.
+    until: Option<Instant>,
+    /// Delay after the next 429, doubled after every one and reset by any other answer.
+    next: Option<Duration>,
+}
+
+impl LichessClient {
+    /// Client of the server at `base_url` instead of lichess.org.
+    pub fn with_base_url(base_url: &str) -> LichessClient {
+        LichessClient {
+            http: http_client(),
+            base_url: Arc::new(RwLock::new(base_url.trim_end_matches('/').to_string())),
+            token: Arc::new(RwLock::new(None)),
+            backoff: Arc::new(Mutex::new(Backoff::default())),
+            first_backoff: FIRST_BACKOFF,
+            max_queue_wait: MAX_QUEUE_WAIT,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_backoff(self, first_backoff: Duration, max_queue_wait: Duration) -> LichessClient {
+        LichessClient {
+            first_backoff,
+            max_queue_wait,
+            ..self
+        }
+    }
+
+    pub fn config(&self) -> Result<LichessConfig, Error> {
+        Ok(LichessConfig {
+            base_url: self.base_url.read()?.clone(),
+            user_agent: String::from(USER_AGENT),
+        })
+    }
+
+    /// Talks to the server at `base_url` from now on. Returns false if it already did.
+    pub fn set_base_url(&self, base_url: &str) -> Result<bool, Error> {
+        let base_url = base_url.trim_end_matches('/');
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            return Err(Error::new(ErrorType::Parse)
+                .with_message(&format!("{} is not an http(s) URL", base_url)));
+        }
+        let mut current = self.base_url.write()?;
+        if *current == base_url {
+            return Ok(false);
+        }
+        *current = base_url.to_string();
+        Ok(true)
+    }
+
+    fn url(&self, path: &str) -> Result<String, Error> {
+        Ok(format!("{}{}", self.base_url.read()?, path))
+    }
+
+    pub fn set_token(&self, token: Option<LichessToken>) -> Result<(), Error> {
+        *self.token.write()? = token;
+        Ok(())
+    }
+
+    /// Account of `token`, which doesn't need to be the one set.
+    pub async fn account(&self, token: &LichessToken) -> Result<LichessAccount, Error> {
+        #[derive(Deserialize)]
+        struct Account {
+            username: String,
+        }
+
+        let response = self
+            .http
+            .get(&self.url("/api/account")?)
+            .bearer_auth(&token.0);
+        let response = self.throttled(response).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(Error::new(ErrorType::Network)
+                .with_message("Lichess refused the personal access token"));
+        }
+        let response = response.error_for_status()?;
+        let scopes = response
+            .headers()
+            .get("X-OAuth-Scopes")
+            .and_then(|scopes| scopes.to_str().ok())
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|scope| !scope.is_empty())
+            .map(String::from)
+            .collect();
+        let account: Account = response.json().await?;
+        Ok(LichessAccount {
+            username: account.username,
+            scopes,
+        })
+    }
+
+    /// PGN of game `game_id`, with clock times and evaluations in comments.
+    pub async fn export_game(&self, game_id: &str) -> Result<PgnGame, Error> {
+        let path = format!("/game/export/{}", game_id);
+        let query = [("clocks", "true"), ("evals", "true")];
+        let pgn = self
+            .get_pgn(&path, &query, &format!("game {}", game_id))
+            .await?;
+        PgnReader::new(pgn.as_bytes()).next().unwrap_or_else(|| {
+            Err(Error::new(ErrorType::Parse).with_message("Lichess sent an empty PGN"))
+        })
+    }
+
+    pub async fn puzzle(&self, puzzle_id: &str) -> Result<ExportedPuzzle, Error> {
+        let request = self
+            .http
+            .get(&self.url(&format!("/api/puzzle/{}", puzzle_id))?);
+        let response = self.send(request, &format!("puzzle {}", puzzle_id)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// One PGN game per chapter of study `study_id`, in study order.
+    pub async fn export_study(&self, study_id: &str) -> Result<Vec<PgnGame>, Error> {
+        let path = format!("/api/study/{}.pgn", study_id);
+        let query = [
+            ("comments", "true"),
+            ("variations", "true"),
+            ("clocks", "true"),
+        ];
+        let pgn = self
+            .get_pgn(&path, &query, &format!("study {}", study_id))
+            .await?;
+        PgnReader::new(pgn.as_bytes()).collect()
+    }
+
+    /// Adds `pgn` to study `study_id` as chapter `name`. Needs a token with the `study:write` scope.
+    pub async fn push_chapter(
+        &self,
+        study_id: &str,
+        name: &str,
+        pgn: &str,
+    ) -> Result<LichessChapter, Error> {
+        #[derive(Deserialize)]
+        struct Imported {
+            chapters: Vec<Chapter>,
+        }
+        #[derive(Deserialize)]
+        struct Chapter {
+            id: String,
+        }
+
+        let token = self.token.read()?.clone().ok_or_else(|| {
+            Error::new(ErrorType::Network).with_message(
+                "Pushing to a study needs a personal access token, set one with lichess_set_token",
+            )
+        })?;
+        let response = self
+            .http
+            .post(&self.url(&format!("/api/study/{}/import-pgn", study_id))?)
+            .bearer_auth(&token.0)
+            .form(&[("name", name), ("pgn", pgn)]);
+        let response = self.throttled(response).await?;
+        if !response.status().is_success() {
+            return Err(refusal(response).await);
+        }
+        let imported: Imported = response.json().await?;
+        let chapter_id = imported
+            .chapters
+            .into_iter()
+            .next()
+            .map(|chapter| chapter.id)
+            .ok_or_else(|| {
+                Error::new(ErrorType::Network).with_message("Lichess created no chapter")
+            })?;
+        Ok(LichessChapter {
+            url: self.url(&format!("/study/{}/{}", study_id, chapter_id))?,
+            study_id: study_id.to_string(),
+            chapter_id,
+        })
+    }
+
+    /// Most recent games of `username` first, reading no more of the export than `filter.max` games.
+    pub async fn user_games(
+        &self,
+        username: &str,
+        filter: &UserGamesFilter,
+    ) -> Result<Vec<LichessGameRow>, Error> {
+        let mut query = vec![
+            ("max", filter.max.to_string()),
+            ("opening", String::from("true")),
+            ("moves", String::from("false")),
+        ];
+        query.extend(filter.perf_type.clone().map(|perf| ("perfType", perf)));
+        query.extend(filter.since.map(|since| ("since", since.to_string())));
+        query.extend(filter.until.map(|until| ("until", until.to_string())));
+        let request = self
+            .http
+            .get(&self.url(&format!("/api/games/user/{}", username))?)
+            .query(&query)
+            .header(reqwest::header::ACCEPT, "application/x-ndjson");
+        let response = self.send(request, &format!("user {}", username)).await?;
+
+        // The rest of the export is never downloaded once there are enough games
+        let mut games = NdJson::new(response);
+        let mut rows = Vec::new();
+        while rows.len() < filter.max as usize {
+            match games.next::<ExportedGame>().await? {
+                Some(game) => rows.push(game.row()),
+                None => break,
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Game `game_id` as it currently stands, moves included.
+    pub async fn game_snapshot(&self, game_id: &str) -> Result<ExportedGame, Error> {
+        let request = self
+            .http
+            .get(&self.url(&format!("/game/export/{}", game_id))?)
+            .query(&[("moves", "true"), ("clocks", "false")])
+            .header(reqwest::header::ACCEPT, "application/json");
+        let response = self.send(request, &format!("game {}", game_id)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Moves of game `game_id` as they are played. The stream ends with the game.
+    async fn stream_moves(&self, game_id: &str) -> Result<NdJson, Error> {
+        let request = self
+            .http
+            .get(&self.url(&format!("/api/stream/game/{}", game_id))?)
+            .header(reqwest::header::ACCEPT, "application/x-ndjson");
+        let response = self.send(request, &format!("game {}", game_id)).await?;
+        Ok(NdJson::new(response))
+    }
+
+    /// PGN of games `game_ids`, 300 per request as lichess allows.
+    pub async fn export_games(&self, game_ids: &[String]) -> Result<String, Error> {
+        let mut pgn = String::new();
+        for ids in game_ids.chunks(300) {
+            let request = self
+                .http
+                .post(&self.url("/api/games/export/_ids")?)
+                .query(&[("clocks", "true"), ("evals", "true")])
+                .header(reqwest::header::ACCEPT, "application/x-chess-pgn")
+                .body(ids.join(","));
+            pgn.push_str(&self.send(request, "game export").await?.text().await?);
+            pgn.push('\n');
+        }
+        Ok(pgn)
+    }
+
+    /// `what` names the requested resource in error messages.
+    async fn get_pgn(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        what: &str,
+    ) -> Result<String, Error> {
+        let request = self
+            .http
+            .get(&self.url(path)?)
+            .query(query)
+            .header(reqwest::header::ACCEPT, "application/x-chess-pgn");
+        Ok(self.send(request, what).await?.text().await?)
+    }
+
+    /// Sends `request` with the token, if any. `what` names the requested resource in error
+    /// messages.
+    async fn send(
+        &self,
+        mut request: reqwest::RequestBuilder,
+        what: &str,
+    ) -> Result<reqwest::Response, Error> {
+        if let Some(token) = &*self.token.read()? {
+            request = request.bearer_auth(&token.0);
+        }
+        let response = self.throttled(request).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(Error::new(ErrorType::Network)
+                .with_message(&format!("There is no lichess {}", what))),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::new(ErrorType::Network)
+                .with_message(&format!(
+                    "The lichess {} is private, set a personal access token with lichess_set_token to read it",
+                    what
+                ))),
+            _ => Ok(response.error_for_status()?),
+        }
+    }
+
+    /// Sends `request` once the backoff is over, and again after a longer one as long as lichess
+    /// answers 429 Too Many Requests. Fails rather than wait more than `max_queue_wait`.
+    async fn throttled(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Error> {
+        loop {
+            let wait = match self.backoff.lock()?.until {
+                Some(until) => until.saturating_duration_since(Instant::now()),
+                None => Duration::from_secs(0),
+            };
+            if wait > self.max_queue_wait {
+                return Err(Error::new(ErrorType::Network).with_message(&format!(
+                    "Lichess is limiting the rate of requests, retry in {} seconds",
+                    wait.as_secs() + 1
+                )));
+            }
+            tokio::time::delay_for(wait).await;
+
+            let attempt = request
+                .try_clone()
+                .expect("Lichess requests have no streamed body");
+            let response = attempt.send().await?;
+            let mut backoff = self.backoff.lock()?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                backoff.next = None;
+                return Ok(response);
+            }
+            let delay = backoff.next.unwrap_or(self.first_backoff);
+            backoff.until = Some(Instant::now() + delay);
+            backoff.next = Some(delay * 2);
+        }
+    }
+}
+
+/// Games of a user to list, at most `max`. `since` and `until` are timestamps in milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserGamesFilter {
+    pub max: u32,
+    /// bullet, blitz, rapid, classical, ...
+    pub perf_type: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+/// Summary of a game, to pick the ones to import.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LichessGameRow {
+    pub id: String,
+    pub white: LichessPlayer,
+    pub black: LichessPlayer,
+    pub result: GameResult,
+    /// When the game started, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub perf: Option<String>,
+    pub rated: bool,
+    pub opening: Option<Opening>,
+}
+
+/// Games are opened, or added to a database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ImportTarget {
+    /// `"state"`
+    State(StateTarget),
+    Database {
+        db_id: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StateTarget {
+    State,
+}
+
+impl Default for ImportTarget {
+    fn default() -> ImportTarget {
+        ImportTarget::State(StateTarget::State)
+    }
+}
+
+/// Game as the lichess JSON exports describe it.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedGame {
+    pub id: String,
+    #[serde(default)]
+    rated: bool,
+    perf: Option<String>,
+    created_at: u64,
+    status: String,
+    players: ExportedPlayers,
+    winner: Option<String>,
+    opening: Option<ExportedOpening>,
+    /// SAN moves, separated by spaces.
+    #[serde(default)]
+    pub moves: String,
+    /// Set when the game didn't start from the standard position.
+    pub initial_fen: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedPlayers {
+    white: ExportedPlayer,
+    black: ExportedPlayer,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ExportedPlayer {
+    user: Option<ExportedUser>,
+    rating: Option<u32>,
+    ai_level: Option<u8>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedUser {
+    name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedOpening {
+    eco: String,
+    name: String,
+}
+
+impl ExportedGame {
+    pub fn result(&self) -> GameResult {
+        match (self.winner.as_deref(), self.status.as_str()) {
+            (Some("white"), _) => GameResult::WhiteWins,
+            (Some("black"), _) => GameResult::BlackWins,
+            _ if !self.is_over() => GameResult::Unknown,
+            (_, "aborted") | (_, "noStart") => GameResult::Unknown,
+            _ => GameResult::Draw,
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        !["created", "started"].contains(&self.status.as_str())
+    }
+
+    pub fn details(&self) -> LichessGame {
+        LichessGame {
+            game_id: self.id.clone(),
+            perf: self.perf.clone(),
+            rated: self.rated,
+            white: self.players.white.player(),
+            black: self.players.black.player(),
+        }
+    }
+
+    fn row(self) -> LichessGameRow {
+        LichessGameRow {
+            white: self.players.white.player(),
+            black: self.players.black.player(),
+            result: self.result(),
+            created_at: self.created_at,
+            perf: self.perf,
+            rated: self.rated,
+            opening: self.opening.map(|opening| {
+                // "Sicilian Defense: Najdorf Variation"
+                let mut name = opening.name.splitn(2, ": ");
+                Opening {
+                    eco: opening.eco,
+                    name: name.next().unwrap_or_default().to_string(),
+                    variation: name.next().map(String::from),
+                }
+            }),
+            id: self.id,
+        }
+    }
+}
+
+impl ExportedPlayer {
+    fn player(&self) -> LichessPlayer {
+        LichessPlayer {
+            name: match (&self.user, self.ai_level) {
+                (Some(user), _) => user.name.clone(),
+                (None, Some(level)) => format!("Stockfish level {}", level),
+                (None, None) => String::from("Anonymous"),
+            },
+            rating: self.rating,
+        }
+    }
+}
+
+/// Puzzle as the lichess puzzle API describes it.
+#[derive(Deserialize, Debug)]
+pub struct ExportedPuzzle {
+    game: PuzzleGame,
+    puzzle: PuzzleDetails,
+}
+
+#[derive(Deserialize, Debug)]
+struct PuzzleGame {
+    id: String,
+    /// SAN moves up to the puzzle, separated by spaces.
+    pgn: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PuzzleDetails {
+    id: String,
+    rating: u32,
+    solution: Vec<String>,
+    #[serde(default)]
+    themes: Vec<String>,
+}
+
+impl ExportedPuzzle {
+    /// Game starting just before the opponent's move that sets up the puzzle, with that move
+    /// played.
+    pub fn into_game(self) -> Result<Game, Error> {
+        let moves: Vec<&str> = self.game.pgn.split_whitespace().collect();
+        let (last_move, moves) = moves.split_last().ok_or_else(|| {
+            Error::new(ErrorType::Parse)
+                .with_message(&format!("Puzzle {} has no moves", self.puzzle.id))
+        })?;
+        let mut position = Chess::default();
+        for san in moves {
+            let san: SanPlus = san.parse()?;
+            let mov = san.san.to_move(&position)?;
+            position.play_unchecked(&mov);
+        }
+
+        let mut game = Game::from_fen(fen(&position))?;
+        game.play_san(last_move)?;
+        Ok(game.with_puzzle(PuzzleData {
+            puzzle_id: self.puzzle.id,
+            game_id: self.game.id,
+            rating: self.puzzle.rating,
+            themes: self.puzzle.themes,
+            solution: self.puzzle.solution,
+        }))
+    }
+}
+
+/// Reads an ND-JSON response one line at a time, as lines arrive.
+struct NdJson {
+    response: reqwest::Response,
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl NdJson {
+    fn new(response: reqwest::Response) -> NdJson {
+        NdJson {
+            response,
+            pending: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Next non-blank line, `None` once the response is over.
+    async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        loop {
+            let line: Vec<u8> = match self.pending.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => self.pending.drain(..=newline).collect(),
+                None if self.finished => std::mem::take(&mut self.pending),
+                None => {
+                    match self.response.chunk().await? {
+                        Some(chunk) => self.pending.extend(chunk),
+                        None => self.finished = true,
+                    }
+                    continue;
+                }
+            };
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                return Ok(Some(serde_json::from_slice(&line)?));
+            }
+            if self.finished && self.pending.is_empty() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Error of a failed request, with the explanation lichess gives as `{"error": "..."}`.
+async fn refusal(response: reqwest::Response) -> Error {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let explanation = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["error"].as_str().map(String::from))
+        .unwrap_or(body);
+    Error::new(ErrorType::Network)
+        .with_message(&format!("Lichess answered {}: {}", status, explanation))
+}
+
+/// Game created from a study chapter.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StudyChapter {
+    pub id: String,
+    pub name: String,
+}
+
+/// HTTP client of the lichess services, identifying bigchess to them.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("TLS backend could not be initialized")
+}
+
+impl Default for LichessClient {
+    fn default() -> LichessClient {
+        LichessClient::with_base_url(LICHESS_URL)
+    }
+}
+
+/// Id of the game at a lichess URL (`https://lichess.org/AbCdEfGh/black`, ...), or the id itself.
+pub fn game_id(game_or_url: &str) -> Result<String, Error> {
+    let segment = path_segments(game_or_url).next().unwrap_or("");
+    // Player URLs append a 4 character secret to the game id
+    if segment.len() == 12 && is_id(&segment[..8]) {
+        return Ok(segment[..8].to_string());
+    }
+    if !is_id(segment) {
+        return Err(Error::new(ErrorType::Parse)
+            .with_message(&format!("{} is not a lichess game", game_or_url)));
+    }
+    Ok(segment.to_string())
+}
+
+/// Id of the study at a lichess URL (`https://lichess.org/study/AbCdEfGh/IjKlMnOp`, ...), or the id
+/// itself.
+pub fn study_id(study_or_url: &str) -> Result<String, Error> {
+    let mut segments = path_segments(study_or_url);
+    let segment = match segments.next() {
+        Some("study") => segments.next().unwrap_or(""),
+        other => other.unwrap_or(""),
+    };
+    if !is_id(segment) {
+        return Err(Error::new(ErrorType::Parse)
+            .with_message(&format!("{} is not a lichess study", study_or_url)));
+    }
+    Ok(segment.to_string())
+}
+
+/// Id of the puzzle at a lichess URL (`https://lichess.org/training/AbC12`), or the id itself.
+pub fn puzzle_id(puzzle_or_url: &str) -> Result<String, Error> {
+    let mut segments = path_segments(puzzle_or_url);
+    let segment = match segments.next() {
+        Some("training") => segments.next().unwrap_or(""),
+        other => other.unwrap_or(""),
+    };
+    if segment.len() != 5 || !segment.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::new(ErrorType::Parse)
+            .with_message(&format!("{} is not a lichess puzzle", puzzle_or_url)));
+    }
+    Ok(segment.to_string())
+}
+
+/// Path of a lichess URL, or the input itself if it isn't one.
+fn path_segments(input: &str) -> impl Iterator<Item = &str> {
+    let trimmed = input.trim();
+    let path = match trimmed.find("://") {
+        Some(scheme_end) => {
+            let after_scheme = &trimmed[scheme_end + 3..];
+            after_scheme
+                .find('/')
+                .map_or("", |i| &after_scheme[i + 1..])
+        }
+        None => trimmed.trim_start_matches("lichess.org/"),
+    };
+    let path = path.split(&['?', '#'][..]).next().unwrap_or("");
+    path.split('/')
+}
+
+fn is_id(segment: &str) -> bool {
+    segment.len() == 8 && segment.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Name of a study chapter: its `ChapterName` tag, or what follows the study name in `Event`.
+pub fn chapter_name(pgn: &PgnGame, number: usize) -> String {
+    if let Some(name) = pgn.header("ChapterName") {
+        return name.to_string();
+    }
+    match pgn.header("Event").and_then(|event| event.split_once(": ")) {
+        Some((_, name)) => name.to_string(),
+        None => format!("Chapter {}", number),
+    }
+}
+
+/// Lichess details of game `game_id`, from the headers of its export.
+pub fn game_details(game_id: &str, pgn: &PgnGame) -> LichessGame {
+    // Events look like "Rated Blitz game" or "Casual Chess960 game"
+    let mut event = pgn.header("Event").unwrap_or("").split_whitespace();
+    let rated = event.next() == Some("Rated");
+    let player = |name: &str, elo: &str| LichessPlayer {
+        name: pgn.header(name).unwrap_or("?").to_string(),
+        rating: pgn.header(elo).and_then(|rating| rating.parse().ok()),
+    };
+    LichessGame {
+        game_id: game_id.to_string(),
+        perf: event.next().map(str::to_lowercase),
+        rated,
+        white: player("White", "WhiteElo"),
+        black: player("Black", "BlackElo"),
+    }
+}
+
+/// Line of a game stream: the whole game first, then one line per move.
+#[derive(Deserialize, Debug)]
+struct StreamedMove {
+    fen: String,
+    /// Move that led to `fen`, in UCI notation.
+    lm: Option<String>,
+}
+
+/// Keeps game `id` in sync with lichess game `game_id` until it's over, reconnecting whenever the
+/// stream of moves breaks.
+pub async fn follow(state: StateHandle, id: String, game_id: String, ticket: JobTicket) {
+    let JobTicket { token, mut stop } = ticket;
+    let outcome = tokio::select! {
+        outcome = follow_moves(&state, &id, &game_id) => outcome.map(|()| false),
+        _ = &mut stop => Ok(true),
+    };
+    let _ = state.jobs().finish(&id, token);
+
+    let notification = outcome.and_then(|stopped| {
+        let result = state.with_game(&id, |game| Ok(game.result()))?;
+        Ok(Notification::LichessFollowFinished {
+            id: id.clone(),
+            result,
+            stopped,
+        })
+    });
+
+    match notification {
+        Ok(notification) => state.notify(response_from_notification(notification)),
+        Err(err) => state.notify(response_from_error(err.with_id(&id))),
+    }
+}
+
+async fn follow_moves(state: &StateHandle, id: &str, game_id: &str) -> Result<(), Error> {
+    let mut retry = FIRST_RETRY;
+    loop {
+        match sync(state, id, game_id, &mut retry).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(err) if err.is_type(ErrorType::Network) => {}
+            Err(err) => return Err(err),
+        }
+        tokio::time::delay_for(retry).await;
+        retry = (retry * 2).min(LAST_RETRY);
+    }
+}
+
+/// Catches up with the game's moves, then plays them as they come until the stream ends. True
+/// once the game is over.
+async fn sync(
+    state: &StateHandle,
+    id: &str,
+    game_id: &str,
+    retry: &mut Duration,
+) -> Result<bool, Error> {
+    let snapshot = state.lichess().game_snapshot(game_id).await?;
+    let response = state.with_game(id, |game| {
+        // Moves already played are found in the tree, only the new ones are added
+        game.navigate_back(u16::MAX);
+        for san in snapshot.moves.split_whitespace() {
+            game.play_san(san)?;
+        }
+        if snapshot.is_over() {
+            game.set_result(snapshot.result());
+        }
+        Ok(response_from_game(id.to_string(), state.game_repr(game)))
+    })?;
+    state.notify(response);
+    if snapshot.is_over() {
+        return Ok(true);
+    }
+
+    let mut moves = state.lichess().stream_moves(game_id).await?;
+    *retry = FIRST_RETRY;
+    while let Some(streamed) = moves.next::<StreamedMove>().await? {
+        if !play_streamed(state, id, streamed)? {
+            // Out of sync, start over from a new snapshot
+            return Ok(false);
+        }
+    }
+    // The stream ends with the game, which the next snapshot confirms
+    Ok(false)
+}
+
+enum Streamed {
+    Played(Box<Response>),
+    /// Like the moves of the first line, which the snapshot had.
+    AlreadyPlayed,
+    OutOfSync,
+}
+
+/// False if the streamed position doesn't follow from the game's current one.
+fn play_streamed(state: &StateHandle, id: &str, streamed: StreamedMove) -> Result<bool, Error> {
+    let board = |fen: &str| fen.split(' ').next().unwrap_or_default().to_string();
+    let played = state.with_game(id, |game| {
+        if board(&game.current_fen()) == board(&streamed.fen) {
+            return Ok(Streamed::AlreadyPlayed);
+        }
+        let uci = match &streamed.lm {
+            Some(uci) => uci,
+            None => return Ok(Streamed::OutOfSync),
+        };
+        let mut position = game.current_position();
+        match uci.parse::<Uci>().map(|uci| uci.to_move(&position)) {
+            Ok(Ok(mov)) => position.play_unchecked(&mov),
+            _ => return Ok(Streamed::OutOfSync),
+        }
+        if board(&fen(&position)) != board(&streamed.fen) {
+            return Ok(Streamed::OutOfSync);
+        }
+        game.play_uci(uci)?;
+        let response = response_from_game(id.to_string(), state.game_repr(game));
+        Ok(Streamed::Played(Box::new(response)))
+    })?;
+
+    match played {
+        Streamed::Played(response) => {
+            state.notify(
+                (*response).with_notification(Notification::LichessFollowMove {
+                    id: id.to_string(),
+                    uci: streamed.lm.unwrap_or_default(),
+                }),
+            );
+            Ok(true)
+        }
+        Streamed::AlreadyPlayed => Ok(true),
+        Streamed::OutOfSync => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Request;
+    use crate::database::describe;
+    use crate::database::tests::temp_database;
+    use crate::session::Session;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const LICHESS_PGN: &str = r#"[Event "Rated Blitz game"]
+[Site "https://lichess.org/AbCdEfGh"]
+[White "alice"]
+[Black "bob"]
+[Result "1-0"]
+[WhiteElo "1912"]
+[BlackElo "?"]
+[TimeControl "180+2"]
+
+1. e4 { [%eval 0.3] [%clk 0:03:00] } 1... e5 { [%eval 0.25] [%clk 0:03:00] } 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0
+
+"#;
+
+    const STUDY_PGN: &str = r#"[Event "Openings: Italian"]
+[Site "https://lichess.org/study/StUdYiDx/ChApTeR1"]
+[Result "*"]
+[ChapterName "Italian"]
+
+1. e4 e5 2. Nf3 Nc6 3. Bc4 (3. Bb5 a6) 3... Bc5 { Giuoco piano } $1 *
+
+[Event "Openings: Lucena"]
+[Site "https://lichess.org/study/StUdYiDx/ChApTeR2"]
+[Result "*"]
+[FEN "1K1k4/1P6/8/8/8/8/r7/2R5 w - - 0 1"]
+[SetUp "1"]
+
+1. Rd1+ Ke7 *
+
+"#;
+
+    #[test]
+    fn game_id() {
+        for input in &[
+            "AbCdEfGh",
+            "https://lichess.org/AbCdEfGh",
+            "https://lichess.org/AbCdEfGh/black#12",
+            "https://lichess.org/AbCdEfGhIjKl",
+            "lichess.org/AbCdEfGh?foo=bar",
+        ] {
+            assert_eq!(super::game_id(input).unwrap(), "AbCdEfGh", "{}", input);
+        }
+        assert!(super::game_id("https://lichess.org/study/AbCdEfGh").is_err());
+        assert!(super::game_id("AbCdEf").is_err());
+        assert_eq!(
+            study_id("https://lichess.org/study/StUdYiDx/ChApTeR1").unwrap(),
+            "StUdYiDx"
+        );
+        assert!(study_id("https://lichess.org/AbCdEfGh").is_ok());
+        assert!(study_id("https://lichess.org/study").is_err());
+    }
+
+    #[tokio::test]
+    async fn import_study() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/study/StUdYiDx.pgn"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(STUDY_PGN))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/study/PrIvAtE1.pgn"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+        let state =
+            StateHandle::default().with_lichess(LichessClient::with_base_url(&server.uri()));
+
+        let response = state
+            .import_lichess_study(String::from("StUdYiDx"), Some(String::from("prep")))
+            .await
+            .unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["study_chapters"][0]["id"], "prep-1");
+        assert_eq!(json["study_chapters"][0]["name"], "Italian");
+        assert_eq!(json["study_chapters"][1]["name"], "Lucena");
+        let lucena = &json["changed_games"][1];
+        assert_eq!(lucena["id"], "prep-2");
+        assert_eq!(lucena["game"]["info"]["title"], "Lucena");
+        assert_eq!(lucena["game"]["fen"], "1K6/1P2k3/8/8/8/8/r7/3R4 w - - 2 2");
+
+        let private = state
+            .import_lichess_study(String::from("PrIvAtE1"), None)
+            .await
+            .unwrap_err();
+        assert!(private.is_type(ErrorType::Network));
+        assert!(describe(&private).contains("token"));
+    }
+
+    #[tokio::test]
+    async fn import_game() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .and(query_param("clocks", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LICHESS_PGN))
+            .mount(&server)
+            .await;
+        let client = LichessClient::with_base_url(&server.uri());
+
+        let pgn = client.export_game("AbCdEfGh").await.unwrap();
+        let details = game_details("AbCdEfGh", &pgn);
+        assert_eq!(details.perf.as_deref(), Some("blitz"));
+        assert!(details.rated);
+        assert_eq!(details.white.rating, Some(1912));
+        assert_eq!(details.black.name, "bob");
+        assert_eq!(details.black.rating, None);
+
+        let missing = client.export_game("Missing1").await.unwrap_err();
+        assert!(missing.is_type(ErrorType::Network));
+
+        let state = StateHandle::default().with_lichess(client);
+        let response = state
+            .import_lichess_game(String::from("https://lichess.org/AbCdEfGh/white"), None)
+            .await
+            .unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["changed_games"][0]["id"], "lichess-AbCdEfGh");
+        let game = &json["changed_games"][0]["game"];
+        assert_eq!(game["info"]["lichess"]["game"]["white"]["name"], "alice");
+        assert_eq!(game["info"]["lichess"]["game"]["perf"], "blitz");
+    }
+
+    #[tokio::test]
+    async fn token() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/account"))
+            .and(header("Authorization", "Bearer lip_secret"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("X-OAuth-Scopes", "study:read, study:write")
+                    .set_body_json(serde_json::json!({"id": "alice", "username": "Alice"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/account"))
+            .respond_with(
+                ResponseTemplate::new(401)
+                    .set_body_json(serde_json::json!({"error": "No such token"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/study/PrIvAtE1.pgn"))
+            .and(header("Authorization", "Bearer lip_secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(STUDY_PGN))
+            .mount(&server)
+            .await;
+        let session_path = std::env::temp_dir().join(format!(
+            "bigchess-lichess-token-{}.json",
+            std::process::id()
+        ));
+        let state = StateHandle::default()
+            .with_lichess(LichessClient::with_base_url(&server.uri()))
+            .with_session(session_path.clone(), Session::default());
+
+        let line = r#"{"method": "lichess_set_token", "params": {"token": "lip_secret"}}"#;
+        let request: Request = serde_json::from_str(line).unwrap();
+        assert!(!format!("{:?}", request).contains("lip_secret"));
+        let wrong = LichessToken(String::from("lip_wrong"));
+        assert!(state.set_lichess_token(wrong).await.is_err());
+        let token = LichessToken(String::from("lip_secret"));
+        let response = state.set_lichess_token(token.clone()).await.unwrap();
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(!json.contains("lip_secret"));
+        let account = &serde_json::from_str::<serde_json::Value>(&json).unwrap()["lichess_account"];
+        assert_eq!(account["username"], "Alice");
+        assert_eq!(
+            account["scopes"],
+            serde_json::json!(["study:read", "study:write"])
+        );
+
+        // Sent with every request from now on
+        assert!(state
+            .import_lichess_study(String::from("PrIvAtE1"), None)
+            .await
+            .is_ok());
+
+        let session = Session::load(&session_path).unwrap();
+        assert_eq!(session.lichess_token, Some(token));
+        assert!(!format!("{:?}", session).contains("lip_secret"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let readable = std::fs::Permissions::from_mode(0o644);
+            std::fs::set_permissions(&session_path, readable).unwrap();
+            assert!(Session::load(&session_path).is_err());
+        }
+
+        state.clear_lichess_token().unwrap();
+        assert_eq!(Session::load(&session_path).unwrap().lichess_token, None);
+        std::fs::remove_file(&session_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn push_chapter() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/study/StUdYiDx/import-pgn"))
+            .and(header("Authorization", "Bearer lip_secret"))
+            .and(body_string_contains("name=My+prep"))
+            .and(body_string_contains("1.+e4+e5+%281...+c5%29+2.+Nf3"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "chapters": [{"id": "ChApTeR9", "name": "My prep"}],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/study/ReAdOnLy/import-pgn"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .set_body_json(serde_json::json!({"error": "Missing scope study:write"})),
+            )
+            .mount(&server)
+            .await;
+        let client = LichessClient::with_base_url(&server.uri());
+        let state = StateHandle::default().with_lichess(client.clone());
+        state.new_game_default("prep").unwrap();
+        for uci in &["e2e4", "e7e5", "g1f3"] {
+            state.play_uci("prep", uci).unwrap();
+        }
+        state.navigate_back("prep", 2, None).unwrap();
+        state.play_uci("prep", "c7c5").unwrap();
+
+        let push = |study_id: &str| {
+            state.push_lichess_study_chapter(
+                String::from("prep"),
+                String::from(study_id),
+                String::from("My prep"),
+            )
+        };
+        let no_token = push("StUdYiDx").await.unwrap_err();
+        assert!(describe(&no_token).contains("lichess_set_token"));
+
+        // Clones share the token
+        client
+            .set_token(Some(LichessToken(String::from("lip_secret"))))
+            .unwrap();
+        let json = serde_json::to_value(push("StUdYiDx").await.unwrap()).unwrap();
+        let chapter = &json["changed_games"][0]["game"]["info"]["lichess"]["chapter"];
+        assert_eq!(chapter["chapter_id"], "ChApTeR9");
+        assert!(chapter["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/study/StUdYiDx/ChApTeR9"));
+
+        let refused = push("ReAdOnLy").await.unwrap_err();
+        assert!(describe(&refused).contains("Missing scope study:write"));
+    }
+
+    #[tokio::test]
+    async fn user_games() {
+        let server = MockServer::start().await;
+        let games = [
+            r#"{"id":"GaMe0001","rated":true,"perf":"blitz","createdAt":1600000000000,"status":"mate","winner":"white","players":{"white":{"user":{"name":"alice"},"rating":1900},"black":{"user":{"name":"bob"},"rating":1850}},"opening":{"eco":"B90","name":"Sicilian Defense: Najdorf Variation","ply":10}}"#,
+            r#"{"id":"GaMe0002","rated":false,"perf":"blitz","createdAt":1590000000000,"status":"draw","players":{"white":{"aiLevel":3},"black":{"user":{"name":"alice"},"rating":1890}}}"#,
+            r#"{"id":"GaMe0003","rated":true,"perf":"blitz","createdAt":1580000000000,"status":"resign","winner":"black","players":{"white":{"user":{"name":"alice"},"rating":1880},"black":{"user":{"name":"carol"},"rating":2000}}}"#,
+        ];
+        Mock::given(method("GET"))
+            .and(path("/api/games/user/alice"))
+            .and(query_param("perfType", "blitz"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(games.join("\n") + "\n"))
+            .mount(&server)
+            .await;
+        // The second game doesn't read, its queen can't reach h4
+        let exported = LICHESS_PGN.replace("AbCdEfGh", "GaMe0001")
+            + &LICHESS_PGN
+                .replace("AbCdEfGh", "GaMe0002")
+                .replace("2. Qh5", "2. Qh4")
+            + &LICHESS_PGN
+                .replace("AbCdEfGh", "GaMe0003")
+                .replace("alice", "carol");
+        Mock::given(method("POST"))
+            .and(path("/api/games/export/_ids"))
+            .and(body_string_contains("GaMe0001"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(exported))
+            .mount(&server)
+            .await;
+        let state =
+            StateHandle::default().with_lichess(LichessClient::with_base_url(&server.uri()));
+
+        let filter = UserGamesFilter {
+            max: 2,
+            perf_type: Some(String::from("blitz")),
+            since: None,
+            until: None,
+        };
+        let json = serde_json::to_value(
+            state
+                .list_lichess_user_games(String::from("alice"), filter)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        let rows = json["lichess_games"].as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["result"], "1-0");
+        assert_eq!(rows[0]["opening"]["variation"], "Najdorf Variation");
+        assert_eq!(rows[1]["white"]["name"], "Stockfish level 3");
+        assert_eq!(rows[1]["result"], "1/2-1/2");
+        assert!(json["warnings"].is_null());
+
+        let ids = vec![String::from("GaMe0001"), String::from("GaMe0003")];
+        let json = serde_json::to_value(
+            state
+                .import_lichess_games(ids.clone(), ImportTarget::default())
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        let opened: Vec<&str> = json["changed_games"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|game| game["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(opened, vec!["lichess-GaMe0001", "lichess-GaMe0003"]);
+        let warnings = json["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0]["type"], "SkippedGame");
+        assert_eq!(warnings[0]["game_id"], "lichess-GaMe0002");
+        assert!(warnings[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Game 2:"));
+
+        let path = temp_database("lichess_user_games");
+        let db_id = String::from("lichess");
+        state
+            .open_database(db_id.clone(), path.display().to_string(), true)
+            .await
+            .unwrap();
+        let target = ImportTarget::Database {
+            db_id: db_id.clone(),
+        };
+        let json = serde_json::to_value(
+            state
+                .import_lichess_games(ids, target.clone())
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["import_summary"]["imported"], 2);
+        assert_eq!(json["import_summary"]["skipped"], 1);
+        assert_eq!(json["warnings"][0]["code"], 2);
+        // Games already in the database are left out
+        let json = serde_json::to_value(
+            state
+                .import_lichess_games(vec![String::from("GaMe0001")], target)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["import_summary"]["imported"], 0);
+        state.close_database(db_id).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn snapshot(status: &str, moves: &str, winner: Option<&str>) -> Value {
+        json!({
+            "id": "AbCdEfGh",
+            "rated": true,
+            "perf": "blitz",
+            "createdAt": 1600000000000u64,
+            "status": status,
+            "players": {
+                "white": {"user": {"name": "alice"}, "rating": 1912},
+                "black": {"user": {"name": "bob"}, "rating": 1850},
+            },
+            "winner": winner,
+            "moves": moves,
+        })
+    }
+
+    async fn follow_finished(
+        notifications: &mut tokio::sync::broadcast::Receiver<Response>,
+    ) -> Value {
+        loop {
+            let response = tokio::time::timeout(Duration::from_secs(10), notifications.recv())
+                .await
+                .expect("following should finish")
+                .unwrap();
+            let json = serde_json::to_value(response).unwrap();
+            if json["notification"]["type"] == "lichess_follow_finished" {
+                return json;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn follow_game() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .and(header("Accept", "application/json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(snapshot("started", "e4", None)))
+            .up_to_n_times(3)
+            .mount(&server)
+            .await;
+        let over = snapshot("resign", "e4 e5 Nf3", Some("white"));
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(over))
+            .mount(&server)
+            .await;
+        // The first connection breaks, the second one streams the moves
+        Mock::given(method("GET"))
+            .and(path("/api/stream/game/AbCdEfGh"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        let stream = [
+            r#"{"id": "AbCdEfGh", "fen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"}"#,
+            r#"{"fen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1", "lm": "e2e4"}"#,
+            r#"{"fen": "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2", "lm": "e7e5"}"#,
+            r#"{"fen": "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2", "lm": "g1f3"}"#,
+        ];
+        Mock::given(method("GET"))
+            .and(path("/api/stream/game/AbCdEfGh"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(stream.join("\n")))
+            .mount(&server)
+            .await;
+
+        let state =
+            StateHandle::default().with_lichess(LichessClient::with_base_url(&server.uri()));
+        let mut notifications = state.subscribe();
+        let request = r#"{"method": "lichess_follow_game", "params": {"game_id": "https://lichess.org/AbCdEfGh"}}"#;
+        let request: Request = serde_json::from_str(request).unwrap();
+        let response = crate::api::dispatch_request(request, &state).await.unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["changed_games"][0]["id"], "lichess-AbCdEfGh");
+        assert_eq!(
+            json["changed_games"][0]["game"]["info"]["lichess"]["game"]["white"]["name"],
+            "alice"
+        );
+
+        let finished = follow_finished(&mut notifications).await;
+        assert_eq!(finished["notification"]["stopped"], false);
+        assert_eq!(finished["notification"]["result"], "1-0");
+        let line = state
+            .with_game("lichess-AbCdEfGh", |game| Ok(game.uci_line()))
+            .unwrap();
+        assert_eq!(line, vec!["e2e4", "e7e5", "g1f3"]);
+        // The game can be changed again
+        assert!(state.navigate_back("lichess-AbCdEfGh", 1, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn unfollow() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(snapshot("started", "e4 c5", None)),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/stream/game/AbCdEfGh"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(60)))
+            .mount(&server)
+            .await;
+        let state =
+            StateHandle::default().with_lichess(LichessClient::with_base_url(&server.uri()));
+        let mut notifications = state.subscribe();
+        state
+            .follow_lichess_game(String::from("AbCdEfGh"), Some(String::from("live")))
+            .await
+            .unwrap();
+
+        let locked = state.play("live", String::from("g1"), String::from("f3"), None, None);
+        assert!(locked.unwrap_err().is_type(ErrorType::Locked));
+        assert!(state.navigate_back("live", 1, None).is_err());
+        let locked = state.set_result("live", GameResult::Draw);
+        assert!(locked.unwrap_err().is_type(ErrorType::Locked));
+        assert!(state
+            .follow_lichess_game(String::from("AbCdEfGh"), Some(String::from("live")))
+            .await
+            .is_err());
+
+        state.stop_job("live").unwrap();
+        let finished = follow_finished(&mut notifications).await;
+        assert_eq!(finished["notification"]["id"], "live");
+        assert_eq!(finished["notification"]["stopped"], true);
+        assert!(state
+            .play("live", String::from("g1"), String::from("f3"), None, None)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn import_puzzle() {
+        let server = MockServer::start().await;
+        let puzzle = json!({
+            "game": {"id": "AbCdEfGh", "pgn": "e4 e5 Nf3 Nc6 Bc4 Nd4"},
+            "puzzle": {
+                "id": "K69di",
+                "rating": 1500,
+                "plays": 1234,
+                "initialPly": 5,
+                "solution": ["f3e5", "d8g5", "e5f7"],
+                "themes": ["fork", "short"],
+            },
+        });
+        Mock::given(method("GET"))
+            .and(path("/api/puzzle/K69di"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(puzzle))
+            .mount(&server)
+            .await;
+        let state =
+            StateHandle::default().with_lichess(LichessClient::with_base_url(&server.uri()));
+
+        assert!(puzzle_id("AbCdEfGh").is_err());
+        let response = state
+            .import_lichess_puzzle(String::from("https://lichess.org/training/K69di"), None)
+            .await
+            .unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["changed_games"][0]["id"], "lichess-puzzle-K69di");
+        let game = &json["changed_games"][0]["game"];
+        assert_eq!(
+            game["fen"],
+            "r1bqkbnr/pppp1ppp/8/4p3/2BnP3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4"
+        );
+        assert_eq!(game["info"]["puzzle"]["rating"], 1500);
+        assert_eq!(game["info"]["puzzle"]["themes"][0], "fork");
+        assert!(game["info"]["puzzle"].get("solution").is_none());
+
+        let solution = state
+            .with_game("lichess-puzzle-K69di", |game| {
+                Ok(game.puzzle().unwrap().solution.clone())
+            })
+            .unwrap();
+        assert_eq!(solution, vec!["f3e5", "d8g5", "e5f7"]);
+        // The game starts before the opponent's move
+        let response = state
+            .navigate_back("lichess-puzzle-K69di", 1, None)
+            .unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(
+            json["changed_games"][0]["game"]["fen"],
+            "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3"
+        );
+    }
+
+    #[tokio::test]
+    async fn backoff() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LICHESS_PGN))
+            .mount(&server)
+            .await;
+        let client = LichessClient::with_base_url(&server.uri())
+            .with_backoff(Duration::from_millis(100), Duration::from_secs(1));
+
+        // Waits 100ms after the first 429, then 200ms after the second one
+        let start = Instant::now();
+        assert!(client.export_game("AbCdEfGh").await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        let start = Instant::now();
+        assert!(client.export_game("AbCdEfGh").await.is_ok());
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        Mock::given(method("GET"))
+            .and(path("/game/export/Limited1"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let impatient = LichessClient::with_base_url(&server.uri())
+            .with_backoff(Duration::from_secs(5), Duration::from_secs(1));
+        let start = Instant::now();
+        let limited = impatient.export_game("Limited1").await.unwrap_err();
+        assert!(limited.is_type(ErrorType::Network));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn base_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/game/export/AbCdEfGh"))
+            .and(header("User-Agent", USER_AGENT))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LICHESS_PGN))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let state = StateHandle::default();
+        state
+            .lichess()
+            .set_token(Some(LichessToken(String::from("lip_secret"))))
+            .unwrap();
+
+        let request = format!(
+            r#"{{"method": "set_lichess_config", "params": {{"base_url": "{}/"}}}}"#,
+            server.uri()
+        );
+        let request: Request = serde_json::from_str(&request).unwrap();
+        let response = crate::api::dispatch_request(request, &state).await.unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["lichess_config"]["base_url"], server.uri());
+        // The token of lichess.org isn't sent to another server
+        assert!(state.lichess().token.read().unwrap().is_none());
+
+        let response = state
+            .import_lichess_game(String::from("AbCdEfGh"), None)
+            .await
+            .unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["changed_games"][0]["id"], "lichess-AbCdEfGh");
+
+        assert!(state
+            .set_lichess_config(Some(String::from("lichess.org")))
+            .is_err());
+        let response = state.set_lichess_config(None).unwrap();
+        let json = serde_json::to_value(response).unwrap();
+        assert_eq!(json["lichess_config"]["base_url"], LICHESS_URL);
+    }
+}
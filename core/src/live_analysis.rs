@@ -0,0 +1,163 @@
+use crate::api::{response_from_error, response_from_notification, Notification};
+use crate::engine::{self, Analysis, EngineHandle};
+use crate::errors::{Error, ErrorType};
+use crate::jobs::JobTicket;
+use crate::state::StateHandle;
+
+use std::time::{Duration, Instant};
+
+use shakmaty::Setup;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// How often the game is checked for a new position to analyze.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Least time between two updates of the same analysis.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Analyzes the current position of game `id` until stopped, following it as moves are played or
+/// the game is navigated. New evaluations are notified as `AnalysisUpdate`.
+pub async fn run(state: StateHandle, id: String, engine: EngineHandle, ticket: JobTicket) {
+    let JobTicket { token, mut stop } = ticket;
+    let outcome = analyze(&state, &id, &engine, &mut stop).await;
+    let _ = state.analyses().finish(&id, token);
+    match outcome {
+        // The game was closed
+        Err(err) if err.is_type(ErrorType::BadHandle) || err.is_type(ErrorType::StaleHandle) => {}
+        Err(err) => state.notify(response_from_error(err.with_id(&id))),
+        Ok(()) => {}
+    }
+}
+
+async fn analyze(
+    state: &StateHandle,
+    id: &str,
+    handle: &EngineHandle,
+    stop: &mut oneshot::Receiver<()>,
+) -> Result<(), Error> {
+    let mut engine = tokio::select! {
+        engine = handle.lock() => engine,
+        _ = &mut *stop => return Ok(()),
+    };
+    engine.restore_options().await?;
+    // `position` command of the position analyzed, and whether the engine still searches it
+    let mut analyzed = String::new();
+    let mut searching = false;
+    let mut last_sent: Option<(Instant, Analysis)> = None;
+    let mut pending = None;
+    loop {
+        let (position, chess960) = state.with_game(id, |game| {
+            let position = engine::position_command(&game.initial_fen(), &game.uci_line());
+            Ok((position, game.is_chess960()))
+        })?;
+        if position != analyzed {
+            engine.set_chess960(chess960).await?;
+            engine.go_infinite(&position).await?;
+            analyzed = position;
+            searching = true;
+            pending = None;
+        }
+
+        // `None` when nothing new was reported in the meantime
+        let info = tokio::select! {
+            info = timeout(POLL_INTERVAL, engine.read_info()), if searching => info.ok(),
+            _ = tokio::time::delay_for(POLL_INTERVAL), if !searching => None,
+            _ = &mut *stop => {
+                engine.stop().await?;
+                return Ok(());
+            }
+        };
+        match info {
+            Some(Ok(Some((evaluation, pv)))) => {
+                pending = state.with_game(id, |game| {
+                    // The game may have moved on since the search started
+                    let position = engine::position_command(&game.initial_fen(), &game.uci_line());
+                    if position != analyzed {
+                        return Ok(None);
+                    }
+                    let pv = game.san_line(&pv);
+                    let turn = game.current_position().turn();
+                    Ok(pv.first().cloned().map(|best_move| Analysis {
+                        best_move,
+                        pv,
+                        evaluation: Some(evaluation.for_white(turn)),
+                    }))
+                })?;
+            }
+            Some(Ok(None)) => searching = false,
+            Some(Err(err)) => return Err(err),
+            None => {}
+        }
+
+        let due = last_sent
+            .as_ref()
+            .is_none_or(|(sent_at, _)| sent_at.elapsed() >= UPDATE_INTERVAL);
+        if due {
+            if let Some(analysis) = pending.take() {
+                if last_sent.as_ref().map(|(_, sent)| sent) != Some(&analysis) {
+                    state.notify(response_from_notification(Notification::AnalysisUpdate {
+                        id: id.to_string(),
+                        analysis: analysis.clone(),
+                    }));
+                    last_sent = Some((Instant::now(), analysis));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tests::{logged_mock_engine, received_commands};
+    use crate::state::StateHandle;
+
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn follows_the_game() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let (engine, log) = logged_mock_engine("live_analysis", &["e7e5@-20", "wait", "wait"]);
+        state.add_engine("mock", engine).await.unwrap();
+        state.new_game_default("g").unwrap();
+        state.play_uci("g", "e2e4").unwrap();
+
+        state.start_analysis("g", None).unwrap();
+        let response = notifications.recv().await.unwrap();
+        let notification: Value = serde_json::to_value(response).unwrap()["notification"].clone();
+        assert_eq!(notification["type"], "analysis_update");
+        let analysis = &notification["analysis"];
+        assert_eq!(analysis["best_move"], "e5");
+        assert_eq!(analysis["evaluation"]["score"]["cp"], 20);
+
+        // Moves can still be played, and the analysis moves along
+        state.play_uci("g", "e7e5").unwrap();
+        tokio::time::delay_for(super::POLL_INTERVAL * 3).await;
+        state.navigate_back("g", 2, None).unwrap();
+        tokio::time::delay_for(super::POLL_INTERVAL * 3).await;
+        state.stop_analysis("g").unwrap();
+        state.stop_analysis("g").unwrap();
+        tokio::time::delay_for(super::POLL_INTERVAL).await;
+        assert!(!state.analyses().is_running("g").unwrap());
+
+        let commands = received_commands(&log);
+        let searched: Vec<&String> = commands
+            .iter()
+            .filter(|command| command.starts_with("position") || command.starts_with("go"))
+            .collect();
+        let start = crate::game::Game::default().initial_fen();
+        assert_eq!(
+            searched,
+            vec![
+                &format!("position fen {} moves e2e4", start),
+                &String::from("go infinite"),
+                &format!("position fen {} moves e2e4 e7e5", start),
+                &String::from("go infinite"),
+                &format!("position fen {}", start),
+                &String::from("go infinite"),
+            ]
+        );
+        assert_eq!(commands.last().unwrap(), "stop");
+    }
+}
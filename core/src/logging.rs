@@ -0,0 +1,111 @@
+use crate::errors::{Error, ErrorType};
+
+use std::path::{Path, PathBuf};
+
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+/// Used when neither `--log-level` nor `RUST_LOG` says otherwise.
+const DEFAULT_LEVEL: &str = "warn";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Plain,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Where and how much the backend logs. Logs never go to stdout, which belongs to the protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    /// `trace`, `debug`, `info`, `warn` or `error`. `RUST_LOG` is read when not set.
+    pub level: Option<String>,
+    /// File rotated daily, used instead of stderr. The date is appended to its name.
+    pub file: Option<PathBuf>,
+    pub format: LogFormat,
+}
+
+/// Installs the subscriber of the whole process. Must be called once, before anything is logged.
+pub fn init(config: &LogConfig) -> Result<(), Error> {
+    tracing::subscriber::set_global_default(subscriber(config)?).map_err(|err| {
+        Error::new(ErrorType::IO).with_message(&format!("Could not set up logging: {}", err))
+    })
+}
+
+fn subscriber(config: &LogConfig) -> Result<Box<dyn Subscriber + Send + Sync>, Error> {
+    let filter = match &config.level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL)),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let subscriber: Box<dyn Subscriber + Send + Sync> = match (&config.file, config.format) {
+        (None, LogFormat::Plain) => Box::new(builder.with_writer(std::io::stderr).finish()),
+        (None, LogFormat::Json) => Box::new(builder.json().with_writer(std::io::stderr).finish()),
+        (Some(path), LogFormat::Plain) => Box::new(
+            builder
+                .with_ansi(false)
+                .with_writer(appender(path)?)
+                .finish(),
+        ),
+        (Some(path), LogFormat::Json) => {
+            Box::new(builder.json().with_writer(appender(path)?).finish())
+        }
+    };
+    Ok(subscriber)
+}
+
+fn appender(path: &Path) -> Result<RollingFileAppender, Error> {
+    let not_a_file = || {
+        Error::new(ErrorType::IO).with_message(&format!("{} is not a file name", path.display()))
+    };
+    let name = path.file_name().ok_or_else(not_a_file)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy())
+        .build(dir)
+        .map_err(|err| {
+            Error::new(ErrorType::IO).with_message(&format!(
+                "Could not open {}: {}",
+                path.display(),
+                err
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_file() {
+        let dir = std::env::temp_dir().join(format!("bigchess-logs-{}", std::process::id()));
+        let config = LogConfig {
+            level: Some(String::from("info")),
+            file: Some(dir.join("backend.log")),
+            format: LogFormat::Json,
+        };
+        tracing::subscriber::with_default(subscriber(&config).unwrap(), || {
+            tracing::debug!("hidden below info");
+            tracing::info!(db_id = "main", "database opened");
+        });
+
+        let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        assert!(file
+            .file_name()
+            .to_string_lossy()
+            .starts_with("backend.log"));
+        let logged = std::fs::read_to_string(file.path()).unwrap();
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "database opened");
+        assert_eq!(line["fields"]["db_id"], "main");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
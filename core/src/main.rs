@@ -1,34 +1,227 @@
+mod analyze;
+mod annotation;
 mod api;
+mod bench;
+mod book;
 mod cli_arguments;
+mod clock;
+mod convert;
 mod database;
+mod depth_analysis;
+mod drill;
+mod eco;
+mod endgame;
 mod engine;
+mod engine_match;
 mod errors;
+mod explorer;
 mod game;
+mod hash;
+mod jobs;
+mod lichess;
+mod live_analysis;
+mod logging;
+mod move_format;
+mod opponent;
+mod perft;
+mod pgn;
+mod puzzle;
+mod replay;
+mod routes;
+mod session;
+mod sockets;
 mod state;
+mod stats;
 mod stdio;
+mod supervisor;
+mod tablebase;
+mod transcript;
 
+use book::Book;
 use errors::Error;
+use explorer::ResponseCache;
+use replay::Recorder;
+use session::Session;
 
 use state::StateHandle;
-use tokio;
+use supervisor::Supervisor;
 
 #[tokio::main]
 async fn main() {
-    let _opts = cli_arguments::parse();
-    let state = StateHandle::default();
+    let config = cli_arguments::parse();
+    errors::capture_backtraces(config.backtraces);
+    game::set_max_plies(config.max_plies);
+    if let Err(err) = logging::init(&config.log) {
+        return exit_gracefully(Err(err));
+    }
+    if let Some(analyze) = config.analyze {
+        exit_command(analyze::run(analyze).await);
+    }
+    if let Some(bench) = config.bench {
+        exit_command(bench::run(&bench, &mut std::io::stdout()).await);
+    }
+    if let Some(perft) = config.perft {
+        exit_command(perft::run(&perft, &mut std::io::stdout()));
+    }
+    if let Some(convert) = config.convert {
+        exit_command(convert::run(&convert));
+    }
+    tracing::info!(version = env!("CARGO_PKG_VERSION"), "backend started");
+    let token = match config.auth_token() {
+        Ok(token) => token,
+        Err(err) => return exit_gracefully(Err(err)),
+    };
+    let state = match Supervisor::new(Supervisor::default_dir()) {
+        Ok(supervisor) => {
+            // Engines of a backend that was killed before it could terminate them
+            let _ = supervisor.reap_orphans();
+            StateHandle::supervised(supervisor)
+        }
+        Err(_) => StateHandle::default(),
+    };
+    let state = match config.book.map(|path| Book::open(&path)) {
+        Some(Ok(book)) => state.with_default_book(book),
+        Some(Err(err)) => return exit_gracefully(Err(err)),
+        None => state,
+    };
+    let state = state.with_request_echo_limit(config.request_echo_limit);
+    let state = match config.record.map(|path| Recorder::create(&path)) {
+        Some(Ok(recorder)) => state.with_recorder(recorder),
+        Some(Err(err)) => return exit_gracefully(Err(err)),
+        None => state,
+    };
+    // A replay starts from a blank state, not from the last session
+    let replaying = config.replay.is_some();
+    let session_path = config.session.or_else(Session::default_path);
+    let session_path = session_path.filter(|_| !replaying);
+    let state = match session_path {
+        Some(path) => match Session::load(&path) {
+            Ok(session) => state.with_session(path, session),
+            Err(err) => return exit_gracefully(Err(err)),
+        },
+        None => state,
+    };
+    let ttl = config.online_cache_ttl;
+    let state = match config
+        .online_cache
+        .map(|path| ResponseCache::open(&path, ttl))
+    {
+        Some(Ok(cache)) => state.with_online_cache(cache),
+        Some(Err(err)) => return exit_gracefully(Err(err)),
+        // Without a usable default file, answers are only cached in memory
+        None => match ResponseCache::default_path().filter(|_| !replaying) {
+            Some(path) => match ResponseCache::open(&path, ttl) {
+                Ok(cache) => state.with_online_cache(cache),
+                Err(_) => state,
+            },
+            None => state,
+        },
+    };
+    if let Some(fen) = config.fen {
+        if let Err(err) = state.new_game_fen(&config.startup_id, fen, false) {
+            return exit_gracefully(Err(err));
+        }
+    }
+    state.add_startup_engines(config.engines).await;
+    if let Some(path) = &config.replay {
+        let result = replay::run(&state, path, config.replay_speed, std::io::stdout()).await;
+        let _ = state.shutdown().await;
+        return exit_gracefully(result);
+    }
+    let mut transports = Vec::new();
+    if config.stdio {
+        transports.push(String::from("stdio"));
+    }
+    let http_server = match config.http.map(|addr| routes::bind(state.clone(), addr)) {
+        Some(Ok((addr, server))) => {
+            tracing::info!(%addr, "serving the HTTP API");
+            transports.push(format!("HTTP on {}", addr));
+            Some(server)
+        }
+        Some(Err(err)) => return exit_gracefully(Err(err)),
+        None => None,
+    };
+    let tcp_server = config
+        .listen_tcp
+        .map(|addr| sockets::listen_tcp(state.clone(), addr, token.clone()));
+    let tcp_server = match tcp_server {
+        Some(Ok((addr, server))) => {
+            tracing::info!(%addr, "serving TCP connections");
+            transports.push(format!("TCP on {}", addr));
+            Some(server)
+        }
+        Some(Err(err)) => return exit_gracefully(Err(err)),
+        None => None,
+    };
+    let unix_server = match &config.listen_unix {
+        Some(path) => match sockets::listen_unix(state.clone(), path, token) {
+            Ok(server) => {
+                tracing::info!(path = %path.display(), "serving Unix socket connections");
+                transports.push(format!("Unix socket {}", path.display()));
+                Some(server)
+            }
+            Err(err) => return exit_gracefully(Err(err)),
+        },
+        None => None,
+    };
+    eprintln!("bigchess-core serving: {}", transports.join(", "));
+    if !config.stdio {
+        // The stdio handler restores the session once the frontend knows the games
+        tokio::spawn(state.clone().restore_session());
+    }
     let stdio_handler = stdio::handler(state.clone());
 
-    let fatal_error = tokio::select! {
-        r1 = stdio_handler => {r1},
+    let result = tokio::select! {
+        r1 = stdio_handler, if config.stdio => {r1},
+        _ = serve(http_server) => Ok(()),
+        _ = serve(tcp_server) => Ok(()),
+        _ = serve(unix_server) => Ok(()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
     };
+    if let Some(path) = &config.listen_unix {
+        let _ = std::fs::remove_file(path);
+    }
+
+    // Engines must not outlive the backend, whatever the reason it stops
+    let _ = state.shutdown().await;
+    if result.is_err() {
+        if let Some(path) = state.dump_session() {
+            tracing::error!(path = %path.display(), "session dumped before exiting");
+        }
+    }
+    exit_gracefully(result);
+}
+
+/// Runs `server` if there is one, never finishes otherwise.
+async fn serve<F: std::future::Future<Output = ()>>(server: Option<F>) {
+    match server {
+        Some(server) => server.await,
+        None => std::future::pending().await,
+    }
+}
 
-    exit_gracefully(fatal_error);
+/// Exits with the outcome of a command run instead of the backend, which failed unless true.
+fn exit_command(outcome: Result<bool, Error>) -> ! {
+    match outcome {
+        Ok(true) => std::process::exit(0),
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }
+    }
 }
 
-// TODO
+// TODO Maybe save current files, etc.
 fn exit_gracefully(result: Result<(), Error>) {
-    // Not sure how to shutdown if there's not an error
-    // Maybe save current files, etc.
-    let fatal_error = api::response_from_error(result.unwrap_err());
-    stdio::send_to_stream(fatal_error, &mut std::io::stdout())
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(err) => {
+            tracing::error!(%err, "fatal error");
+            let fatal_error = api::response_from_error(err);
+            // Nothing more can be done when the output is gone
+            let _ = stdio::send_to_stream(fatal_error, std::io::stdout());
+            std::process::exit(1)
+        }
+    }
 }
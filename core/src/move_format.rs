@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::san::{SanPlus, Suffix};
+use shakmaty::{Chess, Move, Role};
+
+/// How moves are written for display. Games and their exports keep standard SAN.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct MoveFormat {
+    pub notation: Notation,
+    /// Language of the piece letters of SAN and LAN.
+    pub piece_locale: PieceLocale,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Notation {
+    /// `Nxf7+`
+    #[default]
+    San,
+    /// Both squares, as `Ng1-f3` or `Bc4xf7+`.
+    Lan,
+    /// SAN with chess glyphs for the pieces, as `♘f3`.
+    Figurine,
+    /// English sentences for screen readers, as `knight from g1 to f3, check`.
+    Spoken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PieceLocale {
+    #[default]
+    En,
+    De,
+    Fr,
+    Es,
+    It,
+    Nl,
+}
+
+impl PieceLocale {
+    /// Letters of the king, queen, rook, bishop and knight.
+    fn letters(self) -> [char; 5] {
+        match self {
+            PieceLocale::En => ['K', 'Q', 'R', 'B', 'N'],
+            PieceLocale::De => ['K', 'D', 'T', 'L', 'S'],
+            PieceLocale::Fr => ['R', 'D', 'T', 'F', 'C'],
+            PieceLocale::Es | PieceLocale::It => ['R', 'D', 'T', 'A', 'C'],
+            PieceLocale::Nl => ['K', 'D', 'T', 'L', 'P'],
+        }
+    }
+}
+
+const FIGURINES: [char; 5] = ['♔', '♕', '♖', '♗', '♘'];
+
+impl MoveFormat {
+    /// `m` played in `position`.
+    pub fn format(&self, position: &Chess, m: &Move) -> String {
+        let san = SanPlus::from_move(position.clone(), m);
+        match self.notation {
+            Notation::San => pieces(&san.to_string(), self.piece_locale.letters()),
+            Notation::Lan => pieces(&lan(&san, m), self.piece_locale.letters()),
+            Notation::Figurine => pieces(&san.to_string(), FIGURINES),
+            Notation::Spoken => spoken(&san, m),
+        }
+    }
+}
+
+/// Replaces the English piece letters of `text` by `pieces`.
+fn pieces(text: &str, pieces: [char; 5]) -> String {
+    text.chars()
+        .map(|c| match "KQRBN".find(c) {
+            Some(index) => pieces[index],
+            None => c,
+        })
+        .collect()
+}
+
+fn lan(san: &SanPlus, m: &Move) -> String {
+    let suffix = san.suffix.map_or("", suffix_char);
+    match m {
+        Move::Castle { .. } => format!("{}{}", san.san, suffix),
+        _ => {
+            let piece = match m.role() {
+                Role::Pawn => String::new(),
+                role => role.upper_char().to_string(),
+            };
+            let from = m.from().map(|from| from.to_string()).unwrap_or_default();
+            let separator = if m.is_capture() { 'x' } else { '-' };
+            let promotion = m
+                .promotion()
+                .map(|role| format!("={}", role.upper_char()))
+                .unwrap_or_default();
+            format!(
+                "{}{}{}{}{}{}",
+                piece,
+                from,
+                separator,
+                m.to(),
+                promotion,
+                suffix
+            )
+        }
+    }
+}
+
+fn suffix_char(suffix: Suffix) -> &'static str {
+    match suffix {
+        Suffix::Check => "+",
+        Suffix::Checkmate => "#",
+    }
+}
+
+fn spoken(san: &SanPlus, m: &Move) -> String {
+    let mut text = match m {
+        Move::Castle { king, rook } if rook.file() < king.file() => {
+            String::from("castles queenside")
+        }
+        Move::Castle { .. } => String::from("castles kingside"),
+        _ => {
+            let from = m
+                .from()
+                .map(|from| format!(" from {}", from))
+                .unwrap_or_default();
+            let verb = if m.is_capture() { "takes" } else { "to" };
+            format!("{}{} {} {}", role_name(m.role()), from, verb, m.to())
+        }
+    };
+    if let Some(role) = m.promotion() {
+        text.push_str(&format!(", promotes to {}", role_name(role)));
+    }
+    match san.suffix {
+        Some(Suffix::Check) => text.push_str(", check"),
+        Some(Suffix::Checkmate) => text.push_str(", checkmate"),
+        None => (),
+    }
+    text
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::King => "king",
+        Role::Queen => "queen",
+        Role::Rook => "rook",
+        Role::Bishop => "bishop",
+        Role::Knight => "knight",
+        Role::Pawn => "pawn",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::fen::Fen;
+    use shakmaty::uci::Uci;
+
+    #[test]
+    fn notations() {
+        let moves = [
+            // Castling, capture with check, promotion, checkmate
+            ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1"),
+            ("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1", "e8c8"),
+            (
+                "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/8/PPPP1PPP/RNBQK1NR w KQkq - 2 3",
+                "c4f7",
+            ),
+            ("8/4P3/8/8/8/2k5/8/K7 w - - 0 1", "e7e8q"),
+            (
+                "rnbqkbnr/pppp1ppp/8/4p3/6P1/5P2/PPPPP2P/RNBQKBNR b KQkq - 0 2",
+                "d8h4",
+            ),
+            ("8/8/8/8/8/8/8/KN5k w - - 0 1", "b1c3"),
+        ];
+        let expected = [
+            (
+                Notation::San,
+                PieceLocale::En,
+                ["O-O", "O-O-O", "Bxf7+", "e8=Q", "Qh4#", "Nc3"],
+            ),
+            (
+                Notation::San,
+                PieceLocale::De,
+                ["O-O", "O-O-O", "Lxf7+", "e8=D", "Dh4#", "Sc3"],
+            ),
+            (
+                Notation::Lan,
+                PieceLocale::En,
+                ["O-O", "O-O-O", "Bc4xf7+", "e7-e8=Q", "Qd8-h4#", "Nb1-c3"],
+            ),
+            (
+                Notation::Lan,
+                PieceLocale::Fr,
+                ["O-O", "O-O-O", "Fc4xf7+", "e7-e8=D", "Dd8-h4#", "Cb1-c3"],
+            ),
+            (
+                Notation::Figurine,
+                PieceLocale::En,
+                ["O-O", "O-O-O", "♗xf7+", "e8=♕", "♕h4#", "♘c3"],
+            ),
+            (
+                Notation::Spoken,
+                PieceLocale::En,
+                [
+                    "castles kingside",
+                    "castles queenside",
+                    "bishop from c4 takes f7, check",
+                    "pawn from e7 to e8, promotes to queen",
+                    "queen from d8 to h4, checkmate",
+                    "knight from b1 to c3",
+                ],
+            ),
+        ];
+        for (notation, piece_locale, texts) in &expected {
+            let format = MoveFormat {
+                notation: *notation,
+                piece_locale: *piece_locale,
+            };
+            for ((fen, uci), text) in moves.iter().zip(texts) {
+                let fen: Fen = fen.parse().unwrap();
+                let position: Chess = fen.position().unwrap();
+                let m = uci.parse::<Uci>().unwrap().to_move(&position).unwrap();
+                assert_eq!(format.format(&position, &m), *text);
+            }
+        }
+    }
+}
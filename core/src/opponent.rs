@@ -0,0 +1,305 @@
+use crate::api::{response_from_error, response_from_game, Notification};
+use crate::drill::Side;
+use crate::engine::{position_command, EngineHandle};
+use crate::errors::{Error, ErrorType};
+use crate::jobs::JobTicket;
+use crate::state::StateHandle;
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// Engine playing one side of a game, replying to the moves played for the other.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Opponent {
+    /// Default engine when not given.
+    #[serde(default)]
+    pub engine_id: Option<String>,
+    pub side: Side,
+    pub movetime_ms: u64,
+    /// `Skill Level` option of engines supporting it, as Stockfish's 0 to 20. Only set for the
+    /// opponent's own searches.
+    #[serde(default)]
+    pub skill_level: Option<u32>,
+}
+
+/// Plays the opponent's move in game `id`, notified with the game. The move is dropped if the
+/// game was navigated or changed during the search: the opponent waits for the next move played.
+/// Once played, the engine ponders on the reply it predicted, if any.
+pub async fn run(
+    state: StateHandle,
+    id: String,
+    engine: EngineHandle,
+    opponent: Opponent,
+    ticket: JobTicket,
+) {
+    let JobTicket { token, mut stop } = ticket;
+    let outcome = reply(&state, &id, &engine, &opponent, &mut stop).await;
+    let _ = state.opponents().finish(&id, token);
+    match outcome {
+        // The game was closed
+        Err(err) if err.is_type(ErrorType::BadHandle) || err.is_type(ErrorType::StaleHandle) => {}
+        Err(err) => state.notify(response_from_error(err.with_id(&id))),
+        Ok(()) => {}
+    }
+}
+
+async fn reply(
+    state: &StateHandle,
+    id: &str,
+    handle: &EngineHandle,
+    opponent: &Opponent,
+    stop: &mut oneshot::Receiver<()>,
+) -> Result<(), Error> {
+    let (fen, moves, chess960) = state.with_game(id, |game| {
+        Ok((game.initial_fen(), game.uci_line(), game.is_chess960()))
+    })?;
+    let mut engine = tokio::select! {
+        engine = handle.lock() => engine,
+        _ = &mut *stop => return Ok(()),
+    };
+    engine.set_chess960(chess960).await?;
+    match opponent.skill_level {
+        Some(level) => {
+            engine
+                .override_option("Skill Level", &level.to_string())
+                .await?;
+        }
+        None => engine.restore_options().await?,
+    }
+    let position = position_command(&fen, &moves);
+    let go = format!("go movetime {}", opponent.movetime_ms);
+    let budget = Duration::from_millis(opponent.movetime_ms);
+    let best_move = tokio::select! {
+        best_move = engine.best_move(&position, &go, budget) => best_move?,
+        _ = &mut *stop => {
+            engine.stop().await?;
+            return engine.restore_options().await;
+        }
+    };
+
+    let response = state.with_game(id, |game| {
+        if game.uci_line() != moves || !game.opponent_to_move() {
+            return Ok(None);
+        }
+        game.play_uci(&best_move.uci)?;
+        Ok(Some(response_from_game(
+            id.to_string(),
+            state.game_repr(game),
+        )))
+    })?;
+    if response.is_some() {
+        if let Some(predicted) = &best_move.ponder {
+            let mut line = moves;
+            line.push(best_move.uci.clone());
+            line.push(predicted.clone());
+            engine.ponder(&position_command(&fen, &line), &go).await?;
+        }
+    }
+    // Pondering carries on the reply, options included
+    if !engine.is_pondering() {
+        engine.restore_options().await?;
+    }
+    drop(engine);
+
+    if let Some(response) = response {
+        state.notify(response.with_notification(Notification::OpponentMove {
+            id: id.to_string(),
+            uci: best_move.uci,
+        }));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::tests::{after, logged_mock_engine, mock_engine, received_commands};
+
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn replies_to_moves() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let engine = mock_engine(&["e2e4", "g1f3"]);
+        state.add_engine("mock", engine).await.unwrap();
+        state.new_game_default("g").unwrap();
+
+        let opponent = Opponent {
+            engine_id: None,
+            side: Side::White,
+            movetime_ms: 10,
+            skill_level: None,
+        };
+        let response = state.set_opponent("g", Some(opponent)).unwrap();
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(
+            response["changed_games"][0]["game"]["opponent"]["engine_id"],
+            "mock"
+        );
+
+        // White moves first
+        let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(response["notification"]["type"], "opponent_move");
+        assert_eq!(response["notification"]["uci"], "e2e4");
+        assert_eq!(response["changed_games"][0]["game"]["last_move"], "e4");
+
+        state
+            .play("g", "e7".into(), "e5".into(), None, None)
+            .unwrap();
+        let response: Value = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(response["notification"]["uci"], "g1f3");
+
+        // Back to white's turn: the engine waits for a move instead of replying again
+        state.navigate_back("g", 1, None).unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(notifications.try_recv().is_err());
+        assert!(!state.opponents().is_running("g").unwrap());
+        let line = state.with_game("g", |game| Ok(game.uci_line())).unwrap();
+        assert_eq!(line, vec!["e2e4", "e7e5"]);
+
+        state.set_opponent("g", None).unwrap();
+        state.navigate_back("g", 1, None).unwrap();
+        state
+            .play("g", "e7".into(), "e6".into(), None, None)
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(notifications.try_recv().is_err());
+    }
+
+    fn white_opponent() -> Opponent {
+        Opponent {
+            engine_id: None,
+            side: Side::White,
+            movetime_ms: 10,
+            skill_level: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn skill_level_restored() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let (mut config, log) = logged_mock_engine("opponent-skill", &["e2e4", "d7d5"]);
+        config.args.insert(0, String::from("--skill"));
+        state.add_engine("mock", config).await.unwrap();
+        state.new_game_default("g").unwrap();
+
+        let opponent = Opponent {
+            skill_level: Some(3),
+            ..white_opponent()
+        };
+        state.set_opponent("g", Some(opponent)).unwrap();
+        notifications.recv().await.unwrap();
+        state.analyze("g", None, 1).unwrap();
+        notifications.recv().await.unwrap();
+
+        let commands = received_commands(&log);
+        let limited = commands
+            .iter()
+            .position(|c| c == "setoption name Skill Level value 3")
+            .unwrap();
+        assert_eq!(commands[limited + 3], "go movetime 10");
+        let restored = commands
+            .iter()
+            .position(|c| c == "setoption name Skill Level value 20")
+            .unwrap();
+        assert!(restored > limited + 3);
+        let analysis = commands.iter().position(|c| c.starts_with("go depth 1 "));
+        assert!(restored < analysis.unwrap());
+    }
+
+    #[tokio::test]
+    async fn skill_level_unsupported() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let (config, log) = logged_mock_engine("opponent-no-skill", &["e2e4"]);
+        state.add_engine("mock", config).await.unwrap();
+        state.new_game_default("g").unwrap();
+
+        let opponent = Opponent {
+            skill_level: Some(3),
+            ..white_opponent()
+        };
+        state.set_opponent("g", Some(opponent)).unwrap();
+        notifications.recv().await.unwrap();
+        let commands = received_commands(&log);
+        assert!(!commands.iter().any(|c| c.contains("Skill Level")));
+    }
+
+    /// Commands received from the `go ponder` on.
+    fn since_ponder(commands: &[String]) -> &[String] {
+        let start = commands
+            .iter()
+            .position(|c| c == "go ponder movetime 10")
+            .unwrap();
+        &commands[start..]
+    }
+
+    #[tokio::test]
+    async fn ponder_hit() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let (mut config, log) =
+            logged_mock_engine("opponent-ponder-hit", &["e2e4:e7e5", "g1f3:b8c6"]);
+        config.ponder = true;
+        state.add_engine("mock", config).await.unwrap();
+        state.new_game_default("g").unwrap();
+
+        state.set_opponent("g", Some(white_opponent())).unwrap();
+        notifications.recv().await.unwrap();
+        state
+            .play("g", "e7".into(), "e5".into(), None, None)
+            .unwrap();
+        let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(response["notification"]["uci"], "g1f3");
+
+        let commands = received_commands(&log);
+        let pondering = since_ponder(&commands);
+        assert_eq!(
+            commands[commands.len() - pondering.len() - 1],
+            after(&["e2e4", "e7e5"])
+        );
+        assert_eq!(pondering[1], "ponderhit");
+
+        // Pondering on b8c6 ends with the opponent
+        state.set_opponent("g", None).unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let commands = received_commands(&log);
+        let last = &commands[commands.len() - 2..];
+        assert_eq!(last, ["go ponder movetime 10", "stop"]);
+    }
+
+    #[tokio::test]
+    async fn ponder_miss() {
+        let state = StateHandle::default();
+        let mut notifications = state.subscribe();
+        let (mut config, log) =
+            logged_mock_engine("opponent-ponder-miss", &["e2e4:e7e5", "d2d4:g8f6"]);
+        config.ponder = true;
+        state.add_engine("mock", config).await.unwrap();
+        state.new_game_default("g").unwrap();
+
+        state.set_opponent("g", Some(white_opponent())).unwrap();
+        notifications.recv().await.unwrap();
+        state
+            .play("g", "c7".into(), "c5".into(), None, None)
+            .unwrap();
+        let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(response["notification"]["uci"], "d2d4");
+
+        let commands = received_commands(&log);
+        let pondering = since_ponder(&commands);
+        assert_eq!(pondering[1], "stop");
+        assert_eq!(pondering[2], after(&["e2e4", "c7c5"]));
+        assert_eq!(pondering[3], "go movetime 10");
+
+        // Pondering on g8f6 ends when navigating away
+        state.navigate_back("g", 1, None).unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let commands = received_commands(&log);
+        assert_eq!(commands.last().unwrap(), "stop");
+    }
+}
@@ -0,0 +1,229 @@
+use crate::errors::{Error, ErrorType};
+use crate::hash;
+
+use std::io::Write;
+use std::time::Instant;
+
+use shakmaty::uci::Uci;
+use shakmaty::{fen, Chess, MoveList, Position};
+
+/// Positions whose node counts are known, by depth starting at 1.
+const KNOWN: &[(&str, &str, &[u64])] = &[
+    (
+        "start",
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        &[20, 400, 8902, 197_281, 4_865_609, 119_060_324],
+    ),
+    (
+        "Kiwipete",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        &[48, 2039, 97_862, 4_085_603, 193_690_690],
+    ),
+];
+
+/// Parameters of the `perft` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerftConfig {
+    pub fen: Option<String>,
+    pub depth: u32,
+    /// Also count the nodes under each legal move of the position.
+    pub divide: bool,
+    /// Compare the counts with those of the known positions.
+    pub verify: bool,
+    /// Time updating zobrist keys move by move against hashing every position again.
+    pub hash: bool,
+}
+
+/// Leaf nodes of the move tree `depth` plies deep.
+pub fn perft(position: &Chess, depth: u32) -> u64 {
+    shakmaty::perft(position, depth)
+}
+
+/// Leaf nodes under each legal move, in UCI.
+pub fn divide(position: &Chess, depth: u32) -> Vec<(String, u64)> {
+    let mut moves = MoveList::new();
+    position.legal_moves(&mut moves);
+    moves
+        .iter()
+        .map(|m| {
+            let mut child = position.clone();
+            child.play_unchecked(m);
+            let uci = Uci::from_move(position, m).to_string();
+            (uci, perft(&child, depth.saturating_sub(1)))
+        })
+        .collect()
+}
+
+/// XOR of the keys of the positions under `position`, whose key is `key`, updated move by move.
+pub fn incremental_keys(position: &Chess, key: u64, depth: u32) -> u64 {
+    let mut keys = key;
+    if depth > 0 {
+        for m in &position.legals() {
+            let mut child = position.clone();
+            child.play_unchecked(m);
+            keys ^= incremental_keys(&child, hash::update(key, position, m, &child), depth - 1);
+        }
+    }
+    keys
+}
+
+/// Same as `incremental_keys`, hashing every position from scratch.
+pub fn recomputed_keys(position: &Chess, depth: u32) -> u64 {
+    let mut keys = hash::zobrist(position);
+    if depth > 0 {
+        for m in &position.legals() {
+            let mut child = position.clone();
+            child.play_unchecked(m);
+            keys ^= recomputed_keys(&child, depth - 1);
+        }
+    }
+    keys
+}
+
+/// Counts the nodes at every depth up to `config.depth`, writing them with their timing to `out`.
+/// Returns false if `--verify` found a wrong count.
+pub fn run(config: &PerftConfig, out: &mut impl Write) -> Result<bool, Error> {
+    let fen = config.fen.as_deref().unwrap_or(KNOWN[0].1);
+    let setup: fen::Fen = fen.parse()?;
+    let position: Chess = setup.position()?;
+    let expected = if config.verify {
+        let known = known_counts(&position).ok_or_else(|| {
+            Error::new(ErrorType::ChessRules)
+                .with_message("--verify only knows the start position and Kiwipete")
+        })?;
+        Some(known)
+    } else {
+        None
+    };
+
+    let mut correct = true;
+    for depth in 1..=config.depth {
+        let start = Instant::now();
+        let nodes = perft(&position, depth);
+        let seconds = start.elapsed().as_secs_f64();
+        write!(
+            out,
+            "depth {:>2}  {:>12} nodes  {:>8.3}s  {:>12.0} nodes/s",
+            depth,
+            nodes,
+            seconds,
+            nodes as f64 / seconds.max(1e-9)
+        )?;
+        match expected.and_then(|known| known.get(depth as usize - 1)) {
+            Some(&known) if known == nodes => writeln!(out, "  ok")?,
+            Some(&known) => {
+                correct = false;
+                writeln!(out, "  expected {}", known)?;
+            }
+            None => writeln!(out)?,
+        }
+    }
+
+    if config.hash {
+        let start = Instant::now();
+        let incremental = incremental_keys(&position, hash::zobrist(&position), config.depth);
+        let incremental_seconds = start.elapsed().as_secs_f64();
+        let start = Instant::now();
+        let recomputed = recomputed_keys(&position, config.depth);
+        let recomputed_seconds = start.elapsed().as_secs_f64();
+        write!(
+            out,
+            "\nhashing   incremental {:.3}s  recomputed {:.3}s",
+            incremental_seconds, recomputed_seconds
+        )?;
+        if incremental == recomputed {
+            writeln!(out, "  ok")?;
+        } else {
+            correct = false;
+            writeln!(out, "  keys differ")?;
+        }
+    }
+
+    if config.divide {
+        writeln!(out)?;
+        for (uci, nodes) in divide(&position, config.depth) {
+            writeln!(out, "{}: {}", uci, nodes)?;
+        }
+    }
+    Ok(correct)
+}
+
+fn known_counts(position: &Chess) -> Option<&'static [u64]> {
+    let fen = fen::epd(position);
+    KNOWN
+        .iter()
+        .find(|(_, known, _)| known.starts_with(&fen))
+        .map(|(_, _, counts)| *counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(config: &PerftConfig) -> (bool, String) {
+        let mut out = Vec::new();
+        let correct = run(config, &mut out).unwrap();
+        (correct, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn known_positions() {
+        for (name, fen, counts) in KNOWN {
+            let setup: fen::Fen = fen.parse().unwrap();
+            let position: Chess = setup.position().unwrap();
+            for (depth, &count) in counts.iter().enumerate().take(3) {
+                assert_eq!(perft(&position, depth as u32 + 1), count, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let config = PerftConfig {
+            fen: None,
+            depth: 3,
+            divide: true,
+            verify: true,
+            hash: false,
+        };
+        let (correct, out) = output(&config);
+        assert!(correct);
+        assert!(out.contains("depth  3"));
+        assert!(out.contains("8902 nodes"));
+        assert_eq!(out.matches("  ok").count(), 3);
+        // 20 root moves, whose counts add up to the total
+        assert!(out.contains("e2e4: 600\n"));
+        let divided: u64 = out
+            .lines()
+            .filter_map(|line| line.split(": ").nth(1)?.parse::<u64>().ok())
+            .sum();
+        assert_eq!(divided, 8902);
+
+        let kiwipete = PerftConfig {
+            fen: Some(String::from(KNOWN[1].1)),
+            depth: 2,
+            divide: false,
+            verify: true,
+            hash: true,
+        };
+        let (correct, out) = output(&kiwipete);
+        assert!(correct);
+        assert!(out.contains("2039 nodes"));
+        assert!(out.contains("incremental"));
+        assert_eq!(out.matches("  ok").count(), 3);
+
+        let unknown = PerftConfig {
+            fen: Some(String::from("4k3/8/8/8/8/8/8/4K3 w - - 0 1")),
+            ..config.clone()
+        };
+        assert!(run(&unknown, &mut Vec::new()).is_err());
+        let unverified = PerftConfig {
+            verify: false,
+            divide: false,
+            ..unknown
+        };
+        let (correct, out) = output(&unverified);
+        assert!(correct);
+        assert!(!out.contains("ok"));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::errors::{Error, ErrorType};
+
+use std::io::{BufRead, Write};
+
+use shakmaty::san::San;
+use shakmaty::{Chess, Position};
+
+/// A game as written in a PGN file: tag pairs and unparsed movetext.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PgnGame {
+    pub headers: Vec<(String, String)>,
+    pub movetext: String,
+}
+
+/// Element of the movetext. Move numbers are dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    San(String),
+    /// Numeric annotation glyph, `$n` or a suffix like `!?`.
+    Nag(u8),
+    Comment(String),
+    StartVariation,
+    EndVariation,
+    /// Game termination marker (1-0, 0-1, 1/2-1/2, *).
+    Result(String),
+}
+
+/// Tags written first, in this order, as the PGN standard requires.
+const SEVEN_TAG_ROSTER: [&str; 7] = ["Event", "Site", "Date", "Round", "White", "Black", "Result"];
+
+/// Splits a PGN stream into games, reading one game at a time.
+pub struct PgnReader<R: BufRead> {
+    input: R,
+    /// Tag line read while looking for the end of the previous game.
+    pending: Option<String>,
+}
+
+impl<R: BufRead> PgnReader<R> {
+    pub fn new(input: R) -> PgnReader<R> {
+        PgnReader {
+            input,
+            pending: None,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, Error> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(&['\n', '\r'][..]).to_string()))
+    }
+}
+
+impl<R: BufRead> Iterator for PgnReader<R> {
+    type Item = Result<PgnGame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut game = PgnGame::default();
+        let mut open_comment = false;
+        loop {
+            let line = match self.read_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) => return Some(Err(err)),
+            };
+            let trimmed = line.trim();
+
+            if !open_comment && trimmed.starts_with('[') {
+                if !game.movetext.trim().is_empty() {
+                    // Start of the next game
+                    self.pending = Some(line);
+                    break;
+                }
+                if let Some(tag) = parse_tag(trimmed) {
+                    game.headers.push(tag);
+                }
+            } else if !open_comment && trimmed.starts_with('%') {
+                // Escaped line
+            } else {
+                open_comment = comment_still_open(open_comment, &line);
+                game.movetext.push_str(&line);
+                game.movetext.push('\n');
+            }
+        }
+
+        if game.headers.is_empty() && game.movetext.trim().is_empty() {
+            None
+        } else {
+            Some(Ok(game))
+        }
+    }
+}
+
+impl PgnGame {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Position given by the FEN tag, the standard one otherwise.
+    pub fn start_position(&self) -> Result<Chess, Error> {
+        match self.header("FEN") {
+            Some(fen) => {
+                let setup: shakmaty::fen::Fen = fen.parse()?;
+                Ok(setup.position()?)
+            }
+            None => Ok(Chess::default()),
+        }
+    }
+
+    /// Positions of the main line, starting position included.
+    pub fn positions(&self) -> Result<Vec<Chess>, Error> {
+        let mut position = self.start_position()?;
+        let mut positions = vec![position.clone()];
+        for m in self.main_line()? {
+            position.play_unchecked(&m);
+            positions.push(position.clone());
+        }
+        Ok(positions)
+    }
+
+    /// Writes the game in export format: the seven tag roster first ("?" when missing), then the
+    /// other tags, then the movetext ended by the result.
+    pub fn write<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let result = self.header("Result").unwrap_or("*");
+        for tag in SEVEN_TAG_ROSTER.iter() {
+            let value = match *tag {
+                "Result" => result,
+                _ => self.header(tag).unwrap_or("?"),
+            };
+            write_tag(out, tag, value)?;
+        }
+        for (tag, value) in &self.headers {
+            if !SEVEN_TAG_ROSTER.contains(&tag.as_str()) {
+                write_tag(out, tag, value)?;
+            }
+        }
+
+        let movetext = self.movetext.trim();
+        let terminated = matches!(
+            tokenize(movetext)
+                .ok()
+                .and_then(|tokens| tokens.last().cloned()),
+            Some(Token::Result(_))
+        );
+        writeln!(out)?;
+        match (movetext.is_empty(), terminated) {
+            (_, true) => writeln!(out, "{}", movetext)?,
+            (true, false) => writeln!(out, "{}", result)?,
+            (false, false) => writeln!(out, "{} {}", movetext, result)?,
+        }
+        writeln!(out)
+    }
+
+    /// Whether the movetext has comments, NAGs or variations.
+    pub fn has_annotations(&self) -> Result<bool, Error> {
+        Ok(tokenize(&self.movetext)?
+            .iter()
+            .any(|token| !matches!(token, Token::San(_) | Token::Result(_))))
+    }
+
+    /// Moves of the main line, checked against the rules.
+    pub fn main_line(&self) -> Result<Vec<shakmaty::Move>, Error> {
+        let mut position = self.start_position()?;
+        let mut moves = Vec::new();
+        let mut depth = 0;
+        for token in tokenize(&self.movetext)? {
+            match token {
+                Token::StartVariation => depth += 1,
+                Token::EndVariation => depth -= 1,
+                Token::San(san) if depth == 0 => {
+                    let m = san.parse::<San>()?.to_move(&position)?;
+                    position.play_unchecked(&m);
+                    moves.push(m);
+                }
+                _ => {}
+            }
+        }
+        Ok(moves)
+    }
+}
+
+/// `[White "Kasparov, Garry"]`
+fn parse_tag(line: &str) -> Option<(String, String)> {
+    let inner = line.strip_prefix('[')?.trim_end().strip_suffix(']')?;
+    let (name, value) = inner.split_once(char::is_whitespace)?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    Some((name.to_string(), unescaped))
+}
+
+fn write_tag<W: Write>(out: &mut W, name: &str, value: &str) -> std::io::Result<()> {
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    writeln!(out, "[{} \"{}\"]", name, escaped)
+}
+
+/// Whether a `{` comment is still open at the end of `line`.
+fn comment_still_open(mut open: bool, line: &str) -> bool {
+    for c in line.chars() {
+        match c {
+            '{' if !open => open = true,
+            '}' if open => open = false,
+            ';' if !open => return false,
+            _ => {}
+        }
+    }
+    open
+}
+
+pub fn tokenize(movetext: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = movetext.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '{' => {
+                chars.next();
+                let comment: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                tokens.push(Token::Comment(comment.trim().to_string()));
+            }
+            ';' => {
+                let comment: String = chars.by_ref().take_while(|c| *c != '\n').collect();
+                tokens.push(Token::Comment(comment[1..].trim().to_string()));
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::StartVariation);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::EndVariation);
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{}();".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                push_word(&word, &mut tokens)?;
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn push_word(word: &str, tokens: &mut Vec<Token>) -> Result<(), Error> {
+    if let Some(nag) = word.strip_prefix('$') {
+        let nag = nag.parse().map_err(|_| bad_token(word))?;
+        tokens.push(Token::Nag(nag));
+        return Ok(());
+    }
+    if ["1-0", "0-1", "1/2-1/2", "*"].contains(&word) {
+        tokens.push(Token::Result(word.to_string()));
+        return Ok(());
+    }
+
+    // Move numbers, possibly glued to the move (`12.e4`, `12...Nf6`)
+    let after_digits = word.trim_start_matches(|c: char| c.is_ascii_digit());
+    let word = if after_digits.is_empty() || after_digits.starts_with('.') {
+        after_digits.trim_start_matches('.')
+    } else {
+        word
+    };
+    if word.is_empty() {
+        return Ok(());
+    }
+
+    // Castling is sometimes written with zeros
+    let castling = word.replace('0', "O");
+    let word = if word.starts_with("0-0") {
+        castling.as_str()
+    } else {
+        word
+    };
+
+    let san = word.trim_end_matches(&['!', '?'][..]);
+    if san.is_empty() {
+        return Err(bad_token(word));
+    }
+    tokens.push(Token::San(san.to_string()));
+    if let Some(nag) = suffix_nag(&word[san.len()..]) {
+        tokens.push(Token::Nag(nag));
+    }
+    Ok(())
+}
+
+/// Move suffix annotations and their NAG equivalent.
+fn suffix_nag(suffix: &str) -> Option<u8> {
+    match suffix {
+        "!" => Some(1),
+        "?" => Some(2),
+        "!!" => Some(3),
+        "??" => Some(4),
+        "!?" => Some(5),
+        "?!" => Some(6),
+        _ => None,
+    }
+}
+
+fn bad_token(word: &str) -> Error {
+    Error::new(ErrorType::Parse).with_message(&format!("Unexpected token {} in movetext", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMES: &str = r#"[Event "Casual"]
+[White "Anderssen, Adolf"]
+[Black "Kieseritzky, Lionel"]
+
+1. e4 e5 2. f4 {King's Gambit,
+accepted next} exf4?! (2... d5 $1) 3.Bc4 1-0
+
+[Event "Casual"]
+[White "Someone"]
+% escaped line
+1. d4 d5 ; line comment
+2. c4 *
+"#;
+
+    #[test]
+    fn split_games() {
+        let games: Vec<PgnGame> = PgnReader::new(GAMES.as_bytes())
+            .map(|game| game.unwrap())
+            .collect();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].header("White"), Some("Anderssen, Adolf"));
+        assert_eq!(games[0].header("Result"), None);
+        assert!(games[0].movetext.contains("accepted next"));
+        assert_eq!(games[1].headers.len(), 2);
+        assert!(!games[1].movetext.contains("escaped"));
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens =
+            tokenize("1. e4 e5 2. f4 {Gambit} exf4?! (2... d5 $1) 3.Bc4 ; done\n 1-0").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::San(String::from("e4")),
+                Token::San(String::from("e5")),
+                Token::San(String::from("f4")),
+                Token::Comment(String::from("Gambit")),
+                Token::San(String::from("exf4")),
+                Token::Nag(6),
+                Token::StartVariation,
+                Token::San(String::from("d5")),
+                Token::Nag(1),
+                Token::EndVariation,
+                Token::San(String::from("Bc4")),
+                Token::Comment(String::from("done")),
+                Token::Result(String::from("1-0")),
+            ]
+        );
+        assert!(tokenize("1. e4 $x").is_err());
+        assert_eq!(
+            tokenize("5.0-0-0!").unwrap(),
+            vec![Token::San(String::from("O-O-O")), Token::Nag(1)]
+        );
+    }
+
+    #[test]
+    fn write() {
+        let game = PgnGame {
+            headers: vec![
+                (String::from("Annotator"), String::from("Me")),
+                (String::from("White"), String::from("The \"Boss\" \\o/")),
+                (String::from("Result"), String::from("1-0")),
+            ],
+            movetext: String::from("1. e4 {Best by test} e5\n"),
+        };
+        let mut out = Vec::new();
+        game.write(&mut out).unwrap();
+        let written = String::from_utf8(out).unwrap();
+        assert!(written.starts_with("[Event \"?\"]\n[Site \"?\"]"));
+        assert!(written.contains("[White \"The \\\"Boss\\\" \\\\o/\"]\n"));
+        assert!(written.ends_with("[Annotator \"Me\"]\n\n1. e4 {Best by test} e5 1-0\n\n"));
+
+        let read = PgnReader::new(written.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(read.header("White"), game.header("White"));
+        assert_eq!(read.main_line().unwrap(), game.main_line().unwrap());
+    }
+
+    #[test]
+    fn main_line() {
+        let game = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from("1. e4 e5 (1... c5 2. Nf3) 2. Nf3 Nc6 *"),
+        };
+        assert_eq!(game.main_line().unwrap().len(), 4);
+
+        let illegal = PgnGame {
+            headers: Vec::new(),
+            movetext: String::from("1. e4 Ke5"),
+        };
+        assert!(illegal.main_line().is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+/// Wrong attempts at a move before its piece is hinted, as many more before the whole move is.
+pub const DEFAULT_ATTEMPTS_BEFORE_HINT: u32 = 2;
+
+/// Progress through the solution of a puzzle being solved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solving {
+    /// Moves of both sides in UCI notation, the solver's first.
+    solution: Vec<String>,
+    /// Index in `solution` of the solver's next move.
+    next: usize,
+    /// Wrong attempts at the next move.
+    attempts: u32,
+    /// Hints given for the next move: 1 for its piece, 2 for the whole move.
+    hint_level: u32,
+    mistakes: u32,
+    hints: u32,
+    attempts_before_hint: u32,
+    started_at: u64,
+}
+
+impl Solving {
+    pub fn new(solution: Vec<String>, attempts_before_hint: u32, at: u64) -> Solving {
+        Solving {
+            solution,
+            next: 0,
+            attempts: 0,
+            hint_level: 0,
+            mistakes: 0,
+            hints: 0,
+            attempts_before_hint: attempts_before_hint.max(1),
+            started_at: at,
+        }
+    }
+
+    /// Solution move matching the `from`/`to` squares of an attempt, and its promotion piece
+    /// when it gives one: without, the solution's promotion is taken.
+    pub fn expected(&self, from: &str, to: &str, promotion: Option<char>) -> Option<&str> {
+        let expected = self.solution.get(self.next)?;
+        let squares = expected.get(..4)?;
+        let promotes_right = promotion.is_none_or(|piece| expected[4..].starts_with(piece));
+        if squares == format!("{}{}", from, to) && promotes_right {
+            Some(expected)
+        } else {
+            None
+        }
+    }
+
+    /// Steps past the solver's move, returning the opponent's reply if the puzzle goes on.
+    pub fn advance(&mut self) -> Option<&str> {
+        self.attempts = 0;
+        self.hint_level = 0;
+        self.next += 2;
+        self.solution.get(self.next - 1).map(String::as_str)
+    }
+
+    pub fn solved(&self) -> bool {
+        self.next >= self.solution.len()
+    }
+
+    /// Counts a wrong attempt, hinting once there were enough.
+    pub fn reject(&mut self) -> PuzzleAttempt {
+        self.attempts += 1;
+        self.mistakes += 1;
+        let level = (self.attempts / self.attempts_before_hint).min(2);
+        if level > self.hint_level {
+            self.hints += level - self.hint_level;
+            self.hint_level = level;
+        }
+        let expected = &self.solution[self.next];
+        let hint = match level {
+            0 => None,
+            1 => Some(PuzzleHint::Piece {
+                square: expected[..2].to_string(),
+            }),
+            _ => Some(PuzzleHint::Move {
+                uci: expected.clone(),
+            }),
+        };
+        PuzzleAttempt::TryAgain {
+            attempts: self.attempts,
+            hint,
+        }
+    }
+
+    pub fn summary(&self, at: u64) -> PuzzleAttempt {
+        PuzzleAttempt::Solved {
+            mistakes: self.mistakes,
+            hints: self.hints,
+            time_ms: at.saturating_sub(self.started_at),
+        }
+    }
+}
+
+/// Outcome of a move played while solving a puzzle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "result")]
+pub enum PuzzleAttempt {
+    /// The move was played, then the opponent's `reply` in SAN.
+    Correct { reply: String },
+    /// The move wasn't played. `attempts` counts the wrong ones at this move.
+    TryAgain {
+        attempts: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hint: Option<PuzzleHint>,
+    },
+    /// The last move of the solution was played.
+    Solved {
+        mistakes: u32,
+        hints: u32,
+        time_ms: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum PuzzleHint {
+    /// Square of the piece to move.
+    Piece {
+        square: String,
+    },
+    Move {
+        uci: String,
+    },
+}
@@ -0,0 +1,168 @@
+use crate::errors::{self, Error, ErrorType};
+use crate::session;
+use crate::state::StateHandle;
+use crate::stdio::{self, MessageWriter};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Appends every request line received to a file, each after a `# +<ms> ms` comment giving its
+/// time since the recording started. Lines carrying a secret are replaced by a comment. Cloning
+/// shares the file.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+    start: Instant,
+}
+
+/// Request of a recording, with its time since the recording started if known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorded {
+    pub at: Option<Duration>,
+    pub line: String,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder, Error> {
+        let mut file = session::owner_only().create(true).append(true).open(path)?;
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            file,
+            "# bigchess-core {} recording started at {} (unix time)",
+            env!("CARGO_PKG_VERSION"),
+            since_epoch.as_secs()
+        )?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+            start: Instant::now(),
+        })
+    }
+
+    /// Failing to record never fails the request.
+    pub fn record(&self, line: &str) {
+        let elapsed = self.start.elapsed().as_millis();
+        if let Ok(mut file) = self.file.lock() {
+            let written = if errors::carries_secret(line) {
+                writeln!(file, "# +{} ms\n# redacted, carries a secret", elapsed)
+            } else {
+                writeln!(file, "# +{} ms\n{}", elapsed, line)
+            };
+            if let Err(err) = written {
+                tracing::warn!(%err, "could not record a request");
+            }
+        }
+    }
+}
+
+/// Requests of a recording, comments skipped.
+pub fn read(input: impl BufRead) -> Result<Vec<Recorded>, Error> {
+    let mut requests = Vec::new();
+    let mut at = None;
+    for line in input.lines() {
+        let line = line?;
+        match line.strip_prefix('#') {
+            Some(comment) => {
+                let millis = comment
+                    .trim()
+                    .strip_prefix('+')
+                    .and_then(|time| time.strip_suffix("ms")?.trim().parse::<u64>().ok());
+                if let Some(millis) = millis {
+                    at = Some(Duration::from_millis(millis));
+                }
+            }
+            None if line.trim().is_empty() => {}
+            None => requests.push(Recorded {
+                at: at.take(),
+                line,
+            }),
+        }
+    }
+    Ok(requests)
+}
+
+/// Answers the requests recorded at `path` on `out` like stdio would, without notifications.
+/// With a `speed`, requests are spaced as they were recorded, `speed` times faster.
+pub async fn run<W: Write>(
+    state: &StateHandle,
+    path: &Path,
+    speed: Option<f64>,
+    out: W,
+) -> Result<(), Error> {
+    let requests = read(BufReader::new(File::open(path).map_err(|err| {
+        Error::new(ErrorType::IO).with_message(&format!(
+            "Could not read {}: {}",
+            path.display(),
+            err
+        ))
+    })?))?;
+
+    let mut out = MessageWriter::new(out);
+    out.respond(&state.initial_message()?)?;
+    let start = Instant::now();
+    for request in requests {
+        if let (Some(speed), Some(at)) = (speed, request.at) {
+            let due = at.div_f64(speed);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                tokio::time::delay_for(wait).await;
+            }
+        }
+        let response = stdio::dispatch(&request.line, state).await?;
+        out.respond(&response)?;
+        if state.is_shut_down() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_read() {
+        let path =
+            std::env::temp_dir().join(format!("bigchess-recording-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(r#"{"method": "get_all_games", "params": {}}"#);
+        recorder.clone().record("not json");
+        recorder.record(r#"{"method": "lichess_set_token", "params": {"token": "lip_secret"}}"#);
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        assert!(recorded.starts_with("# bigchess-core"));
+        assert!(!recorded.contains("lip_secret"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let requests = read(recorded.as_bytes()).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].line, "not json");
+        assert!(requests[0].at.unwrap() <= requests[1].at.unwrap());
+
+        let handwritten = "# a comment\n\n{}\n# +1500 ms\n{\"a\": 1}\n";
+        let requests = read(handwritten.as_bytes()).unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                Recorded {
+                    at: None,
+                    line: String::from("{}")
+                },
+                Recorded {
+                    at: Some(Duration::from_millis(1500)),
+                    line: String::from("{\"a\": 1}")
+                },
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use crate::api::{dispatch_request, Request, Response};
+use crate::errors::{Error, ErrorType};
+use crate::state::StateHandle;
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// Binds `addr` right away, so that a port already in use is reported before the backend starts.
+/// The server runs until the returned future is dropped.
+pub fn bind(
+    state: StateHandle,
+    addr: SocketAddr,
+) -> Result<(SocketAddr, impl Future<Output = ()>), Error> {
+    warp::serve(routes(state))
+        .try_bind_ephemeral(addr)
+        .map_err(|err| {
+            Error::new(ErrorType::IO)
+                .with_message(&format!("Could not listen on {}: {}", addr, err))
+        })
+}
+
+/// `GET /games` answers like `get_all_games`, `POST /request` takes any request of the stdio
+/// protocol as its body. `GET /metrics` gives the stats in the Prometheus text format.
+pub fn routes(state: StateHandle) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let games = warp::path("games")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: StateHandle| reply(state.get_all_games()));
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: StateHandle| match state.stats() {
+            Ok(stats) => warp::reply::with_status(stats.to_prometheus(), StatusCode::OK),
+            Err(err) => {
+                warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        });
+    let request = warp::path("request")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(|request: Request, state: StateHandle| async move {
+            // Malformed bodies are rejected before, the parsed request is echoed in errors
+            let line = serde_json::to_string(&request).unwrap_or_default();
+            let response = dispatch_request(request, &state).await;
+            let limit = state.request_echo_limit();
+            Ok::<_, Infallible>(reply(
+                response.map(|response| response.echoing(&line, limit)),
+            ))
+        });
+    games.or(metrics).or(request).recover(recover)
+}
+
+fn with_state(
+    state: StateHandle,
+) -> impl Filter<Extract = (StateHandle,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+/// Responses carrying an error get the status of its type.
+fn reply(result: Result<Response, Error>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(response) => {
+            let status = response
+                .error()
+                .map_or(StatusCode::OK, |error| error.error_type().status_code());
+            warp::reply::with_status(warp::reply::json(&response), status)
+        }
+        Err(err) => err.to_http_reply(),
+    }
+}
+
+/// Bodies that aren't requests are answered like malformed stdio lines, other rejections are
+/// left to warp.
+async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<warp::body::BodyDeserializeError>() {
+        Some(err) => {
+            let message = err.to_string();
+            Ok(Error::new(ErrorType::Deserialize)
+                .with_message(&message)
+                .to_http_reply())
+        }
+        None => Err(rejection),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn requests() {
+        let routes = routes(StateHandle::default());
+        let new_game = r#"{"method": "new_game", "params": {"id": "g1"}}"#;
+        let response = warp::test::request()
+            .method("POST")
+            .path("/request")
+            .body(new_game)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request().path("/games").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(json["changed_games"][0]["id"], "g1");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/request")
+            .body("{}")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let illegal = r#"{"method": "play", "params": {"id": "g1", "from": "e2", "to": "e5"}}"#;
+        warp::test::request()
+            .method("POST")
+            .path("/request")
+            .body(illegal)
+            .reply(&routes)
+            .await;
+        let response = warp::test::request().path("/metrics").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let metrics = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(metrics.contains("bigchess_requests_total 2\n"));
+        assert!(metrics.contains("bigchess_errors_total{type=\"ChessRules\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn error_statuses() {
+        let routes = routes(StateHandle::default());
+        let post = |body: &'static str| {
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request()
+                    .method("POST")
+                    .path("/request")
+                    .body(body)
+                    .reply(&routes)
+                    .await;
+                let json: Value = serde_json::from_slice(response.body()).unwrap();
+                (response.status(), json["error"]["code"].clone())
+            }
+        };
+        post(r#"{"method": "new_game", "params": {"id": "g1"}}"#).await;
+
+        let cases = [
+            ("{}", ErrorType::Deserialize),
+            (
+                r#"{"method": "new_game", "params": {"id": "g2", "fen": "not a position"}}"#,
+                ErrorType::Parse,
+            ),
+            (
+                r#"{"method": "play", "params": {"id": "g1", "from": "e2", "to": "e5"}}"#,
+                ErrorType::ChessRules,
+            ),
+            (
+                r#"{"method": "play", "params": {"id": "nowhere", "from": "e2", "to": "e4"}}"#,
+                ErrorType::BadHandle,
+            ),
+            (
+                r#"{"method": "get_database_info", "params": {"db_id": "closed"}}"#,
+                ErrorType::Database,
+            ),
+        ];
+        for (body, error_type) in cases.iter() {
+            let (status, code) = post(body).await;
+            assert_eq!(status, error_type.status_code(), "{}", body);
+            assert_eq!(code, error_type.code(), "{}", body);
+        }
+        assert_eq!(ErrorType::Parse.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(ErrorType::BadHandle.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(ErrorType::Locked.status_code(), StatusCode::LOCKED);
+        assert_eq!(
+            ErrorType::LimitExceeded.status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            ErrorType::PoisonedHandle.status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let response = warp::test::request().path("/nowhere").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::errors::{Error, ErrorType};
+use crate::lichess::LichessToken;
+use crate::move_format::MoveFormat;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// What the backend restores at startup, saved as JSON whenever it changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Session {
+    /// Open databases, the most recently opened last.
+    #[serde(default)]
+    pub databases: Vec<SessionDatabase>,
+    /// Database searched when a request doesn't name one.
+    #[serde(default)]
+    pub default_database: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lichess_token: Option<LichessToken>,
+    /// Self-hosted lila instance used instead of lichess.org.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lichess_url: Option<String>,
+    #[serde(default)]
+    pub move_format: MoveFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionDatabase {
+    pub db_id: String,
+    pub path: String,
+}
+
+impl Session {
+    /// `$XDG_CONFIG_HOME/bigchess/session.json`, or the equivalent of the platform.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config_dir.join("bigchess").join("session.json"))
+    }
+
+    /// An empty session if the file doesn't exist yet. Refuses a file holding a lichess token that
+    /// other users can read.
+    pub fn load(path: &Path) -> Result<Session, Error> {
+        let session: Session = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Session::default()),
+            Err(err) => return Err(err.into()),
+        };
+        if session.lichess_token.is_some() && readable_by_others(path)? {
+            return Err(Error::new(ErrorType::IO).with_message(&format!(
+                "{} holds a lichess token but other users can read it, restrict it to its owner (chmod 600)",
+                path.display()
+            )));
+        }
+        Ok(session)
+    }
+
+    /// Written to a temporary file first, so that a crash never leaves half a session behind.
+    /// That file is created readable by its owner only, and never through a link left in its way.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = path.with_extension("json.tmp");
+        // Left by a crash during an earlier save
+        if let Err(err) = fs::remove_file(&temporary) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+        let mut file = owner_only().write(true).create_new(true).open(&temporary)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        drop(file);
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    pub fn database_opened(&mut self, db_id: &str, path: &str) {
+        self.databases.retain(|database| database.db_id != db_id);
+        self.databases.push(SessionDatabase {
+            db_id: db_id.to_string(),
+            path: path.to_string(),
+        });
+    }
+
+    pub fn database_closed(&mut self, db_id: &str) {
+        self.databases.retain(|database| database.db_id != db_id);
+        if self.default_database.as_deref() == Some(db_id) {
+            self.default_database = None;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn readable_by_others(path: &Path) -> Result<bool, Error> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::metadata(path)?.permissions().mode() & 0o077 != 0)
+}
+
+#[cfg(not(unix))]
+fn readable_by_others(_path: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
+
+/// Options creating files that only their owner can read, for those that may hold a secret.
+#[cfg(unix)]
+pub fn owner_only() -> OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = OpenOptions::new();
+    options.mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+pub fn owner_only() -> OpenOptions {
+    OpenOptions::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::temp_database;
+    use crate::database::Database;
+    use crate::state::StateHandle;
+
+    #[tokio::test]
+    async fn restore() {
+        let session_path =
+            std::env::temp_dir().join(format!("bigchess-session-{}.json", std::process::id()));
+        let database_path = temp_database("session");
+        Database::create(&database_path).unwrap();
+        let missing_path = temp_database("session_missing");
+        let mut session = Session::default();
+        session.database_opened("gone", &missing_path.display().to_string());
+        session.database_opened("main", &database_path.display().to_string());
+        session.default_database = Some(String::from("main"));
+        session.save(&session_path).unwrap();
+
+        let state = StateHandle::default().with_session(session_path.clone(), session);
+        let mut notifications = state.subscribe();
+        state.clone().restore_session().await;
+
+        let gone = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(gone["notification"]["type"], "database_reopened");
+        assert_eq!(gone["notification"]["db_id"], "gone");
+        assert!(gone["notification"]["warning"].is_string());
+        assert!(gone["database"].is_null());
+        let main = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(main["notification"]["db_id"], "main");
+        assert!(main["notification"]["warning"].is_null());
+        assert_eq!(main["database"]["game_count"], 0);
+
+        // The default database is searched when none is named
+        let start = String::from("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        assert!(state.search_position(None, start, 10, 0).await.is_ok());
+
+        let saved = Session::load(&session_path).unwrap();
+        assert_eq!(saved.databases.len(), 1);
+        assert_eq!(saved.default_database.as_deref(), Some("main"));
+        state.close_database(String::from("main")).await.unwrap();
+        assert_eq!(Session::load(&session_path).unwrap(), Session::default());
+
+        std::fs::remove_file(&session_path).unwrap();
+        std::fs::remove_file(&database_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir();
+        let session_path = dir.join(format!("bigchess-private-{}.json", std::process::id()));
+        let target = dir.join(format!("bigchess-private-{}.target", std::process::id()));
+        fs::write(&target, "untouched").unwrap();
+        let planted = session_path.with_extension("json.tmp");
+        std::os::unix::fs::symlink(&target, &planted).unwrap();
+
+        Session::default().save(&session_path).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+        let mode = fs::metadata(&session_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(&session_path).unwrap();
+        fs::remove_file(&target).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use crate::api::{response_from_error, Response};
+use crate::errors::{Error, ErrorType};
+use crate::state::StateHandle;
+use crate::stdio;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// First line of every connection when the backend has a token: `{"token": "..."}`.
+#[derive(Deserialize)]
+struct Authentication {
+    token: String,
+}
+
+/// Binds `addr` right away, so that an address in use is reported before the backend starts.
+/// Every connection is served like stdio until the returned future is dropped.
+pub fn listen_tcp(
+    state: StateHandle,
+    addr: SocketAddr,
+    token: Option<String>,
+) -> Result<(SocketAddr, impl Future<Output = ()>), Error> {
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(TcpListener::from_std)
+        .map_err(|err| cannot_listen(&addr, err))?;
+    let local_addr = listener.local_addr()?;
+    Ok((local_addr, serve_tcp(state, listener, token)))
+}
+
+/// Replaces the socket left behind by a backend that was killed, but not one still answering.
+#[cfg(unix)]
+pub fn listen_unix(
+    state: StateHandle,
+    path: &Path,
+    token: Option<String>,
+) -> Result<impl Future<Output = ()>, Error> {
+    if path.exists() && std::os::unix::net::UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path).map_err(|err| cannot_listen(&path.display(), err))?;
+    Ok(serve_unix(state, listener, token))
+}
+
+#[cfg(not(unix))]
+pub fn listen_unix(
+    _state: StateHandle,
+    _path: &Path,
+    _token: Option<String>,
+) -> Result<std::future::Pending<()>, Error> {
+    Err(Error::new(ErrorType::IO).with_message("Unix sockets are not supported on this platform"))
+}
+
+fn cannot_listen(address: &dyn std::fmt::Display, err: std::io::Error) -> Error {
+    Error::new(ErrorType::IO).with_message(&format!("Could not listen on {}: {}", address, err))
+}
+
+async fn serve_tcp(state: StateHandle, mut listener: TcpListener, token: Option<String>) {
+    let token = token.map(Arc::new);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tracing::debug!(%peer, "TCP connection");
+                tokio::spawn(connection(state.clone(), stream, token.clone()));
+            }
+            Err(err) => tracing::warn!(%err, "could not accept a TCP connection"),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(state: StateHandle, mut listener: UnixListener, token: Option<String>) {
+    let token = token.map(Arc::new);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tracing::debug!("Unix socket connection");
+                tokio::spawn(connection(state.clone(), stream, token.clone()));
+            }
+            Err(err) => tracing::warn!(%err, "could not accept a Unix socket connection"),
+        }
+    }
+}
+
+async fn connection<S>(state: StateHandle, stream: S, token: Option<Arc<String>>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    if let Err(err) = handler(state, stream, token).await {
+        tracing::warn!(%err, "connection closed");
+    }
+}
+
+/// Same protocol as stdio: the games first, then a response per request line and the
+/// notifications.
+async fn handler<S>(state: StateHandle, stream: S, token: Option<Arc<String>>) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    if let Some(token) = token {
+        let line = lines.next_line().await?.unwrap_or_default();
+        let authenticated =
+            serde_json::from_str::<Authentication>(&line).is_ok_and(|auth| auth.token == *token);
+        if !authenticated {
+            let refused = response_from_error(Error::new(ErrorType::Unauthorized));
+            send(refused, &mut writer).await?;
+            return Ok(());
+        }
+    }
+
+    let mut notifications = state.subscribe();
+    send(state.initial_message()?, &mut writer).await?;
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                let response = stdio::dispatch(&line, &state).await?;
+                send(response, &mut writer).await?;
+                if state.is_shut_down() {
+                    return Ok(());
+                }
+            }
+            notification = notifications.recv() => {
+                if let Ok(response) = notification {
+                    send(response, &mut writer).await?;
+                }
+            }
+        }
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(response: Response, writer: &mut W) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(&response)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
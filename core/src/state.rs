@@ -1,76 +1,1707 @@
-use crate::api::{response_from_game, response_from_games, Response};
-use crate::errors::{Error, ErrorType};
-use crate::game::Game;
+use crate::annotation::{self, AnnotationSettings};
+use crate::api::{
+    initial_response, response_from_book_moves, response_from_closed_game,
+    response_from_cloud_eval, response_from_database, response_from_database_game,
+    response_from_databases, response_from_deleted_games, response_from_duplicates,
+    response_from_engine_log, response_from_engines, response_from_explorer, response_from_game,
+    response_from_games, response_from_import_summary, response_from_lichess_account,
+    response_from_lichess_config, response_from_lichess_games, response_from_maintenance,
+    response_from_online_explorer, response_from_pgn, response_from_search, response_from_stats,
+    response_from_study, response_from_tablebase, response_from_tree, Notification, Response,
+};
+use crate::book::Book;
+use crate::clock::{self, TimeControl};
+use crate::database::{
+    self, Database, DatabaseRegistry, ImportOptions, MaintenanceAction, SearchFilters, SortColumn,
+};
+use crate::depth_analysis;
+use crate::drill::Drill;
+use crate::engine::{Engine, EngineConfig, EngineRegistry};
+use crate::engine_match::{self, MatchSettings};
+use crate::errors::{Error, ErrorType, WarningRepr, WarningType};
+use crate::explorer::{CacheMode, ExplorerClient, ExplorerSource, ResponseCache};
+use crate::game::{
+    Annotation, Evaluation, Game, GameInfoUpdate, GameRepr, GameResult, Lichess, Link, LinkMode,
+    Player, Promotion,
+};
+use crate::hash;
+use crate::jobs::Jobs;
+use crate::lichess::{
+    self, ImportTarget, LichessClient, LichessToken, StudyChapter, UserGamesFilter,
+};
+use crate::live_analysis;
+use crate::move_format::MoveFormat;
+use crate::opponent::{self, Opponent};
+use crate::pgn::PgnReader;
+use crate::puzzle;
+use crate::replay::Recorder;
+use crate::session::Session;
+use crate::stats::{Stats, StatsRecorder};
+use crate::supervisor::Supervisor;
+use crate::tablebase::TablebaseClient;
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use tokio::sync::broadcast;
 
 type GameCell = Option<Mutex<Game>>;
 type InnerState = HashMap<String, GameCell>;
 
+/// Notifications not yet read by a lagging receiver are dropped past this amount.
+const NOTIFICATION_CAPACITY: usize = 256;
+
+/// Bytes of a failed request line echoed in its error, unless configured otherwise.
+pub const DEFAULT_REQUEST_ECHO_LIMIT: usize = 256;
+
+/// Games from which `get_all_games` spreads reprs across threads. Below, starting the threads
+/// costs more than it saves.
+const PARALLEL_REPRS_MIN_GAMES: usize = 32;
+
 pub struct StateHandle {
     inner: Arc<RwLock<InnerState>>,
+    engines: EngineRegistry,
+    jobs: Jobs,
+    /// Imports and reindexing, keyed by the id of the database they write to.
+    database_jobs: Jobs,
+    /// PGN exports, keyed by the path of the file they write.
+    exports: Jobs,
+    /// Continuous analyses, which leave their game free to change.
+    analyses: Jobs,
+    /// Replies of engine opponents, see `set_opponent`.
+    opponents: Jobs,
+    notifications: broadcast::Sender<Response>,
+    shut_down: Arc<AtomicBool>,
+    databases: DatabaseRegistry,
+    /// Book whose moves are added to every game representation.
+    book: Option<Arc<Book>>,
+    lichess: LichessClient,
+    tablebase: TablebaseClient,
+    /// Lichess opening explorer and cloud evaluations, with their cache.
+    explorer: ExplorerClient,
+    session: Arc<Mutex<Session>>,
+    /// Where `session` is saved after every change, if anywhere.
+    session_path: Option<Arc<PathBuf>>,
+    /// Problems met while starting, sent with the initial message.
+    startup_warnings: Arc<Mutex<Vec<WarningRepr>>>,
+    /// Where request lines are recorded, if anywhere.
+    recorder: Option<Recorder>,
+    /// Bytes of a failed request line echoed in its error.
+    request_echo_limit: usize,
+    stats: StatsRecorder,
 }
 
 impl StateHandle {
-    pub fn play(&self, id: &String, from: String, to: String) -> Result<Response, Error> {
-        self.game_operation(id, |game| game.play(&from, &to))
+    /// State whose engine processes are recorded by `supervisor`.
+    pub fn supervised(supervisor: Supervisor) -> StateHandle {
+        StateHandle {
+            engines: EngineRegistry::with_supervisor(supervisor),
+            ..StateHandle::default()
+        }
     }
 
-    pub fn navigate_back(&self, id: &String, back: u16) -> Result<Response, Error> {
-        self.game_operation(id, |game| {
+    /// State remembering its open databases in the file at `path`. They are reopened by
+    /// `restore_session`.
+    pub fn with_session(self, path: PathBuf, session: Session) -> StateHandle {
+        // Nothing used the client yet, its locks can't be poisoned. A URL that isn't one keeps
+        // lichess.org.
+        let _ = self.lichess.set_token(session.lichess_token.clone());
+        if let Some(url) = &session.lichess_url {
+            let _ = self.lichess.set_base_url(url);
+        }
+        StateHandle {
+            session: Arc::new(Mutex::new(session)),
+            session_path: Some(Arc::new(path)),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_lichess(self, lichess: LichessClient) -> StateHandle {
+        StateHandle { lichess, ..self }
+    }
+
+    pub fn with_recorder(self, recorder: Recorder) -> StateHandle {
+        StateHandle {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    /// Records a request line as received, if recording.
+    pub fn record_request(&self, line: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(line);
+        }
+    }
+
+    pub fn with_request_echo_limit(self, request_echo_limit: usize) -> StateHandle {
+        StateHandle {
+            request_echo_limit,
+            ..self
+        }
+    }
+
+    pub fn request_echo_limit(&self) -> usize {
+        self.request_echo_limit
+    }
+
+    /// Counts a request answered with `response` in the stats.
+    pub fn record_response(&self, response: &Response) {
+        self.stats.record(response);
+    }
+
+    pub fn stats(&self) -> Result<Stats, Error> {
+        self.stats.snapshot()
+    }
+
+    pub fn get_stats(&self) -> Result<Response, Error> {
+        Ok(response_from_stats(self.stats()?))
+    }
+
+    pub fn with_default_book(self, book: Book) -> StateHandle {
+        StateHandle {
+            book: Some(Arc::new(book)),
+            ..self
+        }
+    }
+
+    /// Lichess explorer and cloud evaluation answers are kept in `cache` rather than in memory.
+    pub fn with_online_cache(self, cache: ResponseCache) -> StateHandle {
+        StateHandle {
+            explorer: ExplorerClient::new(cache),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_explorer(self, explorer: ExplorerClient) -> StateHandle {
+        StateHandle { explorer, ..self }
+    }
+
+    /// Representation of `game`, with the moves of the default book if there is one.
+    pub fn game_repr(&self, game: &Game) -> GameRepr {
+        let mut repr = game.get_repr();
+        if let Some(book) = &self.book {
+            repr.book_moves = book.probe(&game.current_position());
+        }
+        let format = self
+            .session
+            .lock()
+            .map_or_else(|_| MoveFormat::default(), |s| s.move_format);
+        if let Some((position, m)) = game.last_move() {
+            repr.last_move = Some(format.format(position, &m));
+        }
+        repr
+    }
+
+    pub fn get_tree(&self, id: &str) -> Result<Response, Error> {
+        Ok(response_from_tree(
+            self.with_game(id, |game| Ok(game.tree_repr()))?,
+        ))
+    }
+
+    /// Whole tree of game `id` as PGN text, in export format.
+    pub fn export_pgn(&self, id: &str) -> Result<Response, Error> {
+        Ok(response_from_pgn(self.pgn_of(id)?))
+    }
+
+    fn pgn_of(&self, id: &str) -> Result<String, Error> {
+        let mut pgn = Vec::new();
+        self.with_game(id, |game| Ok(game.to_pgn()))?
+            .write(&mut pgn)?;
+        Ok(String::from_utf8(pgn).expect("PGN is written from strings"))
+    }
+
+    /// Moves `book_path` gives for the current position of game `id`, or the one given by `fen`.
+    pub fn probe_book(
+        &self,
+        id: Option<String>,
+        fen: Option<String>,
+        book_path: String,
+    ) -> Result<Response, Error> {
+        let position = self.position_of(id, fen)?;
+        let book = Book::open(Path::new(&book_path))?;
+        Ok(response_from_book_moves(book.probe(&position)))
+    }
+
+    pub async fn probe_online_tablebase(
+        &self,
+        id: Option<String>,
+        fen: Option<String>,
+    ) -> Result<Response, Error> {
+        let position = self.position_of(id, fen)?;
+        Ok(response_from_tablebase(
+            self.tablebase.probe(&position).await?,
+        ))
+    }
+
+    pub async fn explore_lichess(
+        &self,
+        id: Option<String>,
+        fen: Option<String>,
+        source: ExplorerSource,
+        cache: CacheMode,
+    ) -> Result<Response, Error> {
+        let fen = shakmaty::fen::fen(&self.position_of(id, fen)?);
+        let explorer = self.explorer.explore(&fen, source, cache).await?;
+        Ok(response_from_online_explorer(explorer))
+    }
+
+    pub async fn lichess_cloud_eval(
+        &self,
+        id: Option<String>,
+        fen: Option<String>,
+        multi_pv: u32,
+        cache: CacheMode,
+    ) -> Result<Response, Error> {
+        let fen = shakmaty::fen::fen(&self.position_of(id, fen)?);
+        let eval = self.explorer.cloud_eval(&fen, multi_pv, cache).await?;
+        Ok(response_from_cloud_eval(eval))
+    }
+
+    /// `at_ms` times the move of a game on the clock, see `Game::play_timed`.
+    pub fn play(
+        &self,
+        id: &str,
+        from: String,
+        to: String,
+        promotion: Option<Promotion>,
+        at_ms: Option<u64>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        let response = self.with_game(id, |game| {
+            let (attempt, step) = if game.solving() {
+                (Some(game.play_puzzle(&from, &to, promotion, at)?), None)
+            } else if game.drilling() {
+                (None, Some(game.play_drill(&from, &to, promotion)?))
+            } else {
+                game.play_timed(&from, &to, promotion, at)?;
+                (None, None)
+            };
+            let mut response = response_from_game(id.to_string(), self.game_repr(game));
+            if let Some(attempt) = attempt {
+                response = response.with_puzzle_attempt(attempt);
+            }
+            if let Some(step) = step {
+                response = response.with_drill(step);
+            }
+            Ok(response)
+        })?;
+        self.opponent_reply(id)?;
+        Ok(response)
+    }
+
+    pub fn play_uci(&self, id: &str, uci: &str) -> Result<Response, Error> {
+        self.game_operation(id, |game| game.play_uci(uci))
+    }
+
+    pub fn navigate_back(
+        &self,
+        id: &str,
+        back: u16,
+        at_ms: Option<u64>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        self.linked_navigation(id, |game| {
             game.navigate_back(back);
+            game.sync_clock(at);
+        })
+    }
+
+    pub fn navigate_forward(
+        &self,
+        id: &str,
+        forward: u16,
+        at_ms: Option<u64>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        self.linked_navigation(id, |game| {
+            game.navigate_forward(forward);
+            game.sync_clock(at);
+        })
+    }
+
+    pub fn navigate_to(&self, id: &str, ply: usize, at_ms: Option<u64>) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        self.linked_navigation(id, |game| {
+            game.navigate_to(ply);
+            game.sync_clock(at);
+        })
+    }
+
+    pub fn navigate_to_end(&self, id: &str, at_ms: Option<u64>) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        self.linked_navigation(id, |game| {
+            game.navigate_to_end();
+            game.sync_clock(at);
+        })
+    }
+
+    /// Links `ids` as group `group_id`, in place of the games it had and of their other groups.
+    pub fn link_games(
+        &self,
+        group_id: String,
+        ids: Vec<String>,
+        mode: LinkMode,
+    ) -> Result<Response, Error> {
+        self.state_operation(|state| {
+            // Unknown games fail the request before anything changes
+            for id in &ids {
+                state.get_game(id).map(drop)?;
+            }
+            let mut changed = unlink(state, &group_id)?;
+            for id in &ids {
+                let link = Link {
+                    group_id: group_id.clone(),
+                    mode,
+                };
+                state.get_game(id)?.set_link(Some(link));
+                if !changed.contains(id) {
+                    changed.push(id.clone());
+                }
+            }
+            Ok(changed)
+        })
+    }
+
+    pub fn unlink_games(&self, group_id: &str) -> Result<Response, Error> {
+        self.state_operation(|state| unlink(state, group_id))
+    }
+
+    /// Starts game `id`'s clock from its current position, or removes it without `time_control`.
+    pub fn set_clock(
+        &self,
+        id: &str,
+        time_control: Option<TimeControl>,
+        at_ms: Option<u64>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        self.game_operation(id, |game| {
+            match time_control {
+                Some(time_control) => game.start_clock(time_control, at),
+                None => game.stop_clock(),
+            }
+            Ok(())
+        })
+    }
+
+    pub fn set_puzzle(
+        &self,
+        id: &str,
+        solution: Option<Vec<String>>,
+        attempts_before_hint: Option<u32>,
+        at_ms: Option<u64>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        let attempts_before_hint =
+            attempts_before_hint.unwrap_or(puzzle::DEFAULT_ATTEMPTS_BEFORE_HINT);
+        self.game_operation(id, |game| {
+            game.start_solving(solution, attempts_before_hint, at)
+        })
+    }
+
+    pub fn set_result(&self, id: &str, result: GameResult) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| {
+            game.set_result(result);
+            Ok(())
+        })
+    }
+
+    pub fn set_players(
+        &self,
+        id: &str,
+        white: Option<Player>,
+        black: Option<Player>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| game.set_players(white, black))
+    }
+
+    pub fn set_game_info(&self, id: &str, fields: GameInfoUpdate) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| game.update_info(fields))
+    }
+
+    pub fn set_evaluation(
+        &self,
+        id: &str,
+        evaluation: Option<Evaluation>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| game.evaluate_current(evaluation))
+    }
+
+    pub fn set_annotation(
+        &self,
+        id: &str,
+        annotation: Option<Annotation>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| game.annotate_current(annotation))
+    }
+
+    pub fn set_comment(&self, id: &str, comment: String) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| game.set_comment(comment))
+    }
+
+    pub fn delete_continuations(&self, id: &str) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| {
+            game.truncate_from_current();
+            Ok(())
+        })
+    }
+
+    pub fn mark_repertoire_move(&self, id: &str, marked: bool) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        self.game_operation(id, |game| game.mark_repertoire(marked))
+    }
+
+    pub async fn start_drill(
+        &self,
+        id: &str,
+        color: shakmaty::Color,
+        depth_limit: Option<u32>,
+    ) -> Result<Response, Error> {
+        self.check_not_busy(id)?;
+        let positions = self.with_game(id, |game| Ok(game.drill_positions(color, depth_limit)))?;
+        let frequencies = self.move_frequencies(positions).await?;
+        self.with_game(id, |game| {
+            let step = game.start_drill(Drill::new(color, depth_limit, frequencies));
+            Ok(response_from_game(id.to_string(), self.game_repr(game)).with_drill(step))
+        })
+    }
+
+    /// Games of each move (SAN) from `positions` in the default database, by Zobrist key of the
+    /// position. Empty without a default database.
+    async fn move_frequencies(
+        &self,
+        positions: Vec<shakmaty::Chess>,
+    ) -> Result<HashMap<u64, Vec<(String, u64)>>, Error> {
+        let db_id = match self.session.lock()?.default_database.clone() {
+            Some(db_id) => db_id,
+            None => return Ok(HashMap::new()),
+        };
+        self.read_database(&db_id, move |database| {
+            positions
+                .iter()
+                .map(|position| {
+                    let explorer = database.explore(position)?;
+                    let moves = explorer.moves.into_iter().map(|m| (m.san, m.games));
+                    Ok((hash::zobrist(position), moves.collect()))
+                })
+                .collect()
+        })
+        .await
+    }
+
+    pub fn clock_tick(&self, id: &str, at_ms: Option<u64>) -> Result<Response, Error> {
+        let at = at_ms.unwrap_or_else(clock::now_ms);
+        self.game_operation(id, |game| {
+            game.tick_clock(at);
             Ok(())
         })
     }
 
+    /// Opens `game` under `id`, replacing any game already there.
+    pub fn open_game(&self, id: &str, game: Game) -> Result<(), Error> {
+        self.write_games()?
+            .insert(id.to_string(), Some(Mutex::new(game)));
+        Ok(())
+    }
+
+    pub fn close_game(&self, id: &str) -> Result<Response, Error> {
+        self.write_games()?.close_game(id)?;
+        Ok(response_from_closed_game(id.to_string()))
+    }
+
+    /// Reprs of the games, sorted by id. Games whose lock is poisoned are left out with a warning
+    /// rather than failing the others.
     pub fn get_all_games(&self) -> Result<Response, Error> {
-        // self.state_operation returns a response with all state, so no extra operation is needed
-        self.state_operation(|_| Ok(()))
+        let games = self.read_games()?;
+        let threads = std::thread::available_parallelism().map_or(1, usize::from);
+        let mut all_games = Vec::new();
+        let mut warnings = Vec::new();
+        for repr in self.game_reprs(&games, threads) {
+            match repr {
+                Ok(game) => all_games.push(Ok(game)),
+                Err(warning) => warnings.push(warning),
+            }
+        }
+        Ok(response_from_games(all_games.into_iter())?.with_warnings(warnings))
+    }
+
+    /// Reprs of `games` sorted by id, spread across `threads` when there are enough games.
+    fn game_reprs(
+        &self,
+        games: &InnerState,
+        threads: usize,
+    ) -> Vec<Result<(String, GameRepr), WarningRepr>> {
+        let mut handles: Vec<(&String, &Mutex<Game>)> = games
+            .iter()
+            .filter_map(|(id, cell)| Some((id, cell.as_ref()?)))
+            .collect();
+        handles.sort_unstable_by_key(|&(id, _)| id);
+        let repr = |&(id, game): &(&String, &Mutex<Game>)| match game.lock() {
+            Ok(game) => Ok((id.clone(), self.game_repr(&game))),
+            Err(_) => Err(WarningRepr::new(
+                WarningType::PoisonedGame,
+                "Left out, a thread panicked while changing the game",
+            )
+            .with_game_id(id)),
+        };
+        if handles.len() < PARALLEL_REPRS_MIN_GAMES || threads <= 1 {
+            return handles.iter().map(repr).collect();
+        }
+        let repr = &repr;
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = handles
+                .chunks(handles.len().div_ceil(threads))
+                .map(|chunk| scope.spawn(move || chunk.iter().map(repr).collect::<Vec<_>>()))
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        })
     }
 
-    pub fn new_game_default(&self, id: &String) -> Result<Response, Error> {
+    pub fn new_game_default(&self, id: &str) -> Result<Response, Error> {
         self.state_operation(|state| {
             state.new_game_default(id)?;
+            Ok(vec![id.to_string()])
+        })
+    }
+
+    pub fn new_game_fen(&self, id: &str, fen: String, chess960: bool) -> Result<Response, Error> {
+        self.state_operation(|state| {
+            state.new_game_fen(id, fen, chess960)?;
+            Ok(vec![id.to_string()])
+        })
+    }
+
+    pub fn new_game_pgn(&self, id: &str, pgn: &str) -> Result<Response, Error> {
+        self.state_operation(|state| {
+            state.new_game_pgn(id, pgn)?;
+            Ok(vec![id.to_string()])
+        })
+    }
+
+    /// Starts an engine and registers it under `engine_id`. Responds with all registered engines.
+    pub async fn add_engine(
+        &self,
+        engine_id: &str,
+        config: EngineConfig,
+    ) -> Result<Response, Error> {
+        let engine = Engine::start(config).await?;
+        self.engines.insert(engine_id, engine)?;
+        Ok(response_from_engines(self.engines.reprs()?))
+    }
+
+    /// Starts the engines given on the command line. Those which fail are reported as warnings
+    /// of the initial message instead.
+    pub async fn add_startup_engines(&self, engines: Vec<(String, EngineConfig)>) {
+        for (engine_id, config) in engines {
+            let path = config.path.clone();
+            if let Err(err) = self.add_engine(&engine_id, config).await {
+                tracing::warn!(%engine_id, %err, "could not start engine");
+                if let Ok(mut warnings) = self.startup_warnings.lock() {
+                    let message =
+                        format!("Could not start engine {} ({}): {}", engine_id, path, err);
+                    warnings.push(WarningRepr::new(WarningType::Engine, &message));
+                }
+            }
+        }
+    }
+
+    /// First message of every connection: the games, the engines and the startup warnings.
+    pub fn initial_message(&self) -> Result<Response, Error> {
+        let warnings = self.startup_warnings.lock()?.clone();
+        Ok(initial_response(
+            self.get_all_games()?,
+            self.engines.reprs()?,
+            warnings,
+        ))
+    }
+
+    /// `engine_id`, or the first engine registered when none is given.
+    pub fn engine_id_or_default(&self, engine_id: Option<String>) -> Result<String, Error> {
+        self.engines.id_or_default(engine_id)
+    }
+
+    /// Searches the current position of game `id` to `depth` with engine `engine_id`, or the
+    /// default engine, in the background. The result is notified as `AnalysisFinished`. Fails
+    /// rather than waits if the engine is busy with another search.
+    pub fn analyze(
+        &self,
+        id: &str,
+        engine_id: Option<String>,
+        depth: u32,
+    ) -> Result<Response, Error> {
+        if depth > depth_analysis::MAX_DEPTH {
+            return Err(Error::new(ErrorType::LimitExceeded).with_message(&format!(
+                "Analyses are limited to depth {}",
+                depth_analysis::MAX_DEPTH
+            )));
+        }
+        self.check_not_busy(id)?;
+        let handle = self.engines.get(&self.engines.id_or_default(engine_id)?)?;
+        let response = self.game_operation(id, |_| Ok(()))?;
+        let engine = handle.try_lock_owned().map_err(|_| {
+            Error::new(ErrorType::Locked).with_message("The engine is busy with another search")
+        })?;
+        // Another analysis is replaced
+        self.analyses.stop(id)?;
+        let ticket = self.analyses.start(id)?;
+        tokio::spawn(depth_analysis::run(
+            self.clone(),
+            id.to_string(),
+            engine,
+            depth,
+            ticket,
+        ));
+        Ok(response)
+    }
+
+    /// Sets a UCI option of engine `engine_id`, which must not be searching. Responds with every
+    /// engine.
+    pub async fn set_engine_option(
+        &self,
+        engine_id: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<Response, Error> {
+        let handle = self.engines.get(engine_id)?;
+        let mut engine = handle.try_lock().map_err(|_| {
+            Error::new(ErrorType::Locked).with_message("The engine is busy with another search")
+        })?;
+        let warning = if engine.set_option(name, value).await? {
+            None
+        } else {
+            let message = format!("The engine has no option named {}, it may ignore it", name);
+            Some(WarningRepr::new(WarningType::UnknownEngineOption, &message))
+        };
+        Ok(response_from_engines(self.engines.reprs()?).with_warnings(warning))
+    }
+
+    pub fn get_engine_log(&self, engine_id: &str) -> Result<Response, Error> {
+        let lines = self.engines.transcript(engine_id)?.lines()?;
+        Ok(response_from_engine_log(engine_id, lines))
+    }
+
+    /// Starts an engine match on game `id` in the background. Moves are reported through notifications.
+    pub fn start_engine_match(&self, id: &str, settings: MatchSettings) -> Result<Response, Error> {
+        self.engines.get(&settings.white_engine_id)?;
+        self.engines.get(&settings.black_engine_id)?;
+        let response = self.game_operation(id, |_| Ok(()))?;
+
+        let ticket = self.jobs.start(id)?;
+        tokio::spawn(engine_match::run(
+            self.clone(),
+            id.to_string(),
+            settings,
+            ticket,
+        ));
+        Ok(response)
+    }
+
+    /// Evaluates the current line of game `id` in the background. Progress is reported through notifications.
+    pub fn start_annotation(
+        &self,
+        id: &str,
+        settings: AnnotationSettings,
+    ) -> Result<Response, Error> {
+        self.engines.get(&settings.engine_id)?;
+        let response = self.game_operation(id, |_| Ok(()))?;
+
+        let ticket = self.jobs.start(id)?;
+        tokio::spawn(annotation::run(
+            self.clone(),
+            id.to_string(),
+            settings,
+            ticket,
+        ));
+        Ok(response)
+    }
+
+    /// Analyzes game `id` with engine `engine_id`, or the default engine, in the background until
+    /// stopped. The analysis follows the game's current position, see `live_analysis`.
+    pub fn start_analysis(&self, id: &str, engine_id: Option<String>) -> Result<Response, Error> {
+        let engine = self.engines.get(&self.engines.id_or_default(engine_id)?)?;
+        let response = self.game_operation(id, |_| Ok(()))?;
+        // Another engine takes over
+        self.analyses.stop(id)?;
+        let ticket = self.analyses.start(id)?;
+        tokio::spawn(live_analysis::run(
+            self.clone(),
+            id.to_string(),
+            engine,
+            ticket,
+        ));
+        Ok(response)
+    }
+
+    /// Lets an engine play `opponent.side` of game `id`, or removes the opponent when `None`. The
+    /// engine replies to the moves played from then on, and moves right away if it is its turn.
+    pub fn set_opponent(&self, id: &str, opponent: Option<Opponent>) -> Result<Response, Error> {
+        let opponent = match opponent {
+            Some(mut opponent) => {
+                let engine_id = self.engines.id_or_default(opponent.engine_id)?;
+                self.engines.get(&engine_id)?;
+                opponent.engine_id = Some(engine_id);
+                Some(opponent)
+            }
+            None => None,
+        };
+        self.opponents.stop(id)?;
+        if let Some(previous) = self.with_game(id, |game| Ok(game.opponent().cloned()))? {
+            self.stop_pondering(&previous);
+        }
+        let response = self.game_operation(id, |game| {
+            game.set_opponent(opponent);
+            Ok(())
+        })?;
+        self.opponent_reply(id)?;
+        Ok(response)
+    }
+
+    /// Starts the engine opponent's reply if it is to move in game `id`. A reply still searching
+    /// an earlier position is dropped.
+    fn opponent_reply(&self, id: &str) -> Result<(), Error> {
+        let opponent = self.with_game(id, |game| {
+            Ok(game.opponent().cloned().filter(|_| game.opponent_to_move()))
+        })?;
+        let opponent = match opponent {
+            Some(opponent) => opponent,
+            None => return Ok(()),
+        };
+        let engine = self
+            .engines
+            .get(&self.engines.id_or_default(opponent.engine_id.clone())?)?;
+        self.opponents.stop(id)?;
+        let ticket = self.opponents.start(id)?;
+        tokio::spawn(opponent::run(
+            self.clone(),
+            id.to_string(),
+            engine,
+            opponent,
+            ticket,
+        ));
+        Ok(())
+    }
+
+    /// Stops `opponent`'s engine pondering on the reply it predicted and restores the options it
+    /// changed. Done in the background, as another search may hold the engine.
+    fn stop_pondering(&self, opponent: &Opponent) {
+        let engine = self
+            .engines
+            .id_or_default(opponent.engine_id.clone())
+            .and_then(|engine_id| self.engines.get(&engine_id));
+        if let Ok(engine) = engine {
+            tokio::spawn(async move {
+                let mut engine = engine.lock().await;
+                if engine.stop_pondering().await.is_ok() {
+                    let _ = engine.restore_options().await;
+                }
+            });
+        }
+    }
+
+    /// Does nothing if game `id` isn't being analyzed.
+    pub fn stop_analysis(&self, id: &str) -> Result<Response, Error> {
+        self.analyses.stop(id)?;
+        Ok(Response::default())
+    }
+
+    /// Stops the background task (engine match, annotation, ...) running on game `id`, if any.
+    pub fn stop_job(&self, id: &str) -> Result<Response, Error> {
+        self.jobs.stop(id)?;
+        self.game_operation(id, |_| Ok(()))
+    }
+
+    /// Opens the database at `path`, or creates it, under `db_id`. A database already open under
+    /// that id is closed.
+    pub async fn open_database(
+        &self,
+        db_id: String,
+        path: String,
+        create: bool,
+    ) -> Result<Response, Error> {
+        let databases = self.databases.clone();
+        let (id, opened_path) = (db_id.clone(), path.clone());
+        let repr = tokio::task::spawn_blocking(move || {
+            let path = Path::new(&path);
+            let database = if create {
+                Database::create(path)?
+            } else {
+                Database::open(path)?
+            };
+            let repr = database.repr(&db_id)?;
+            databases.insert(&db_id, database)?;
+            Ok::<_, Error>(repr)
+        })
+        .await??;
+        let warning = self.update_session(|session| session.database_opened(&id, &opened_path))?;
+        Ok(response_from_database(repr).with_warnings(warning))
+    }
+
+    /// Reopens the databases of the previous session, sending a `DatabaseReopened` notification
+    /// for each. Those that can't be opened anymore are forgotten.
+    pub async fn restore_session(self) {
+        let saved = match self.session.lock() {
+            Ok(session) => session.databases.clone(),
+            Err(_) => return,
+        };
+        for saved in saved {
+            let reopened = if Path::new(&saved.path).exists() {
+                self.open_database(saved.db_id.clone(), saved.path.clone(), false)
+                    .await
+            } else {
+                Err(Error::new(ErrorType::IO)
+                    .with_message(&format!("{} no longer exists", saved.path)))
+            };
+            let (response, warning) = match reopened {
+                Ok(response) => (response, None),
+                Err(err) => {
+                    let _ = self.update_session(|session| session.database_closed(&saved.db_id));
+                    (Response::default(), Some(database::describe(&err)))
+                }
+            };
+            self.notify(response.with_notification(Notification::DatabaseReopened {
+                db_id: saved.db_id,
+                path: saved.path,
+                warning,
+            }));
+        }
+    }
+
+    pub async fn set_default_database(&self, db_id: String) -> Result<Response, Error> {
+        self.read_database(&db_id, |_| Ok(())).await?;
+        let warning = self.update_session(|session| session.default_database = Some(db_id))?;
+        Ok(Response::default().with_warnings(warning))
+    }
+
+    /// Stops the job writing to database `db_id`, then closes it once the queries running on it are done.
+    pub async fn close_database(&self, db_id: String) -> Result<Response, Error> {
+        self.database_jobs.stop(&db_id)?;
+        let databases = self.databases.clone();
+        let id = db_id.clone();
+        tokio::task::spawn_blocking(move || databases.remove(&id)).await??;
+        let warning = self.update_session(|session| session.database_closed(&db_id))?;
+        Ok(Response::default().with_warnings(warning))
+    }
+
+    pub async fn get_database_info(&self, db_id: String) -> Result<Response, Error> {
+        let id = db_id.clone();
+        let repr = self
+            .read_database(&db_id, move |database| database.repr(&id))
+            .await?;
+        Ok(response_from_database(repr))
+    }
+
+    pub async fn list_databases(&self) -> Result<Response, Error> {
+        let databases = self.databases.clone();
+        let reprs = tokio::task::spawn_blocking(move || {
+            databases
+                .ids()?
+                .iter()
+                .map(|id| databases.read(id, |database| database.repr(id)))
+                .collect::<Result<Vec<_>, Error>>()
+        })
+        .await??;
+        Ok(response_from_databases(reprs))
+    }
+
+    /// Imports the PGN file at `path` into database `db_id` in the background. Only one import
+    /// runs per database.
+    pub async fn start_database_import(
+        &self,
+        db_id: String,
+        path: String,
+        options: ImportOptions,
+    ) -> Result<Response, Error> {
+        // Fail right away rather than in a notification
+        self.read_database(&db_id, |_| Ok(())).await?;
+
+        let ticket = self.database_jobs.start(&db_id)?;
+        tokio::spawn(database::import(self.clone(), db_id, path, options, ticket));
+        Ok(Response::default())
+    }
+
+    pub async fn search_position(
+        &self,
+        db_id: Option<String>,
+        fen: String,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Response, Error> {
+        let db_id = self.database_or_default(db_id)?;
+        let setup: shakmaty::fen::Fen = fen.parse()?;
+        let position: shakmaty::Chess = setup.position()?;
+        let results = self
+            .read_database(&db_id, move |database| {
+                database.search_position(&position, limit, offset)
+            })
+            .await?;
+        Ok(response_from_search(results))
+    }
+
+    pub async fn search_database(
+        &self,
+        db_id: String,
+        filters: SearchFilters,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Response, Error> {
+        let results = self
+            .read_database(&db_id, move |database| {
+                database.search(&filters, limit, offset)
+            })
+            .await?;
+        Ok(response_from_search(results))
+    }
+
+    pub async fn search_database_text(
+        &self,
+        db_id: String,
+        query: String,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Response, Error> {
+        let results = self
+            .read_database(&db_id, move |database| {
+                database.search_text(&query, limit, offset)
+            })
+            .await?;
+        Ok(response_from_search(results))
+    }
+
+    pub async fn explore_database(
+        &self,
+        db_id: Option<String>,
+        id: Option<String>,
+        fen: Option<String>,
+    ) -> Result<Response, Error> {
+        let db_id = self.database_or_default(db_id)?;
+        let position = self.position_of(id, fen)?;
+        let explorer = self
+            .read_database(&db_id, move |database| database.explore(&position))
+            .await?;
+        Ok(response_from_explorer(explorer))
+    }
+
+    pub async fn list_database_games(
+        &self,
+        db_id: String,
+        sort_by: Option<SortColumn>,
+        descending: bool,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Response, Error> {
+        let results = self
+            .read_database(&db_id, move |database| {
+                database.list_games(sort_by, descending, limit, offset)
+            })
+            .await?;
+        Ok(response_from_search(results))
+    }
+
+    pub async fn delete_database_games(
+        &self,
+        db_id: String,
+        ids: Vec<i64>,
+    ) -> Result<Response, Error> {
+        let deleted = self
+            .write_database(&db_id, move |database| database.delete_games(&ids))
+            .await?;
+        Ok(response_from_deleted_games(deleted))
+    }
+
+    pub async fn update_database_headers(
+        &self,
+        db_id: String,
+        db_game_id: i64,
+        fields: HashMap<String, Option<String>>,
+    ) -> Result<Response, Error> {
+        let game = self
+            .write_database(&db_id, move |database| {
+                database.update_headers(db_game_id, fields)
+            })
+            .await?;
+        Ok(response_from_database_game(game))
+    }
+
+    pub async fn find_database_duplicates(
+        &self,
+        db_id: String,
+        limit: u32,
+    ) -> Result<Response, Error> {
+        let duplicates = self
+            .read_database(&db_id, move |database| database.find_duplicates(limit))
+            .await?;
+        Ok(response_from_duplicates(duplicates))
+    }
+
+    /// Current position of game `id`, or the one given by `fen`.
+    fn position_of(
+        &self,
+        id: Option<String>,
+        fen: Option<String>,
+    ) -> Result<shakmaty::Chess, Error> {
+        match (id, fen) {
+            (Some(id), _) => self.with_game(&id, |game| Ok(game.current_position())),
+            (None, Some(fen)) => {
+                let setup: shakmaty::fen::Fen = fen.parse()?;
+                Ok(setup.position()?)
+            }
+            (None, None) => Err(Error::new(ErrorType::Deserialize)
+                .with_message("Expected the id of a game or a fen")),
+        }
+    }
+
+    /// Opens stored game `db_game_id` of database `db_id` under `as_id`, or `<db_id>-<db_game_id>`.
+    pub async fn open_game_from_database(
+        &self,
+        db_id: String,
+        db_game_id: i64,
+        as_id: Option<String>,
+    ) -> Result<Response, Error> {
+        let game = self
+            .read_database(&db_id, move |database| database.open_game(db_game_id))
+            .await?
+            .with_database_id(&db_id, db_game_id);
+        let id = as_id.unwrap_or_else(|| format!("{}-{}", db_id, db_game_id));
+        let repr = self.game_repr(&game);
+        self.write_games()?
+            .insert(id.clone(), Some(Mutex::new(game)));
+        Ok(response_from_game(id, repr))
+    }
+
+    /// Checks `token` with lichess, then sends it with every lichess request and saves it in the
+    /// session.
+    pub async fn set_lichess_token(&self, token: LichessToken) -> Result<Response, Error> {
+        let account = self.lichess.account(&token).await?;
+        self.lichess.set_token(Some(token.clone()))?;
+        let warning = self.update_session(|session| session.lichess_token = Some(token))?;
+        Ok(response_from_lichess_account(account).with_warnings(warning))
+    }
+
+    /// Talks to the lila instance at `base_url`, or lichess.org if `None`, from now on. Changing
+    /// server forgets the token, which belongs to the previous one.
+    pub fn set_lichess_config(&self, base_url: Option<String>) -> Result<Response, Error> {
+        let base_url = base_url.unwrap_or_else(|| String::from(lichess::LICHESS_URL));
+        let mut warning = None;
+        if self.lichess.set_base_url(&base_url)? {
+            self.lichess.set_token(None)?;
+            warning = self.update_session(|session| {
+                session.lichess_token = None;
+                session.lichess_url = Some(base_url).filter(|url| url != lichess::LICHESS_URL);
+            })?;
+        }
+        Ok(response_from_lichess_config(self.lichess.config()?).with_warnings(warning))
+    }
+
+    /// Writes the last moves of games in `format` from now on, and responds with every game.
+    pub fn set_move_format(&self, format: MoveFormat) -> Result<Response, Error> {
+        let warning = self.update_session(|session| session.move_format = format)?;
+        Ok(self.get_all_games()?.with_warnings(warning))
+    }
+
+    pub fn clear_lichess_token(&self) -> Result<Response, Error> {
+        self.lichess.set_token(None)?;
+        let warning = self.update_session(|session| session.lichess_token = None)?;
+        Ok(Response::default().with_warnings(warning))
+    }
+
+    /// Adds game `id` as chapter `chapter_name` of lichess study `study_id` (its id or URL), and
+    /// records the chapter in the game's lichess details.
+    pub async fn push_lichess_study_chapter(
+        &self,
+        id: String,
+        study_id: String,
+        chapter_name: String,
+    ) -> Result<Response, Error> {
+        let study_id = lichess::study_id(&study_id)?;
+        let pgn = self.pgn_of(&id)?;
+        let chapter = self
+            .lichess
+            .push_chapter(&study_id, &chapter_name, &pgn)
+            .await?;
+        self.game_operation(&id, |game| {
+            game.set_lichess_chapter(chapter);
             Ok(())
         })
     }
 
+    pub async fn list_lichess_user_games(
+        &self,
+        username: String,
+        filter: UserGamesFilter,
+    ) -> Result<Response, Error> {
+        let rows = self.lichess.user_games(&username, &filter).await?;
+        Ok(response_from_lichess_games(rows))
+    }
+
+    /// Opens lichess games `game_ids` as `lichess-<game id>`, or adds them to a database, leaving
+    /// out the ones it already has.
+    pub async fn import_lichess_games(
+        &self,
+        game_ids: Vec<String>,
+        target: ImportTarget,
+    ) -> Result<Response, Error> {
+        let game_ids = game_ids
+            .iter()
+            .map(|id| lichess::game_id(id))
+            .collect::<Result<Vec<_>, Error>>()?;
+        match target {
+            ImportTarget::State(_) => {
+                let pgn = self.lichess.export_games(&game_ids).await?;
+                let mut games = Vec::with_capacity(game_ids.len());
+                let mut warnings = Vec::new();
+                for (index, pgn) in PgnReader::new(pgn.as_bytes()).enumerate() {
+                    let pgn = pgn?;
+                    let game_id = lichess::game_id(pgn.header("Site").unwrap_or(""))?;
+                    let id = format!("lichess-{}", game_id);
+                    let lichess = Lichess {
+                        game: Some(lichess::game_details(&game_id, &pgn)),
+                        chapter: None,
+                    };
+                    match Game::from_pgn(&pgn) {
+                        Ok(game) => games.push((id, game.with_lichess(lichess))),
+                        // The other games are still worth opening
+                        Err(err) => {
+                            let message =
+                                format!("Game {}: {}", index + 1, database::describe(&err));
+                            let warning = WarningRepr::new(WarningType::SkippedGame, &message);
+                            warnings.push(warning.with_game_id(&id));
+                        }
+                    }
+                }
+                let mut inner = self.write_games()?;
+                let mut reprs = Vec::with_capacity(games.len());
+                for (id, game) in games {
+                    reprs.push(Ok((id.clone(), self.game_repr(&game))));
+                    inner.insert(id, Some(Mutex::new(game)));
+                }
+                Ok(response_from_games(reprs.into_iter())?.with_warnings(warnings))
+            }
+            ImportTarget::Database { db_id } => {
+                // Fail before downloading anything
+                self.read_database(&db_id, |_| Ok(())).await?;
+                let pgn = self.lichess.export_games(&game_ids).await?;
+                let options = ImportOptions {
+                    skip_duplicates: true,
+                };
+                let summary = self
+                    .write_database(&db_id, move |database| {
+                        database.import_pgn(pgn.as_bytes(), options, |_| {}, || false)
+                    })
+                    .await?;
+                let warnings: Vec<WarningRepr> = summary
+                    .warnings
+                    .iter()
+                    .map(|warning| WarningRepr::new(WarningType::SkippedGame, warning))
+                    .collect();
+                Ok(response_from_import_summary(summary).with_warnings(warnings))
+            }
+        }
+    }
+
+    /// Opens lichess game `game_or_url` (its id or URL) under `as_id`, or `lichess-<game id>`.
+    pub async fn import_lichess_game(
+        &self,
+        game_or_url: String,
+        as_id: Option<String>,
+    ) -> Result<Response, Error> {
+        let game_id = lichess::game_id(&game_or_url)?;
+        let pgn = self.lichess.export_game(&game_id).await?;
+        let lichess = Lichess {
+            game: Some(lichess::game_details(&game_id, &pgn)),
+            chapter: None,
+        };
+        let game = Game::from_pgn(&pgn)?.with_lichess(lichess);
+        let id = as_id.unwrap_or_else(|| format!("lichess-{}", game_id));
+        let repr = self.game_repr(&game);
+        self.write_games()?
+            .insert(id.clone(), Some(Mutex::new(game)));
+        Ok(response_from_game(id, repr))
+    }
+
+    /// Opens lichess game `game_or_url` (its id or URL) under `as_id`, or `lichess-<game id>`, and
+    /// plays its moves as they are played on lichess, until it's over or the task is stopped.
+    pub async fn follow_lichess_game(
+        &self,
+        game_or_url: String,
+        as_id: Option<String>,
+    ) -> Result<Response, Error> {
+        let game_id = lichess::game_id(&game_or_url)?;
+        let snapshot = self.lichess.game_snapshot(&game_id).await?;
+        let game = match snapshot.initial_fen.as_deref() {
+            Some(fen) if fen != "startpos" => Game::from_fen(fen.to_string())?,
+            _ => Game::default(),
+        };
+        let game = game.with_lichess(Lichess {
+            game: Some(snapshot.details()),
+            chapter: None,
+        });
+        let id = as_id.unwrap_or_else(|| format!("lichess-{}", game_id));
+
+        let ticket = self.jobs.start(&id)?;
+        let repr = self.game_repr(&game);
+        self.write_games()?
+            .insert(id.clone(), Some(Mutex::new(game)));
+        tokio::spawn(lichess::follow(self.clone(), id.clone(), game_id, ticket));
+        Ok(response_from_game(id, repr))
+    }
+
+    /// Opens lichess puzzle `puzzle_or_url` (its id or URL) under `as_id`, or
+    /// `lichess-puzzle-<puzzle id>`, at the position to solve.
+    pub async fn import_lichess_puzzle(
+        &self,
+        puzzle_or_url: String,
+        as_id: Option<String>,
+    ) -> Result<Response, Error> {
+        let puzzle_id = lichess::puzzle_id(&puzzle_or_url)?;
+        let game = self.lichess.puzzle(&puzzle_id).await?.into_game()?;
+        let id = as_id.unwrap_or_else(|| format!("lichess-puzzle-{}", puzzle_id));
+        let repr = self.game_repr(&game);
+        self.write_games()?
+            .insert(id.clone(), Some(Mutex::new(game)));
+        Ok(response_from_game(id, repr))
+    }
+
+    /// Opens every chapter of lichess study `study_or_url` (its id or URL) as a game. Chapters
+    /// that can't be read as a game fail the whole import, before any game is opened.
+    pub async fn import_lichess_study(
+        &self,
+        study_or_url: String,
+        as_prefix: Option<String>,
+    ) -> Result<Response, Error> {
+        let study_id = lichess::study_id(&study_or_url)?;
+        let prefix = as_prefix.unwrap_or_else(|| format!("lichess-{}", study_id));
+        let games = self
+            .lichess
+            .export_study(&study_id)
+            .await?
+            .iter()
+            .enumerate()
+            .map(|(i, pgn)| {
+                let name = lichess::chapter_name(pgn, i + 1);
+                let game = Game::from_pgn(pgn)?.with_title(&name);
+                let chapter = StudyChapter {
+                    id: format!("{}-{}", prefix, i + 1),
+                    name,
+                };
+                Ok((chapter, game))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut chapters = Vec::with_capacity(games.len());
+        let mut inner = self.write_games()?;
+        for (chapter, game) in games {
+            chapters.push((chapter.clone(), self.game_repr(&game)));
+            inner.insert(chapter.id, Some(Mutex::new(game)));
+        }
+        Ok(response_from_study(chapters))
+    }
+
+    /// Stops the import or reindexing running on database `db_id`.
+    pub fn stop_database_job(&self, db_id: &str) -> Result<Response, Error> {
+        self.database_jobs.stop(db_id)?;
+        Ok(Response::default())
+    }
+
+    /// Checks the indexes of database `db_id` in the background, rebuilding them unless `check_only`.
+    pub async fn start_database_reindex(
+        &self,
+        db_id: String,
+        check_only: bool,
+    ) -> Result<Response, Error> {
+        self.read_database(&db_id, |_| Ok(())).await?;
+
+        let ticket = self.database_jobs.start(&db_id)?;
+        tokio::spawn(database::reindex(self.clone(), db_id, check_only, ticket));
+        Ok(Response::default())
+    }
+
+    /// Runs `actions` on database `db_id`. Fails with `ErrorType::Locked` while an import or a
+    /// reindexing is running on it.
+    pub async fn maintain_database(
+        &self,
+        db_id: String,
+        actions: Vec<MaintenanceAction>,
+    ) -> Result<Response, Error> {
+        let ticket = self.database_jobs.start(&db_id)?;
+        let id = db_id.clone();
+        let outcome = self
+            .write_database(&db_id, move |database| {
+                Ok((database.maintain(&actions)?, database.repr(&id)?))
+            })
+            .await;
+        self.database_jobs.finish(&db_id, ticket.token)?;
+        let (results, repr) = outcome?;
+        Ok(response_from_maintenance(results, repr))
+    }
+
+    pub fn database_jobs(&self) -> &Jobs {
+        &self.database_jobs
+    }
+
+    /// Exports the games of database `db_id` matching `filters` to the PGN file at `path` in the
+    /// background.
+    pub async fn start_database_export(
+        &self,
+        db_id: String,
+        path: String,
+        filters: SearchFilters,
+        sort_by: Option<SortColumn>,
+        descending: bool,
+    ) -> Result<Response, Error> {
+        self.read_database(&db_id, |_| Ok(())).await?;
+
+        let ticket = self.exports.start(&path)?;
+        tokio::spawn(database::export(
+            self.clone(),
+            db_id,
+            path,
+            filters,
+            sort_by,
+            descending,
+            ticket,
+        ));
+        Ok(Response::default())
+    }
+
+    pub fn stop_database_export(&self, path: &str) -> Result<Response, Error> {
+        self.exports.stop(path)?;
+        Ok(Response::default())
+    }
+
+    pub fn exports(&self) -> &Jobs {
+        &self.exports
+    }
+
+    /// Runs `query` on database `db_id` in a blocking task, so that disk IO never stalls the async loop.
+    pub async fn read_database<C, T>(&self, db_id: &str, query: C) -> Result<T, Error>
+    where
+        C: FnOnce(&Database) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (databases, db_id) = (self.databases.clone(), db_id.to_string());
+        tokio::task::spawn_blocking(move || databases.read(&db_id, query)).await?
+    }
+
+    /// Like `read_database`, for operations changing the database. They run one at a time.
+    pub async fn write_database<C, T>(&self, db_id: &str, operation: C) -> Result<T, Error>
+    where
+        C: FnOnce(&mut Database) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (databases, db_id) = (self.databases.clone(), db_id.to_string());
+        tokio::task::spawn_blocking(move || databases.write(&db_id, operation)).await?
+    }
+
+    /// Stops background tasks and terminates every engine. Requests should not be handled afterwards.
+    pub async fn shutdown(&self) -> Result<Response, Error> {
+        self.shut_down.store(true, Ordering::SeqCst);
+        self.jobs.stop_all()?;
+        self.database_jobs.stop_all()?;
+        self.exports.stop_all()?;
+        self.analyses.stop_all()?;
+        self.opponents.stop_all()?;
+        self.engines.shutdown().await?;
+        Ok(Response::default())
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Runs `closure` on game `id` and returns its result instead of a response.
+    pub fn with_game<C, T>(&self, id: &str, closure: C) -> Result<T, Error>
+    where
+        C: FnOnce(&mut MutexGuard<Game>) -> Result<T, Error>,
+    {
+        let error = {
+            let read_guard = self.read_games()?;
+            let game_guard = read_guard.get_game(id);
+            match game_guard {
+                Ok(mut game_guard) => return closure(&mut game_guard),
+                Err(error) => error,
+            }
+        };
+        Err(self.quarantine_poisoned(id, error)?)
+    }
+
+    pub fn engines(&self) -> &EngineRegistry {
+        &self.engines
+    }
+
+    pub fn jobs(&self) -> &Jobs {
+        &self.jobs
+    }
+
+    pub fn analyses(&self) -> &Jobs {
+        &self.analyses
+    }
+
+    pub fn opponents(&self) -> &Jobs {
+        &self.opponents
+    }
+
+    pub fn lichess(&self) -> &LichessClient {
+        &self.lichess
+    }
+
+    /// Fails with `ErrorType::Locked` while a background task (engine match, followed lichess
+    /// game, ...) drives game `id`.
+    fn check_not_busy(&self, id: &str) -> Result<(), Error> {
+        if self.jobs.is_running(id)? {
+            return Err(Error::new(ErrorType::Locked).with_id(id));
+        }
+        Ok(())
+    }
+
+    /// `db_id` if given, else the session's default database.
+    fn database_or_default(&self, db_id: Option<String>) -> Result<String, Error> {
+        match db_id {
+            Some(db_id) => Ok(db_id),
+            None => self
+                .session
+                .lock()?
+                .default_database
+                .clone()
+                .ok_or_else(|| {
+                    Error::new(ErrorType::Parse)
+                        .with_message("No db_id was given and there is no default database")
+                }),
+        }
+    }
+
+    fn read_games(&self) -> Result<RwLockReadGuard<'_, InnerState>, Error> {
+        if self.inner.is_poisoned() {
+            self.quarantine()?;
+        }
+        Ok(self.inner.read()?)
+    }
+
+    fn write_games(&self) -> Result<RwLockWriteGuard<'_, InnerState>, Error> {
+        if self.inner.is_poisoned() {
+            self.quarantine()?;
+        }
+        Ok(self.inner.write()?)
+    }
+
+    /// Quarantines game `id` when a panic poisoned its lock, which makes its handle stale
+    /// rather than fatal. Other errors are given back as they are.
+    fn quarantine_poisoned(&self, id: &str, error: Error) -> Result<Error, Error> {
+        if !error.is_type(ErrorType::PoisonedHandle) {
+            return Ok(error);
+        }
+        self.quarantine()?;
+        Ok(Error::new(ErrorType::StaleHandle).with_id(id))
+    }
+
+    /// Keeps serving after a thread panicked while holding games: those whose own lock is
+    /// poisoned may be half changed and are dropped, the others are trusted. The dropped ids are
+    /// sent in a `GamesQuarantined` notification.
+    fn quarantine(&self) -> Result<(), Error> {
+        let ids = {
+            let (mut games, poisoned) = match self.inner.write() {
+                Ok(games) => (games, false),
+                Err(poisoned) => (poisoned.into_inner(), true),
+            };
+            let mut ids: Vec<String> = games
+                .iter()
+                .filter(|(_, cell)| matches!(cell, Some(game) if game.is_poisoned()))
+                .map(|(id, _)| id.clone())
+                .collect();
+            // Another request quarantined them first
+            if ids.is_empty() && !poisoned {
+                return Ok(());
+            }
+            ids.sort();
+            for id in &ids {
+                games.remove(id);
+            }
+            self.inner.clear_poison();
+            ids
+        };
+        tracing::error!(
+            ?ids,
+            "a thread panicked while holding the games, quarantined some"
+        );
+        self.notify(Response::default().with_notification(Notification::GamesQuarantined { ids }));
+        Ok(())
+    }
+
+    /// Writes the session next to its file, or in the user's config directory, as a last resort
+    /// before exiting on an unrecoverable error. It may hold the lichess token, so it never goes
+    /// to the shared temporary directory. Returns where it was written.
+    pub fn dump_session(&self) -> Option<PathBuf> {
+        let session = match self.session.lock() {
+            Ok(session) => session,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let path = match &self.session_path {
+            Some(path) => path.with_extension("crash.json"),
+            None => Session::default_path()?.with_extension("crash.json"),
+        };
+        session.save(&path).ok().map(|_| path)
+    }
+
+    /// Applies `change` to the session, then saves it. The change itself went fine, failing to
+    /// save it is only a warning.
+    fn update_session<C>(&self, change: C) -> Result<Option<WarningRepr>, Error>
+    where
+        C: FnOnce(&mut Session),
+    {
+        let mut session = self.session.lock()?;
+        change(&mut session);
+        let saved = match &self.session_path {
+            Some(path) => session.save(path),
+            None => Ok(()),
+        };
+        Ok(saved.err().map(|err| {
+            tracing::warn!(%err, "could not save the session");
+            let message = format!("Could not save the session: {}", err);
+            WarningRepr::new(WarningType::Session, &message)
+        }))
+    }
+
+    /// Sends an unsolicited response to every subscriber (the stdio loop, ...).
+    pub fn notify(&self, response: Response) {
+        // Nobody listening is not an error: the notification is simply lost
+        let _ = self.notifications.send(response);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Response> {
+        self.notifications.subscribe()
+    }
+
     /// Applies operation to a specific game located at `index`, responds with an error or with the modified game.
-    fn game_operation<C>(&self, id: &String, closure: C) -> Result<Response, Error>
+    fn game_operation<C>(&self, id: &str, closure: C) -> Result<Response, Error>
     where
-        C: Fn(&mut MutexGuard<Game>) -> Result<(), Error>,
+        C: FnOnce(&mut MutexGuard<Game>) -> Result<(), Error>,
     {
-        let read_guard = self.inner.read()?;
-        let mut game_guard = read_guard.get_game(id)?;
-        closure(&mut game_guard)?;
+        self.with_game(id, |game| {
+            closure(game)?;
+            Ok(response_from_game(id.to_string(), self.game_repr(game)))
+        })
+    }
 
-        Ok(response_from_game(id.clone(), game_guard.get_repr()))
+    /// Navigates game `id`, then the games linked to it as their group's mode says. The linked
+    /// games are moved directly rather than through requests, so they don't mirror in turn.
+    /// Busy games and games whose tree misses the position are left alone.
+    fn linked_navigation<C>(&self, id: &str, navigate: C) -> Result<Response, Error>
+    where
+        C: Fn(&mut Game),
+    {
+        let (link, position, opponent) = self.with_game(id, |game| {
+            navigate(game);
+            Ok((
+                game.link().cloned(),
+                game.current_position(),
+                game.opponent().cloned(),
+            ))
+        })?;
+        let mut opponents: Vec<Opponent> = opponent.into_iter().collect();
+        let games = self.read_games()?;
+        let mut changed = vec![id.to_string()];
+        if let Some(link) = link {
+            let mut others: Vec<&String> = games.keys().filter(|other| *other != id).collect();
+            others.sort_unstable();
+            for other in others {
+                if self.check_not_busy(other).is_err() {
+                    continue;
+                }
+                // Locked one at a time, as concurrent navigations of the group would deadlock
+                let mut game = match games.get_game(other) {
+                    Ok(game) if game.link() == Some(&link) => game,
+                    _ => continue,
+                };
+                let moved = match link.mode {
+                    LinkMode::Ply => {
+                        navigate(&mut game);
+                        true
+                    }
+                    LinkMode::Position => game.go_to_position(&position),
+                };
+                if moved {
+                    changed.push(other.clone());
+                    opponents.extend(game.opponent().cloned());
+                }
+            }
+        }
+        let reprs = changed.into_iter().map(|id| {
+            let repr = self.game_repr(&*games.get_game(&id)?);
+            Ok((id, repr))
+        });
+        // The predicted reply won't be played from another position
+        for opponent in &opponents {
+            self.stop_pondering(opponent);
+        }
+        response_from_games(reprs)
     }
 
-    /// Applies operation requiring access to the whole state. This is necessary to access all games or to add/delete a game.
-    /// Returned response contains all games since all state has potentially been modified
-    ///
+    /// Applies operation requiring access to the whole state, like adding a game.
+    /// The closure returns the ids of the games it changed, which the response contains.
     fn state_operation<C>(&self, closure: C) -> Result<Response, Error>
     where
-        C: Fn(&mut RwLockWriteGuard<InnerState>) -> Result<(), Error>,
+        C: FnOnce(&mut RwLockWriteGuard<InnerState>) -> Result<Vec<String>, Error>,
     {
-        let mut guard = self.inner.write()?;
-        closure(&mut guard)?;
+        let mut guard = self.write_games()?;
+        let changed = closure(&mut guard)?;
 
-        let all_games = guard
-            .all_games()
-            .map(|r| r.map(|(id, game)| (id, game.get_repr())));
-        Ok(response_from_games(all_games)?)
+        let changed_games = changed.into_iter().map(|id| {
+            let repr = self.game_repr(&*guard.get_game(&id)?);
+            Ok((id, repr))
+        });
+        response_from_games(changed_games)
     }
 }
 
 impl Default for StateHandle {
     fn default() -> StateHandle {
-        let state = StateHandle {
+        let (notifications, _) = broadcast::channel(NOTIFICATION_CAPACITY);
+        StateHandle {
             inner: Arc::new(RwLock::new(HashMap::new())),
-        };
-        state
+            engines: EngineRegistry::default(),
+            jobs: Jobs::default(),
+            database_jobs: Jobs::default(),
+            exports: Jobs::default(),
+            analyses: Jobs::default(),
+            opponents: Jobs::default(),
+            notifications,
+            shut_down: Arc::new(AtomicBool::new(false)),
+            databases: DatabaseRegistry::default(),
+            book: None,
+            lichess: LichessClient::default(),
+            tablebase: TablebaseClient::default(),
+            explorer: ExplorerClient::default(),
+            session: Arc::new(Mutex::new(Session::default())),
+            session_path: None,
+            startup_warnings: Arc::new(Mutex::new(Vec::new())),
+            recorder: None,
+            request_echo_limit: DEFAULT_REQUEST_ECHO_LIMIT,
+            stats: StatsRecorder::default(),
+        }
     }
 }
 
@@ -78,20 +1709,54 @@ impl Clone for StateHandle {
     fn clone(&self) -> StateHandle {
         StateHandle {
             inner: Arc::clone(&self.inner),
+            engines: self.engines.clone(),
+            jobs: self.jobs.clone(),
+            database_jobs: self.database_jobs.clone(),
+            exports: self.exports.clone(),
+            analyses: self.analyses.clone(),
+            opponents: self.opponents.clone(),
+            notifications: self.notifications.clone(),
+            shut_down: Arc::clone(&self.shut_down),
+            databases: self.databases.clone(),
+            book: self.book.clone(),
+            lichess: self.lichess.clone(),
+            tablebase: self.tablebase.clone(),
+            explorer: self.explorer.clone(),
+            session: Arc::clone(&self.session),
+            session_path: self.session_path.clone(),
+            startup_warnings: Arc::clone(&self.startup_warnings),
+            recorder: self.recorder.clone(),
+            request_echo_limit: self.request_echo_limit,
+            stats: self.stats.clone(),
         }
     }
 }
 
 trait StateOperations {
-    fn get_game(&self, id: &String) -> Result<MutexGuard<Game>, Error>;
-    fn all_games(&self) -> GamesIterator;
-    fn close_game(&mut self, id: &String) -> Result<(), Error>;
-    fn new_game_default(&mut self, id: &String) -> Result<(), Error>;
-    fn new_game_fen(&mut self, id: &String, fen: String) -> Result<(), Error>;
+    fn get_game(&self, id: &str) -> Result<MutexGuard<'_, Game>, Error>;
+    fn all_games(&self) -> GamesIterator<'_>;
+    fn close_game(&mut self, id: &str) -> Result<(), Error>;
+    fn new_game_default(&mut self, id: &str) -> Result<(), Error>;
+    fn new_game_fen(&mut self, id: &str, fen: String, chess960: bool) -> Result<(), Error>;
+    fn new_game_pgn(&mut self, id: &str, pgn: &str) -> Result<(), Error>;
+}
+
+/// Unlinks the games of group `group_id`, returning their ids.
+fn unlink(state: &InnerState, group_id: &str) -> Result<Vec<String>, Error> {
+    let mut unlinked = Vec::new();
+    for game in state.all_games() {
+        let (id, mut game) = game?;
+        if game.link().map(|link| link.group_id.as_str()) == Some(group_id) {
+            game.set_link(None);
+            unlinked.push(id);
+        }
+    }
+    unlinked.sort_unstable();
+    Ok(unlinked)
 }
 
 impl StateOperations for InnerState {
-    fn get_game(&self, id: &String) -> Result<MutexGuard<Game>, Error> {
+    fn get_game(&self, id: &str) -> Result<MutexGuard<'_, Game>, Error> {
         self.get(id)
             .ok_or(Error::new(ErrorType::BadHandle).with_id(id))?
             .as_ref()
@@ -100,17 +1765,17 @@ impl StateOperations for InnerState {
             .map_err(|_| Error::new(ErrorType::PoisonedHandle).with_id(id))
     }
 
-    fn all_games(&self) -> GamesIterator {
+    fn all_games(&self) -> GamesIterator<'_> {
         GamesIterator::from(self)
     }
 
-    fn close_game(&mut self, id: &String) -> Result<(), Error> {
+    fn close_game(&mut self, id: &str) -> Result<(), Error> {
         let element = self
             .get_mut(id)
-            .ok_or(Error::new(ErrorType::BadHandle).with_id(&id))?;
+            .ok_or(Error::new(ErrorType::BadHandle).with_id(id))?;
 
         match element {
-            None => Err(Error::new(ErrorType::StaleHandle).with_id(&id)),
+            None => Err(Error::new(ErrorType::StaleHandle).with_id(id)),
             Some(_) => {
                 element.take();
                 Ok(())
@@ -118,21 +1783,45 @@ impl StateOperations for InnerState {
         }
     }
 
-    fn new_game_default(&mut self, id: &String) -> Result<(), Error> {
+    fn new_game_default(&mut self, id: &str) -> Result<(), Error> {
         let game = Some(Mutex::from(Game::default()));
-        self.insert(id.clone(), game);
+        self.insert(id.to_string(), game);
         Ok(())
     }
 
-    fn new_game_fen(&mut self, id: &String, fen: String) -> Result<(), Error> {
-        let game = Some(Mutex::from(Game::from_fen(fen)?));
-        self.insert(id.clone(), game)
-            .ok_or(Error::new(ErrorType::BadHandle).with_id(&id))?;
+    fn new_game_fen(&mut self, id: &str, fen: String, chess960: bool) -> Result<(), Error> {
+        let game = Game::from_fen(fen)
+            .map_err(|e| e.with_id(id))?
+            .with_chess960(chess960);
+        self.insert(id.to_string(), Some(Mutex::from(game)));
+        Ok(())
+    }
+
+    /// The first game of `pgn`, at its starting position. Anything wrong with it is a parse error.
+    fn new_game_pgn(&mut self, id: &str, pgn: &str) -> Result<(), Error> {
+        let parse = || -> Result<Game, Error> {
+            let pgn = PgnReader::new(pgn.as_bytes())
+                .next()
+                .ok_or_else(|| Error::new(ErrorType::Parse).with_message("No game in the PGN"))??;
+            let mut game = Game::from_pgn(&pgn)?;
+            game.navigate_to_start();
+            Ok(game)
+        };
+        let game = parse().map_err(|err| match err.error_type {
+            ErrorType::Parse => err.with_id(id),
+            _ => Error {
+                source: Some(Box::from(err)),
+                ..Error::new(ErrorType::Parse)
+            }
+            .with_id(id),
+        })?;
+        self.insert(id.to_string(), Some(Mutex::from(game)));
         Ok(())
     }
 }
 
 type HashMapIter<'a> = dyn Iterator<Item = (&'a String, &'a Option<Mutex<Game>>)> + 'a;
+/// Open games, closed ones skipped. A poisoned game ends the iteration with an error naming it.
 struct GamesIterator<'a> {
     hashmap_iter: Box<HashMapIter<'a>>,
     is_poisoned: bool,
@@ -146,16 +1835,16 @@ impl<'a> Iterator for GamesIterator<'a> {
             return None;
         }
 
-        match self.hashmap_iter.next() {
-            None => None,
-            Some((_, None)) => self.next(),
-            Some((id, Some(mutex))) => match mutex.lock() {
-                Ok(lock) => Some(Ok((id.clone(), lock))),
-                Err(err) => {
-                    self.is_poisoned = true;
-                    Some(Err(err.into()))
-                }
-            },
+        let (id, mutex) = self
+            .hashmap_iter
+            .by_ref()
+            .find_map(|(id, cell)| Some((id, cell.as_ref()?)))?;
+        match mutex.lock() {
+            Ok(lock) => Some(Ok((id.clone(), lock))),
+            Err(err) => {
+                self.is_poisoned = true;
+                Some(Err(Error::from(err).with_id(id)))
+            }
         }
     }
 }
@@ -170,4 +1859,666 @@ impl<'a> From<&'a InnerState> for GamesIterator<'a> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn game_ids(state: &InnerState) -> Vec<String> {
+        let mut ids: Vec<String> = state.all_games().map(|game| game.unwrap().0).collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn response_cardinality() {
+        let state = StateHandle::default();
+        let dispatch = |request: Value| {
+            let state = state.clone();
+            async move {
+                let request = serde_json::from_value(request).unwrap();
+                let response = crate::api::dispatch_request(request, &state).await;
+                serde_json::to_value(response.unwrap()).unwrap()
+            }
+        };
+        for i in 0..5 {
+            let request =
+                serde_json::json!({"method": "new_game", "params": {"id": format!("g{}", i)}});
+            let response = dispatch(request).await;
+            assert_eq!(response["changed_games"].as_array().unwrap().len(), 1);
+            assert_eq!(response["changed_games"][0]["id"], format!("g{}", i));
+        }
+        let fen = "4k3/8/8/8/8/8/8/4K3 w - - 0 1";
+        let request = serde_json::json!({"method": "new_game", "params": {"id": "g5", "fen": fen}});
+        assert_eq!(
+            dispatch(request).await["changed_games"][0]["game"]["fen"],
+            fen
+        );
+
+        let request = serde_json::json!({"method": "close_game", "params": {"id": "g2"}});
+        let response = dispatch(request.clone()).await;
+        assert_eq!(response["closed_games"], serde_json::json!(["g2"]));
+        assert_eq!(response["changed_games"], serde_json::json!([]));
+        let response = dispatch(request).await;
+        assert_eq!(response["error"]["type"], "StaleHandle");
+        assert_eq!(response["error"]["game_id"], "g2");
+        let request = serde_json::json!({"method": "close_game", "params": {"id": "unknown"}});
+        let response = dispatch(request).await;
+        assert_eq!(response["error"]["type"], "BadHandle");
+        assert_eq!(response["error"]["game_id"], "unknown");
+
+        let all = dispatch(serde_json::json!({"method": "get_all_games", "params": {}})).await;
+        assert_eq!(all["changed_games"].as_array().unwrap().len(), 5);
+        assert!(all.get("closed_games").is_none());
+    }
+
+    #[tokio::test]
+    async fn puzzle_solving() {
+        let state = StateHandle::default();
+        let dispatch = |method: &str, params: Value| {
+            let state = state.clone();
+            let request = serde_json::json!({"method": method, "params": params});
+            async move {
+                let request = serde_json::from_value(request).unwrap();
+                let response = crate::api::dispatch_request(request, &state).await;
+                serde_json::to_value(response.unwrap()).unwrap()
+            }
+        };
+        let fen = "r5k1/5ppp/8/8/8/8/3Q1PPP/3R2K1 w - - 0 1";
+        dispatch("new_game", serde_json::json!({"id": "p", "fen": fen})).await;
+        let params = serde_json::json!({
+            "id": "p",
+            "solution_uci": ["d2d8", "a8d8", "d1d8"],
+            "attempts_before_hint": 1,
+            "at_ms": 1000,
+        });
+        let response = dispatch("set_puzzle", params).await;
+        assert_eq!(response["changed_games"][0]["game"]["solving"], true);
+        let play = |from: &str, to: &str, at_ms: u64| {
+            let params = serde_json::json!({"id": "p", "from": from, "to": to, "at_ms": at_ms});
+            dispatch("play", params)
+        };
+
+        // Wrong moves aren't played, hinting the piece then the whole move
+        let response = play("d2", "d7", 2000).await;
+        assert_eq!(
+            response["puzzle_attempt"],
+            serde_json::json!({"result": "try_again", "attempts": 1, "hint": {"type": "piece", "square": "d2"}})
+        );
+        assert_eq!(response["changed_games"][0]["game"]["fen"], fen);
+        let response = play("d2", "d7", 3000).await;
+        assert_eq!(response["puzzle_attempt"]["hint"]["uci"], "d2d8");
+        assert_eq!(play("d2", "d9", 3000).await["error"]["type"], "Parse");
+
+        let response = play("d2", "d8", 4000).await;
+        assert_eq!(
+            response["puzzle_attempt"],
+            serde_json::json!({"result": "correct", "reply": "Rxd8"})
+        );
+        // The rest of the solution is kept from reprs and exports
+        assert!(!response.to_string().contains("d1d8"));
+        let movetext = state
+            .with_game("p", |game| Ok(game.to_pgn().movetext))
+            .unwrap();
+        assert!(!movetext.contains("Rxd8#"));
+
+        let response = play("d1", "d8", 9000).await;
+        assert_eq!(
+            response["puzzle_attempt"],
+            serde_json::json!({"result": "solved", "mistakes": 2, "hints": 2, "time_ms": 8000})
+        );
+        assert_eq!(response["changed_games"][0]["game"]["solving"], false);
+        // Solved, moves are played as usual again
+        dispatch("navigate_back", serde_json::json!({"id": "p", "back": 3})).await;
+        let response = play("d2", "d7", 10_000).await;
+        assert!(response.get("puzzle_attempt").is_none());
+
+        let params = serde_json::json!({"id": "p", "solution_uci": ["d2d8", "a8a1"]});
+        assert_eq!(
+            dispatch("set_puzzle", params).await["error"]["type"],
+            "ChessRules"
+        );
+    }
+
+    #[tokio::test]
+    async fn repertoire_drill() {
+        let state = StateHandle::default();
+        let dispatch = |method: &str, params: Value| {
+            let state = state.clone();
+            let request = serde_json::json!({"method": method, "params": params});
+            async move {
+                let request = serde_json::from_value(request).unwrap();
+                let response = crate::api::dispatch_request(request, &state).await;
+                serde_json::to_value(response.unwrap()).unwrap()
+            }
+        };
+        let play = |from: &str, to: &str| {
+            dispatch(
+                "play",
+                serde_json::json!({"id": "r", "from": from, "to": to}),
+            )
+        };
+        let back = |back: u16| {
+            dispatch(
+                "navigate_back",
+                serde_json::json!({"id": "r", "back": back}),
+            )
+        };
+        let mark = || dispatch("mark_repertoire_move", serde_json::json!({"id": "r"}));
+        // 1. e4 (1. d4) e5 2. Nf3 Nc6, with 1. d4 and 2... Nc6 unmarked
+        dispatch("new_game", serde_json::json!({"id": "r"})).await;
+        for (from, to) in &[("e2", "e4"), ("e7", "e5"), ("g1", "f3")] {
+            play(from, to).await;
+            mark().await;
+        }
+        play("b8", "c6").await;
+        back(4).await;
+        play("d2", "d4").await;
+        assert_eq!(
+            back(1).await["changed_games"][0]["game"]["repertoire_move"],
+            "e4"
+        );
+
+        let response = dispatch(
+            "start_drill",
+            serde_json::json!({"id": "r", "color": "white"}),
+        )
+        .await;
+        assert_eq!(response["drill"], serde_json::json!({"result": "ready"}));
+        let game = &response["changed_games"][0]["game"];
+        assert_eq!(game["drilling"], true);
+        assert!(game.get("repertoire_move").is_none());
+
+        // Misses aren't played
+        let response = play("d2", "d4").await;
+        assert_eq!(
+            response["drill"],
+            serde_json::json!({"result": "miss", "expected": "e4", "misses": 1})
+        );
+        assert_eq!(
+            response["changed_games"][0]["game"]["fen"],
+            shakmaty::fen::fen(&shakmaty::Chess::default())
+        );
+        assert_eq!(
+            play("e2", "e4").await["drill"],
+            serde_json::json!({"result": "hit", "reply": "e5", "streak": 1})
+        );
+        // Nothing is marked after 2... Nc6
+        let response = play("g1", "f3").await;
+        assert_eq!(
+            response["drill"],
+            serde_json::json!({"result": "line_end", "reply": "Nc6", "hits": 2, "misses": 1, "best_streak": 2})
+        );
+        assert_eq!(response["changed_games"][0]["game"]["drilling"], false);
+        let saved = state.with_game("r", |game| Ok(game.to_saved())).unwrap();
+        assert_eq!(saved.tree.misses, 1);
+        assert!(saved.moves[0].repertoire && !saved.moves[3].repertoire);
+
+        back(4).await;
+        let drill = serde_json::json!({"id": "r", "color": "white", "depth_limit": 1});
+        dispatch("start_drill", drill).await;
+        assert_eq!(
+            play("e2", "e4").await["drill"],
+            serde_json::json!({"result": "line_end", "hits": 1, "misses": 0, "best_streak": 1})
+        );
+        // Black's repertoire, from the reply to 1. e4
+        let drill = serde_json::json!({"id": "r", "color": "black"});
+        let response = dispatch("start_drill", drill).await;
+        assert_eq!(response["drill"], serde_json::json!({"result": "ready"}));
+        assert_eq!(
+            play("e7", "e5").await["drill"],
+            serde_json::json!({"result": "line_end", "reply": "Nf3", "hits": 1, "misses": 0, "best_streak": 1})
+        );
+    }
+
+    #[tokio::test]
+    async fn linked_games() {
+        let state = StateHandle::default();
+        let dispatch = |method: &str, params: Value| {
+            let state = state.clone();
+            let request = serde_json::json!({"method": method, "params": params});
+            async move {
+                let request = serde_json::from_value(request).unwrap();
+                let response = crate::api::dispatch_request(request, &state).await;
+                serde_json::to_value(response.unwrap()).unwrap()
+            }
+        };
+        let open = |id: &'static str, moves: &'static [&'static str]| {
+            let state = state.clone();
+            async move {
+                state.new_game_default(id).unwrap();
+                for uci in moves {
+                    state.play_uci(id, uci).unwrap();
+                }
+            }
+        };
+        let back = |id: &str, back: u16| {
+            dispatch("navigate_back", serde_json::json!({"id": id, "back": back}))
+        };
+        let moved = |response: &Value| -> Vec<(String, String)> {
+            response["changed_games"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|game| {
+                    let fen = game["game"]["fen"].as_str().unwrap();
+                    (game["id"].as_str().unwrap().to_string(), fen.to_string())
+                })
+                .collect()
+        };
+        let fen = |id: &str| state.with_game(id, |game| Ok(game.current_fen())).unwrap();
+        let plies = |id: &str| state.with_game(id, |game| Ok(game.line().len())).unwrap();
+
+        open("a", &["e2e4", "e7e5", "g1f3"]).await;
+        open("b", &["d2d4", "d7d5", "c2c4", "e7e6"]).await;
+        open("c", &["c2c4"]).await;
+        let link = serde_json::json!({"group_id": "g", "game_ids": ["a", "b", "c"], "mode": "ply"});
+        let response = dispatch("link_games", link).await;
+        assert_eq!(
+            response["changed_games"][1]["game"]["link"],
+            serde_json::json!({"group_id": "g", "mode": "ply"})
+        );
+        // Mirrored once, two plies back wherever each game was
+        let response = back("b", 2).await;
+        assert_eq!(
+            moved(&response),
+            vec![
+                (String::from("b"), fen("b")),
+                (String::from("a"), fen("a")),
+                (String::from("c"), fen("c")),
+            ]
+        );
+        assert_eq!(plies("a"), 1);
+        assert_eq!(plies("b"), 2);
+        assert_eq!(plies("c"), 0);
+        let forward = serde_json::json!({"id": "b", "forward": 2});
+        let response = dispatch("navigate_forward", forward).await;
+        assert_eq!(moved(&response).len(), 3);
+        assert_eq!((plies("a"), plies("b"), plies("c")), (3, 4, 1));
+        back("b", 2).await;
+
+        let response = dispatch("unlink_games", serde_json::json!({"group_id": "g"})).await;
+        assert_eq!(response["changed_games"].as_array().unwrap().len(), 3);
+        assert!(response["changed_games"][0]["game"].get("link").is_none());
+        assert_eq!(moved(&back("a", 1).await).len(), 1);
+
+        // The same position reached by a transposition
+        open("a", &["e2e4", "e7e5", "g1f3", "b8c6"]).await;
+        open("b", &["g1f3", "b8c6", "e2e4", "e7e5", "f1c4"]).await;
+        open("c", &["d2d4", "d7d5"]).await;
+        let link =
+            serde_json::json!({"group_id": "g", "game_ids": ["a", "b", "c"], "mode": "position"});
+        dispatch("link_games", link).await;
+        let response = back("a", 0).await;
+        assert_eq!(
+            moved(&response),
+            vec![(String::from("a"), fen("a")), (String::from("b"), fen("b"))]
+        );
+        assert_eq!(plies("b"), 4);
+        let response = back("a", 4).await;
+        assert_eq!(moved(&response).len(), 3);
+        assert_eq!(fen("c"), fen("a"));
+    }
+
+    #[tokio::test]
+    async fn move_format() {
+        let state = StateHandle::default();
+        state.new_game_default("a").unwrap();
+        for uci in &["e2e4", "d7d5", "e4d5"] {
+            state.play_uci("a", uci).unwrap();
+        }
+        let last_move = |response: Response| {
+            let response = serde_json::to_value(response).unwrap();
+            response["changed_games"][0]["game"]["last_move"].clone()
+        };
+        assert_eq!(last_move(state.get_all_games().unwrap()), "exd5");
+
+        let request = serde_json::json!({
+            "method": "set_move_format",
+            "params": {"notation": "spoken"},
+        });
+        let request = serde_json::from_value(request).unwrap();
+        let response = crate::api::dispatch_request(request, &state).await;
+        assert_eq!(last_move(response.unwrap()), "pawn from e4 takes d5");
+
+        let format = MoveFormat {
+            notation: crate::move_format::Notation::Lan,
+            piece_locale: crate::move_format::PieceLocale::De,
+        };
+        state.set_move_format(format).unwrap();
+        let response = state.play_uci("a", "d8d5").unwrap();
+        assert_eq!(last_move(response), "Dd8xd5");
+        let response = state.navigate_back("a", 4, None).unwrap();
+        assert!(
+            serde_json::to_value(response).unwrap()["changed_games"][0]["game"]
+                .get("last_move")
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn analyze() {
+        let state = StateHandle::default();
+        let mut dead = crate::engine::tests::mock_engine(&[]);
+        dead.path = String::from("/nonexistent/engine");
+        let error = state.add_engine("dead", dead).await.unwrap_err();
+        assert_eq!(error.error_type, ErrorType::IO);
+        let engine = crate::engine::tests::mock_engine(&["e7e5@-20", "g8h6"]);
+        state.add_engine("mock", engine).await.unwrap();
+
+        state.new_game_default("g").unwrap();
+        state.play_uci("g", "e2e4").unwrap();
+        let mut notifications = state.subscribe();
+        state.analyze("g", None, 12).unwrap();
+        assert_eq!(
+            finished_analysis(&mut notifications).await,
+            serde_json::json!({
+                "best_move": "e5",
+                "pv": ["e5"],
+                "evaluation": {"score": {"cp": 20}, "depth": 1},
+            })
+        );
+        state.analyze("g", Some(String::from("mock")), 12).unwrap();
+        assert_eq!(
+            finished_analysis(&mut notifications).await["best_move"],
+            "Nh6"
+        );
+        let error = state.analyze("g", Some(String::from("dead")), 12);
+        assert_eq!(error.unwrap_err().error_type, ErrorType::Engine);
+
+        let error = state.analyze("g", None, depth_analysis::MAX_DEPTH + 1);
+        assert_eq!(error.unwrap_err().error_type, ErrorType::LimitExceeded);
+        let ticket = state.jobs().start("g").unwrap();
+        let error = state.analyze("g", None, 12);
+        assert_eq!(error.unwrap_err().error_type, ErrorType::Locked);
+        state.jobs().finish("g", ticket.token).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_depth_analysis() {
+        let state = StateHandle::default();
+        let engine = crate::engine::tests::mock_engine(&["wait"]);
+        state.add_engine("mock", engine).await.unwrap();
+        state.new_game_default("g").unwrap();
+        let mut notifications = state.subscribe();
+
+        state.analyze("g", None, 30).unwrap();
+        let error = state.analyze("g", None, 30).unwrap_err();
+        assert_eq!(error.error_type, ErrorType::Locked);
+        tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+        state.stop_analysis("g").unwrap();
+        assert!(finished_analysis(&mut notifications).await.is_null());
+    }
+
+    async fn finished_analysis(notifications: &mut broadcast::Receiver<Response>) -> Value {
+        let response = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(response["notification"]["type"], "analysis_finished");
+        response["notification"]["analysis"].clone()
+    }
+
+    #[tokio::test]
+    async fn set_engine_option() {
+        let state = StateHandle::default();
+        let (engine, log) = crate::engine::tests::logged_mock_engine("set_engine_option", &[]);
+        state.add_engine("mock", engine).await.unwrap();
+
+        let set = |name: &'static str, value: &'static str| {
+            let state = state.clone();
+            async move {
+                let response = state.set_engine_option("mock", name, value).await.unwrap();
+                serde_json::to_value(response).unwrap()
+            }
+        };
+        let response = set("ponder", "true").await;
+        assert_eq!(response["engines"][0]["id"], "mock");
+        assert!(response.get("warnings").is_none());
+        let response = set("Hash", "256").await;
+        assert_eq!(response["warnings"][0]["type"], "UnknownEngineOption");
+
+        let commands = crate::engine::tests::received_commands(&log);
+        let sent: Vec<&String> = commands
+            .iter()
+            .filter(|command| command.starts_with("setoption"))
+            .collect();
+        assert_eq!(
+            sent[sent.len() - 2..],
+            [
+                "setoption name ponder value true",
+                "setoption name Hash value 256"
+            ]
+        );
+        let missing = state.set_engine_option("none", "Hash", "1").await;
+        assert_eq!(missing.unwrap_err().error_type, ErrorType::Engine);
+    }
+
+    #[tokio::test]
+    async fn new_game_from_pgn() {
+        let state = StateHandle::default();
+        let new_game = |pgn: &str| {
+            let request =
+                serde_json::json!({"method": "new_game", "params": {"id": "g", "pgn": pgn}});
+            let request = serde_json::from_value(request).unwrap();
+            let state = state.clone();
+            async move {
+                let response = crate::api::dispatch_request(request, &state).await;
+                serde_json::to_value(response.unwrap()).unwrap()
+            }
+        };
+
+        let pgn = "[White \"Morphy\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Nf3 (2. f4) d6 1-0\n";
+        let response = new_game(pgn).await;
+        let game = &response["changed_games"][0]["game"];
+        assert_eq!(game["fen"], Game::default().initial_fen());
+        assert_eq!(
+            game["info"]["headers"][0],
+            serde_json::json!(["White", "Morphy"])
+        );
+        let main_line = state.with_game("g", |game| Ok(game.main_line())).unwrap();
+        assert_eq!(main_line.len(), 4);
+
+        let fen = "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1";
+        let pgn = format!("[SetUp \"1\"]\n[FEN \"{}\"]\n\n1. e4 Kd7 *\n", fen);
+        assert_eq!(new_game(&pgn).await["changed_games"][0]["game"]["fen"], fen);
+
+        for pgn in &["1. e4 e5 2. Nf6", "1. e4 (e5", ""] {
+            assert_eq!(new_game(pgn).await["error"]["type"], "Parse", "{}", pgn);
+        }
+    }
+
+    #[tokio::test]
+    async fn export_pgn() {
+        let state = StateHandle::default();
+        let fen = "r3k2r/pp3ppp/8/8/8/8/PP3PPP/R3K2R b KQkq - 3 12";
+        let pgn = format!(
+            "[Event \"Club\"]\n[FEN \"{}\"]\n[SetUp \"1\"]\n\n\
+             12... O-O 13. O-O-O (13. O-O {{Safer}} Rfd8 (13... a6)) 13... Rfd8 *\n",
+            fen
+        );
+        state.new_game_pgn("a", &pgn).unwrap();
+        let response = serde_json::to_value(state.export_pgn("a").unwrap()).unwrap();
+        let exported = response["pgn"].as_str().unwrap();
+        assert!(exported.starts_with("[Event \"Club\"]\n[Site \"?\"]\n"));
+        assert!(exported.contains("[Result \"*\"]\n"));
+        assert!(exported.contains(&format!("[FEN \"{}\"]\n[SetUp \"1\"]\n", fen)));
+
+        state.new_game_pgn("b", exported).unwrap();
+        let tree = |id: &str| state.with_game(id, |game| Ok(game.to_saved())).unwrap();
+        let (a, b) = (tree("a"), tree("b"));
+        assert!(exported.ends_with(
+            "\n\n12... O-O 13. O-O-O (13. O-O { Safer } 13... Rfd8 (13... a6)) 13... Rfd8 *\n\n"
+        ));
+        assert_eq!((a.fen, a.moves, a.tree), (b.fen, b.moves, b.tree));
+    }
+
+    #[test]
+    fn games_iterator() {
+        let mut state = InnerState::new();
+        assert!(state.all_games().next().is_none());
+        for id in &["g1", "g2", "g3", "g4"] {
+            state.new_game_default(id).unwrap();
+        }
+        assert_eq!(game_ids(&state), ["g1", "g2", "g3", "g4"]);
+
+        // Closed games leave stale handles, which are skipped
+        state.close_game("g2").unwrap();
+        state.close_game("g3").unwrap();
+        assert_eq!(game_ids(&state), ["g1", "g4"]);
+        let mut games = state.all_games();
+        games.next();
+        games.next();
+        assert!(games.next().is_none());
+        assert!(games.next().is_none());
+        drop(games);
+
+        let poisoned = std::panic::catch_unwind(|| {
+            let _game = state["g4"].as_ref().unwrap().lock().unwrap();
+            panic!("poisoning g4 on purpose");
+        });
+        assert!(poisoned.is_err());
+        let items: Vec<_> = state.all_games().collect();
+        let error = items.iter().find_map(|item| item.as_ref().err()).unwrap();
+        assert_eq!(error.error_type, ErrorType::PoisonedHandle);
+        assert_eq!(error.id.as_deref(), Some("g4"));
+    }
+
+    #[test]
+    fn all_games_reprs() {
+        let ids = |response: &Value| -> Vec<String> {
+            response["changed_games"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|game| game["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+        // Few games on the calling thread, then enough to spread them across threads
+        for &count in &[3, PARALLEL_REPRS_MIN_GAMES * 2] {
+            let state = StateHandle::default();
+            let mut expected: Vec<String> = (0..count).map(|i| format!("g{}", i)).collect();
+            for id in expected.iter().rev() {
+                state.new_game_default(id).unwrap();
+            }
+            state
+                .play("g1", "e2".into(), "e4".into(), None, None)
+                .unwrap();
+            expected.sort();
+            let response = serde_json::to_value(state.get_all_games().unwrap()).unwrap();
+            assert_eq!(ids(&response), expected);
+            let g1 = &response["changed_games"][1];
+            assert_eq!(g1["id"], "g1");
+            assert_eq!(
+                g1["game"]["fen"],
+                state
+                    .read_games()
+                    .unwrap()
+                    .get_game("g1")
+                    .unwrap()
+                    .current_fen()
+            );
+
+            let poisoned = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let games = state.read_games().unwrap();
+                let _game = games.get_game("g2").unwrap();
+                panic!("poisoning g2 on purpose");
+            }));
+            assert!(poisoned.is_err());
+            let response = serde_json::to_value(state.get_all_games().unwrap()).unwrap();
+            expected.retain(|id| id != "g2");
+            assert_eq!(ids(&response), expected);
+            assert_eq!(response["warnings"][0]["type"], "PoisonedGame");
+            assert_eq!(response["warnings"][0]["game_id"], "g2");
+
+            // However many threads compute them
+            let games = state.read_games().unwrap();
+            let reprs = |threads| {
+                let reprs = state.game_reprs(&games, threads);
+                let reprs: Vec<_> = reprs.iter().map(Result::as_ref).collect();
+                serde_json::to_value(reprs).unwrap()
+            };
+            assert_eq!(reprs(4), reprs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn quarantine() {
+        let state = StateHandle::default();
+        for id in &["g1", "g2", "g3"] {
+            state.new_game_default(id).unwrap();
+        }
+        let mut notifications = state.subscribe();
+
+        let panicking = state.clone();
+        let panicked = std::thread::spawn(move || {
+            let games = panicking.inner.write().unwrap();
+            let _g2 = games.get_game("g2").unwrap();
+            panic!("poisoning the games on purpose");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(state.inner.is_poisoned());
+
+        let games = serde_json::to_value(state.get_all_games().unwrap()).unwrap();
+        let mut ids: Vec<&str> = games["changed_games"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|game| game["id"].as_str().unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["g1", "g3"]);
+        assert!(!state.inner.is_poisoned());
+        let notification = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(notification["notification"]["type"], "games_quarantined");
+        assert_eq!(notification["notification"]["ids"], Value::from(vec!["g2"]));
+
+        // Still served
+        state
+            .play("g1", String::from("e2"), String::from("e4"), None, None)
+            .unwrap();
+        assert!(state
+            .play("g2", String::from("e2"), String::from("e4"), None, None)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn quarantine_game() {
+        let state = StateHandle::default();
+        for id in &["g1", "g2"] {
+            state.new_game_default(id).unwrap();
+        }
+        let mut notifications = state.subscribe();
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            state.with_game("g1", |_| -> Result<(), Error> {
+                panic!("poisoning the game on purpose")
+            })
+        }));
+        assert!(panicked.is_err());
+        assert!(!state.inner.is_poisoned());
+
+        // The next request on the game is answered, not fatal
+        let request =
+            serde_json::json!({"method": "navigate_back", "params": {"id": "g1", "back": 1}});
+        let request = serde_json::from_value(request).unwrap();
+        let result = crate::api::dispatch_request(request, &state).await;
+        let response = crate::api::handle_fatal_error(result).unwrap();
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(response["error"]["type"], "StaleHandle");
+        let notification = serde_json::to_value(notifications.recv().await.unwrap()).unwrap();
+        assert_eq!(notification["notification"]["type"], "games_quarantined");
+        assert_eq!(notification["notification"]["ids"], Value::from(vec!["g1"]));
+
+        state
+            .play("g2", String::from("e2"), String::from("e4"), None, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn dump_session() {
+        let path = std::env::temp_dir().join(format!("bigchess-dump-{}.json", std::process::id()));
+        let state = StateHandle::default().with_session(path.clone(), Session::default());
+        let dumped = state.dump_session().unwrap();
+        assert_eq!(dumped, path.with_extension("crash.json"));
+        assert!(Session::load(&dumped).is_ok());
+        std::fs::remove_file(&dumped).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use crate::api::Response;
+use crate::errors::Error;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Errors kept in `Stats::last_errors`.
+pub const LAST_ERRORS: usize = 20;
+
+/// Counts of what the backend answered since it started.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub requests: u64,
+    /// By error type.
+    pub errors: BTreeMap<String, u64>,
+    /// By warning type.
+    pub warnings: BTreeMap<String, u64>,
+    /// Most recent first.
+    pub last_errors: VecDeque<RecentError>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RecentError {
+    /// Milliseconds since the unix epoch.
+    pub time: u64,
+    pub code: u16,
+    pub message: String,
+}
+
+/// Shared by every clone of the state.
+#[derive(Debug, Clone, Default)]
+pub struct StatsRecorder(Arc<Mutex<Stats>>);
+
+impl StatsRecorder {
+    /// Counts a request with its response.
+    pub fn record(&self, response: &Response) {
+        let mut stats = match self.0.lock() {
+            Ok(stats) => stats,
+            Err(_) => return,
+        };
+        stats.requests += 1;
+        if let Some(error) = response.error() {
+            let error_type = format!("{:?}", error.error_type());
+            *stats.errors.entry(error_type).or_default() += 1;
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            stats.last_errors.push_front(RecentError {
+                time: time.as_millis() as u64,
+                code: error.error_type().code(),
+                message: error.describe(),
+            });
+            stats.last_errors.truncate(LAST_ERRORS);
+        }
+        for warning in response.warnings() {
+            let warning_type = format!("{:?}", warning.warning_type());
+            *stats.warnings.entry(warning_type).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> Result<Stats, Error> {
+        Ok(self.0.lock()?.clone())
+    }
+}
+
+impl Stats {
+    /// Counters in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "# TYPE bigchess_requests_total counter");
+        let _ = writeln!(text, "bigchess_requests_total {}", self.requests);
+        let _ = writeln!(text, "# TYPE bigchess_errors_total counter");
+        for (error_type, count) in &self.errors {
+            let _ = writeln!(
+                text,
+                "bigchess_errors_total{{type=\"{}\"}} {}",
+                error_type, count
+            );
+        }
+        let _ = writeln!(text, "# TYPE bigchess_warnings_total counter");
+        for (warning_type, count) in &self.warnings {
+            let _ = writeln!(
+                text,
+                "bigchess_warnings_total{{type=\"{}\"}} {}",
+                warning_type, count
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::response_from_error;
+    use crate::errors::{ErrorType, WarningRepr, WarningType};
+
+    #[test]
+    fn counters() {
+        let recorder = StatsRecorder::default();
+        recorder.record(&Response::default());
+        for _ in 0..LAST_ERRORS {
+            recorder.record(&response_from_error(Error::new(ErrorType::ChessRules)));
+        }
+        let parse = Error::new(ErrorType::Notation).with_message("Qh9 is not a move");
+        recorder.record(&response_from_error(parse));
+        let warning = WarningRepr::new(WarningType::SkippedGame, "Game 2: illegal move");
+        recorder.record(&Response::default().with_warnings(vec![warning.clone(), warning]));
+
+        let stats = recorder.snapshot().unwrap();
+        assert_eq!(stats.requests, LAST_ERRORS as u64 + 3);
+        assert_eq!(stats.errors["ChessRules"], LAST_ERRORS as u64);
+        assert_eq!(stats.errors["Notation"], 1);
+        assert_eq!(stats.warnings["SkippedGame"], 2);
+        assert_eq!(stats.last_errors.len(), LAST_ERRORS);
+        assert_eq!(stats.last_errors[0].code, ErrorType::Notation.code());
+        assert!(stats.last_errors[0].message.contains("Qh9 is not a move"));
+        assert_eq!(stats.last_errors[1].code, ErrorType::ChessRules.code());
+
+        let metrics = stats.to_prometheus();
+        assert!(metrics.contains(&format!("bigchess_requests_total {}\n", LAST_ERRORS + 3)));
+        assert!(metrics.contains("bigchess_errors_total{type=\"Notation\"} 1\n"));
+        assert!(metrics.contains("bigchess_warnings_total{type=\"SkippedGame\"} 2\n"));
+    }
+}
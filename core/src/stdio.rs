@@ -2,53 +2,302 @@ use crate::api::*;
 use crate::errors::Error;
 use crate::state::StateHandle;
 
-use std::fmt::Debug;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
 
-use serde_json;
 use tokio::io;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+/// How long notifications wait in the buffer for others to be written along.
+const NOTIFICATION_WINDOW: Duration = Duration::from_millis(10);
+
+/// Buffered bytes written at once, whether or not the window is over.
+const NOTIFICATION_BYTES: usize = 32 * 1024;
 
 pub async fn handler(state: StateHandle) -> Result<(), Error> {
-    let mut stdout = std::io::stdout();
-    let stdin = io::stdin();
-    let mut stdin_lines = BufReader::new(stdin).lines();
+    let stdin = BufReader::new(io::stdin());
+    serve(state, stdin, std::io::stdout()).await
+}
 
-    send_initial_message(&state, &mut stdout)?;
+/// Answers the requests read from `input` on `output`, notifications in between.
+async fn serve<R, W>(state: StateHandle, input: R, output: W) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: Write,
+{
+    let mut lines = input.lines();
+    let mut writer = MessageWriter::new(output);
+    let mut notifications = state.subscribe();
+
+    if let Err(err) = writer.respond(&state.initial_message()?) {
+        return write_failed(err);
+    }
+    // Reopened databases are announced through notifications, once the frontend knows the games
+    tokio::spawn(state.clone().restore_session());
 
     loop {
-        let new_line = stdin_lines.next_line().await?.unwrap_or_default();
-        let response = dispatch(&*new_line, &state)?;
-        send_to_stream(response, &mut stdout);
+        let deadline = writer.deadline();
+        let written = tokio::select! {
+            new_line = lines.next_line() => {
+                let new_line = match new_line? {
+                    Some(line) => line,
+                    // The frontend is gone
+                    None => return Ok(()),
+                };
+                let response = dispatch(&new_line, &state).await?;
+                let written = writer.respond(&response);
+                if state.is_shut_down() {
+                    return written.or_else(write_failed);
+                }
+                written
+            }
+            notification = notifications.recv() => match notification {
+                Ok(response) => writer.notify(&response),
+                // A lagging receiver only loses the oldest notifications
+                Err(_) => Ok(()),
+            },
+            _ = tokio::time::delay_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                writer.flush()
+            }
+        };
+        if let Err(err) = written {
+            return write_failed(err);
+        }
     }
 }
 
-fn send_initial_message<W: Write + Debug>(
-    state: &StateHandle,
-    stream: &mut W,
-) -> Result<(), Error> {
-    let message = state.get_all_games()?;
-    send_to_stream(message, stream);
-    Ok(())
+/// Output can't be written: a closed pipe means the frontend is gone, which shuts the backend
+/// down like a closed input does.
+fn write_failed(err: std::io::Error) -> Result<(), Error> {
+    if err.kind() == std::io::ErrorKind::BrokenPipe {
+        tracing::warn!(%err, "output closed, shutting down");
+        return Ok(());
+    }
+    Err(err.into())
+}
+
+/// Writes messages as lines of JSON, each whole. Responses are flushed right away, notifications
+/// after `NOTIFICATION_WINDOW` or once `NOTIFICATION_BYTES` pile up.
+pub struct MessageWriter<W: Write> {
+    stream: BufWriter<W>,
+    /// Serialized message, kept to reuse its allocation.
+    line: Vec<u8>,
+    /// When the oldest notification left in the buffer was written.
+    pending_since: Option<Instant>,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(stream: W) -> MessageWriter<W> {
+        MessageWriter {
+            stream: BufWriter::with_capacity(NOTIFICATION_BYTES, stream),
+            line: Vec::new(),
+            pending_since: None,
+        }
+    }
+
+    /// Writes `response` and flushes it along with the notifications before it.
+    pub fn respond(&mut self, response: &Response) -> std::io::Result<()> {
+        self.write(response)?;
+        self.flush()
+    }
+
+    /// Writes `notification`, only flushed once the buffer is full or the window is over.
+    pub fn notify(&mut self, notification: &Response) -> std::io::Result<()> {
+        self.write(notification)?;
+        self.pending_since.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+
+    /// When buffered notifications are due to be flushed.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending_since.map(|since| since + NOTIFICATION_WINDOW)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.pending_since = None;
+        self.stream.flush()
+    }
+
+    /// Serializes the message before writing it, so that a failure can't leave half of it.
+    fn write(&mut self, message: &Response) -> std::io::Result<()> {
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, message)?;
+        self.line.push(b'\n');
+        self.stream.write_all(&self.line)
+    }
 }
 
 /// Only returns Err(Error) when it is not recoverable
 /// All other errors are returned in the form of Ok(Response)
-fn dispatch(line: &str, state: &StateHandle) -> Result<Response, Error> {
-    match serde_json::from_str(line) {
-        Ok(request) => dispatch_request(request, state),
-        Err(err) => return Ok(response_from_error(err.into())),
-    }
+pub async fn dispatch(line: &str, state: &StateHandle) -> Result<Response, Error> {
+    state.record_request(line);
+    let response = match serde_json::from_str(line) {
+        Ok(request) => dispatch_request(request, state).await?,
+        Err(err) => {
+            let response = response_from_error(Error::from(err));
+            state.record_response(&response);
+            response
+        }
+    };
+    Ok(response.echoing(line, state.request_echo_limit()))
 }
 
-pub fn send_to_stream<W: Write + Debug>(response: Response, mut stream: W) {
-    serde_json::to_writer(&mut stream, &response)
-        .expect("Unrecoverable error: could not serialize response object to stdout.");
+/// Writes a single response to `stream`, flushed.
+pub fn send_to_stream<W: Write>(response: Response, stream: W) -> Result<(), Error> {
+    Ok(MessageWriter::new(stream).respond(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    async fn error(line: &str, state: &StateHandle) -> Value {
+        let response = dispatch(line, state).await.unwrap();
+        serde_json::to_value(&response).unwrap()["error"].clone()
+    }
+
+    #[tokio::test]
+    async fn echoed_requests() {
+        let state = StateHandle::default();
+        let malformed = r#"{"method": "play", "params"#;
+        let error = error(malformed, &state).await;
+        assert_eq!(error["type"], "Deserialize");
+        assert_eq!(error["request"]["method"], Value::Null);
+        assert_eq!(error["request"]["line"], malformed);
+        assert_eq!(error["request"]["truncated"], false);
+
+        dispatch(r#"{"method": "new_game", "params": {"id": "g1"}}"#, &state)
+            .await
+            .unwrap();
+        let illegal = r#"{"method": "play", "params": {"id": "g1", "from": "e2", "to": "e5"}}"#;
+        let error = self::error(illegal, &state).await;
+        assert_eq!(error["type"], "ChessRules");
+        assert_eq!(error["code"], 3);
+        assert_eq!(error["request"]["method"], "play");
+        assert_eq!(error["request"]["line"], illegal);
+
+        let state = state.with_request_echo_limit(10);
+        let error = self::error(illegal, &state).await;
+        assert_eq!(error["request"]["line"], r#"{"method":"#);
+        assert_eq!(error["request"]["truncated"], true);
+
+        let succeeded = dispatch(r#"{"method": "get_all_games", "params": {}}"#, &state)
+            .await
+            .unwrap();
+        assert!(serde_json::to_value(&succeeded).unwrap()["error"].is_null());
+
+        let stats = dispatch(r#"{"method": "get_stats", "params": {}}"#, &state)
+            .await
+            .unwrap();
+        let stats = &serde_json::to_value(&stats).unwrap()["stats"];
+        // The stats request itself isn't counted yet
+        assert_eq!(stats["requests"], 5);
+        assert_eq!(stats["errors"]["Deserialize"], 1);
+        assert_eq!(stats["errors"]["ChessRules"], 2);
+        let last_errors = stats["last_errors"].as_array().unwrap();
+        assert_eq!(last_errors.len(), 3);
+        assert_eq!(last_errors[0]["code"], 3);
+        assert_eq!(last_errors[2]["code"], 1);
+    }
+
+    #[derive(Default)]
+    struct Recording {
+        writes: Vec<Vec<u8>>,
+        flushes: usize,
+    }
+
+    impl Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    struct Failing(std::io::ErrorKind);
 
-    write!(&mut stream, "\n")
-        .and_then(|_| stream.flush())
-        .expect(&*format!(
-            "Unrecoverable error: could not write to stream {:?}",
-            stream,
-        ));
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(self.0, "failing on purpose"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::new(self.0, "failing on purpose"))
+        }
+    }
+
+    #[test]
+    fn coalesced_notifications() {
+        let notification = response_from_notification(Notification::EngineMatchMove {
+            id: String::from("g1"),
+            engine_id: String::from("e1"),
+            uci: String::from("e2e4"),
+            ply: 1,
+        });
+        let mut writer = MessageWriter::new(Recording::default());
+        for _ in 0..3 {
+            writer.notify(&notification).unwrap();
+        }
+        assert!(writer.stream.get_ref().writes.is_empty());
+        assert!(writer.deadline().is_some());
+
+        // A response takes the notifications before it along, in one write
+        writer.respond(&Response::default()).unwrap();
+        assert!(writer.deadline().is_none());
+        let recording = writer.stream.get_ref();
+        assert_eq!(recording.writes.len(), 1);
+        assert_eq!(recording.flushes, 1);
+        let lines: Vec<Value> = recording.writes[0]
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2]["notification"]["type"], "engine_match_move");
+        assert!(lines[3]["notification"].is_null());
+
+        // Past the buffer's capacity, notifications are written whole without waiting
+        let mut writer = MessageWriter::new(Recording::default());
+        while writer.stream.get_ref().writes.is_empty() {
+            writer.notify(&notification).unwrap();
+        }
+        let written = &writer.stream.get_ref().writes[0];
+        assert!(written.len() <= NOTIFICATION_BYTES);
+        assert!(written.ends_with(b"}\n"));
+        assert_eq!(writer.stream.get_ref().flushes, 0);
+    }
+
+    #[tokio::test]
+    async fn closed_output() {
+        let input = BufReader::new(&b"{\"method\": \"get_all_games\", \"params\": {}}\n"[..]);
+        let broken = Failing(std::io::ErrorKind::BrokenPipe);
+        assert!(serve(StateHandle::default(), input, broken).await.is_ok());
+
+        let input = BufReader::new(&b""[..]);
+        let full = Failing(std::io::ErrorKind::Other);
+        let error = serve(StateHandle::default(), input, full)
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_type, crate::errors::ErrorType::IO);
+    }
+
+    #[tokio::test]
+    async fn redacted_requests() {
+        let state = StateHandle::default();
+        let set_token = r#"{"method": "lichess_set_token", "params": {"tokn": "lip_secret"}}"#;
+        let error = error(set_token, &state).await;
+        assert_eq!(error["request"]["method"], "lichess_set_token");
+        assert_eq!(error["request"]["line"], Value::Null);
+        assert!(!error.to_string().contains("lip_secret"));
+
+        let malformed = r#"{"method": "lichess_set_token", "params": {"token": "lip_secret""#;
+        let error = self::error(malformed, &state).await;
+        assert_eq!(error["request"]["method"], Value::Null);
+        assert!(!error.to_string().contains("lip_secret"));
+    }
 }
@@ -0,0 +1,304 @@
+use crate::errors::{Error, ErrorType};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Keeps track of the engine processes spawned by this backend in `<dir>/<backend pid>.engines`,
+/// so that a later backend can kill them if this one dies without cleaning up (SIGKILL, crash, ...).
+/// Engines run in their own process group, whose id is the engine's pid.
+/// Each line holds an engine's pid and its start time, so that a process which reused the pid
+/// since is never killed. The directory must belong to the user and be private to them.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    dir: PathBuf,
+    backend_pid: u32,
+    /// Held while the engines file is read and rewritten, as engines start and quit concurrently.
+    file: Arc<Mutex<()>>,
+}
+
+impl Supervisor {
+    pub fn new(dir: PathBuf) -> Result<Supervisor, Error> {
+        create_private_dir(&dir)?;
+        Ok(Supervisor {
+            dir,
+            backend_pid: std::process::id(),
+            file: Arc::default(),
+        })
+    }
+
+    /// Shared by every backend run by the user, and by them only.
+    pub fn default_dir() -> PathBuf {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(runtime_dir) => PathBuf::from(runtime_dir).join("bigchess-engines"),
+            None => std::env::temp_dir().join(format!("bigchess-engines-{}", user_id())),
+        }
+    }
+
+    pub fn register(&self, engine_pid: u32) -> Result<(), Error> {
+        let _file = self.file.lock()?;
+        let mut engines = self.registered()?;
+        engines.push((engine_pid, start_time(engine_pid)));
+        self.save(&engines)
+    }
+
+    pub fn unregister(&self, engine_pid: u32) -> Result<(), Error> {
+        let _file = self.file.lock()?;
+        let mut engines = self.registered()?;
+        engines.retain(|(pid, _)| *pid != engine_pid);
+        self.save(&engines)
+    }
+
+    /// Kills the engines left behind by backends which are no longer running.
+    /// Returns the number of process groups killed.
+    pub fn reap_orphans(&self) -> Result<usize, Error> {
+        let mut killed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let backend_pid = match path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok())
+            {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if backend_pid == self.backend_pid || is_running(backend_pid) {
+                continue;
+            }
+
+            for (engine_pid, started) in read_engines(&path)? {
+                // Pids 0 and 1 would signal the backend's own group or every process
+                let same_process = started.is_some() && start_time(engine_pid) == started;
+                if engine_pid > 1 && same_process && kill_process_group(engine_pid) {
+                    killed += 1;
+                }
+            }
+            fs::remove_file(&path)?;
+        }
+        Ok(killed)
+    }
+
+    fn registered(&self) -> Result<Vec<(u32, Option<u64>)>, Error> {
+        let path = self.path();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        read_engines(&path)
+    }
+
+    fn save(&self, engines: &[(u32, Option<u64>)]) -> Result<(), Error> {
+        if engines.is_empty() {
+            if self.path().exists() {
+                fs::remove_file(self.path())?;
+            }
+            return Ok(());
+        }
+        let lines: Vec<String> = engines
+            .iter()
+            .map(|(pid, started)| match started {
+                Some(started) => format!("{} {}", pid, started),
+                None => pid.to_string(),
+            })
+            .collect();
+        fs::write(self.path(), lines.join("\n"))?;
+        Ok(())
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}.engines", self.backend_pid))
+    }
+}
+
+/// Pids with their start time, when it was known.
+fn read_engines(path: &Path) -> Result<Vec<(u32, Option<u64>)>, Error> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            Some((pid, fields.next().and_then(|started| started.parse().ok())))
+        })
+        .collect())
+}
+
+/// Start time of process `pid` in clock ticks since boot, from `/proc/<pid>/stat`. Unknown
+/// without procfs, where orphans are then never killed.
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may hold spaces and parentheses, the fields after it don't
+    let fields = &stat[stat.rfind(')')? + 1..];
+    // Field 22 of the file, the 20th after the command name
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if let Err(err) = fs::DirBuilder::new().mode(0o700).create(dir) {
+        if err.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(err.into());
+        }
+    }
+    // Another user may have created it first, in a shared temporary directory
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != user_id()
+        || metadata.permissions().mode() & 0o077 != 0
+    {
+        return Err(Error::new(ErrorType::IO).with_message(&format!(
+            "{} must be a directory private to its owner",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn user_id() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+fn user_id() -> u32 {
+    0
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists, EPERM means it belongs to someone else
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// Kills the process group led by `pid`. Returns false if there was nothing to kill.
+#[cfg(unix)]
+pub fn kill_process_group(pid: u32) -> bool {
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn kill_process_group(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::process::CommandExt;
+
+    #[test]
+    fn reap_orphans() {
+        let dir = std::env::temp_dir().join(format!("bigchess-supervisor-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let supervisor = Supervisor::new(dir.clone()).unwrap();
+
+        let mut orphan = std::process::Command::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        // A backend which exited without cleaning up after its engine
+        let mut exited = std::process::Command::new("true").spawn().unwrap();
+        let dead_backend = exited.id();
+        exited.wait().unwrap();
+        let started = start_time(orphan.id()).unwrap();
+        let lines = format!(
+            "{} {}\n1 0\n{} {}\n{}",
+            orphan.id(),
+            started,
+            std::process::id(),
+            started + 1,
+            std::process::id()
+        );
+        fs::write(dir.join(format!("{}.engines", dead_backend)), lines).unwrap();
+
+        // Engines of running backends are left alone
+        supervisor.register(4_000_000).unwrap();
+        supervisor.register(4_000_001).unwrap();
+        supervisor.unregister(4_000_000).unwrap();
+        assert_eq!(supervisor.registered().unwrap(), vec![(4_000_001, None)]);
+
+        // Only the orphan is killed: init, a pid reused since and an unknown start are skipped
+
+        assert_eq!(supervisor.reap_orphans().unwrap(), 1);
+        assert!(!orphan.wait().unwrap().success());
+        assert!(supervisor.path().exists());
+        assert!(!dir.join(format!("{}.engines", dead_backend)).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn concurrent_registrations() {
+        let dir = std::env::temp_dir().join(format!(
+            "bigchess-supervisor-concurrent-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let supervisor = Supervisor::new(dir.clone()).unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let supervisor = supervisor.clone();
+                std::thread::spawn(move || {
+                    for pid in (0..20).map(|i| 4_000_000 + thread * 100 + i) {
+                        supervisor.register(pid).unwrap();
+                        if pid % 2 == 0 {
+                            supervisor.unregister(pid).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut pids: Vec<u32> = supervisor
+            .registered()
+            .unwrap()
+            .into_iter()
+            .map(|(pid, _)| pid)
+            .collect();
+        pids.sort_unstable();
+        let expected: Vec<u32> = (0..8)
+            .flat_map(|thread| (0..20).map(move |i| 4_000_000 + thread * 100 + i))
+            .filter(|pid| pid % 2 == 1)
+            .collect();
+        assert_eq!(pids, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!(
+            "bigchess-supervisor-private-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        Supervisor::new(dir.clone()).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // Others could plant pids in it
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(Supervisor::new(dir.clone()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use crate::errors::{Error, ErrorType};
+use crate::lichess;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{Chess, Setup};
+
+pub const TABLEBASE_URL: &str = "https://tablebase.lichess.ovh";
+/// Largest positions covered by the online tablebase, kings included.
+pub const MAX_PIECES: usize = 7;
+
+/// Outcome of a position with perfect play, for the side to move.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TablebaseCategory {
+    #[serde(alias = "maybe-win", alias = "syzygy-win")]
+    Win,
+    /// Won, but not before the 50-move rule draws the game.
+    CursedWin,
+    Draw,
+    /// Lost, but the 50-move rule draws the game first.
+    BlessedLoss,
+    #[serde(alias = "maybe-loss", alias = "syzygy-loss")]
+    Loss,
+    /// The tablebase could not be reached.
+    Unavailable,
+    #[serde(other)]
+    Unknown,
+}
+
+impl TablebaseCategory {
+    /// Same outcome, for the other side.
+    fn flipped(self) -> TablebaseCategory {
+        match self {
+            TablebaseCategory::Win => TablebaseCategory::Loss,
+            TablebaseCategory::CursedWin => TablebaseCategory::BlessedLoss,
+            TablebaseCategory::BlessedLoss => TablebaseCategory::CursedWin,
+            TablebaseCategory::Loss => TablebaseCategory::Win,
+            other => other,
+        }
+    }
+}
+
+/// Tablebase result of a position and of each of its legal moves. Every category, dtz and dtm is
+/// from the point of view of the side to move, so that the best move has the best category.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TablebaseProbe {
+    pub category: TablebaseCategory,
+    /// Distance to the next capture or pawn move, in plies.
+    pub dtz: Option<i32>,
+    /// Distance to mate, in plies.
+    pub dtm: Option<i32>,
+    pub moves: Vec<TablebaseMove>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TablebaseMove {
+    pub uci: String,
+    pub san: String,
+    pub category: TablebaseCategory,
+    pub dtz: Option<i32>,
+    pub dtm: Option<i32>,
+}
+
+impl TablebaseProbe {
+    fn unavailable() -> TablebaseProbe {
+        TablebaseProbe {
+            category: TablebaseCategory::Unavailable,
+            dtz: None,
+            dtm: None,
+            moves: Vec::new(),
+        }
+    }
+}
+
+/// Answer of the lichess tablebase, whose moves are scored for the opponent.
+#[derive(Deserialize)]
+struct LichessProbe {
+    category: TablebaseCategory,
+    dtz: Option<i32>,
+    dtm: Option<i32>,
+    #[serde(default)]
+    moves: Vec<LichessMove>,
+}
+
+#[derive(Deserialize)]
+struct LichessMove {
+    uci: String,
+    san: String,
+    category: TablebaseCategory,
+    dtz: Option<i32>,
+    dtm: Option<i32>,
+}
+
+impl From<LichessProbe> for TablebaseProbe {
+    fn from(probe: LichessProbe) -> TablebaseProbe {
+        TablebaseProbe {
+            category: probe.category,
+            dtz: probe.dtz,
+            dtm: probe.dtm,
+            moves: probe
+                .moves
+                .into_iter()
+                .map(|m| TablebaseMove {
+                    uci: m.uci,
+                    san: m.san,
+                    category: m.category.flipped(),
+                    dtz: m.dtz.map(|dtz| -dtz),
+                    dtm: m.dtm.map(|dtm| -dtm),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Client of the lichess tablebase, remembering every position it was asked about. Cloning shares
+/// the cache.
+#[derive(Debug, Clone)]
+pub struct TablebaseClient {
+    http: reqwest::Client,
+    base_url: String,
+    cache: Arc<Mutex<HashMap<String, TablebaseProbe>>>,
+}
+
+impl TablebaseClient {
+    /// Client of the server at `base_url` instead of the lichess tablebase, for tests.
+    pub fn with_base_url(base_url: &str) -> TablebaseClient {
+        TablebaseClient {
+            http: lichess::http_client(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Fails for positions with more than `MAX_PIECES` pieces. A tablebase that can't be reached
+    /// gives an `Unavailable` probe, which isn't cached.
+    pub async fn probe(&self, position: &Chess) -> Result<TablebaseProbe, Error> {
+        if position.board().occupied().count() > MAX_PIECES {
+            return Err(Error::new(ErrorType::ChessRules).with_message(&format!(
+                "Only positions with at most {} pieces are in the tablebase",
+                MAX_PIECES
+            )));
+        }
+        let fen = shakmaty::fen::fen(position);
+        if let Some(probe) = self.cache.lock()?.get(&fen) {
+            return Ok(probe.clone());
+        }
+        match self.fetch(&fen).await {
+            Ok(probe) => {
+                self.cache.lock()?.insert(fen, probe.clone());
+                Ok(probe)
+            }
+            Err(_) => Ok(TablebaseProbe::unavailable()),
+        }
+    }
+
+    async fn fetch(&self, fen: &str) -> Result<TablebaseProbe, Error> {
+        let probe: LichessProbe = self
+            .http
+            .get(&format!("{}/standard", self.base_url))
+            .query(&[("fen", fen)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(probe.into())
+    }
+}
+
+impl Default for TablebaseClient {
+    fn default() -> TablebaseClient {
+        TablebaseClient::with_base_url(TABLEBASE_URL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const WON: &str = "4k3/8/4K3/4P3/8/8/8/8 w - - 0 1";
+    const DRAWN: &str = "4k3/8/8/4K3/8/8/8/8 w - - 0 1";
+
+    fn position(fen: &str) -> Chess {
+        let setup: shakmaty::fen::Fen = fen.parse().unwrap();
+        setup.position().unwrap()
+    }
+
+    #[tokio::test]
+    async fn probe() {
+        let server = MockServer::start().await;
+        let won = serde_json::json!({
+            "category": "win",
+            "dtz": 3,
+            "dtm": null,
+            "moves": [
+                {"uci": "e6f6", "san": "Kf6", "category": "loss", "dtz": -2, "dtm": null},
+                {"uci": "e5e6", "san": "e6", "category": "draw", "dtz": 0, "dtm": null},
+            ],
+        });
+        Mock::given(method("GET"))
+            .and(path("/standard"))
+            .and(query_param("fen", WON))
+            .respond_with(ResponseTemplate::new(200).set_body_json(won))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let drawn = serde_json::json!({"category": "draw", "dtz": 0, "dtm": 0, "moves": []});
+        Mock::given(method("GET"))
+            .and(path("/standard"))
+            .and(query_param("fen", DRAWN))
+            .respond_with(ResponseTemplate::new(200).set_body_json(drawn))
+            .mount(&server)
+            .await;
+        let client = TablebaseClient::with_base_url(&server.uri());
+
+        let probe = client.probe(&position(WON)).await.unwrap();
+        assert_eq!(probe.category, TablebaseCategory::Win);
+        assert_eq!(probe.dtz, Some(3));
+        assert_eq!(probe.dtm, None);
+        assert_eq!(probe.moves[0].category, TablebaseCategory::Win);
+        assert_eq!(probe.moves[0].dtz, Some(2));
+        assert_eq!(probe.moves[1].category, TablebaseCategory::Draw);
+        // Answered from the cache, the mock expects a single request
+        assert_eq!(client.probe(&position(WON)).await.unwrap(), probe);
+
+        let probe = client.probe(&position(DRAWN)).await.unwrap();
+        assert_eq!(probe.category, TablebaseCategory::Draw);
+        assert_eq!(probe.dtm, Some(0));
+
+        let offline = TablebaseClient::with_base_url("http://127.0.0.1:9");
+        let probe = offline.probe(&position(WON)).await.unwrap();
+        assert_eq!(probe.category, TablebaseCategory::Unavailable);
+
+        assert!(client.probe(&Chess::default()).await.is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::errors::Error;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Lines longer than this are truncated, engines can send huge `info string` lines.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Recent lines exchanged with an engine, kept in a bounded ring buffer and optionally copied to a file.
+/// Recording only appends under a short lock, file writes happen in a separate task.
+#[derive(Clone)]
+pub struct Transcript {
+    lines: Arc<Mutex<VecDeque<TranscriptLine>>>,
+    capacity: usize,
+    tee: Option<mpsc::UnboundedSender<String>>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TranscriptLine {
+    /// Milliseconds since the unix epoch.
+    pub time_ms: u128,
+    pub direction: Direction,
+    pub line: String,
+}
+
+impl Transcript {
+    /// Keeps the last `capacity` lines. When `tee_path` is given, every line is also appended to that file.
+    pub fn new(capacity: usize, tee_path: Option<&str>) -> Result<Transcript, Error> {
+        let tee = match tee_path {
+            None => None,
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                let (sender, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(write_lines(tokio::fs::File::from_std(file), receiver));
+                Some(sender)
+            }
+        };
+
+        Ok(Transcript {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity.min(4096)))),
+            capacity,
+            tee,
+        })
+    }
+
+    pub fn record(&self, direction: Direction, line: &str) {
+        let entry = TranscriptLine {
+            time_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default(),
+            direction,
+            line: truncate(line, MAX_LINE_LENGTH).to_string(),
+        };
+
+        if let Some(tee) = &self.tee {
+            let arrow = match direction {
+                Direction::Sent => ">>",
+                Direction::Received => "<<",
+            };
+            // The file is best effort, the ring buffer is still there if it can't be written
+            let _ = tee.send(format!("{} {} {}\n", entry.time_ms, arrow, entry.line));
+        }
+
+        if let Ok(mut lines) = self.lines.lock() {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            if self.capacity > 0 {
+                lines.push_back(entry);
+            }
+        }
+    }
+
+    /// Recorded lines, oldest first.
+    pub fn lines(&self) -> Result<Vec<TranscriptLine>, Error> {
+        Ok(self.lines.lock()?.iter().cloned().collect())
+    }
+}
+
+async fn write_lines(mut file: tokio::fs::File, mut lines: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = lines.recv().await {
+        if file.write_all(line.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn truncate(line: &str, max_length: usize) -> &str {
+    if line.len() <= max_length {
+        return line;
+    }
+    let mut end = max_length;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(transcript: &Transcript) -> Vec<String> {
+        transcript
+            .lines()
+            .unwrap()
+            .into_iter()
+            .map(|l| l.line)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ring_buffer() {
+        let transcript = Transcript::new(3, None).unwrap();
+        transcript.record(Direction::Sent, "uci");
+        transcript.record(Direction::Received, "id name Mock");
+        transcript.record(Direction::Received, "uciok");
+        assert_eq!(texts(&transcript), vec!["uci", "id name Mock", "uciok"]);
+
+        transcript.record(Direction::Sent, "isready");
+        transcript.record(Direction::Received, "readyok");
+        assert_eq!(texts(&transcript), vec!["uciok", "isready", "readyok"]);
+
+        let lines = transcript.lines().unwrap();
+        assert_eq!(lines[1].direction, Direction::Sent);
+        assert!(lines[0].time_ms <= lines[2].time_ms);
+    }
+
+    #[tokio::test]
+    async fn line_length() {
+        let transcript = Transcript::new(10, None).unwrap();
+        let long = format!("info string {}", "é".repeat(MAX_LINE_LENGTH));
+        transcript.record(Direction::Received, &long);
+        let recorded = &transcript.lines().unwrap()[0].line;
+        assert!(recorded.len() <= MAX_LINE_LENGTH);
+        assert!(long.starts_with(recorded.as_str()));
+    }
+
+    #[tokio::test]
+    async fn tee() {
+        let path = std::env::temp_dir().join(format!("bigchess-tee-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let transcript = Transcript::new(10, Some(path.to_str().unwrap())).unwrap();
+        transcript.record(Direction::Sent, "uci");
+        transcript.record(Direction::Received, "uciok");
+
+        // Let the logger task catch up
+        tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+        let written = std::fs::read_to_string(&path).unwrap();
+        let written: Vec<&str> = written
+            .lines()
+            .map(|l| l.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(written, vec![">> uci", "<< uciok"]);
+    }
+}
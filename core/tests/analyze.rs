@@ -0,0 +1,86 @@
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const GAMES: &str = r#"[White "Alice"]
+[Black "Bob"]
+[Result "1-0"]
+
+1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0
+
+[White "Carol"]
+[Black "Dave"]
+[Result "*"]
+
+1. d4 d5 2. c4 *
+
+[White "Erin"]
+[Black "Frank"]
+[Result "*"]
+
+1. e4 e5 2. Ke3 *
+"#;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bigchess-analyze-{}-{}", std::process::id(), name))
+}
+
+/// Mock engine giving +0.25 to the side to move in every position, through a script taking no arguments.
+fn engine() -> PathBuf {
+    let mock = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/mock_engine.sh");
+    let path = temp_path("engine.sh");
+    let moves = vec!["e2e4@25"; 100].join(" ");
+    std::fs::write(&path, format!("#!/bin/sh\nexec {} {}\n", mock, moves)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+fn analyze(engine: &Path, input: &Path, output: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bigchess-core"))
+        .arg("analyze")
+        .arg("--engine")
+        .arg(engine)
+        .args(["--movetime", "10", "--threads", "2"])
+        .arg("--in")
+        .arg(input)
+        .arg("--out")
+        .arg(output)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn annotate_file() {
+    let engine = engine();
+    let input = temp_path("games.pgn");
+    let output = temp_path("annotated.pgn");
+    std::fs::write(&input, GAMES).unwrap();
+
+    let result = analyze(&engine, &input, &output);
+    // The illegal king move fails the last game
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("Alice - Bob: accuracy"));
+    assert!(stderr.contains("Erin - Frank: failed"));
+    assert!(stderr.contains("2 games annotated, 1 failed"));
+
+    let annotated = std::fs::read_to_string(&output).unwrap();
+    let games: Vec<&str> = annotated.split("[Event ").skip(1).collect();
+    assert_eq!(games.len(), 3);
+    assert!(games[0].contains("[WhiteAccuracy \"81.3\"]"));
+    assert!(games[0].contains("1. e4 { [%eval -0.25] }"));
+    assert!(games[0].contains("4. Qxf7# 1-0"));
+    assert!(games[1].contains("[White \"Carol\"]"));
+    assert!(games[1].contains("2. c4 { [%eval -0.25] }"));
+    // Written as it was read
+    assert!(games[2].contains("1. e4 e5 2. Ke3 *"));
+
+    std::fs::write(&input, GAMES.split("[White \"Erin\"]").next().unwrap()).unwrap();
+    assert!(analyze(&engine, &input, &output).status.success());
+
+    for path in &[engine, input, output] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+fn backend(port: u16) -> Command {
+    let session = std::env::temp_dir().join(format!("bigchess-http-session-{}.json", port));
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bigchess-core"));
+    command
+        .args(["--http-port", &port.to_string(), "--no-stdio", "--session"])
+        .arg(session)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    command
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+struct Backend(Child);
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::test]
+async fn serves_games() {
+    let port = free_port();
+    let _backend = Backend(backend(port).spawn().unwrap());
+    let url = format!("http://127.0.0.1:{}", port);
+    let client = reqwest::Client::new();
+
+    let new_game = r#"{"method": "new_game", "params": {"id": "g1"}}"#;
+    let mut created = None;
+    for _ in 0..50 {
+        match client
+            .post(&format!("{}/request", url))
+            .body(new_game)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                created = Some(response);
+                break;
+            }
+            // Not listening yet
+            Err(_) => tokio::time::delay_for(Duration::from_millis(100)).await,
+        }
+    }
+    assert!(created
+        .expect("backend should listen")
+        .status()
+        .is_success());
+
+    let games: serde_json::Value = client
+        .get(&format!("{}/games", url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(games["changed_games"][0]["id"], "g1");
+}
+
+#[test]
+fn port_in_use() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let output = backend(port).output().unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Could not listen on 127.0.0.1:{}", port)));
+}
@@ -0,0 +1,110 @@
+#!/bin/sh
+# Minimal scripted UCI engine used by the test suite.
+# Every search is answered with the next move given on the command line, written `e2e4`, or
+# `e2e4:e7e5` to also predict the opponent's reply (`bestmove e2e4 ponder e7e5`).
+# A `@score` suffix sets the reported score: `e2e4@-35` for centipawns, `e2e4@#3` for a mate,
+# optionally followed by win/draw/loss statistics (`e2e4@35=600,300,100`), reported once
+# UCI_ShowWDL is enabled.
+# The special move `wait` makes the engine search until it receives `stop`.
+#
+# Leading flags:
+#   --log=<path>  append received commands to that file
+#   --bare        advertise no options
+#   --skill       also advertise a Skill Level option, from 0 to 20
+
+options="Ponder UCI_Chess960 UCI_ShowWDL"
+while true; do
+    case "$1" in
+        --log=*)
+            log="${1#--log=}"
+            shift
+            ;;
+        --bare)
+            options=
+            shift
+            ;;
+        --skill)
+            skill=1
+            shift
+            ;;
+        *)
+            break
+            ;;
+    esac
+done
+
+bestmove() {
+    move="${1%%@*}"
+    case "$1" in
+        *@*) score="${1#*@}" ;;
+        *) score=0 ;;
+    esac
+    wdl=
+    case "$score" in
+        *=*)
+            if [ -n "$show_wdl" ]; then
+                wdl=" wdl $(echo "${score#*=}" | tr , ' ')"
+            fi
+            score="${score%%=*}"
+            ;;
+    esac
+    case "$score" in
+        \#*) score="mate ${score#\#}" ;;
+        *) score="cp $score" ;;
+    esac
+    echo "info depth 1 score $score$wdl pv ${move%%:*}"
+    case "$move" in
+        *:*) echo "bestmove ${move%%:*} ponder ${move#*:}" ;;
+        *) echo "bestmove $move" ;;
+    esac
+}
+
+while read -r line; do
+    if [ -n "$log" ]; then
+        echo "$line" >> "$log"
+    fi
+
+    case "$line" in
+        uci)
+            echo "id name Mock Engine"
+            for option in $options; do
+                echo "option name $option type check default false"
+            done
+            if [ -n "$skill" ]; then
+                echo "option name Skill Level type spin default 20 min 0 max 20"
+            fi
+            echo "uciok"
+            ;;
+        "setoption name UCI_ShowWDL value true")
+            show_wdl=1
+            ;;
+        isready)
+            echo "readyok"
+            ;;
+        "go ponder"*)
+            searching=1
+            ;;
+        go*)
+            if [ "$1" = "wait" ]; then
+                searching=1
+            else
+                bestmove "$1"
+            fi
+            shift
+            ;;
+        ponderhit)
+            searching=
+            bestmove "$1"
+            shift
+            ;;
+        stop)
+            if [ -n "$searching" ]; then
+                searching=
+                echo "bestmove 0000"
+            fi
+            ;;
+        quit)
+            exit 0
+            ;;
+    esac
+done
@@ -0,0 +1,75 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde_json::Value;
+
+const RECORDING: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/scholars_mate.recording"
+);
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bigchess-replay-{}-{}", std::process::id(), name))
+}
+
+/// Every line the backend answered, `requests` written on its stdin.
+fn backend(args: &[&str], extra: &[&Path], requests: &[&str]) -> Vec<Value> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bigchess-core"))
+        .args(args)
+        .args(extra)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for request in requests {
+        writeln!(stdin, "{}", request).unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn replays_a_recording() {
+    let answers = backend(&["--replay", RECORDING, "--replay-speed", "20"], &[], &[]);
+    // The initial message, then one answer per request
+    assert_eq!(answers.len(), 12);
+    assert!(answers[0]["changed_games"].as_array().unwrap().is_empty());
+    let mate = "r1bqkb1r/pppp1Qpp/2n2n2/4p3/2B1P3/8/PPPP1PPP/RNB1K1NR b KQkq - 0 4";
+    assert_eq!(answers[8]["changed_games"][0]["game"]["fen"], mate);
+    let before_mate = "r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 4 4";
+    assert_eq!(answers[9]["changed_games"][0]["game"]["fen"], before_mate);
+    assert_eq!(answers[10]["error"]["type"], "Deserialize");
+    assert_eq!(answers[11]["changed_games"][0]["game"]["fen"], before_mate);
+}
+
+#[test]
+fn records_what_it_replays() {
+    let recording = temp_path("session.recording");
+    let _ = std::fs::remove_file(&recording);
+    let requests: Vec<String> = std::fs::read_to_string(RECORDING)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    let requests: Vec<&str> = requests.iter().map(String::as_str).collect();
+    let session = temp_path("session.json");
+    let live = backend(
+        &["--record"],
+        &[&recording, Path::new("--session"), &session],
+        &requests,
+    );
+
+    let replayed = backend(&["--replay"], &[&recording], &[]);
+    assert_eq!(replayed, live);
+    std::fs::remove_file(&recording).unwrap();
+    let _ = std::fs::remove_file(&session);
+}
@@ -0,0 +1,100 @@
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+struct Backend(Child);
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bigchess-sockets-{}-{}", std::process::id(), name))
+}
+
+/// Retries while the backend starts.
+fn connect<S>(connect: impl Fn() -> std::io::Result<S>) -> S {
+    for _ in 0..50 {
+        if let Ok(stream) = connect() {
+            return stream;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    panic!("backend should listen");
+}
+
+/// Sends each line and reads as many answers, after the games sent once connected.
+fn exchange<S: std::io::Read + Write>(stream: S, lines: &[&str], answers: usize) -> Vec<Value> {
+    let mut reader = BufReader::new(stream);
+    for line in lines {
+        writeln!(reader.get_mut(), "{}", line).unwrap();
+    }
+    (0..answers)
+        .map(|_| {
+            let mut answer = String::new();
+            reader.read_line(&mut answer).unwrap();
+            serde_json::from_str(&answer).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn transports() {
+    let port = free_port();
+    let socket = temp_path("backend.sock");
+    let backend = Command::new(env!("CARGO_BIN_EXE_bigchess-core"))
+        .args(["--listen-tcp", &format!("127.0.0.1:{}", port)])
+        .arg("--listen-unix")
+        .arg(&socket)
+        .args(["--auth-token", "secret", "--no-stdio", "--session"])
+        .arg(temp_path("session.json"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut backend = Backend(backend);
+
+    let mut started = String::new();
+    let stderr = backend.0.stderr.take().unwrap();
+    BufReader::new(stderr).read_line(&mut started).unwrap();
+    assert!(started.contains(&format!("TCP on 127.0.0.1:{}", port)));
+    assert!(started.contains("Unix socket"));
+    assert!(!started.contains("stdio"));
+
+    let authenticate = r#"{"token": "secret"}"#;
+    let new_game = r#"{"method": "new_game", "params": {"id": "g1"}}"#;
+    let tcp = connect(|| TcpStream::connect(("127.0.0.1", port)));
+    let answers = exchange(tcp, &[authenticate, new_game], 2);
+    assert!(answers[0]["error"].is_null());
+    assert_eq!(answers[1]["changed_games"][0]["id"], "g1");
+    assert_eq!(answers[1]["changed_games"][0]["game"]["moves"], json!([]));
+
+    // Both transports share the games
+    let unix = connect(|| UnixStream::connect(&socket));
+    let answers = exchange(unix, &[authenticate], 1);
+    assert_eq!(answers[0]["changed_games"][0]["id"], "g1");
+
+    let refused = connect(|| TcpStream::connect(("127.0.0.1", port)));
+    let answers = exchange(refused, &[r#"{"token": "guess"}"#, new_game], 1);
+    assert_eq!(answers[0]["error"]["type"], "Unauthorized");
+    std::fs::remove_file(&socket).unwrap();
+}
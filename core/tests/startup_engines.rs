@@ -0,0 +1,86 @@
+#![cfg(unix)]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde_json::Value;
+
+const MOCK_ENGINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/mock_engine.sh");
+
+/// Every line the backend answered with `requests` on stdin, the initial message first.
+fn backend(args: &[&str], requests: &[&str]) -> Vec<Value> {
+    let session = std::env::temp_dir().join("bigchess-startup-engines-session.json");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bigchess-core"))
+        .args(args)
+        .arg("--session")
+        .arg(session)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for request in requests {
+        writeln!(stdin, "{}", request).unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn registered_at_startup() {
+    let mock = format!("{}:mock", MOCK_ENGINE);
+    let args = [
+        "--engine",
+        &mock,
+        "--engine",
+        "/nonexistent/engine",
+        "--engine",
+        MOCK_ENGINE,
+        "--engine-option",
+        "Hash=64",
+    ];
+    let new_game = r#"{"method": "new_game", "params": {"id": "g1"}}"#;
+    // No engine_id: the first engine is used
+    let quick_eval =
+        r#"{"method": "quick_eval", "params": {"id": "g1", "movetime_per_ply_ms": 10}}"#;
+    let answers = backend(&args, &[new_game, quick_eval]);
+
+    let initial = &answers[0];
+    let ids: Vec<&str> = initial["engines"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|engine| engine["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["mock", "mock_engine"]);
+    let warnings = initial["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["type"], "Engine");
+    assert!(warnings[0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("/nonexistent/engine"));
+
+    let evaluated = answers
+        .iter()
+        .skip(1)
+        .find(|answer| answer["notification"].is_null() && answer["changed_games"][0]["id"] == "g1")
+        .unwrap();
+    assert!(evaluated["error"].is_null());
+}
+
+#[test]
+fn no_default_engine() {
+    let new_game = r#"{"method": "new_game", "params": {"id": "g1"}}"#;
+    let quick_eval =
+        r#"{"method": "quick_eval", "params": {"id": "g1", "movetime_per_ply_ms": 10}}"#;
+    let answers = backend(&["--start"], &[new_game, quick_eval]);
+    assert!(answers[0]["engines"].is_null());
+    assert!(answers[0]["warnings"].is_null());
+    assert_eq!(answers[2]["error"]["type"], "Engine");
+}
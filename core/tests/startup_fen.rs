@@ -0,0 +1,41 @@
+use std::process::{Command, Output, Stdio};
+
+fn backend(args: &[&str]) -> Output {
+    let session = std::env::temp_dir().join("bigchess-startup-fen-session.json");
+    Command::new(env!("CARGO_BIN_EXE_bigchess-core"))
+        .args(args)
+        .arg("--session")
+        .arg(session)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+/// First line of stdout, sent before any request.
+fn initial_message(output: &Output) -> serde_json::Value {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    serde_json::from_str(stdout.lines().next().unwrap()).unwrap()
+}
+
+#[test]
+fn startup_game() {
+    let fen = "8/8/8/8/8/4k3/4p3/4K3 w - - 0 1";
+    let output = backend(&["--fen", fen]);
+    assert!(output.status.success());
+    let message = initial_message(&output);
+    assert_eq!(message["changed_games"][0]["id"], "startup");
+    assert_eq!(message["changed_games"][0]["game"]["fen"], fen);
+
+    let output = backend(&["--fen", fen, "--id", "pawn-ending"]);
+    let message = initial_message(&output);
+    assert_eq!(message["changed_games"][0]["id"], "pawn-ending");
+}
+
+#[test]
+fn invalid_fen() {
+    let output = backend(&["--fen", "8/8/8/8/8/8/8/8 w - - 0 1"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--fen"));
+}